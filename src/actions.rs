@@ -0,0 +1,234 @@
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use std::path::Path;
+
+// The name of the marker attribute, or alternate data stream on Windows, that mark sets.
+#[cfg_attr(
+    not(any(target_os = "linux", target_family = "windows")),
+    allow(dead_code)
+)]
+const MARKER: &str = "cloak";
+
+// Enum of what can be done to the files and folders that match
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Action {
+    // Hide them, with a dot on Unix or the hidden attribute on Windows, or by moving them into the vault
+    Hide,
+    // Take away permission to write to them, or set the read-only attribute on Windows
+    ReadOnly,
+    // Change their permissions to the mode given by --chmod
+    Chmod,
+    // Set a marker attribute on them, named cloak, so other tools can find them
+    Mark,
+}
+
+// The actions to take on each file and folder that matches. Everything other than hiding is done first, while the
+// path still has its original name. Each is only done if it hasn't been already, so running the same command twice
+// changes nothing the second time. Symlinks are only ever hidden, since their permissions are their targets', and
+// Linux doesn't allow marking them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Actions {
+    hide: bool,
+    read_only: bool,
+    chmod: Option<u32>,
+    mark: bool,
+}
+
+// Only hide, as cloak always has.
+impl Default for Actions {
+    fn default() -> Self {
+        Self {
+            hide: true,
+            read_only: false,
+            chmod: None,
+            mark: false,
+        }
+    }
+}
+
+impl Actions {
+    // Build the actions to take from the ones given, with the mode to set if chmod is one of them. Fails if any of them
+    // isn't supported on this platform.
+    pub fn new(actions: &[Action], mode: Option<u32>) -> Result<Self> {
+        let chmod = actions.contains(&Action::Chmod);
+        match (chmod, mode) {
+            (true, None) => bail!("--action chmod needs the mode to set with --chmod"),
+            (false, Some(_)) => bail!("--chmod is only used with --action chmod"),
+            _ => {}
+        }
+        if chmod && actions.contains(&Action::ReadOnly) {
+            bail!("--action read-only can't be used with --action chmod, which sets whether it can be written itself")
+        }
+        if chmod && cfg!(not(target_family = "unix")) {
+            bail!("--action chmod is only supported on Unix")
+        }
+        if actions.contains(&Action::Mark)
+            && cfg!(not(any(target_os = "linux", target_family = "windows")))
+        {
+            bail!("--action mark is only supported on Linux and Windows so far")
+        }
+        Ok(Self {
+            hide: actions.contains(&Action::Hide),
+            read_only: actions.contains(&Action::ReadOnly),
+            chmod: mode,
+            mark: actions.contains(&Action::Mark),
+        })
+    }
+
+    // Returns true if matching paths are to be hidden.
+    pub fn hides(&self) -> bool {
+        self.hide
+    }
+
+    // Returns true if anything other than hiding is to be done.
+    pub fn extra(&self) -> bool {
+        self.read_only || self.chmod.is_some() || self.mark
+    }
+
+    // Returns true if anything other than hiding is still left to be done to a path. Anything that can't be checked is
+    // counted as left to do, so that the error is reported when doing it.
+    pub fn pending(&self, path: &Path) -> bool {
+        let Ok(metadata) = path.symlink_metadata() else {
+            return self.extra();
+        };
+        !metadata.is_symlink()
+            && ((self.read_only && !metadata.permissions().readonly())
+                || self.chmod.is_some_and(|mode| !has_mode(&metadata, mode))
+                || (self.mark && !is_marked(path)))
+    }
+
+    // Do everything other than hiding to a path, skipping anything already done. Returns true if anything was changed.
+    pub fn apply(&self, path: &Path) -> Result<bool> {
+        use anyhow::Context;
+
+        let metadata = path
+            .symlink_metadata()
+            .with_context(|| format!("Failed to read permissions of {}", path.display()))?;
+        if metadata.is_symlink() {
+            return Ok(false);
+        }
+
+        let mut changed = false;
+        if let Some(mode) = self.chmod.filter(|&mode| !has_mode(&metadata, mode)) {
+            set_mode(path, mode)?;
+            changed = true;
+        }
+        if self.read_only {
+            let mut permissions = metadata.permissions();
+            if !permissions.readonly() {
+                permissions.set_readonly(true);
+                std::fs::set_permissions(path, permissions)
+                    .with_context(|| format!("Failed to make {} read-only", path.display()))?;
+                changed = true;
+            }
+        }
+        if self.mark && !is_marked(path) {
+            mark(path)?;
+            changed = true;
+        }
+        Ok(changed)
+    }
+}
+
+// Helper function to check if a path already has the given permissions.
+#[cfg(target_family = "unix")]
+fn has_mode(metadata: &std::fs::Metadata, mode: u32) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    metadata.permissions().mode() & 0o7777 == mode
+}
+
+// Modes can't be set on Windows, which is refused up front.
+#[cfg(target_family = "windows")]
+fn has_mode(_: &std::fs::Metadata, _: u32) -> bool {
+    true
+}
+
+// Helper function to set the permissions of a path.
+#[cfg(target_family = "unix")]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use anyhow::Context;
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).with_context(|| {
+        format!(
+            "Failed to change permissions of {} to {mode:o}",
+            path.display()
+        )
+    })
+}
+
+// Modes can't be set on Windows, which is refused up front.
+#[cfg(target_family = "windows")]
+fn set_mode(_: &Path, _: u32) -> Result<()> {
+    bail!("--action chmod is only supported on Unix")
+}
+
+// Helper function to check if a path has the marker attribute.
+#[cfg(target_os = "linux")]
+fn is_marked(path: &Path) -> bool {
+    use nix::libc;
+    use std::os::unix::ffi::OsStrExt;
+
+    let (Ok(path), Ok(name)) = (
+        std::ffi::CString::new(path.as_os_str().as_bytes()),
+        std::ffi::CString::new(format!("user.{MARKER}")),
+    ) else {
+        return false;
+    };
+    unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) >= 0 }
+}
+
+// Helper function to check if a path has the marker stream.
+#[cfg(target_family = "windows")]
+fn is_marked(path: &Path) -> bool {
+    marker_stream(path).exists()
+}
+
+// Marking isn't supported anywhere else, which is refused up front.
+#[cfg(not(any(target_os = "linux", target_family = "windows")))]
+fn is_marked(_: &Path) -> bool {
+    true
+}
+
+// Helper function to set the marker attribute on a path.
+#[cfg(target_os = "linux")]
+fn mark(path: &Path) -> Result<()> {
+    use anyhow::Context;
+    use nix::libc;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .with_context(|| format!("Failed to mark {}", path.display()))?;
+    let name = std::ffi::CString::new(format!("user.{MARKER}"))?;
+    let result =
+        unsafe { libc::lsetxattr(c_path.as_ptr(), name.as_ptr(), b"1".as_ptr().cast(), 1, 0) };
+    if result < 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to mark {}", path.display()));
+    }
+    Ok(())
+}
+
+// Helper function to write the marker stream on a path.
+#[cfg(target_family = "windows")]
+fn mark(path: &Path) -> Result<()> {
+    use anyhow::Context;
+
+    std::fs::write(marker_stream(path), b"1")
+        .with_context(|| format!("Failed to mark {}", path.display()))
+}
+
+// Marking isn't supported anywhere else, which is refused up front.
+#[cfg(not(any(target_os = "linux", target_family = "windows")))]
+fn mark(_: &Path) -> Result<()> {
+    bail!("--action mark is only supported on Linux and Windows so far")
+}
+
+// Helper function to get the path of the marker stream of a path.
+#[cfg(target_family = "windows")]
+fn marker_stream(path: &Path) -> std::path::PathBuf {
+    let mut stream = path.as_os_str().to_owned();
+    stream.push(format!(":{MARKER}"));
+    stream.into()
+}
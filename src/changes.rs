@@ -0,0 +1,629 @@
+use crate::{context, filesystem::CreateModes, output};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+// Where the change history of a root was up to at some point, so what's changed in it since then can be read back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Cursor {
+    // The NTFS change journal of the volume the root is on, by the id of the journal, which changes if it's deleted and
+    // created again, and the update sequence number it was going to give the next change.
+    Usn { journal: u64, next: i64 },
+    // The FSEvents history on macOS, by the id of the last event.
+    FsEvents { event: u64 },
+}
+
+// Remembers where the change history of each root was up to when a run started, in a state file, so the next run
+// given the same file only has to look at what changed since then, rather than walking everything. That's read from
+// the NTFS change journal on Windows, and the FSEvents history on macOS. Roots whose history can't be read, that
+// haven't been searched with the file before, or whose history doesn't go back far enough, are searched in full.
+#[derive(Debug)]
+pub struct Since {
+    path: PathBuf,
+    modes: CreateModes,
+    cursors: BTreeMap<PathBuf, Cursor>,
+    changed: BTreeMap<PathBuf, Vec<PathBuf>>,
+}
+
+impl Since {
+    // Load the state file at the given path, and read what's changed below each root since it was saved. If it doesn't
+    // exist yet, then every root is searched in full, and it's created when it's saved. Roots are kept by the paths
+    // they resolve to, so the same state file works from any directory.
+    pub fn load(
+        path: PathBuf,
+        roots: &[String],
+        modes: CreateModes,
+        ctx: &context::RunContext,
+    ) -> Result<Self> {
+        let mut cursors: BTreeMap<PathBuf, Cursor> = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .with_context(|| format!("Failed to parse state file {}", path.display()))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read state file {}", path.display()))
+            }
+        };
+
+        // Find out where each root's history is up to before anything is searched, so whatever changes during the run
+        // is picked up by the next one.
+        let mut changed = BTreeMap::new();
+        for root in roots {
+            let Ok(resolved) = fs::canonicalize(root) else {
+                continue;
+            };
+            let now = match cursor(&resolved) {
+                Ok(Some(now)) => now,
+                Ok(None) => continue,
+                Err(e) => {
+                    ctx.reporter.report(&output::Event::Notice(format!(
+                        "Searching {root} in full, since its changes can't be tracked: {e:#}"
+                    )));
+                    continue;
+                }
+            };
+            let Some(last) = cursors.insert(resolved.clone(), now) else {
+                continue;
+            };
+            match changes(&resolved, last, now) {
+                Ok(Some(paths)) => {
+                    if ctx.verbose {
+                        ctx.reporter.report(&output::Event::Info(format!(
+                            "Searching {} paths in {root} that changed since the last run",
+                            paths.len()
+                        )));
+                    }
+                    // Give the paths back below the root as it was given, the same as walking it would
+                    let paths = paths
+                        .iter()
+                        .filter_map(|path| path.strip_prefix(&resolved).ok())
+                        .map(|relative| match relative.as_os_str().is_empty() {
+                            true => PathBuf::from(root),
+                            false => Path::new(root).join(relative),
+                        })
+                        .collect();
+                    changed.insert(PathBuf::from(root), paths);
+                }
+                Ok(None) => ctx.reporter.report(&output::Event::Notice(format!(
+                    "Searching {root} in full, since its change history doesn't go back to the last run"
+                ))),
+                Err(e) => ctx.reporter.report(&output::Event::Notice(format!(
+                    "Searching {root} in full, since its changes couldn't be read: {e:#}"
+                ))),
+            }
+        }
+
+        Ok(Self {
+            path,
+            modes,
+            cursors,
+            changed,
+        })
+    }
+
+    // Returns the paths that changed below a root since the last run, as it was given, or None if it has to be searched
+    // in full.
+    pub fn changed(&self, root: &Path) -> Option<&[PathBuf]> {
+        self.changed.get(root).map(Vec::as_slice)
+    }
+
+    // Save where each root's history was up to when the run started, for the next run.
+    pub fn save(&self) -> Result<()> {
+        let mut contents = serde_json::to_vec_pretty(&self.cursors)
+            .with_context(|| format!("Failed to write state file {}", self.path.display()))?;
+        contents.push(b'\n');
+        self.modes
+            .write(&self.path, contents)
+            .with_context(|| format!("Failed to write state file {}", self.path.display()))
+    }
+}
+
+// Windows only function to get where the change journal of the volume a root is on is up to. Reading it needs
+// administrator rights, and a volume that keeps one, such as NTFS or ReFS.
+#[cfg(target_family = "windows")]
+fn cursor(root: &Path) -> Result<Option<Cursor>> {
+    let journal = usn::Volume::of(root)?.query()?;
+    Ok(Some(Cursor::Usn {
+        journal: journal.id,
+        next: journal.next,
+    }))
+}
+
+// macOS only function to get the id of the last FSEvents event, which covers every volume.
+#[cfg(target_os = "macos")]
+fn cursor(_root: &Path) -> Result<Option<Cursor>> {
+    let event = unsafe { fsevent_sys::FSEventsGetCurrentEventId() };
+    Ok(Some(Cursor::FsEvents { event }))
+}
+
+// There's no history of changes to read anywhere else, so roots are always searched in full.
+#[cfg(not(any(windows, target_os = "macos")))]
+fn cursor(_root: &Path) -> Result<Option<Cursor>> {
+    Ok(None)
+}
+
+// Windows only function to read the paths below a root that changed between two points in the change journal of its
+// volume, or None if the journal was replaced, or has been trimmed past the first of them since.
+#[cfg(target_family = "windows")]
+fn changes(root: &Path, last: Cursor, now: Cursor) -> Result<Option<Vec<PathBuf>>> {
+    use std::collections::HashMap;
+
+    let (
+        Cursor::Usn {
+            journal,
+            next: from,
+        },
+        Cursor::Usn {
+            journal: now_journal,
+            next: until,
+        },
+    ) = (last, now)
+    else {
+        return Ok(None);
+    };
+    if journal != now_journal {
+        return Ok(None);
+    }
+
+    let volume = usn::Volume::of(root)?;
+    let Some(files) = volume.read(journal, from, until)? else {
+        return Ok(None);
+    };
+
+    // Look up each folder by its id only once, leaving out anything in a folder that's gone since
+    let mut parents: HashMap<u64, Option<PathBuf>> = HashMap::new();
+    Ok(Some(
+        files
+            .into_values()
+            .filter_map(|(parent, name)| {
+                let parent = parents
+                    .entry(parent)
+                    .or_insert_with(|| volume.path_of(parent).ok());
+                parent.as_ref().map(|parent| parent.join(name))
+            })
+            .filter(|path| path.starts_with(root))
+            .collect(),
+    ))
+}
+
+// macOS only function to read the paths below a root that changed between two FSEvents events, or None if the history
+// says the whole root has to be searched again.
+#[cfg(target_os = "macos")]
+fn changes(root: &Path, last: Cursor, now: Cursor) -> Result<Option<Vec<PathBuf>>> {
+    use anyhow::bail;
+    use fsevent_sys::{self as fsevent, core_foundation as cf};
+    use std::ffi::{c_char, c_void, CStr, CString, OsStr};
+    use std::os::unix::ffi::OsStrExt;
+
+    let (Cursor::FsEvents { event: since }, Cursor::FsEvents { event: until }) = (last, now) else {
+        return Ok(None);
+    };
+
+    // What the events replayed so far have said
+    struct History<'a> {
+        root: &'a Path,
+        until: u64,
+        paths: Vec<PathBuf>,
+        whole: bool,
+    }
+
+    // Gather up the path of each event up to the one the run started at, and stop once the history has been replayed.
+    // A folder whose events were coalesced or dropped has to be searched through again, which the whole root does if
+    // it's the root or above it.
+    extern "C" fn callback(
+        _stream: fsevent::FSEventStreamRef,
+        info: *mut c_void,
+        count: usize,
+        paths: *mut c_void,
+        flags: *const fsevent::FSEventStreamEventFlags,
+        ids: *const fsevent::FSEventStreamEventId,
+    ) {
+        let history = unsafe { &mut *(info as *mut History) };
+        let paths = unsafe { std::slice::from_raw_parts(paths as *const *const c_char, count) };
+        let flags = unsafe { std::slice::from_raw_parts(flags, count) };
+        let ids = unsafe { std::slice::from_raw_parts(ids, count) };
+        for ((&path, &flags), &id) in paths.iter().zip(flags).zip(ids) {
+            if flags & fsevent::kFSEventStreamEventFlagHistoryDone != 0 {
+                unsafe { cf::CFRunLoopStop(cf::CFRunLoopGetCurrent()) };
+                continue;
+            }
+            if id > history.until {
+                continue;
+            }
+            let path = PathBuf::from(OsStr::from_bytes(
+                unsafe { CStr::from_ptr(path) }.to_bytes(),
+            ));
+            if flags & fsevent::kFSEventStreamEventFlagMustScanSubDirs != 0
+                && history.root.starts_with(&path)
+            {
+                history.whole = true;
+            }
+            history.paths.push(path);
+        }
+    }
+
+    let mut history = History {
+        root,
+        until,
+        paths: Vec::new(),
+        whole: false,
+    };
+    let root_string = CString::new(root.as_os_str().as_bytes())
+        .with_context(|| format!("Failed to read the history of {}", root.display()))?;
+    unsafe {
+        let cf_root = cf::CFStringCreateWithCString(
+            std::ptr::null_mut(),
+            root_string.as_ptr(),
+            cf::kCFStringEncodingUTF8,
+        );
+        if cf_root.is_null() {
+            bail!("Failed to read the history of {}", root.display());
+        }
+        let cf_roots =
+            cf::CFArrayCreateMutable(std::ptr::null_mut(), 0, &cf::kCFTypeArrayCallBacks);
+        cf::CFArrayAppendValue(cf_roots, cf_root);
+        cf::CFRelease(cf_root);
+
+        let context = fsevent::FSEventStreamContext {
+            version: 0,
+            info: &mut history as *mut History as *mut c_void,
+            retain: None,
+            release: None,
+            copy_description: None,
+        };
+        let stream = fsevent::FSEventStreamCreate(
+            std::ptr::null_mut(),
+            callback,
+            &context,
+            cf_roots,
+            since,
+            0.0,
+            fsevent::kFSEventStreamCreateFlagFileEvents | fsevent::kFSEventStreamCreateFlagNoDefer,
+        );
+        cf::CFRelease(cf_roots);
+        if stream.is_null() {
+            bail!("Failed to read the history of {}", root.display());
+        }
+
+        // Replay the history on this thread until the callback says it's done
+        fsevent::FSEventStreamScheduleWithRunLoop(
+            stream,
+            cf::CFRunLoopGetCurrent(),
+            cf::kCFRunLoopDefaultMode,
+        );
+        let started = fsevent::FSEventStreamStart(stream) != 0;
+        if started {
+            cf::CFRunLoopRun();
+            fsevent::FSEventStreamStop(stream);
+        }
+        fsevent::FSEventStreamInvalidate(stream);
+        fsevent::FSEventStreamRelease(stream);
+        if !started {
+            bail!("Failed to read the history of {}", root.display());
+        }
+    }
+
+    Ok((!history.whole).then(|| {
+        history
+            .paths
+            .into_iter()
+            .filter(|path| path.starts_with(root))
+            .collect()
+    }))
+}
+
+// There's no history of changes to read anywhere else.
+#[cfg(not(any(windows, target_os = "macos")))]
+fn changes(_root: &Path, _last: Cursor, _now: Cursor) -> Result<Option<Vec<PathBuf>>> {
+    Ok(None)
+}
+
+// Reading the NTFS change journal on Windows. The journal keeps a record of every change to every file and folder on a
+// volume, in order, each one numbered with an update sequence number, or USN, and naming the file by its id and the
+// folder it's in by its id, rather than by path.
+#[cfg(target_family = "windows")]
+mod usn {
+    use anyhow::{Context, Result};
+    use std::collections::HashMap;
+    use std::ffi::OsString;
+    use std::io::Error;
+    use std::os::windows::ffi::{OsStrExt, OsStringExt};
+    use std::path::{Path, PathBuf};
+
+    use winapi::{
+        shared::{
+            minwindef::{DWORD, FALSE, LPVOID},
+            winerror::ERROR_JOURNAL_ENTRY_DELETED,
+        },
+        um::{
+            fileapi::{
+                CreateFileW, GetFinalPathNameByHandleW, GetVolumeNameForVolumeMountPointW,
+                GetVolumePathNameW, OPEN_EXISTING,
+            },
+            handleapi::{CloseHandle, INVALID_HANDLE_VALUE},
+            ioapiset::DeviceIoControl,
+            winbase::{FileIdType, OpenFileById, FILE_FLAG_BACKUP_SEMANTICS, FILE_ID_DESCRIPTOR},
+            winioctl::{FSCTL_QUERY_USN_JOURNAL, FSCTL_READ_USN_JOURNAL},
+            winnt::{
+                FILE_READ_ATTRIBUTES, FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE,
+                GENERIC_READ, HANDLE,
+            },
+        },
+    };
+
+    // Reasons a record gives for a change that mean the name it has is gone
+    const USN_REASON_FILE_DELETE: u32 = 0x0000_0200;
+    const USN_REASON_RENAME_OLD_NAME: u32 = 0x0000_1000;
+
+    // The size of the fixed part of a version 2 record, which the name of the file follows
+    const RECORD_LEN: usize = 60;
+
+    // What FSCTL_QUERY_USN_JOURNAL gives back, as USN_JOURNAL_DATA_V0, which winapi doesn't declare
+    #[repr(C)]
+    #[derive(Default)]
+    struct JournalData {
+        id: u64,
+        first: i64,
+        next: i64,
+        lowest_valid: i64,
+        max: i64,
+        maximum_size: u64,
+        allocation_delta: u64,
+    }
+
+    // What FSCTL_READ_USN_JOURNAL is asked, as READ_USN_JOURNAL_DATA_V0, which winapi doesn't declare either
+    #[repr(C)]
+    struct ReadData {
+        start: i64,
+        reason_mask: u32,
+        return_only_on_close: u32,
+        timeout: u64,
+        bytes_to_wait_for: u64,
+        journal: u64,
+    }
+
+    // Where a volume's change journal is up to
+    pub struct Journal {
+        pub id: u64,
+        pub next: i64,
+        lowest_valid: i64,
+    }
+
+    // An open handle to a volume, for reading its change journal, closed when it's dropped
+    pub struct Volume {
+        handle: HANDLE,
+    }
+
+    impl Volume {
+        // Open the volume a path is on.
+        pub fn of(path: &Path) -> Result<Self> {
+            // Find the folder the volume is mounted at, then the name of the volume itself. The standard library
+            // resolves paths to the \\?\C:\ form, which the volume functions only take without the prefix.
+            let path = path
+                .to_str()
+                .and_then(|path| path.strip_prefix(r"\\?\"))
+                .filter(|path| path.as_bytes().get(1) == Some(&b':'))
+                .map_or(path, Path::new);
+            let wide_path = path
+                .as_os_str()
+                .encode_wide()
+                .chain(Some(0))
+                .collect::<Vec<_>>();
+            let mut mount_point = vec![0u16; 32768];
+            let mut name = vec![0u16; 64];
+            let found = unsafe {
+                GetVolumePathNameW(
+                    wide_path.as_ptr(),
+                    mount_point.as_mut_ptr(),
+                    mount_point.len() as DWORD,
+                ) != FALSE
+                    && GetVolumeNameForVolumeMountPointW(
+                        mount_point.as_ptr(),
+                        name.as_mut_ptr(),
+                        name.len() as DWORD,
+                    ) != FALSE
+            };
+            if !found {
+                return Err(Error::last_os_error()).with_context(|| {
+                    format!("Failed to find the volume {} is on", path.display())
+                });
+            }
+
+            // The volume is opened by its name without the trailing backslash, which would open its root folder
+            // instead
+            let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+            name.truncate(len);
+            if name.last() == Some(&u16::from(b'\\')) {
+                name.pop();
+            }
+            name.push(0);
+            let handle = unsafe {
+                CreateFileW(
+                    name.as_ptr(),
+                    GENERIC_READ,
+                    FILE_SHARE_READ | FILE_SHARE_WRITE,
+                    std::ptr::null_mut(),
+                    OPEN_EXISTING,
+                    0,
+                    std::ptr::null_mut(),
+                )
+            };
+            if handle == INVALID_HANDLE_VALUE {
+                return Err(Error::last_os_error()).with_context(|| {
+                    format!("Failed to open the volume {} is on", path.display())
+                });
+            }
+            Ok(Self { handle })
+        }
+
+        // Find out where the volume's change journal is up to.
+        pub fn query(&self) -> Result<Journal> {
+            let mut data = JournalData::default();
+            let mut returned: DWORD = 0;
+            let result = unsafe {
+                DeviceIoControl(
+                    self.handle,
+                    FSCTL_QUERY_USN_JOURNAL,
+                    std::ptr::null_mut(),
+                    0,
+                    &mut data as *mut JournalData as LPVOID,
+                    std::mem::size_of::<JournalData>() as DWORD,
+                    &mut returned,
+                    std::ptr::null_mut(),
+                )
+            };
+            if result == FALSE {
+                return Err(Error::last_os_error())
+                    .with_context(|| "Failed to query the change journal");
+            }
+            Ok(Journal {
+                id: data.id,
+                next: data.next,
+                lowest_valid: data.lowest_valid,
+            })
+        }
+
+        // Read the records of the journal from one USN up to another, returning the last name each file had, as the
+        // id of the folder it was in and its name in that folder, by the id of the file. Files whose last record says
+        // they were deleted, or renamed away, are left out. Returns None if the journal was replaced, or the first of
+        // the records has been trimmed off of it since.
+        pub fn read(
+            &self,
+            journal: u64,
+            from: i64,
+            until: i64,
+        ) -> Result<Option<HashMap<u64, (u64, OsString)>>> {
+            let current = self.query()?;
+            if current.id != journal || from < current.lowest_valid {
+                return Ok(None);
+            }
+
+            let mut files = HashMap::new();
+            let mut buffer = vec![0u64; 8192];
+            let mut start = from;
+            while start < until {
+                let request = ReadData {
+                    start,
+                    reason_mask: u32::MAX,
+                    return_only_on_close: 0,
+                    timeout: 0,
+                    bytes_to_wait_for: 0,
+                    journal,
+                };
+                let mut returned: DWORD = 0;
+                let result = unsafe {
+                    DeviceIoControl(
+                        self.handle,
+                        FSCTL_READ_USN_JOURNAL,
+                        &request as *const ReadData as LPVOID,
+                        std::mem::size_of::<ReadData>() as DWORD,
+                        buffer.as_mut_ptr() as LPVOID,
+                        (buffer.len() * 8) as DWORD,
+                        &mut returned,
+                        std::ptr::null_mut(),
+                    )
+                };
+                if result == FALSE {
+                    let e = Error::last_os_error();
+                    if e.raw_os_error() == Some(ERROR_JOURNAL_ENTRY_DELETED as i32) {
+                        return Ok(None);
+                    }
+                    return Err(e).with_context(|| "Failed to read the change journal");
+                }
+
+                // The buffer starts with the USN to read from next, followed by as many records as fit
+                let bytes = unsafe {
+                    std::slice::from_raw_parts(buffer.as_ptr() as *const u8, returned as usize)
+                };
+                let Some(next) = bytes
+                    .get(..8)
+                    .map(|next| i64::from_le_bytes(next.try_into().unwrap()))
+                else {
+                    break;
+                };
+                let mut offset = 8;
+                while let Some(record) = bytes.get(offset..offset + RECORD_LEN) {
+                    let u16_at = |at: usize| u16::from_le_bytes([record[at], record[at + 1]]);
+                    let u32_at =
+                        |at: usize| u32::from_le_bytes(record[at..at + 4].try_into().unwrap());
+                    let u64_at =
+                        |at: usize| u64::from_le_bytes(record[at..at + 8].try_into().unwrap());
+                    let length = u32_at(0) as usize;
+                    if length == 0 {
+                        break;
+                    }
+
+                    // Only version 2 records are given back when reading this way
+                    if u16_at(4) == 2 {
+                        let usn = u64_at(24) as i64;
+                        if usn >= until {
+                            return Ok(Some(files));
+                        }
+                        let (file, parent, reason) = (u64_at(8), u64_at(16), u32_at(40));
+                        let name_len = u16_at(56) as usize;
+                        let name_offset = offset + u16_at(58) as usize;
+                        let name: Vec<u16> = bytes
+                            .get(name_offset..name_offset + name_len)
+                            .unwrap_or_default()
+                            .chunks_exact(2)
+                            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                            .collect();
+                        if reason & (USN_REASON_FILE_DELETE | USN_REASON_RENAME_OLD_NAME) != 0 {
+                            files.remove(&file);
+                        } else {
+                            files.insert(file, (parent, OsString::from_wide(&name)));
+                        }
+                    }
+                    offset += length;
+                }
+                if next <= start {
+                    break;
+                }
+                start = next;
+            }
+            Ok(Some(files))
+        }
+
+        // Returns the path of a file or folder on the volume, by its id.
+        pub fn path_of(&self, id: u64) -> Result<PathBuf> {
+            let mut descriptor: FILE_ID_DESCRIPTOR = unsafe { std::mem::zeroed() };
+            descriptor.dwSize = std::mem::size_of::<FILE_ID_DESCRIPTOR>() as DWORD;
+            descriptor.Type = FileIdType;
+            unsafe { *descriptor.u.FileId_mut().QuadPart_mut() = id as i64 };
+            let handle = unsafe {
+                OpenFileById(
+                    self.handle,
+                    &mut descriptor,
+                    FILE_READ_ATTRIBUTES,
+                    FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE,
+                    std::ptr::null_mut(),
+                    FILE_FLAG_BACKUP_SEMANTICS,
+                )
+            };
+            if handle == INVALID_HANDLE_VALUE {
+                return Err(Error::last_os_error())
+                    .with_context(|| format!("Failed to open file {id} by its id"));
+            }
+            let mut path = vec![0u16; 32768];
+            let len = unsafe {
+                GetFinalPathNameByHandleW(handle, path.as_mut_ptr(), path.len() as DWORD, 0)
+            };
+            let e = Error::last_os_error();
+            unsafe { CloseHandle(handle) };
+            if len == 0 || len as usize > path.len() {
+                return Err(e).with_context(|| format!("Failed to get the path of file {id}"));
+            }
+            Ok(PathBuf::from(OsString::from_wide(&path[..len as usize])))
+        }
+    }
+
+    impl Drop for Volume {
+        fn drop(&mut self) {
+            unsafe { CloseHandle(self.handle) };
+        }
+    }
+}
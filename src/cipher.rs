@@ -0,0 +1,175 @@
+use anyhow::{bail, Context, Result};
+use std::io::Write;
+use std::path::Path;
+use std::process::ExitCode;
+
+// Environment variable the passphrase is read from, if it isn't given in a file.
+#[cfg_attr(not(feature = "encrypt"), allow(dead_code))]
+const PASSPHRASE_VAR: &str = "CLOAK_PASSPHRASE";
+
+// What every file cloak encrypts starts with, followed by the version of the format, so an encrypted file is never
+// mistaken for a plaintext one, or the other way round.
+const MAGIC: &[u8] = b"cloak-encrypted\0\x01";
+
+#[cfg_attr(not(feature = "encrypt"), allow(dead_code))]
+const SALT_LEN: usize = 16;
+#[cfg_attr(not(feature = "encrypt"), allow(dead_code))]
+const NONCE_LEN: usize = 24;
+#[cfg_attr(not(feature = "encrypt"), allow(dead_code))]
+const LENGTH_LEN: usize = 8;
+
+// Encrypts the files cloak keeps about what it hid, such as the journal and the report, with a passphrase, since a
+// plaintext list of every hidden path gives away what was hidden. The key is derived from the passphrase with Argon2id,
+// using a random salt for each file, and the file is sealed with XChaCha20-Poly1305 under a random nonce, with both
+// stored at its start, followed by the length of what was sealed. Files written a section at a time, like the report,
+// are made of several of these one after another. A wrong passphrase or a file that was tampered with is caught, rather
+// than read as garbage.
+#[cfg(feature = "encrypt")]
+#[derive(Clone)]
+pub struct Cipher {
+    passphrase: Vec<u8>,
+}
+
+// The passphrase is never printed.
+#[cfg(feature = "encrypt")]
+impl std::fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Cipher").finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "encrypt")]
+impl Cipher {
+    // Build a new cipher with the passphrase in the given file, or in the CLOAK_PASSPHRASE environment variable if no
+    // file is given. A single line ending at the end of the file is ignored.
+    pub fn load(file: Option<&Path>) -> Result<Self> {
+        let passphrase = match file {
+            Some(file) => {
+                let mut passphrase = std::fs::read(file)
+                    .with_context(|| format!("Failed to read passphrase from {}", file.display()))?;
+                if passphrase.ends_with(b"\n") {
+                    passphrase.pop();
+                    if passphrase.ends_with(b"\r") {
+                        passphrase.pop();
+                    }
+                }
+                passphrase
+            }
+            None => std::env::var(PASSPHRASE_VAR)
+                .with_context(|| {
+                    format!("--encrypt-metadata needs a passphrase in {PASSPHRASE_VAR} or --passphrase-file")
+                })?
+                .into_bytes(),
+        };
+        if passphrase.is_empty() {
+            bail!("The passphrase to encrypt with can't be empty");
+        }
+        Ok(Self { passphrase })
+    }
+
+    // Encrypt the contents of a file, or of a section of one.
+    pub fn encrypt(&self, contents: &[u8]) -> Result<Vec<u8>> {
+        use chacha20poly1305::aead::rand_core::RngCore;
+        use chacha20poly1305::aead::{Aead, OsRng};
+
+        let mut salt = [0; SALT_LEN];
+        let mut nonce = [0; NONCE_LEN];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut nonce);
+        let sealed = self
+            .key(&salt)?
+            .encrypt(&nonce.into(), contents)
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt"))?;
+        let length = (sealed.len() as u64).to_le_bytes();
+        Ok([MAGIC, &salt, &nonce, &length, &sealed].concat())
+    }
+
+    // Decrypt the contents of a file read from the given path, joining up each of its sections.
+    pub fn decrypt(&self, contents: &[u8], path: &Path) -> Result<Vec<u8>> {
+        use chacha20poly1305::aead::Aead;
+
+        if !is_encrypted(contents) {
+            bail!("{} isn't encrypted", path.display());
+        }
+        let mut decrypted = Vec::new();
+        let mut rest = contents;
+        while !rest.is_empty() {
+            let Some(section) = rest.strip_prefix(MAGIC) else {
+                bail!(
+                    "{} has something other than encrypted sections in it",
+                    path.display()
+                );
+            };
+            if section.len() < SALT_LEN + NONCE_LEN + LENGTH_LEN {
+                bail!("{} is encrypted, but cut short", path.display());
+            }
+            let (salt, section) = section.split_at(SALT_LEN);
+            let (nonce, section) = section.split_at(NONCE_LEN);
+            let (length, section) = section.split_at(LENGTH_LEN);
+            let length = u64::from_le_bytes(length.try_into()?);
+            let Some(sealed) = usize::try_from(length)
+                .ok()
+                .and_then(|length| section.get(..length))
+            else {
+                bail!("{} is encrypted, but cut short", path.display());
+            };
+            rest = &section[sealed.len()..];
+            let opened = self.key(salt)?.decrypt(nonce.into(), sealed).map_err(|_| {
+                anyhow::anyhow!(
+                    "Failed to decrypt {}, as the passphrase is wrong or it was changed since it was written",
+                    path.display()
+                )
+            })?;
+            decrypted.extend(opened);
+        }
+        Ok(decrypted)
+    }
+
+    // Helper function to derive the key for a file from the passphrase and the file's salt.
+    fn key(&self, salt: &[u8]) -> Result<chacha20poly1305::XChaCha20Poly1305> {
+        use chacha20poly1305::KeyInit;
+
+        let mut key = [0; 32];
+        argon2::Argon2::default()
+            .hash_password_into(&self.passphrase, salt, &mut key)
+            .map_err(|e| anyhow::anyhow!("Failed to derive key from passphrase: {e}"))?;
+        Ok(chacha20poly1305::XChaCha20Poly1305::new(&key.into()))
+    }
+}
+
+// Stand-in for a cipher in builds without the encrypt feature. One can never be built, so encrypting is rejected up
+// front.
+#[cfg(not(feature = "encrypt"))]
+#[derive(Clone, Debug)]
+pub enum Cipher {}
+
+#[cfg(not(feature = "encrypt"))]
+impl Cipher {
+    pub fn load(_file: Option<&Path>) -> Result<Self> {
+        bail!("Encryption isn't supported by this build of cloak. Rebuild it with the encrypt feature enabled.")
+    }
+
+    pub fn encrypt(&self, _contents: &[u8]) -> Result<Vec<u8>> {
+        match *self {}
+    }
+
+    pub fn decrypt(&self, _contents: &[u8], _path: &Path) -> Result<Vec<u8>> {
+        match *self {}
+    }
+}
+
+// Decrypt a journal or report encrypted with --encrypt-metadata, and print it.
+pub fn run(file: &Path, passphrase_file: Option<&Path>) -> Result<ExitCode> {
+    let contents =
+        std::fs::read(file).with_context(|| format!("Failed to read {}", file.display()))?;
+    let contents = Cipher::load(passphrase_file)?.decrypt(&contents, file)?;
+    std::io::stdout()
+        .write_all(&contents)
+        .with_context(|| "Failed to print decrypted contents")?;
+    Ok(ExitCode::SUCCESS)
+}
+
+// Returns true if the contents of a file were encrypted by cloak.
+pub fn is_encrypted(contents: &[u8]) -> bool {
+    contents.starts_with(MAGIC)
+}
@@ -0,0 +1,93 @@
+use crate::{
+    actions, cooldown, empty, exempt, filesystem, filter, git, journal, limit, matcher, mounts,
+    output, prompt, report, rules, scan, summary, unhiders, vault, verify,
+};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Everything that decides how paths are found, filtered and hidden during a run, shared by searching, watching and
+// the pipeline they both use. It only borrows, so it's cheap to copy, and a new option only has to be added here
+// rather than to every function it passes through.
+#[derive(Clone, Copy, Debug)]
+pub struct RunContext<'a> {
+    // What to hide
+    pub matcher: &'a matcher::Matcher,
+    pub types: Option<&'a [filesystem::ObjectType]>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub older_than: Option<Duration>,
+    pub ownership: Option<&'a filter::Ownership>,
+    pub newer_than: Option<Duration>,
+    pub requires_sibling: Option<&'a str>,
+    pub git: Option<&'a git::Git>,
+    pub mimes: Option<&'a globset::GlobSet>,
+    pub scanner: Option<&'a scan::Scanner>,
+    pub empty: Option<&'a empty::EmptyFolders>,
+    pub root_rules: Option<&'a rules::RootRules>,
+    pub settle: Option<Duration>,
+    pub relative: bool,
+
+    // Where to look for it, with the paths themselves at depth 0
+    pub min_depth: usize,
+    pub max_depth: usize,
+    pub include_root: filter::IncludeRoot,
+    pub actions: actions::Actions,
+    pub follow_symlinks: bool,
+    pub skip_hidden_dirs: bool,
+    pub deref: bool,
+    pub deref_within: Option<&'a [PathBuf]>,
+    pub mounts: Option<&'a Arc<mounts::Mounts>>,
+
+    // How to hide it
+    pub test: bool,
+    pub force: bool,
+    pub unhide: bool,
+    pub verbose: bool,
+    pub include_ads: bool,
+    pub batch: bool,
+    pub prefer_parent: Option<f64>,
+    pub op_timeout: Option<Duration>,
+    pub on_conflict: filesystem::OnConflict,
+    pub max_operations: Option<usize>,
+    pub vault: Option<&'a vault::Vault>,
+
+    // Shared state for the whole run
+    pub dir_times: Option<&'a filesystem::DirTimes>,
+    pub report: Option<&'a report::Report>,
+    pub journal: Option<&'a journal::Journal>,
+    pub exemptions: Option<&'a exempt::Exemptions>,
+    pub unhiders: Option<&'a unhiders::Unhiders>,
+    pub limit: Option<&'a limit::Limit>,
+    pub verifier: Option<&'a verify::Verifier>,
+    pub prompt: Option<&'a prompt::Prompt>,
+    pub cooldown: Option<&'a cooldown::Cooldown>,
+    pub summary: &'a summary::Summary,
+    pub reporter: &'a Arc<dyn output::Reporter>,
+}
+
+impl<'a> RunContext<'a> {
+    // Returns the rules of its own for a root, if it has any. They're kept for as long as they're held, even if the
+    // rules files are read again in the meantime.
+    pub fn rules_for(&self, root: Option<&Path>) -> Option<Arc<rules::Rules>> {
+        self.root_rules
+            .zip(root)
+            .and_then(|(root_rules, root)| root_rules.get(root))
+    }
+
+    // Returns the context to use for paths found under a root, given the rules it has of its own, if any, from
+    // rules_for. Their matcher and types take the place of the ones given for every other path.
+    pub fn with_rules<'b>(&self, rules: Option<&'b rules::Rules>) -> RunContext<'b>
+    where
+        'a: 'b,
+    {
+        match rules {
+            Some(rules) => RunContext {
+                matcher: &rules.matcher,
+                types: rules.types.as_deref(),
+                ..*self
+            },
+            None => *self,
+        }
+    }
+}
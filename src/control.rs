@@ -0,0 +1,309 @@
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use serde_json::Value;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+// Enum of the commands a running watcher can be sent through its control socket
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ControlCommand {
+    // How long it has been watching, whether it's paused, what it's watching, the counts so far, and how long each
+    // stage of handling a path has taken
+    Status,
+    // Stop hiding anything until resumed
+    Pause,
+    // Start hiding again, searching everything for whatever changed while paused
+    Resume,
+    // Search everything again, to catch any changes that were missed
+    Rescan,
+    // Read the --path-rules files again, and search everything again with them
+    Reload,
+}
+
+// A socket a running watcher listens on for commands, one per connection. Each command is sent as a line, and answered
+// with a line of JSON, either with what was asked for or an error. It's only looked at between events, so it never
+// holds anything up. The socket is removed once watching stops. Only the watcher listens on one, so builds without it
+// never do.
+#[cfg_attr(not(feature = "watch"), allow(dead_code))]
+#[derive(Debug)]
+pub struct Control {
+    #[cfg(target_family = "unix")]
+    path: PathBuf,
+    #[cfg(target_family = "unix")]
+    listener: std::os::unix::net::UnixListener,
+}
+
+#[cfg_attr(not(feature = "watch"), allow(dead_code))]
+impl Control {
+    // Start listening on a socket at the given path. A socket left behind by a watcher that's no longer running is
+    // replaced, but one that's still being listened on isn't.
+    #[cfg(target_family = "unix")]
+    pub fn bind(path: PathBuf) -> Result<Self> {
+        use anyhow::Context;
+        use std::os::unix::net::{UnixListener, UnixStream};
+
+        if path.exists() {
+            if UnixStream::connect(&path).is_ok() {
+                bail!("Another watcher is already listening on {}", path.display());
+            }
+            std::fs::remove_file(&path).with_context(|| {
+                format!("Failed to remove stale control socket {}", path.display())
+            })?;
+        }
+        let listener = UnixListener::bind(&path)
+            .with_context(|| format!("Failed to listen on control socket {}", path.display()))?;
+        listener
+            .set_nonblocking(true)
+            .with_context(|| format!("Failed to listen on control socket {}", path.display()))?;
+        Ok(Self { path, listener })
+    }
+
+    // Named pipes aren't supported yet.
+    #[cfg(target_family = "windows")]
+    pub fn bind(_: PathBuf) -> Result<Self> {
+        bail!("Control sockets are only supported on Unix so far")
+    }
+
+    // Answer every command waiting to be read, using answer to work out what to reply with.
+    #[cfg(target_family = "unix")]
+    pub fn serve(&self, mut answer: impl FnMut(ControlCommand) -> Value) {
+        use std::io::{BufRead, BufReader};
+
+        while let Ok((stream, _)) = self.listener.accept() {
+            // Don't let a client that never sends anything hold up the watcher.
+            let _ = stream.set_nonblocking(false);
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(1)));
+            let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+
+            let mut line = String::new();
+            let reply = match BufReader::new(&stream).read_line(&mut line) {
+                Ok(_) => match ControlCommand::from_str(line.trim(), true) {
+                    Ok(command) => answer(command),
+                    Err(_) => {
+                        serde_json::json!({ "error": format!("Unknown command {}", line.trim()) })
+                    }
+                },
+                Err(e) => serde_json::json!({ "error": format!("Failed to read command: {e}") }),
+            };
+            let _ = writeln!(&stream, "{reply}");
+        }
+    }
+
+    // There's never anything to answer without a socket.
+    #[cfg(target_family = "windows")]
+    pub fn serve(&self, _: impl FnMut(ControlCommand) -> Value) {}
+}
+
+impl Drop for Control {
+    fn drop(&mut self) {
+        #[cfg(target_family = "unix")]
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// Send a command to the watcher listening on a control socket, and print out its reply. Fails if it replied with an
+// error.
+pub fn send(socket: &Path, command: ControlCommand) -> Result<ExitCode> {
+    let reply = request(socket, command)?;
+    if let Some(error) = reply["error"].as_str() {
+        eprintln!("Error: {error}");
+        return Ok(ExitCode::FAILURE);
+    }
+
+    match command {
+        ControlCommand::Status => {
+            let uptime = reply["uptime_secs"].as_f64().unwrap_or_default();
+            println!(
+                "{:<20} {:>12}",
+                "State",
+                if reply["paused"] == true {
+                    "paused"
+                } else {
+                    "watching"
+                }
+            );
+            println!(
+                "{:<20} {:>12}",
+                "Uptime",
+                format!("{:.2?}", std::time::Duration::from_secs_f64(uptime))
+            );
+            for root in reply["roots"].as_array().into_iter().flatten() {
+                println!("{:<20} {}", "Root", root.as_str().unwrap_or_default());
+            }
+            for count in reply["counts"].as_array().into_iter().flatten() {
+                println!(
+                    "{:<20} {:>12}",
+                    count[0].as_str().unwrap_or_default(),
+                    count[1].as_u64().unwrap_or_default()
+                );
+            }
+            print!("{}", latency(&reply));
+        }
+        _ => println!("{}", reply["message"].as_str().unwrap_or_default()),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+// Print out a single screen of stats from the watcher listening on a control socket: how long it has been watching, how
+// many events it has received and how fast, how many are waiting to be handled, how many paths it has hidden, skipped
+// and failed on, and how long each stage of handling them has taken. If live is set, then the screen is drawn again
+// every interval until interrupted, in place when the output is a terminal. Events per second are worked out from the
+// events received since the last screen, so the first is the average since it started.
+pub fn stats(socket: &Path, live: bool, interval: Duration) -> Result<ExitCode> {
+    use std::io::IsTerminal;
+
+    let terminal = std::io::stdout().is_terminal();
+    let mut last: Option<(Instant, u64)> = None;
+    loop {
+        let reply = request(socket, ControlCommand::Status)?;
+        if let Some(error) = reply["error"].as_str() {
+            eprintln!("Error: {error}");
+            return Ok(ExitCode::FAILURE);
+        }
+        let now = Instant::now();
+        let uptime = Duration::from_secs_f64(reply["uptime_secs"].as_f64().unwrap_or_default());
+        let events = reply["events"].as_u64().unwrap_or_default();
+        let rate = match last {
+            Some((at, before)) => events.saturating_sub(before) as f64 / (now - at).as_secs_f64(),
+            None => events as f64 / uptime.as_secs_f64().max(1.0),
+        };
+        last = Some((now, events));
+
+        let count = |name: &str| {
+            reply["counts"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter(|count| {
+                    count[0]
+                        .as_str()
+                        .is_some_and(|count| count.starts_with(name))
+                })
+                .filter_map(|count| count[1].as_u64())
+                .sum::<u64>()
+        };
+        let roots = reply["roots"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut screen = format!(
+            "{} {roots}\n\n",
+            if reply["paused"] == true {
+                "Paused on"
+            } else {
+                "Watching"
+            }
+        );
+        for (name, value) in [
+            ("Uptime", {
+                let secs = uptime.as_secs();
+                format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+            }),
+            ("Events/sec", format!("{rate:.1}")),
+            ("Events", events.to_string()),
+            (
+                "Queued",
+                reply["queued"].as_u64().unwrap_or_default().to_string(),
+            ),
+            ("Scanned", count("Scanned").to_string()),
+            ("Hidden", count("Hidden").to_string()),
+            ("Already hidden", count("Already hidden").to_string()),
+            ("Skipped", count("Skipped").to_string()),
+            ("Errors", count("Errors").to_string()),
+        ] {
+            screen.push_str(&format!("{name:<20} {value:>12}\n"));
+        }
+        screen.push_str(&latency(&reply));
+
+        // Move to the top left and clear the screen before drawing over it.
+        if live && terminal {
+            print!("\x1b[H\x1b[2J{screen}");
+        } else {
+            println!("{screen}");
+        }
+        let _ = std::io::stdout().flush();
+        if !live {
+            return Ok(ExitCode::SUCCESS);
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+// Helper function to lay out how long each stage of handling a path has taken, from a status reply, as a table after a
+// blank line. Returns nothing if no stage has run yet.
+fn latency(reply: &Value) -> String {
+    let stages = reply["latency"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|stage| stage["count"].as_u64().is_some_and(|count| count > 0))
+        .collect::<Vec<_>>();
+    if stages.is_empty() {
+        return String::new();
+    }
+
+    let time = |ms: &Value| {
+        format!(
+            "{:.2?}",
+            Duration::from_secs_f64(ms.as_f64().unwrap_or_default() / 1000.0)
+        )
+    };
+    let mut table = format!(
+        "\n{:<20} {:>12} {:>12} {:>12} {:>12}\n",
+        "Stage", "Paths", "Mean", "p95", "p99"
+    );
+    for stage in stages {
+        let name = stage["stage"]
+            .as_str()
+            .unwrap_or_default()
+            .replace('_', " ");
+        let mut chars = name.chars();
+        let name = chars
+            .next()
+            .map(|first| first.to_uppercase().chain(chars).collect::<String>())
+            .unwrap_or_default();
+        table.push_str(&format!(
+            "{name:<20} {:>12} {:>12} {:>12} {:>12}\n",
+            stage["count"].as_u64().unwrap_or_default(),
+            time(&stage["mean_ms"]),
+            time(&stage["p95_ms"]),
+            time(&stage["p99_ms"])
+        ));
+    }
+    table
+}
+
+// Helper function to send a command and read back the reply.
+#[cfg(target_family = "unix")]
+fn request(socket: &Path, command: ControlCommand) -> Result<Value> {
+    use anyhow::Context;
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::UnixStream;
+
+    let name = command
+        .to_possible_value()
+        .map(|value| value.get_name().to_owned())
+        .unwrap_or_default();
+    let mut stream = UnixStream::connect(socket)
+        .with_context(|| format!("Failed to connect to a watcher on {}", socket.display()))?;
+    writeln!(stream, "{name}")
+        .with_context(|| format!("Failed to send {name} to {}", socket.display()))?;
+
+    let mut reply = String::new();
+    BufReader::new(stream)
+        .read_line(&mut reply)
+        .with_context(|| format!("Failed to read reply from {}", socket.display()))?;
+    serde_json::from_str(&reply)
+        .with_context(|| format!("Failed to parse reply from {}", socket.display()))
+}
+
+// Named pipes aren't supported yet.
+#[cfg(target_family = "windows")]
+fn request(_: &Path, _: ControlCommand) -> Result<Value> {
+    bail!("Control sockets are only supported on Unix so far")
+}
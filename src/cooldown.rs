@@ -0,0 +1,91 @@
+use crate::output::{Event, Reporter};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// How many times in a row hiding a path can fail before it's left alone for a while
+const FAILURES_BEFORE_COOLDOWN: usize = 3;
+
+// The failures of a single path, and when it was last put on cooldown, if it has been.
+#[derive(Debug)]
+struct Failures {
+    count: usize,
+    cooling_since: Option<Instant>,
+}
+
+// Keeps track of paths that keep failing to be hidden, so they can be left alone for a while rather than failing again
+// on every event. Any path that has been put on cooldown is kept as a problem path until it's hidden successfully.
+#[derive(Debug)]
+pub struct Cooldown {
+    duration: Duration,
+    failures: Mutex<HashMap<PathBuf, Failures>>,
+}
+
+// Cooldowns are only ever started by the watcher, so builds without it never build one.
+#[cfg_attr(not(feature = "watch"), allow(dead_code))]
+impl Cooldown {
+    // Build a new cooldown, which leaves paths alone for the given duration.
+    pub fn new(duration: Duration) -> Self {
+        Self {
+            duration,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Returns true if a path is on cooldown, and shouldn't be tried again yet.
+    pub fn is_cooling(&self, path: &Path) -> bool {
+        self.failures
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(path)
+            .and_then(|failures| failures.cooling_since)
+            .is_some_and(|cooling_since| cooling_since.elapsed() < self.duration)
+    }
+
+    // Record a failure to hide a path. Returns true if that put it on cooldown. A path that fails again after its
+    // cooldown ends goes straight back on cooldown.
+    pub fn record_failure(&self, path: &Path) -> bool {
+        let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        let failures = failures.entry(path.to_path_buf()).or_insert(Failures {
+            count: 0,
+            cooling_since: None,
+        });
+        failures.count += 1;
+        if failures.count >= FAILURES_BEFORE_COOLDOWN {
+            failures.cooling_since = Some(Instant::now());
+            true
+        } else {
+            false
+        }
+    }
+
+    // Record a path being handled successfully, forgetting any failures.
+    pub fn record_success(&self, path: &Path) {
+        self.failures
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(path);
+    }
+
+    // Report every path that has been put on cooldown, along with how many times it has failed.
+    pub fn report_problems(&self, reporter: &dyn Reporter) {
+        let failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        let mut problems: Vec<_> = failures
+            .iter()
+            .filter(|(_, failures)| failures.cooling_since.is_some())
+            .collect();
+        if problems.is_empty() {
+            return;
+        }
+        problems.sort_by(|a, b| a.0.cmp(b.0));
+
+        reporter.report(&Event::Notice(format!("{} problem paths:", problems.len())));
+        for (path, failures) in problems {
+            reporter.report(&Event::Problem {
+                path,
+                failures: failures.count,
+            });
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::{HashSet, VecDeque};
+use std::hash::Hash;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+// The queues waiting to be handled, one for each lane, along with the keys of everything in them and the number of
+// workers handling them
+#[derive(Debug)]
+struct Lanes<K, T> {
+    high: VecDeque<(Option<K>, T)>,
+    normal: VecDeque<(Option<K>, T)>,
+    queued: HashSet<K>,
+    workers: usize,
+}
+
+impl<K, T> Lanes<K, T> {
+    fn len(&self) -> usize {
+        self.high.len() + self.normal.len()
+    }
+}
+
+// Queues up work in two lanes, so that high priority work is always handled before anything else that's waiting. Work
+// is pushed here, and handled by at most max_workers workers at once, each popping work until there's none left. At
+// most capacity pieces of work wait at once, and work with the same key as something already waiting is dropped, so a
+// burst of work can neither use up memory nor every thread in the pool.
+#[derive(Debug)]
+pub struct Dispatcher<K, T> {
+    lanes: Mutex<Lanes<K, T>>,
+    room: Condvar,
+    capacity: usize,
+    max_workers: usize,
+}
+
+impl<K: Clone + Eq + Hash, T> Dispatcher<K, T> {
+    // Build a new dispatcher, with both lanes empty. Both limits are at least 1.
+    pub fn new(capacity: usize, max_workers: usize) -> Self {
+        Self {
+            lanes: Mutex::new(Lanes {
+                high: VecDeque::new(),
+                normal: VecDeque::new(),
+                queued: HashSet::new(),
+                workers: 0,
+            }),
+            room: Condvar::new(),
+            capacity: capacity.max(1),
+            max_workers: max_workers.max(1),
+        }
+    }
+
+    // Queue up a piece of work in the high or normal priority lane, unless work with the same key is already waiting.
+    // Returns true if a new worker should be started to handle it, which is counted as started straight away.
+    pub fn push(&self, key: Option<K>, item: T, high: bool) -> bool {
+        let mut lanes = self.lanes.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(key) = &key {
+            if !lanes.queued.insert(key.clone()) {
+                return false;
+            }
+        }
+        if high {
+            lanes.high.push_back((key, item));
+        } else {
+            lanes.normal.push_back((key, item));
+        }
+        if lanes.workers < self.max_workers {
+            lanes.workers += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Take the next piece of work, from the high priority lane if there's anything in it. Returns None once there's
+    // nothing left, at which point the worker that asked is counted as finished.
+    pub fn pop(&self) -> Option<T> {
+        let mut lanes = self.lanes.lock().unwrap_or_else(|e| e.into_inner());
+        let Some((key, item)) = lanes.high.pop_front().or_else(|| lanes.normal.pop_front()) else {
+            lanes.workers -= 1;
+            return None;
+        };
+        if let Some(key) = key {
+            lanes.queued.remove(&key);
+        }
+        self.room.notify_all();
+        Some(item)
+    }
+
+    // Returns how many pieces of work are waiting to be handled, in both lanes.
+    pub fn waiting(&self) -> usize {
+        self.lanes.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    // Wait up to the timeout for there to be room for more work. Returns true if there is.
+    pub fn wait_for_room(&self, timeout: Duration) -> bool {
+        let lanes = self.lanes.lock().unwrap_or_else(|e| e.into_inner());
+        let (lanes, _) = self
+            .room
+            .wait_timeout_while(lanes, timeout, |lanes| lanes.len() >= self.capacity)
+            .unwrap_or_else(|e| e.into_inner());
+        lanes.len() < self.capacity
+    }
+}
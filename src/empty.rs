@@ -0,0 +1,54 @@
+use crate::filesystem;
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// Keeps track of the folders hidden with --only-empty, under the paths they were hidden as, so that a watcher can show
+// them again once something visible is made in them, rather than leaving a folder that's now in use hidden. Only
+// folders hidden where they are by this run are tracked, so nothing hidden by anything else is ever shown again.
+#[derive(Debug, Default)]
+pub struct EmptyFolders {
+    hidden: Mutex<HashSet<PathBuf>>,
+}
+
+// Folders are only ever shown again by the watcher, so builds without it never check.
+#[cfg_attr(not(feature = "watch"), allow(dead_code))]
+impl EmptyFolders {
+    // Record that an empty folder was hidden as the given path.
+    pub fn record(&self, hidden: &Path) {
+        self.hidden
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(hidden.to_path_buf());
+    }
+
+    // Returns true if a folder was hidden for being empty, but has something visible in it now, in which case it stops
+    // being tracked. Anything that can't be read is taken to still be empty.
+    pub fn filled(&self, dir: &Path) -> bool {
+        let mut hidden = self.hidden.lock().unwrap_or_else(|e| e.into_inner());
+        if !hidden.contains(dir) {
+            return false;
+        }
+        if !is_empty(dir).unwrap_or(true) {
+            hidden.remove(dir);
+            return true;
+        }
+        false
+    }
+}
+
+// Function to check whether a folder has nothing visible in it. Hidden entries don't count, so a folder with only
+// hidden files in it, or ones cloak hid, is still empty.
+pub fn is_empty(dir: &Path) -> Result<bool> {
+    for entry in fs::read_dir(&*filesystem::reachable(dir)?)
+        .with_context(|| format!("Failed to read directory {}", dir.display()))?
+    {
+        let entry = entry.with_context(|| format!("Failed to read directory {}", dir.display()))?;
+        if !filesystem::is_hidden(&dir.join(entry.file_name()))? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
@@ -0,0 +1,119 @@
+use crate::filesystem::{absolute, CreateModes};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// A single line of the exemptions file: a path that was deliberately unhidden, and until when it's left alone, in
+// seconds since the Unix epoch, or forever if not given
+#[derive(Debug, Serialize, Deserialize)]
+struct Line {
+    path: PathBuf,
+    until: Option<u64>,
+}
+
+// What has been read from the exemptions file so far, and how far into it
+#[derive(Debug, Default)]
+struct State {
+    exempt: HashMap<PathBuf, Option<SystemTime>>,
+    read: u64,
+}
+
+// Paths that were deliberately unhidden, shared between runs through a file, so a watcher enforcing hiding doesn't hide
+// them again straight away. Unhiding appends a line to the file for each path before unhiding it, and everything else
+// reads whatever has been appended since it last looked before deciding to hide a path, so a watcher sees an
+// exemption before it sees the path being unhidden. Paths are kept absolute, so the same file works from any directory.
+#[derive(Debug)]
+pub struct Exemptions {
+    path: PathBuf,
+    exempt_for: Option<Duration>,
+    modes: CreateModes,
+    state: Mutex<State>,
+}
+
+impl Exemptions {
+    // Build a new set of exemptions kept in the given file, which is created the first time a path is recorded. Paths
+    // recorded are left alone for exempt_for, or forever if it isn't given.
+    pub fn new(path: PathBuf, exempt_for: Option<Duration>, modes: CreateModes) -> Self {
+        Self {
+            path,
+            exempt_for,
+            modes,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    // Record that a path is about to be unhidden deliberately.
+    pub fn record(&self, path: &Path) -> Result<()> {
+        let until = self.exempt_for.map(|exempt_for| {
+            (SystemTime::now() + exempt_for)
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        });
+        let mut line = serde_json::to_string(&Line {
+            path: absolute(path),
+            until,
+        })?;
+        line.push('\n');
+
+        self.modes
+            .open_options()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .with_context(|| format!("Failed to write exemptions {}", self.path.display()))
+    }
+
+    // Returns true if a path was deliberately unhidden, and is still being left alone. Anything appended to the file
+    // since it was last read is read first. If it can't be read, then only what was already read is checked.
+    pub fn is_exempt(&self, path: &Path) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = self.refresh(&mut state);
+        state
+            .exempt
+            .get(&absolute(path))
+            .is_some_and(|until| until.is_none_or(|until| SystemTime::now() < until))
+    }
+
+    // Helper function to read any whole lines appended to the file since it was last read. If the file has shrunk,
+    // then it was replaced, so it's read again from the start.
+    fn refresh(&self, state: &mut State) -> Result<()> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let len = file.metadata()?.len();
+        if len < state.read {
+            *state = State::default();
+        }
+        if len == state.read {
+            return Ok(());
+        }
+
+        let mut appended = Vec::new();
+        file.seek(SeekFrom::Start(state.read))?;
+        file.take(len - state.read).read_to_end(&mut appended)?;
+
+        // Leave any line that's still being written for next time.
+        let Some(end) = appended.iter().rposition(|&b| b == b'\n') else {
+            return Ok(());
+        };
+        for line in appended[..end].split(|&b| b == b'\n') {
+            if let Ok(line) = serde_json::from_slice::<Line>(line) {
+                let until = line
+                    .until
+                    .map(|until| UNIX_EPOCH + Duration::from_secs(until));
+                state.exempt.insert(line.path, until);
+            }
+        }
+        state.read += end as u64 + 1;
+        Ok(())
+    }
+}
@@ -1,31 +1,578 @@
-use std::{fs, path::Path};
+use std::{
+    borrow::Cow,
+    cell::OnceCell,
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex, MutexGuard, OnceLock, RwLock},
+    thread,
+    time::{Duration, SystemTime},
+};
 
 use anyhow::{Context, Result};
 use clap::ValueEnum;
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
 
-// Enum of types of objects to hide
-#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+use crate::matcher::Rule;
+
+// Enum of types of objects to hide. Sockets, fifos and devices only exist on Unix, and junctions and other reparse
+// points that link elsewhere only exist on Windows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum ObjectType {
     File,
     Folder,
     Symlink,
+    Socket,
+    Fifo,
+    BlockDevice,
+    CharDevice,
+    Junction,
+    ReparsePoint,
     Unknown,
 }
 
+impl ObjectType {
+    // Returns true if the object links to somewhere else, rather than being what it links to.
+    pub fn is_link(self) -> bool {
+        matches!(self, Self::Symlink | Self::Junction | Self::ReparsePoint)
+    }
+}
+
+// Enum of what to do when hiding a path on Unix would rename it over something that already has its hidden name
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OnConflict {
+    #[default]
+    Skip,
+    Overwrite,
+    Suffix,
+}
+
+// What hiding or unhiding a path did to it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    // It was hidden or unhidden, and is now at this path
+    Changed(PathBuf),
+    // It was already as it should be
+    Unchanged,
+    // It was left alone, because something already has the name it would be given, at this path. Hiding doesn't
+    // rename anything on Windows, so it only ever is there when moving into a vault.
+    Conflict(PathBuf),
+}
+
+// The permissions given to the files and folders cloak makes for itself, such as reports, journals and the vault,
+// since they list exactly what was meant to be hidden. They're only given to new ones, so anything that already exists
+// keeps the permissions it has, and the umask can still take more away. Windows has no modes, so new files and folders
+// get whatever they inherit from the folder they're in.
+#[cfg_attr(target_family = "windows", allow(dead_code))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CreateModes {
+    file: u32,
+    dir: u32,
+}
+
+// Only the user can read or write files, or look in folders.
+impl Default for CreateModes {
+    fn default() -> Self {
+        Self {
+            file: 0o600,
+            dir: 0o700,
+        }
+    }
+}
+
+impl CreateModes {
+    // Build modes for new files and folders, using the defaults for any not given.
+    pub fn new(file: Option<u32>, dir: Option<u32>) -> Self {
+        let default = Self::default();
+        Self {
+            file: file.unwrap_or(default.file),
+            dir: dir.unwrap_or(default.dir),
+        }
+    }
+
+    // Returns the options to open a file with, so it's given the file mode if it's created.
+    pub fn open_options(&self) -> fs::OpenOptions {
+        #[allow(unused_mut)]
+        let mut options = fs::OpenOptions::new();
+        #[cfg(target_family = "unix")]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, self.file);
+        options
+    }
+
+    // Create a file, or empty it if it already exists.
+    pub fn create(&self, path: &Path) -> std::io::Result<fs::File> {
+        self.open_options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+    }
+
+    // Write a whole file, creating it if it doesn't exist.
+    pub fn write(&self, path: &Path, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+        use std::io::Write;
+
+        self.create(path)?.write_all(contents.as_ref())
+    }
+
+    // Create a folder and any folders it's in that don't exist yet.
+    pub fn create_dir_all(&self, path: &Path) -> std::io::Result<()> {
+        #[allow(unused_mut)]
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(target_family = "unix")]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, self.dir);
+        builder.create(path)
+    }
+}
+
+// Records the access and modification times of directories before entries in them are renamed, so they can be
+// restored after a batch of renames. The gate is held for reading while renaming and for writing while restoring, so
+// a rename can never happen between a restore and the directory's times being recorded again. Clones share the same
+// records, so a clone can be handed to an operation running on a thread of its own.
+#[derive(Clone, Debug, Default)]
+pub struct DirTimes {
+    gate: Arc<RwLock<()>>,
+    times: Arc<Mutex<HashMap<PathBuf, (FileTime, FileTime)>>>,
+}
+
+impl DirTimes {
+    // Restore the recorded times of every directory, and forget them. Every directory is attempted, even if restoring
+    // an earlier one fails, but only the first error is returned.
+    pub fn restore(&self) -> Result<()> {
+        let _gate = self.gate.write().unwrap_or_else(|e| e.into_inner());
+        let times = std::mem::take(&mut *self.times.lock().unwrap_or_else(|e| e.into_inner()));
+
+        let mut result = Ok(());
+        for (dir, (atime, mtime)) in times {
+            let restored = reachable(&dir)
+                .and_then(|reachable| Ok(filetime::set_file_times(&*reachable, atime, mtime)?));
+            if let Err(e) = restored {
+                if result.is_ok() {
+                    result = Err(e).with_context(|| {
+                        format!(
+                            "Failed to restore timestamps of directory {}",
+                            dir.display()
+                        )
+                    });
+                }
+            }
+        }
+        result
+    }
+
+    // Record the times of a directory, unless they have already been recorded since the last restore.
+    fn record(&self, dir: &Path) -> Result<()> {
+        // The parent of a bare file name is empty, which is the current directory.
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+
+        let mut times = self.times.lock().unwrap_or_else(|e| e.into_inner());
+        if !times.contains_key(dir) {
+            let metadata = fs::metadata(&*reachable(dir)?).with_context(|| {
+                format!("Failed to get timestamps of directory {}", dir.display())
+            })?;
+            times.insert(
+                dir.to_path_buf(),
+                (
+                    FileTime::from_last_access_time(&metadata),
+                    FileTime::from_last_modification_time(&metadata),
+                ),
+            );
+        }
+        Ok(())
+    }
+}
+
+// A path along with what's known about the object at it, so each system call for it is only made once. The type can
+// be given up front by whatever found the path, like the walker, and the metadata is only read when it's first needed.
+// Symlinks aren't followed, so both are always those of the path itself. The pattern that included it is kept once it's
+// been matched, so whatever records it being hidden doesn't need to match it again.
+#[derive(Clone, Debug)]
+pub struct Info {
+    path: PathBuf,
+    object_type: OnceCell<ObjectType>,
+    metadata: OnceCell<fs::Metadata>,
+    rule: OnceCell<Rule>,
+}
+
+impl Info {
+    // Build a new info for a path, with nothing known about it yet.
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            object_type: OnceCell::new(),
+            metadata: OnceCell::new(),
+            rule: OnceCell::new(),
+        }
+    }
+
+    // Build a new info for a path, along with its type and metadata if they're already known.
+    pub fn with(
+        path: PathBuf,
+        object_type: Option<ObjectType>,
+        metadata: Option<fs::Metadata>,
+    ) -> Self {
+        Self {
+            path,
+            object_type: object_type.map_or_else(OnceCell::new, OnceCell::from),
+            metadata: metadata.map_or_else(OnceCell::new, OnceCell::from),
+            rule: OnceCell::new(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Returns the metadata of the object at the path, reading it if it hasn't been read already.
+    pub fn metadata(&self) -> Result<&fs::Metadata> {
+        if let Some(metadata) = self.metadata.get() {
+            return Ok(metadata);
+        }
+        let metadata = fs::symlink_metadata(&*reachable(&self.path)?)
+            .with_context(|| format!("Failed to get metadata for path {}", self.path.display()))?;
+        Ok(self.metadata.get_or_init(|| metadata))
+    }
+
+    // Keep metadata that was read elsewhere, such as on another thread, unless some was already read.
+    pub fn set_metadata(&self, metadata: fs::Metadata) {
+        let _ = self.metadata.set(metadata);
+    }
+
+    // Keep the pattern that included the path, unless one was already kept.
+    pub fn set_rule(&self, rule: Rule) {
+        let _ = self.rule.set(rule);
+    }
+
+    // Returns the pattern that included the path, if it's been matched by one.
+    pub fn rule(&self) -> Option<&Rule> {
+        self.rule.get()
+    }
+
+    // Returns the type of the object at the path, from its metadata if it wasn't given up front.
+    pub fn object_type(&self) -> Result<ObjectType> {
+        if let Some(object_type) = self.object_type.get() {
+            return Ok(*object_type);
+        }
+        let object_type = type_of(&self.path, self.metadata()?.file_type());
+        Ok(*self.object_type.get_or_init(|| object_type))
+    }
+
+    // Returns the time the object at the path was last modified. A broken symlink still has a modification time.
+    pub fn modified(&self) -> Result<SystemTime> {
+        self.metadata()?.modified().with_context(|| {
+            format!(
+                "Failed to get modification time of path {}",
+                self.path.display()
+            )
+        })
+    }
+
+    // Returns the size of the object at the path. Symlinks and junctions are followed, so their size is that of their
+    // target.
+    pub fn size(&self) -> Result<u64> {
+        if self.object_type()?.is_link() {
+            size(&self.path)
+        } else {
+            Ok(self.metadata()?.len())
+        }
+    }
+
+    // Windows only function to check if the object at the path is hidden, by checking its hidden attribute. The
+    // attribute of a symlink itself is checked, rather than that of its target.
+    #[cfg(target_family = "windows")]
+    pub fn is_hidden(&self) -> Result<bool> {
+        use std::os::windows::fs::MetadataExt;
+
+        use winapi::um::winnt::FILE_ATTRIBUTE_HIDDEN;
+
+        let attributes = self.metadata()?.file_attributes();
+        Ok(attributes & FILE_ATTRIBUTE_HIDDEN == FILE_ATTRIBUTE_HIDDEN)
+    }
+
+    // Unix only function to check if the object at the path is hidden, by checking if its name starts with a dot.
+    #[cfg(target_family = "unix")]
+    pub fn is_hidden(&self) -> Result<bool> {
+        Ok(self
+            .path
+            .file_name()
+            .is_some_and(|file_name| file_name.as_encoded_bytes().starts_with(b".")))
+    }
+}
+
+// A path the system can be handed, even if the path it was made from is too long to be used as it is. On Linux, the
+// directories along such a path are opened a piece at a time, each relative to the last, and the rest of the path is
+// reached through the last one opened, which stays open until this is dropped. Elsewhere the path is used as it is.
+pub struct Reachable<'a> {
+    path: Cow<'a, Path>,
+    #[cfg(target_os = "linux")]
+    _dir: Option<std::os::fd::OwnedFd>,
+}
+
+impl std::ops::Deref for Reachable<'_> {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+// How many threads operations with a timeout are run on. An operation that hangs keeps its thread until it finishes,
+// so this is also the most threads a filesystem that has stopped responding can tie up.
+const BLOCKING_THREADS: usize = 8;
+
+// An operation waiting for one of the threads operations with a timeout are run on
+type Job = Box<dyn FnOnce() + Send>;
+
+// Enum of how far along an operation run with a timeout is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operation {
+    Waiting,
+    Running,
+    GivenUp,
+    Finished,
+}
+
+// The threads operations with a timeout are run on, each taking the next one waiting in turn, along with the mount
+// points that have stopped responding, and how many operations are still hung on each of them. The threads are
+// started the first time an operation needs one.
+struct Blocking {
+    jobs: mpsc::Sender<Job>,
+    unresponsive: Mutex<HashMap<PathBuf, usize>>,
+}
+
+impl Blocking {
+    // Returns the threads, starting them if they haven't been already. Any that can't be started are done without.
+    fn get() -> &'static Self {
+        static BLOCKING: OnceLock<Blocking> = OnceLock::new();
+
+        BLOCKING.get_or_init(|| {
+            let (jobs, queue) = mpsc::channel::<Job>();
+            let queue = Arc::new(Mutex::new(queue));
+            for index in 0..BLOCKING_THREADS {
+                let queue = Arc::clone(&queue);
+                let _ = thread::Builder::new()
+                    .name(format!("cloak-blocking-{index}"))
+                    .spawn(move || loop {
+                        let job = queue.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                        match job {
+                            Ok(job) => job(),
+                            Err(_) => break,
+                        }
+                    });
+            }
+            Self {
+                jobs,
+                unresponsive: Mutex::new(HashMap::new()),
+            }
+        })
+    }
+
+    // Returns the mount points that have stopped responding, with how many operations are hung on each of them.
+    fn unresponsive(&self) -> MutexGuard<'_, HashMap<PathBuf, usize>> {
+        self.unresponsive.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
 // --- public functions --- //
 
-// Returns true if the path matches one of the given types.
-pub fn matches_type(path: &Path, types: &[ObjectType]) -> Result<bool> {
-    // Get the type of the object at the path
-    let object_type = object_type(path)?;
+// Run an operation on a path, giving up on it if a timeout is given and it takes longer than that, such as on a dying
+// disk or a hung network mount. A system call that has blocked can't be cancelled, so the operation is run on one of a
+// few threads kept for it, and left to finish there in the background if it times out. The mount point it was on then
+// counts as unresponsive, and anything else on it is skipped straight away, without tying up another thread, until
+// every operation that hung on it has finished. Operations that time out before a thread is free for them are skipped.
+pub fn within<T: Send + 'static>(
+    path: &Path,
+    timeout: Option<Duration>,
+    operation: impl FnOnce(&Path) -> Result<T> + Send + 'static,
+) -> Result<T> {
+    use anyhow::bail;
+
+    let Some(timeout) = timeout else {
+        return operation(path);
+    };
+
+    let blocking = Blocking::get();
+    let mount = crate::mounts::mount_of(path);
+    if blocking.unresponsive().contains_key(&mount) {
+        bail!(
+            "Skipped path {}, since the filesystem at {} isn't responding",
+            path.display(),
+            mount.display()
+        );
+    }
+
+    // The operation is only started if it hasn't been given up on yet, and if it's given up on while it's running, the
+    // mount point stays unresponsive until it finishes.
+    let (sender, receiver) = mpsc::sync_channel(1);
+    let state = Arc::new(Mutex::new(Operation::Waiting));
+    let owned = path.to_path_buf();
+    let job = {
+        let state = Arc::clone(&state);
+        let mount = mount.clone();
+        move || {
+            {
+                let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+                if *state == Operation::GivenUp {
+                    return;
+                }
+                *state = Operation::Running;
+            }
+            let result = operation(&owned);
+            {
+                let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+                if *state == Operation::GivenUp {
+                    let mut unresponsive = Blocking::get().unresponsive();
+                    if let Some(hung) = unresponsive.get_mut(&mount) {
+                        *hung -= 1;
+                        if *hung == 0 {
+                            unresponsive.remove(&mount);
+                        }
+                    }
+                }
+                *state = Operation::Finished;
+            }
+            let _ = sender.send(result);
+        }
+    };
+    if blocking.jobs.send(Box::new(job)).is_err() {
+        bail!("Failed to start operation on path {}", path.display());
+    }
 
-    // Check if the object type matches one of the given types
-    Ok(types.iter().any(|t| *t == object_type))
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+            match *state {
+                Operation::Finished => {
+                    drop(state);
+                    return receiver.recv().unwrap_or_else(|_| {
+                        bail!("Operation on path {} failed unexpectedly", path.display())
+                    });
+                }
+                Operation::Running => {
+                    *blocking.unresponsive().entry(mount).or_insert(0) += 1;
+                }
+                Operation::Waiting | Operation::GivenUp => {}
+            }
+            *state = Operation::GivenUp;
+            bail!(
+                "Gave up on path {} after {timeout:?}, since the filesystem isn't responding",
+                path.display()
+            )
+        }
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+            bail!("Operation on path {} failed unexpectedly", path.display())
+        }
+    }
 }
 
-// Windows only function to hide a file or folder
+// Linux only function to get a path the object at a path can be reached through, even if it is longer than PATH_MAX,
+// as it can be in very deep trees. Shorter paths are returned as they are.
+#[cfg(target_os = "linux")]
+pub fn reachable(path: &Path) -> Result<Reachable<'_>> {
+    use nix::{
+        fcntl::{openat, OFlag, AT_FDCWD},
+        libc::PATH_MAX,
+        sys::stat::Mode,
+    };
+    use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+
+    if path.as_os_str().len() < PATH_MAX as usize {
+        return Ok(Reachable {
+            path: Cow::Borrowed(path),
+            _dir: None,
+        });
+    }
+
+    // Open the directories along the path a piece at a time, leaving room in each piece for the prefix it's reached
+    // through.
+    let max_piece = PATH_MAX as usize - 64;
+    let flags = OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC;
+    let mut dir: Option<OwnedFd> = None;
+    let mut piece = PathBuf::new();
+    for component in path.components() {
+        let length = piece.as_os_str().len() + component.as_os_str().len() + 1;
+        if !piece.as_os_str().is_empty() && length >= max_piece {
+            let opened = match &dir {
+                Some(dir) => openat(dir.as_fd(), &piece, flags, Mode::empty()),
+                None => openat(AT_FDCWD, &piece, flags, Mode::empty()),
+            }
+            .with_context(|| format!("Failed to open directories along path {}", path.display()))?;
+            dir = Some(opened);
+            piece.clear();
+        }
+        piece.push(component);
+    }
+
+    let path = match &dir {
+        Some(dir) => Path::new("/proc/self/fd")
+            .join(dir.as_raw_fd().to_string())
+            .join(piece),
+        None => piece,
+    };
+    Ok(Reachable {
+        path: Cow::Owned(path),
+        _dir: dir,
+    })
+}
+
+// Function to get a path the object at a path can be reached through, on systems other than Linux. The path is always
+// returned as it is, since Windows handles long paths itself, and other systems can't reach past PATH_MAX this way.
+#[cfg(not(target_os = "linux"))]
+pub fn reachable(path: &Path) -> Result<Reachable<'_>> {
+    Ok(Reachable {
+        path: Cow::Borrowed(path),
+    })
+}
+
+// Returns the size of the object at a path.
+pub fn size(path: &Path) -> Result<u64> {
+    fs::metadata(&*reachable(path)?)
+        .map(|metadata| metadata.len())
+        .with_context(|| format!("Failed to get size of path {}", path.display()))
+}
+
+// Returns the target of a symlink, with every symlink along the way resolved.
+pub fn resolve(path: &Path) -> Result<PathBuf> {
+    fs::canonicalize(&*reachable(path)?)
+        .with_context(|| format!("Failed to resolve target of symlink {}", path.display()))
+}
+
+// Check if a file or folder is hidden.
+pub fn is_hidden(path: &Path) -> Result<bool> {
+    Info::new(path.to_path_buf()).is_hidden()
+}
+
+// Function to make a path absolute, without resolving symlinks, so it's the same however it was reached. If the current
+// directory can't be read, then it's returned as it is.
+pub fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// Function to check that a path exists and is hidden, reading everything about it afresh, such as to check that hiding
+// it stuck.
+pub fn verify_hidden(path: &Path) -> Result<bool> {
+    let info = Info::new(path.to_path_buf());
+    info.metadata()?;
+    info.is_hidden()
+}
+
+// Windows only function to hide a file or folder. Hiding doesn't rename anything on Windows, so there are never any
+// directory times to preserve, nor any conflicts. Symlinks are hidden themselves, rather than their targets. The
+// attributes are taken from the info, so they're only read if they haven't been already.
 #[cfg(target_family = "windows")]
-pub fn hide(path: &Path) -> Result<()> {
+pub fn hide(
+    info: &Info,
+    _dir_times: Option<&DirTimes>,
+    _on_conflict: OnConflict,
+) -> Result<Change> {
     use std::{
         io::Error,
         os::windows::{ffi::OsStrExt, fs::MetadataExt},
@@ -37,9 +584,8 @@ pub fn hide(path: &Path) -> Result<()> {
     };
 
     // Get the current file attributes
-    let attributes = fs::metadata(path)
-        .with_context(|| format!("Failed to get file attributes for {}", path.display()))?
-        .file_attributes();
+    let path = info.path();
+    let attributes = info.metadata()?.file_attributes();
 
     // Convert the path to a wide string for the Windows API
     let wide_path = path
@@ -50,78 +596,601 @@ pub fn hide(path: &Path) -> Result<()> {
 
     // Check if the file is already hidden. Otherwise, hide it.
     if attributes & FILE_ATTRIBUTE_HIDDEN == FILE_ATTRIBUTE_HIDDEN {
-        Ok(())
+        Ok(Change::Unchanged)
     } else {
         let result =
             unsafe { SetFileAttributesW(wide_path.as_ptr(), attributes | FILE_ATTRIBUTE_HIDDEN) };
         if result == FALSE {
-            Err::<(), anyhow::Error>(Error::last_os_error().into())
+            Err::<Change, anyhow::Error>(Error::last_os_error().into())
                 .with_context(|| format!("Failed to hide path {}", path.display()))
         } else {
-            Ok(())
+            Ok(Change::Changed(path.to_path_buf()))
         }
     }
 }
 
-// Unix only function to hide a file or folder. Just prepends a dot to the file name.
+// Unix only function to hide a file or folder. Just prepends a dot to the file name. If something already has that
+// name, then the path is left alone, renamed over it, or given the first free name with a number after it, such as
+// .foo.1, depending on on_conflict. If dir_times is given, then the times of the parent directory are recorded before
+// renaming, so they can be restored later.
 #[cfg(target_family = "unix")]
-pub fn hide(path: &Path) -> Result<()> {
+pub fn hide(info: &Info, dir_times: Option<&DirTimes>, on_conflict: OnConflict) -> Result<Change> {
+    use anyhow::anyhow;
+
+    let path = info.path();
+
     // Get the file name from the path
     let file_name = path
         .file_name()
         .ok_or_else(|| anyhow!("Failed to get file name from path {}", path.display()))?;
 
-    // Change the file name to a string
-    let file_name = file_name.to_str().ok_or_else(|| {
-        anyhow!(
-            "Failed to convert file name to string from path {}",
-            path.display()
-        )
-    })?;
+    // Check if the file is already hidden, going by the raw bytes of its name, so names that aren't valid UTF-8 are
+    // hidden too. Otherwise, hide it.
+    if file_name.as_encoded_bytes().starts_with(b".") {
+        return Ok(Change::Unchanged);
+    }
 
-    // Check if the file is already hidden. Otherwise, hide it.
-    if file_name.starts_with('.') {
-        OK(())
+    // Get the new file name, unless something already has it
+    let hidden = hidden_path(path);
+    let hidden_name = hidden.file_name().unwrap_or(file_name);
+    let mut new_file_name = hidden_name.to_os_string();
+    if let Some(existing) = conflict(path)? {
+        match on_conflict {
+            OnConflict::Skip => return Ok(Change::Conflict(existing)),
+            OnConflict::Overwrite => {}
+            OnConflict::Suffix => {
+                let mut suffix = 1;
+                while reachable(&existing.with_file_name(&new_file_name))?
+                    .symlink_metadata()
+                    .is_ok()
+                {
+                    new_file_name = hidden_name.to_os_string();
+                    new_file_name.push(format!(".{suffix}"));
+                    suffix += 1;
+                }
+            }
+        }
+    }
+
+    // Rename the file
+    rename(path, &new_file_name, dir_times)?;
+
+    Ok(Change::Changed(path.with_file_name(new_file_name)))
+}
+
+// Function to check whether something other than the path itself already has the name it would be given when hidden,
+// returning the path of whatever does. Hiding doesn't rename anything on Windows, so there it never does.
+pub fn conflict(path: &Path) -> Result<Option<PathBuf>> {
+    let hidden = hidden_path(path);
+    if hidden != path {
+        existing(&hidden)
     } else {
-        // Get the parent directory
-        let parent = path.parent().with_context(|| {
-            format!("Failed to get parent directory of path {}", path.display())
-        })?;
+        Ok(None)
+    }
+}
 
-        // Get the new file name
-        let new_file_name = format!(".{}", file_name);
+// Function to check whether something already has a name, returning its path as it's actually named. On a filesystem
+// that ignores case, that can be a name that only differs in case, such as .foo when asking for .Foo, which is found
+// by listing the folder it's in, so whatever is reported or replaced is named as it really is.
+pub fn existing(path: &Path) -> Result<Option<PathBuf>> {
+    if reachable(path)?.symlink_metadata().is_err() {
+        return Ok(None);
+    }
+    let (Some(parent), Some(file_name)) = (path.parent(), path.file_name()) else {
+        return Ok(Some(path.to_path_buf()));
+    };
+    if !case_insensitive(parent) {
+        return Ok(Some(path.to_path_buf()));
+    }
 
-        // Rename the file
-        fs::rename(path, parent.join(new_file_name))
-            .with_context(|| format!("Failed to rename path {}", path.display()))?;
+    // Prefer an exact match, in case the folder itself is case sensitive after all.
+    let mut found = None;
+    for entry in fs::read_dir(&*reachable(parent)?)
+        .with_context(|| format!("Failed to read directory {}", parent.display()))?
+    {
+        let name = entry
+            .with_context(|| format!("Failed to read directory {}", parent.display()))?
+            .file_name();
+        if name == file_name {
+            return Ok(Some(path.to_path_buf()));
+        }
+        if found.is_none() && same_ignoring_case(&name, file_name) {
+            found = Some(name);
+        }
+    }
+    Ok(Some(
+        path.with_file_name(found.as_deref().unwrap_or(file_name)),
+    ))
+}
 
-        Ok(())
+// Helper function to check if two names are the same but for case.
+fn same_ignoring_case(a: &std::ffi::OsStr, b: &std::ffi::OsStr) -> bool {
+    match (a.to_str(), b.to_str()) {
+        (Some(a), Some(b)) => a.to_lowercase() == b.to_lowercase(),
+        _ => a
+            .as_encoded_bytes()
+            .eq_ignore_ascii_case(b.as_encoded_bytes()),
     }
 }
 
-// --- private functions --- //
+// Unix only function to check whether the filesystem a folder is on ignores case, as the default ones on macOS do, and
+// as mounted FAT, exFAT or NTFS volumes and some network shares do anywhere. It's probed once per filesystem, by
+// looking up the folder, or the nearest folder above it on the same filesystem with a letter in its name, under that
+// name with its case swapped, and checking whether that finds the same folder. If there's nothing to probe, then it's
+// taken to follow the platform's default.
+#[cfg(target_family = "unix")]
+pub fn case_insensitive(dir: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
 
-// Returns the type of object at a path.
-fn object_type(path: &Path) -> Result<ObjectType> {
-    // Get the metadata for the path
-    let metadata = fs::metadata(path).with_context(|| {
+    static PROBED: OnceLock<Mutex<HashMap<u64, bool>>> = OnceLock::new();
+
+    let dir = absolute(dir);
+    let Ok(metadata) = fs::symlink_metadata(&dir) else {
+        return cfg!(target_os = "macos");
+    };
+    let mut probed = PROBED
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    *probed.entry(metadata.dev()).or_insert_with(|| {
+        for ancestor in dir.ancestors() {
+            let (Some(name), Ok(original)) = (
+                ancestor.file_name().and_then(|name| name.to_str()),
+                fs::symlink_metadata(ancestor),
+            ) else {
+                continue;
+            };
+            if original.dev() != metadata.dev() {
+                break;
+            }
+            let swapped = name
+                .chars()
+                .map(|c| {
+                    if c.is_lowercase() {
+                        c.to_uppercase().collect::<String>()
+                    } else {
+                        c.to_lowercase().collect()
+                    }
+                })
+                .collect::<String>();
+            if swapped != name {
+                return fs::symlink_metadata(ancestor.with_file_name(swapped)).is_ok_and(
+                    |swapped| swapped.dev() == original.dev() && swapped.ino() == original.ino(),
+                );
+            }
+        }
+        cfg!(target_os = "macos")
+    })
+}
+
+// Windows only function to check whether the filesystem a folder is on ignores case. NTFS does, unless case
+// sensitivity has been turned on for the folder, and the exact name is always preferred in case it has.
+#[cfg(target_family = "windows")]
+pub fn case_insensitive(_dir: &Path) -> bool {
+    true
+}
+
+// Windows only function to unhide a file or folder, by clearing its hidden attribute. Nothing is renamed, so the
+// original path is never needed. The attributes are taken from the info, so they're only read if they haven't been
+// already.
+#[cfg(target_family = "windows")]
+pub fn unhide(
+    info: &Info,
+    _dir_times: Option<&DirTimes>,
+    _original: Option<&Path>,
+) -> Result<Change> {
+    use std::{
+        io::Error,
+        os::windows::{ffi::OsStrExt, fs::MetadataExt},
+    };
+
+    use winapi::{
+        shared::minwindef::FALSE,
+        um::{fileapi::SetFileAttributesW, winnt::FILE_ATTRIBUTE_HIDDEN},
+    };
+
+    // Get the current file attributes
+    let path = info.path();
+    let attributes = info.metadata()?.file_attributes();
+
+    // Convert the path to a wide string for the Windows API
+    let wide_path = path
+        .as_os_str()
+        .encode_wide()
+        .chain(Some(0))
+        .collect::<Vec<_>>();
+
+    // Check if the file is hidden. If it is, unhide it.
+    if attributes & FILE_ATTRIBUTE_HIDDEN != FILE_ATTRIBUTE_HIDDEN {
+        Ok(Change::Unchanged)
+    } else {
+        let result =
+            unsafe { SetFileAttributesW(wide_path.as_ptr(), attributes & !FILE_ATTRIBUTE_HIDDEN) };
+        if result == FALSE {
+            Err::<Change, anyhow::Error>(Error::last_os_error().into())
+                .with_context(|| format!("Failed to unhide path {}", path.display()))
+        } else {
+            Ok(Change::Changed(path.to_path_buf()))
+        }
+    }
+}
+
+// Unix only function to unhide a file or folder. Just removes the dot from the start of the file name, unless the
+// original path it had before it was hidden is given, in which case it gets back the name it had. Fails rather than
+// replace anything that already has the unhidden name.
+#[cfg(target_family = "unix")]
+pub fn unhide(
+    info: &Info,
+    dir_times: Option<&DirTimes>,
+    original: Option<&Path>,
+) -> Result<Change> {
+    use anyhow::bail;
+
+    let path = info.path();
+
+    // Get the path without the dot. If it's the same, then the file isn't hidden.
+    let unhidden = unhidden_as(path, original);
+    if unhidden == path {
+        return Ok(Change::Unchanged);
+    }
+
+    // Check nothing already has the unhidden name
+    if let Some(existing) = existing(&unhidden)? {
+        bail!(
+            "Failed to unhide path {} because {} already exists",
+            path.display(),
+            existing.display()
+        );
+    }
+
+    // Rename the file
+    let new_file_name = unhidden
+        .file_name()
+        .with_context(|| format!("Failed to get file name from path {}", path.display()))?;
+    rename(path, new_file_name, dir_times)?;
+
+    Ok(Change::Changed(unhidden))
+}
+
+// Windows only function to get the path a file or folder will have once it's hidden. Hiding doesn't rename anything on
+// Windows, so it's always the same path.
+#[cfg(target_family = "windows")]
+pub fn hidden_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+// Unix only function to get the path a file or folder will have once it's hidden, by adding a dot to the start of its
+// file name. Paths that are already hidden are returned as they are.
+#[cfg(target_family = "unix")]
+pub fn hidden_path(path: &Path) -> PathBuf {
+    use std::ffi::OsString;
+
+    match path.file_name() {
+        Some(file_name) if !file_name.as_encoded_bytes().starts_with(b".") => {
+            let mut hidden = OsString::from(".");
+            hidden.push(file_name);
+            path.with_file_name(hidden)
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+// Function to get the path a file or folder will have once it's unhidden. If the original path it had before it was
+// hidden is given, then it gets back the name it had. Otherwise it's the same as unhidden_path.
+pub fn unhidden_as(path: &Path, original: Option<&Path>) -> PathBuf {
+    match original.and_then(Path::file_name) {
+        Some(file_name) if cfg!(target_family = "unix") => path.with_file_name(file_name),
+        _ => unhidden_path(path),
+    }
+}
+
+// Windows only function to get the path a file or folder had before it was hidden. Hiding doesn't rename anything on
+// Windows, so it's always the same path.
+#[cfg(target_family = "windows")]
+pub fn unhidden_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+// Unix only function to get the path a file or folder had before it was hidden, by removing the dot from the start of
+// its file name. Paths that aren't hidden are returned as they are.
+#[cfg(target_family = "unix")]
+pub fn unhidden_path(path: &Path) -> PathBuf {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    match path.file_name().map(OsStr::as_bytes) {
+        Some([b'.', rest @ ..]) if !rest.is_empty() => path.with_file_name(OsStr::from_bytes(rest)),
+        _ => path.to_path_buf(),
+    }
+}
+
+// Windows only function to list the names of the alternate data streams of a file or folder. The default data stream
+// is not included.
+#[cfg(target_family = "windows")]
+pub fn alternate_data_streams(path: &Path) -> Result<Vec<String>> {
+    use std::{io::Error, os::windows::ffi::OsStrExt};
+
+    use winapi::{
+        ctypes::c_void,
+        shared::{minwindef::FALSE, winerror::ERROR_HANDLE_EOF},
+        um::{
+            fileapi::{FindClose, FindFirstStreamW, FindNextStreamW, FindStreamInfoStandard},
+            handleapi::INVALID_HANDLE_VALUE,
+        },
+    };
+
+    // Layout of WIN32_FIND_STREAM_DATA, which winapi doesn't define.
+    #[repr(C)]
+    struct FindStreamData {
+        stream_size: i64,
+        stream_name: [u16; 260 + 36],
+    }
+
+    // Convert the path to a wide string for the Windows API
+    let wide_path = path
+        .as_os_str()
+        .encode_wide()
+        .chain(Some(0))
+        .collect::<Vec<_>>();
+
+    let mut data = FindStreamData {
+        stream_size: 0,
+        stream_name: [0; 260 + 36],
+    };
+    let mut streams = Vec::new();
+
+    // Find the first stream. If there are no streams at all, then the handle is invalid and the error is EOF.
+    let handle = unsafe {
+        FindFirstStreamW(
+            wide_path.as_ptr(),
+            FindStreamInfoStandard,
+            &mut data as *mut _ as *mut c_void,
+            0,
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        let error = Error::last_os_error();
+        return if error.raw_os_error() == Some(ERROR_HANDLE_EOF as i32) {
+            Ok(streams)
+        } else {
+            Err::<Vec<String>, anyhow::Error>(error.into()).with_context(|| {
+                format!(
+                    "Failed to list alternate data streams of {}",
+                    path.display()
+                )
+            })
+        };
+    }
+
+    // Stream names are of the form ":name:$DATA". The default data stream has an empty name, so skip it.
+    loop {
+        let len = data
+            .stream_name
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(data.stream_name.len());
+        let name = String::from_utf16_lossy(&data.stream_name[..len]);
+        let name = name.strip_prefix(':').unwrap_or(&name);
+        let name = name.strip_suffix(":$DATA").unwrap_or(name);
+        if !name.is_empty() {
+            streams.push(name.to_owned());
+        }
+
+        if unsafe { FindNextStreamW(handle, &mut data as *mut _ as *mut c_void) } == FALSE {
+            break;
+        }
+    }
+    let error = Error::last_os_error();
+    unsafe { FindClose(handle) };
+
+    if error.raw_os_error() == Some(ERROR_HANDLE_EOF as i32) {
+        Ok(streams)
+    } else {
+        Err::<Vec<String>, anyhow::Error>(error.into()).with_context(|| {
+            format!(
+                "Failed to list alternate data streams of {}",
+                path.display()
+            )
+        })
+    }
+}
+
+// Unix systems don't have alternate data streams, so there are never any to list.
+#[cfg(target_family = "unix")]
+pub fn alternate_data_streams(_path: &Path) -> Result<Vec<String>> {
+    Ok(Vec::new())
+}
+
+// Function to move a file or folder to somewhere else entirely, such as into or out of a vault, creating any folders
+// it needs on the way. If something is already there, then the path is left alone, moved over it, or given the first
+// free name with a number after it, such as foo.1, depending on on_conflict. Both have to be on the same filesystem.
+// If dir_times is given, then the times of the directory it's moved out of are recorded before moving, so they can be
+// restored later.
+pub fn relocate(
+    path: &Path,
+    to: &Path,
+    dir_times: Option<&DirTimes>,
+    on_conflict: OnConflict,
+) -> Result<Change> {
+    // Work out where to move it, unless something is already there
+    let mut to = to.to_path_buf();
+    if let Some(existing) = existing(&to)? {
+        match on_conflict {
+            OnConflict::Skip => return Ok(Change::Conflict(existing)),
+            OnConflict::Overwrite => {}
+            OnConflict::Suffix => {
+                let file_name = to
+                    .file_name()
+                    .with_context(|| format!("Failed to get file name from path {}", to.display()))?
+                    .to_owned();
+                let mut suffix = 1;
+                loop {
+                    let mut suffixed = file_name.clone();
+                    suffixed.push(format!(".{suffix}"));
+                    let candidate = to.with_file_name(suffixed);
+                    if reachable(&candidate)?.symlink_metadata().is_err() {
+                        to = candidate;
+                        break;
+                    }
+                    suffix += 1;
+                }
+            }
+        }
+    }
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(&*reachable(parent)?)
+            .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+    }
+
+    // Record the times of the directory it's moved out of, holding the gate until the move is done
+    let parent = path
+        .parent()
+        .with_context(|| format!("Failed to get parent directory of path {}", path.display()))?;
+    let _gate = match dir_times {
+        Some(dir_times) => {
+            let gate = dir_times.gate.read().unwrap_or_else(|e| e.into_inner());
+            dir_times.record(parent)?;
+            Some(gate)
+        }
+        None => None,
+    };
+
+    fs::rename(&*reachable(path)?, &*reachable(&to)?).with_context(|| {
         format!(
-            "Failed to get metadata for path {}",
-            path.display()
+            "Failed to move path {} to {}, which have to be on the same filesystem",
+            path.display(),
+            to.display()
         )
     })?;
+    Ok(Change::Changed(to))
+}
+
+// Unix only function to rename a file or folder within its directory. If dir_times is given, then the times of the
+// directory are recorded before renaming, so they can be restored later.
+#[cfg(target_family = "unix")]
+fn rename(
+    path: &Path,
+    new_file_name: impl AsRef<Path>,
+    dir_times: Option<&DirTimes>,
+) -> Result<()> {
+    // Get the parent directory
+    let parent = path
+        .parent()
+        .with_context(|| format!("Failed to get parent directory of path {}", path.display()))?;
+
+    // Record the times of the parent directory, holding the gate until the rename is done
+    let _gate = match dir_times {
+        Some(dir_times) => {
+            let gate = dir_times.gate.read().unwrap_or_else(|e| e.into_inner());
+            dir_times.record(parent)?;
+            Some(gate)
+        }
+        None => None,
+    };
+
+    // Rename through the parent directory, so paths too long to be used as they are can still be renamed
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("Failed to get file name from path {}", path.display()))?;
+    let reachable_parent = reachable(parent)?;
+    fs::rename(
+        reachable_parent.join(file_name),
+        reachable_parent.join(new_file_name),
+    )
+    .with_context(|| format!("Failed to rename path {}", path.display()))
+}
 
+// Returns the type of object at a path. Symlinks aren't followed, so they are always reported as symlinks, even if
+// they're broken.
+pub fn object_type(path: &Path) -> Result<ObjectType> {
+    Info::new(path.to_path_buf()).object_type()
+}
+
+// Returns the type of object a file type is for, which was read from the path without following symlinks.
+pub fn type_of(path: &Path, file_type: fs::FileType) -> ObjectType {
     // Check if the path is a file
-    if metadata.is_file() {
-        Ok(ObjectType::File)
+    if file_type.is_file() {
+        ObjectType::File
         // Check if the path is a directory
-    } else if metadata.is_dir() {
-        Ok(ObjectType::Folder)
+    } else if file_type.is_dir() {
+        ObjectType::Folder
         // Check if the path is a symbolic link
-    } else if metadata.is_symlink() {
-        Ok(ObjectType::Symlink)
-        // Otherwise, return an error
+    } else if file_type.is_symlink() {
+        link_type(path)
+        // Otherwise, it's something else
     } else {
-        Ok(ObjectType::Unknown)
+        special_type(file_type)
+    }
+}
+
+// Unix only function to get the type of anything that isn't a file, folder or symlink.
+#[cfg(target_family = "unix")]
+fn special_type(file_type: fs::FileType) -> ObjectType {
+    use std::os::unix::fs::FileTypeExt;
+
+    if file_type.is_socket() {
+        ObjectType::Socket
+    } else if file_type.is_fifo() {
+        ObjectType::Fifo
+    } else if file_type.is_block_device() {
+        ObjectType::BlockDevice
+    } else if file_type.is_char_device() {
+        ObjectType::CharDevice
+    } else {
+        ObjectType::Unknown
+    }
+}
+
+// Windows has nothing else it can tell apart.
+#[cfg(target_family = "windows")]
+fn special_type(_file_type: fs::FileType) -> ObjectType {
+    ObjectType::Unknown
+}
+
+// Unix only function to get the type of link at a path. Every link is a symlink.
+#[cfg(target_family = "unix")]
+pub fn link_type(_path: &Path) -> ObjectType {
+    ObjectType::Symlink
+}
+
+// Windows only function to get the type of link at a path, from its reparse tag. Junctions, and any other reparse
+// point that links elsewhere, are read as symlinks by the standard library, but are told apart here, so they can be
+// picked out with --types. A link whose tag can't be read is taken to be a symlink.
+#[cfg(target_family = "windows")]
+pub fn link_type(path: &Path) -> ObjectType {
+    use std::os::windows::ffi::OsStrExt;
+
+    use winapi::um::{
+        fileapi::{FindClose, FindFirstFileW},
+        handleapi::INVALID_HANDLE_VALUE,
+        minwinbase::WIN32_FIND_DATAW,
+        winnt::{FILE_ATTRIBUTE_REPARSE_POINT, IO_REPARSE_TAG_MOUNT_POINT, IO_REPARSE_TAG_SYMLINK},
+    };
+
+    let Ok(reachable) = reachable(path) else {
+        return ObjectType::Symlink;
+    };
+
+    // Convert the path to a wide string for the Windows API
+    let wide_path = reachable
+        .as_os_str()
+        .encode_wide()
+        .chain(Some(0))
+        .collect::<Vec<_>>();
+
+    // The reparse tag of a reparse point is only given by finding it, in what's otherwise a reserved field.
+    let mut data: WIN32_FIND_DATAW = unsafe { std::mem::zeroed() };
+    let handle = unsafe { FindFirstFileW(wide_path.as_ptr(), &mut data) };
+    if handle == INVALID_HANDLE_VALUE {
+        return ObjectType::Symlink;
+    }
+    unsafe { FindClose(handle) };
+
+    if data.dwFileAttributes & FILE_ATTRIBUTE_REPARSE_POINT == 0 {
+        return ObjectType::Symlink;
+    }
+    match data.dwReserved0 {
+        IO_REPARSE_TAG_SYMLINK => ObjectType::Symlink,
+        IO_REPARSE_TAG_MOUNT_POINT => ObjectType::Junction,
+        _ => ObjectType::ReparsePoint,
     }
 }
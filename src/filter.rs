@@ -1,45 +1,691 @@
-use std::path::Path;
+use crate::empty::{self, EmptyFolders};
 use crate::filesystem;
-use crate::filesystem::ObjectType;
-use crate::matcher::Matcher;
+use crate::filesystem::{Info, ObjectType};
+use crate::git::{Git, GitState};
+use crate::matcher::{MatchOn, Matcher};
+use crate::output::{Event, Reporter};
+use crate::scan::Scanner;
+use crate::summary::{SkipReason, Summary};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use globset::GlobSet;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+// How much of the start of a file is read to work out the type of its content, which is more than any type that can be
+// recognized needs.
+const MIME_SNIFF_BYTES: u64 = 8192;
+
+// Enum of whether the paths being searched or watched can be hidden themselves, and not just what's in them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum IncludeRoot {
+    #[default]
+    Never,
+    Allow,
+    Only,
+}
 
 // Handler function to check if a path matches the given file_types, handling errors and printing out verbose messages,
-// as necessary.
-pub fn file_type_matches(path: &Path, types: Option<&[ObjectType]>, verbose: bool) -> bool {
-    types.map_or(true, |types| {
+// as necessary. The type is only read from the filesystem if it isn't already known.
+pub fn file_type_matches(
+    info: &Info,
+    types: Option<&[ObjectType]>,
+    verbose: bool,
+    summary: &Summary,
+    reporter: &dyn Reporter,
+) -> bool {
+    let path = info.path();
+    types.is_none_or(|types| {
         // If there's an error, print it out and return false.
-        filesystem::matches_type(path, types)
+        info.object_type()
+            .map(|object_type| types.contains(&object_type))
             .inspect(|r| {
-                if verbose && !r {
-                    println!(
-                        "Skipping {} because it's not a file or folder",
-                        path.display()
-                    );
+                if !r {
+                    summary.record_skipped(SkipReason::Type);
+                    if verbose {
+                        reporter.report(&Event::Skipped {
+                            path,
+                            because: "it's not one of the types to hide".to_owned(),
+                        });
+                    }
                 }
             })
-            .inspect_err(|e| eprintln!("{e}"))
+            .inspect_err(|e| {
+                reporter.report(&Event::Error(e));
+                summary.record_error(e);
+            })
             .unwrap_or(false)
     })
 }
 
-// Helper function to check if a path matches the given matcher
-pub fn path_matches_pattern(path: &Path, matcher: &Matcher, verbose: bool) -> bool {
-    let res = matcher.matches(path);
+// Handler function to check if a path is a file with a size between the given minimum and maximum, if either is given,
+// handling errors and printing out verbose messages, as necessary. Only files have a meaningful size, so anything else
+// is skipped when a size is given.
+pub fn size_in_range(
+    info: &Info,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    verbose: bool,
+    summary: &Summary,
+    reporter: &dyn Reporter,
+) -> bool {
+    if min_size.is_none() && max_size.is_none() {
+        return true;
+    }
+    let path = info.path();
+    info.object_type()
+        .and_then(|object_type| {
+            Ok(object_type == ObjectType::File && {
+                let size = info.size()?;
+                min_size.is_none_or(|min_size| size >= min_size)
+                    && max_size.is_none_or(|max_size| size <= max_size)
+            })
+        })
+        .inspect(|r| {
+            if !r {
+                summary.record_skipped(SkipReason::Size);
+                if verbose {
+                    reporter.report(&Event::Skipped {
+                        path,
+                        because: "it's not a file within the size limits".to_owned(),
+                    });
+                }
+            }
+        })
+        .inspect_err(|e| {
+            reporter.report(&Event::Error(e));
+            summary.record_error(e);
+        })
+        .unwrap_or(false)
+}
+
+// Handler function to check if a path was last modified at least older_than ago, and at most newer_than ago, if either
+// is given, handling errors and printing out verbose messages, as necessary.
+pub fn age_in_range(
+    info: &Info,
+    older_than: Option<Duration>,
+    newer_than: Option<Duration>,
+    verbose: bool,
+    summary: &Summary,
+    reporter: &dyn Reporter,
+) -> bool {
+    if older_than.is_none() && newer_than.is_none() {
+        return true;
+    }
+    let path = info.path();
+    info.modified()
+        .map(|modified| {
+            // Anything modified in the future is treated as brand new.
+            let age = SystemTime::now()
+                .duration_since(modified)
+                .unwrap_or(Duration::ZERO);
+            older_than.is_none_or(|older_than| age >= older_than)
+                && newer_than.is_none_or(|newer_than| age <= newer_than)
+        })
+        .inspect(|r| {
+            if !r {
+                summary.record_skipped(SkipReason::Age);
+                if verbose {
+                    reporter.report(&Event::Skipped {
+                        path,
+                        because: "it was modified outside of the ages to hide".to_owned(),
+                    });
+                }
+            }
+        })
+        .inspect_err(|e| {
+            reporter.report(&Event::Error(e));
+            summary.record_error(e);
+        })
+        .unwrap_or(false)
+}
+
+// Who a path has to belong to, and which permissions it has to have, for it to be hidden. Only Unix has owners, groups
+// and modes like these, so elsewhere one is never built, and nothing is left alone for them.
+#[cfg_attr(not(target_family = "unix"), allow(dead_code))]
+#[derive(Debug)]
+pub struct Ownership {
+    owners: Option<Vec<u32>>,
+    groups: Option<Vec<u32>>,
+    perm: Option<u32>,
+}
+
+impl Ownership {
+    // Build the filter from the users and groups a path can belong to, given by name or id, and the permission bits
+    // that all have to be set on it. Returns None if there's nothing to filter by.
+    #[cfg(target_family = "unix")]
+    pub fn new(
+        owners: Option<Vec<String>>,
+        groups: Option<Vec<String>>,
+        perm: Option<u32>,
+    ) -> Result<Option<Self>> {
+        if owners.is_none() && groups.is_none() && perm.is_none() {
+            return Ok(None);
+        }
+        let resolve = |names: Option<Vec<String>>, lookup: fn(&str) -> Result<u32>| {
+            names
+                .map(|names| {
+                    names
+                        .iter()
+                        .map(|name| name.parse().or_else(|_| lookup(name)))
+                        .collect::<Result<Vec<_>>>()
+                })
+                .transpose()
+        };
+        Ok(Some(Self {
+            owners: resolve(owners, user_id)?,
+            groups: resolve(groups, group_id)?,
+            perm,
+        }))
+    }
+
+    // Owners, groups and modes are only checked on Unix.
+    #[cfg(not(target_family = "unix"))]
+    pub fn new(
+        _owners: Option<Vec<String>>,
+        _groups: Option<Vec<String>>,
+        _perm: Option<u32>,
+    ) -> Result<Option<Self>> {
+        Ok(None)
+    }
+}
+
+// Helper function to look up the uid of a user by name.
+#[cfg(target_os = "linux")]
+fn user_id(name: &str) -> Result<u32> {
+    nix::unistd::User::from_name(name)
+        .ok()
+        .flatten()
+        .map(|user| user.uid.as_raw())
+        .with_context(|| format!("Unknown user {name}"))
+}
+
+// Helper function to look up the gid of a group by name.
+#[cfg(target_os = "linux")]
+fn group_id(name: &str) -> Result<u32> {
+    nix::unistd::Group::from_name(name)
+        .ok()
+        .flatten()
+        .map(|group| group.gid.as_raw())
+        .with_context(|| format!("Unknown group {name}"))
+}
+
+// Users can only be looked up by name on Linux so far.
+#[cfg(all(target_family = "unix", not(target_os = "linux")))]
+fn user_id(name: &str) -> Result<u32> {
+    anyhow::bail!(
+        "Users can only be given by name on Linux so far, so give the uid of {name} instead"
+    )
+}
+
+// Groups can only be looked up by name on Linux so far.
+#[cfg(all(target_family = "unix", not(target_os = "linux")))]
+fn group_id(name: &str) -> Result<u32> {
+    anyhow::bail!(
+        "Groups can only be given by name on Linux so far, so give the gid of {name} instead"
+    )
+}
+
+// Handler function to check if a path belongs to one of the owners and groups, and has every permission bit, that are
+// given, handling errors and printing out verbose messages, as necessary. Only Unix has them, so anywhere else every
+// path passes.
+#[cfg(target_family = "unix")]
+pub fn ownership_matches(
+    info: &Info,
+    ownership: Option<&Ownership>,
+    verbose: bool,
+    summary: &Summary,
+    reporter: &dyn Reporter,
+) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    let Some(ownership) = ownership else {
+        return true;
+    };
+    let path = info.path();
+    let metadata = match info.metadata() {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            reporter.report(&Event::Error(&e));
+            summary.record_error(&e);
+            return false;
+        }
+    };
+    let mode = metadata.mode() & 0o7777;
+    let because = if ownership
+        .owners
+        .as_ref()
+        .is_some_and(|owners| !owners.contains(&metadata.uid()))
+    {
+        format!("it's owned by uid {}", metadata.uid())
+    } else if ownership
+        .groups
+        .as_ref()
+        .is_some_and(|groups| !groups.contains(&metadata.gid()))
+    {
+        format!("its group is gid {}", metadata.gid())
+    } else if ownership.perm.is_some_and(|perm| mode & perm != perm) {
+        format!(
+            "its permissions are {mode:03o}, without every bit of {:03o}",
+            ownership.perm.unwrap_or_default()
+        )
+    } else {
+        return true;
+    };
+    summary.record_skipped(SkipReason::Ownership);
+    if verbose {
+        reporter.report(&Event::Skipped { path, because });
+    }
+    false
+}
+
+// Owners, groups and modes are only checked on Unix.
+#[cfg(not(target_family = "unix"))]
+pub fn ownership_matches(
+    _info: &Info,
+    _ownership: Option<&Ownership>,
+    _verbose: bool,
+    _summary: &Summary,
+    _reporter: &dyn Reporter,
+) -> bool {
+    true
+}
+
+// Handler function to check if a path was last modified before the given cutoff, so that files that are still being
+// written aren't hidden, handling errors and printing out verbose messages, as necessary.
+pub fn settled(
+    info: &Info,
+    cutoff: Option<SystemTime>,
+    verbose: bool,
+    summary: &Summary,
+    reporter: &dyn Reporter,
+) -> bool {
+    let path = info.path();
+    cutoff.is_none_or(|cutoff| {
+        info.modified()
+            .map(|modified| modified <= cutoff)
+            .inspect(|r| {
+                if !r {
+                    summary.record_skipped(SkipReason::Unsettled);
+                    if verbose {
+                        reporter.report(&Event::Skipped {
+                            path,
+                            because: "it was modified after the search started".to_owned(),
+                        });
+                    }
+                }
+            })
+            .inspect_err(|e| {
+                reporter.report(&Event::Error(e));
+                summary.record_error(e);
+            })
+            .unwrap_or(false)
+    })
+}
+
+// Handler function to check if a path is between the minimum and maximum depths below the path it was found in, and if
+// it's that path itself, whether it's allowed to be hidden. Paths whose depth isn't known are always let through.
+#[allow(clippy::too_many_arguments)]
+pub fn depth_in_range(
+    path: &Path,
+    depth: Option<usize>,
+    min_depth: usize,
+    max_depth: usize,
+    include_root: IncludeRoot,
+    verbose: bool,
+    summary: &Summary,
+    reporter: &dyn Reporter,
+) -> bool {
+    depth.is_none_or(|depth| {
+        if depth == 0 && include_root == IncludeRoot::Never {
+            summary.record_skipped(SkipReason::Depth);
+            if verbose {
+                reporter.report(&Event::Skipped {
+                    path,
+                    because: "it's one of the paths being searched, which are only hidden with --include-root allow"
+                        .to_owned(),
+                });
+            }
+            return false;
+        }
+        let in_range = (min_depth..=max_depth).contains(&depth);
+        if !in_range {
+            summary.record_skipped(SkipReason::Depth);
+            if verbose {
+                reporter.report(&Event::Skipped {
+                    path,
+                    because: format!(
+                        "it's at depth {depth}, outside of {min_depth} to {max_depth}"
+                    ),
+                });
+            }
+        }
+        in_range
+    })
+}
+
+// Helper function to check if the sibling of a path named by the given template exists, if one is required. The
+// template can refer to the path's file name as {name}, its name without the extension as {stem}, and its extension as
+// {ext}. For example, "{stem}.meta" requires a .meta file alongside the path.
+pub fn sibling_exists(
+    path: &Path,
+    template: Option<&str>,
+    verbose: bool,
+    summary: &Summary,
+    reporter: &dyn Reporter,
+) -> bool {
+    template.is_none_or(|template| {
+        let part = |part: Option<&std::ffi::OsStr>| {
+            part.map(|part| part.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        };
+        let sibling = template
+            .replace("{name}", &part(path.file_name()))
+            .replace("{stem}", &part(path.file_stem()))
+            .replace("{ext}", &part(path.extension()));
+        let sibling = path.with_file_name(sibling);
+
+        let exists =
+            filesystem::reachable(&sibling).is_ok_and(|sibling| sibling.symlink_metadata().is_ok());
+        if !exists {
+            summary.record_skipped(SkipReason::NoSibling);
+            if verbose {
+                reporter.report(&Event::Skipped {
+                    path,
+                    because: format!("its sibling {} does not exist", sibling.display()),
+                });
+            }
+        }
+        exists
+    })
+}
+
+// Helper function to check if the content of a file is one of the given types, if any are given, such as image/png, as
+// worked out from the magic bytes at its start. Folders, symlinks, and files whose content isn't a type that can be
+// recognized never match.
+pub fn mime_matches(
+    info: &Info,
+    mimes: Option<&GlobSet>,
+    verbose: bool,
+    summary: &Summary,
+    reporter: &dyn Reporter,
+) -> bool {
+    mimes.is_none_or(|mimes| {
+        let path = info.path();
+        let mime = match sniff(info) {
+            Ok(mime) => mime,
+            Err(e) => {
+                reporter.report(&Event::Error(&e));
+                summary.record_error(&e);
+                return false;
+            }
+        };
+        let matched = mime.is_some_and(|mime| mimes.is_match(mime));
+        if !matched {
+            summary.record_skipped(SkipReason::Mime);
+            if verbose {
+                reporter.report(&Event::Skipped {
+                    path,
+                    because: match mime {
+                        Some(mime) => format!("its content is {mime}"),
+                        None => "its content isn't a type that can be recognized".to_owned(),
+                    },
+                });
+            }
+        }
+        matched
+    })
+}
+
+// Helper function to work out the type of a file's content from the magic bytes at its start, if it's a file.
+fn sniff(info: &Info) -> Result<Option<&'static str>> {
+    use std::io::Read;
+
+    if info.object_type()? != ObjectType::File {
+        return Ok(None);
+    }
+    let mut start = Vec::with_capacity(MIME_SNIFF_BYTES as usize);
+    std::fs::File::open(&*filesystem::reachable(info.path())?)
+        .and_then(|file| file.take(MIME_SNIFF_BYTES).read_to_end(&mut start))
+        .with_context(|| {
+            format!(
+                "Failed to read {} to work out its type",
+                info.path().display()
+            )
+        })?;
+    Ok(infer::get(&start).map(|kind| kind.mime_type()))
+}
+
+// Helper function to check if anything in a file matches the content regexes, if there are any. Only files are
+// scanned, so folders and symlinks are never hidden when there are. Anything that can't be read is reported and left
+// alone.
+pub fn content_matches(
+    info: &Info,
+    scanner: Option<&Scanner>,
+    verbose: bool,
+    summary: &Summary,
+    reporter: &dyn Reporter,
+) -> bool {
+    scanner.is_none_or(|scanner| {
+        let path = info.path();
+        let matched = match info.object_type() {
+            Ok(ObjectType::File) => scanner.matches(path),
+            Ok(_) => Ok(false),
+            Err(e) => Err(e),
+        };
+        let matched = match matched {
+            Ok(matched) => matched,
+            Err(e) => {
+                reporter.report(&Event::Error(&e));
+                summary.record_error(&e);
+                return false;
+            }
+        };
+        if !matched {
+            summary.record_skipped(SkipReason::Content);
+            if verbose {
+                reporter.report(&Event::Skipped {
+                    path,
+                    because: match info.object_type() {
+                        Ok(ObjectType::File) => format!(
+                            "nothing in its first {} bytes matches the content regexes",
+                            scanner.max_bytes()
+                        ),
+                        _ => "it isn't a file, so it has no content to match".to_owned(),
+                    },
+                });
+            }
+        }
+        matched
+    })
+}
+
+// Helper function to check if a path is a folder with nothing visible in it, if only empty folders are to be hidden.
+// Files and symlinks are left to the other filters. Anything that can't be read is reported and left alone.
+pub fn empty_if_folder(
+    info: &Info,
+    empty: Option<&EmptyFolders>,
+    verbose: bool,
+    summary: &Summary,
+    reporter: &dyn Reporter,
+) -> bool {
+    if empty.is_none() {
+        return true;
+    }
+    let path = info.path();
+    let empty = match info.object_type() {
+        Ok(ObjectType::Folder) => empty::is_empty(path),
+        Ok(_) => Ok(true),
+        Err(e) => Err(e),
+    };
+    match empty {
+        Ok(true) => true,
+        Ok(false) => {
+            summary.record_skipped(SkipReason::NotEmpty);
+            if verbose {
+                reporter.report(&Event::Skipped {
+                    path,
+                    because: "it has something visible in it".to_owned(),
+                });
+            }
+            false
+        }
+        Err(e) => {
+            reporter.report(&Event::Error(&e));
+            summary.record_error(&e);
+            false
+        }
+    }
+}
+
+// Helper function to check if git thinks of a path the way it has to for it to be hidden, if it has to think anything
+// of it. Anything git fails on is reported and left alone.
+pub fn git_allows(
+    path: &Path,
+    git: Option<&Git>,
+    verbose: bool,
+    summary: &Summary,
+    reporter: &dyn Reporter,
+) -> bool {
+    git.is_none_or(|git| {
+        let state = match git.state(path) {
+            Ok(state) => state,
+            Err(e) => {
+                reporter.report(&Event::Error(&e));
+                summary.record_error(&e);
+                GitState::Unknown
+            }
+        };
+        let allowed = git.allows(state);
+        if !allowed {
+            summary.record_skipped(SkipReason::Git);
+            if verbose {
+                reporter.report(&Event::Skipped {
+                    path,
+                    because: match state {
+                        GitState::Tracked => "git tracks it".to_owned(),
+                        GitState::Untracked => "git doesn't ignore it".to_owned(),
+                        GitState::Ignored => "git ignores it".to_owned(),
+                        GitState::Outside => "it isn't in a git repository".to_owned(),
+                        GitState::Unknown => "git couldn't tell what it is".to_owned(),
+                    },
+                });
+            }
+        }
+        allowed
+    })
+}
+
+// Helper function to check if a path found under a root, if known, matches the given matcher, relative to the root if
+// relative is set. The pattern that included it, if any, is kept with it.
+pub fn path_matches_pattern(
+    info: &Info,
+    root: Option<&Path>,
+    relative: bool,
+    matcher: &Matcher,
+    verbose: bool,
+    summary: &Summary,
+    reporter: &dyn Reporter,
+) -> bool {
+    let path = info.path();
+    let folder = info.object_type().ok() == Some(ObjectType::Folder);
+    let mut res = matcher.matches_entry(path, root, relative, folder);
+    if let Some(rule) = res.rule.take() {
+        info.set_rule(rule);
+    }
+    if !res.result {
+        summary.record_skipped(if res.matcher_type.is_some() {
+            SkipReason::Excluded
+        } else {
+            SkipReason::Unmatched
+        });
+    }
     if verbose {
         if let Some(path) = res.lossy {
-            eprintln!("Path {path} is not valid UTF-8. This may cause issues.");
+            reporter.report(&Event::Notice(format!(
+                "Path {path} is not valid UTF-8, so it is shown with replacement characters."
+            )));
         }
         if !res.result {
+            let subject = match res.match_on {
+                MatchOn::Path => "it",
+                MatchOn::Name => "its name",
+            };
             if let Some(matcher_type) = res.matcher_type {
-                println!(
-                    "Skipping {} because it is excluded by a {matcher_type} pattern", path.display()
-                );
+                reporter.report(&Event::Skipped {
+                    path,
+                    because: format!("{subject} is excluded by a {matcher_type} pattern"),
+                });
             } else {
-                println!(
-                    "Skipping {} because it did not match any patterns", path.display()
-                );
+                reporter.report(&Event::Skipped {
+                    path,
+                    because: format!("{subject} did not match any patterns"),
+                });
             }
         }
     }
     res.result
-}
\ No newline at end of file
+}
+
+// Handler function to get the path to hide. If deref is set and the path is a symlink, then that's its target,
+// otherwise it's the path itself, along with everything already known about it. The target keeps the pattern the
+// symlink was included by. Returns None if the target couldn't be resolved, handling errors as necessary.
+pub fn dereference(
+    info: Info,
+    deref: bool,
+    verbose: bool,
+    summary: &Summary,
+    reporter: &dyn Reporter,
+) -> Option<Info> {
+    if !deref || !info.object_type().is_ok_and(ObjectType::is_link) {
+        return Some(info);
+    }
+    let path = info.path();
+    filesystem::resolve(path)
+        .inspect(|target| {
+            if verbose {
+                reporter.report(&Event::Info(format!(
+                    "Following {} to {}",
+                    path.display(),
+                    target.display()
+                )));
+            }
+        })
+        .inspect_err(|e| {
+            reporter.report(&Event::Error(e));
+            summary.record_error(e);
+        })
+        .ok()
+        .map(|target| {
+            let target = Info::new(target);
+            if let Some(rule) = info.rule() {
+                target.set_rule(rule.clone());
+            }
+            target
+        })
+}
+
+// Handler function to list the alternate data streams of a path, handling errors as necessary. Returns an empty list if
+// there are none, or if they weren't requested.
+pub fn alternate_data_streams(
+    path: &Path,
+    include_ads: bool,
+    summary: &Summary,
+    reporter: &dyn Reporter,
+) -> Vec<String> {
+    if !include_ads {
+        return Vec::new();
+    }
+    filesystem::alternate_data_streams(path).unwrap_or_else(|e| {
+        reporter.report(&Event::Error(&e));
+        summary.record_error(&e);
+        Vec::new()
+    })
+}
+
+// Helper function to get a path relative to the root it was found under, if one is given, for matching against. The
+// path is used as it is if it isn't inside the root, like the target of a dereferenced symlink.
+pub fn relative_to<'a>(path: &'a Path, root: Option<&Path>) -> &'a Path {
+    root.and_then(|root| path.strip_prefix(root).ok())
+        .unwrap_or(path)
+}
@@ -0,0 +1,235 @@
+use crate::filesystem;
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+
+// Enum of what git has to think of a path for it to be hidden
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum GitMode {
+    // Files and folders git doesn't track, but doesn't ignore either
+    Untracked,
+    // Files and folders git ignores
+    Ignored,
+    // Either of them
+    Both,
+}
+
+// What git thinks of a path
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GitState {
+    // It's tracked, or a folder with something tracked in it, or the repository's own .git
+    Tracked,
+    Untracked,
+    Ignored,
+    // It isn't in a repository at all
+    Outside,
+    // Git couldn't say, because it failed or the path has gone
+    Unknown,
+}
+
+// What git said about a single repository the last time it was asked. Paths are relative to the top of the repository.
+// Tracked paths include every folder with something tracked in it, and untracked or ignored folders cover everything
+// in them, as git lists them whole.
+#[derive(Debug, Default)]
+struct Repo {
+    tracked: HashSet<PathBuf>,
+    untracked: HashSet<PathBuf>,
+    ignored: HashSet<PathBuf>,
+}
+
+// Asks git what it thinks of paths, so only untracked or ignored ones are hidden. Each repository is asked once, with
+// git status and git ls-files, and asked again whenever a path it didn't know about turns up, such as one made while
+// watching. Paths git can't say anything about, because it failed or they've gone, are left alone.
+#[derive(Debug)]
+pub struct Git {
+    mode: GitMode,
+    repos: Mutex<HashMap<PathBuf, Option<Repo>>>,
+}
+
+impl Git {
+    // Build a new git filter, checking that git can be run first.
+    pub fn new(mode: GitMode) -> Result<Self> {
+        let found = Command::new("git")
+            .arg("--version")
+            .output()
+            .is_ok_and(|output| output.status.success());
+        if !found {
+            bail!("--git needs git to be installed and on the PATH");
+        }
+        Ok(Self {
+            mode,
+            repos: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Returns true if git thinks of a path the way it has to for it to be hidden.
+    pub fn allows(&self, state: GitState) -> bool {
+        matches!(
+            (self.mode, state),
+            (GitMode::Untracked | GitMode::Both, GitState::Untracked)
+                | (GitMode::Ignored | GitMode::Both, GitState::Ignored)
+        )
+    }
+
+    // Returns what git thinks of a path. The path itself is never taken as the top of a repository, so a repository
+    // inside another is judged by the outer one, the same way git status shows it.
+    pub fn state(&self, path: &Path) -> Result<GitState> {
+        let path = resolve(path);
+        let Some(top) = path
+            .ancestors()
+            .skip(1)
+            .find(|dir| dir.join(".git").symlink_metadata().is_ok())
+        else {
+            return Ok(GitState::Outside);
+        };
+        let relative = path.strip_prefix(top).unwrap_or(&path);
+        if relative.starts_with(".git") {
+            return Ok(GitState::Tracked);
+        }
+
+        // Git is only asked once about a repository it failed in, so the error is only reported once.
+        let mut repos = self.repos.lock().unwrap_or_else(|e| e.into_inner());
+        match repos.get(top) {
+            Some(Some(repo)) => {
+                if let Some(state) = repo.state(relative) {
+                    return Ok(state);
+                }
+            }
+            Some(None) => return Ok(GitState::Unknown),
+            None => {}
+        }
+
+        // Ask git again if it wasn't asked yet, or didn't know about the path when it was.
+        let repo = Repo::load(top);
+        let state = repo
+            .as_ref()
+            .ok()
+            .and_then(|repo| repo.state(relative))
+            .unwrap_or(GitState::Unknown);
+        let error = repo.as_ref().err().map(|e| format!("{e:#}"));
+        repos.insert(top.to_path_buf(), repo.ok());
+        match error {
+            Some(error) => bail!(error),
+            None => Ok(state),
+        }
+    }
+}
+
+impl Repo {
+    // Ask git about every untracked, ignored and tracked path in the repository at the given top folder.
+    fn load(top: &Path) -> Result<Self> {
+        let mut repo = Self::default();
+
+        // Porcelain entries are a two letter status, a space and the path. Renames and copies are followed by the path
+        // they came from, which is skipped.
+        let status = git(
+            top,
+            &[
+                "status",
+                "--porcelain",
+                "-z",
+                "--ignored",
+                "--untracked-files=normal",
+            ],
+        )?;
+        let mut entries = status.split(|&b| b == 0).filter(|entry| !entry.is_empty());
+        while let Some(entry) = entries.next() {
+            let (Some(code), Some(path)) = (entry.get(..2), entry.get(3..)) else {
+                continue;
+            };
+            let path = path_from(path.strip_suffix(b"/").unwrap_or(path));
+            match code {
+                b"??" => {
+                    repo.untracked.insert(path);
+                }
+                b"!!" => {
+                    repo.ignored.insert(path);
+                }
+                [b'R' | b'C', _] => {
+                    entries.next();
+                }
+                _ => {}
+            }
+        }
+
+        // Every folder with something tracked in it counts as tracked too, since hiding it would hide what's tracked.
+        let tracked = git(top, &["ls-files", "-z"])?;
+        for path in tracked.split(|&b| b == 0).filter(|path| !path.is_empty()) {
+            for path in path_from(path).ancestors() {
+                if path.as_os_str().is_empty() || !repo.tracked.insert(path.to_path_buf()) {
+                    break;
+                }
+            }
+        }
+        Ok(repo)
+    }
+
+    // Returns what git thinks of a path, if it knows. The path is checked first, then the folders it's in, since
+    // untracked and ignored folders are listed whole.
+    fn state(&self, path: &Path) -> Option<GitState> {
+        if self.tracked.contains(path) {
+            return Some(GitState::Tracked);
+        }
+        path.ancestors()
+            .take_while(|path| !path.as_os_str().is_empty())
+            .find_map(|path| {
+                if self.ignored.contains(path) {
+                    Some(GitState::Ignored)
+                } else if self.untracked.contains(path) {
+                    Some(GitState::Untracked)
+                } else {
+                    None
+                }
+            })
+    }
+}
+
+// Helper function to run git in a repository, and get what it wrote out.
+fn git(top: &Path, args: &[&str]) -> Result<Vec<u8>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(top)
+        .args(args)
+        .output()
+        .with_context(|| format!("Failed to run git {} in {}", args[0], top.display()))?;
+    if !output.status.success() {
+        bail!(
+            "Failed to run git {} in {}: {}",
+            args[0],
+            top.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+// Helper function to turn a path written out by git into a path. Git writes paths as they're stored on Unix, and as
+// UTF-8 on Windows.
+#[cfg(target_family = "unix")]
+fn path_from(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+// Helper function to turn a path written out by git into a path. Git writes paths as they're stored on Unix, and as
+// UTF-8 on Windows.
+#[cfg(target_family = "windows")]
+fn path_from(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+// Helper function to resolve the folder a path is in, leaving the path itself as it is, since it could be a symlink.
+fn resolve(path: &Path) -> PathBuf {
+    let path = filesystem::absolute(path);
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => {
+            fs::canonicalize(parent).map_or(path.clone(), |parent| parent.join(name))
+        }
+        _ => path,
+    }
+}
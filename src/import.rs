@@ -0,0 +1,119 @@
+use crate::actions::{Action, Actions};
+use crate::filesystem::{self, CreateModes};
+use crate::output::{self, ColorChoice, OutputFormat};
+use crate::{journal, walk};
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+// Enum of the ways files and folders could have been hidden by something other than cloak
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ImportFrom {
+    // The hidden attribute on Windows, such as set with attrib +h
+    Attrib,
+    // A dot at the start of the name on Unix, such as from renaming by hand
+    Dotfiles,
+}
+
+// Walk the paths for everything already hidden the given way, and register it with cloak, by recording it in the
+// journal under the name it has without the dot, and marking it with the cloak attribute, as asked. Anything already in
+// the journal or marked is left as it is, so importing the same tree twice changes nothing the second time. If test is
+// set, then only what would be imported is printed out. Returns an exit code of 1 if anything couldn't be imported.
+pub fn run(
+    from: ImportFrom,
+    paths: &[PathBuf],
+    journal: Option<PathBuf>,
+    mark: bool,
+    test: bool,
+) -> Result<ExitCode> {
+    match from {
+        ImportFrom::Attrib if cfg!(not(target_family = "windows")) => {
+            bail!("--from attrib imports the hidden attribute, which only Windows has")
+        }
+        ImportFrom::Dotfiles if cfg!(not(target_family = "unix")) => {
+            bail!(
+                "--from dotfiles imports names starting with a dot, which only hide things on Unix"
+            )
+        }
+        _ => {}
+    }
+    if journal.is_none() && !mark {
+        bail!("Nothing to import into. Give a --journal to record them in, --mark to mark them, or both");
+    }
+    let marks = mark
+        .then(|| Actions::new(&[Action::Mark], None))
+        .transpose()?;
+    let journal = journal
+        .map(|path| journal::Journal::load(path, None, CreateModes::default(), None))
+        .transpose()?;
+
+    let reporter = output::reporter(OutputFormat::Human, false, None, ColorChoice::default());
+    let (mut imported, mut errors) = (0, 0);
+    for root in paths {
+        let options = walk::Options {
+            follow_symlinks: false,
+            skip_hidden_dirs: false,
+            max_depth: usize::MAX,
+            prune: None,
+            mounts: None,
+            verbose: false,
+            reporter: reporter.clone(),
+        };
+        for entry in walk::walk(root, options) {
+            let path = match entry.and_then(|entry| {
+                Ok((entry.depth > 0 && filesystem::is_hidden(&entry.path)?).then_some(entry.path))
+            }) {
+                Ok(Some(path)) => path,
+                Ok(None) => continue,
+                Err(e) => {
+                    eprintln!("{e:#}");
+                    errors += 1;
+                    continue;
+                }
+            };
+
+            // Only record what isn't already known, so nothing that's there is overwritten.
+            let record = journal
+                .as_ref()
+                .filter(|journal| journal.original(&path).is_none());
+            let pending = marks.is_some_and(|marks| marks.pending(&path));
+            if record.is_none() && !pending {
+                continue;
+            }
+            if test {
+                println!("Would import {}", path.display());
+                imported += 1;
+                continue;
+            }
+            let result = record
+                .map_or(Ok(()), |journal| {
+                    journal.record(&path, &filesystem::unhidden_path(&path))
+                })
+                .and_then(|()| marks.map_or(Ok(false), |marks| marks.apply(&path)));
+            match result {
+                Ok(_) => {
+                    println!("Imported {}", path.display());
+                    imported += 1;
+                }
+                Err(e) => {
+                    eprintln!("{e:#}");
+                    errors += 1;
+                }
+            }
+        }
+    }
+
+    if let Some(journal) = journal.filter(|_| !test) {
+        journal.write()?;
+    }
+    println!(
+        "{} {imported} hidden files and folders, with {errors} errors",
+        if test { "Would import" } else { "Imported" }
+    );
+    Ok(if errors > 0 {
+        ExitCode::from(1)
+    } else {
+        ExitCode::SUCCESS
+    })
+}
@@ -0,0 +1,339 @@
+use crate::cipher::{self, Cipher};
+use crate::filesystem::{absolute, CreateModes};
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// Enum of formats a journal can be kept in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum JournalFormat {
+    Json,
+    Sqlite,
+}
+
+// Where a journal keeps its entries. Paths are always absolute by the time they get here.
+trait Store: Debug + Send + Sync {
+    // Record that a path was hidden under another one.
+    fn record(&self, hidden: &Path, original: &Path) -> Result<()>;
+
+    // Returns the path a hidden path had before it was hidden, if it was recorded.
+    fn original(&self, hidden: &Path) -> Option<PathBuf>;
+
+    // Returns every hidden path recorded, along with the path it had before it was hidden.
+    fn entries(&self) -> Vec<(PathBuf, PathBuf)>;
+
+    // Forget a hidden path.
+    fn forget(&self, hidden: &Path) -> Result<()>;
+
+    // Save anything that hasn't been saved yet.
+    fn write(&self) -> Result<()>;
+}
+
+// Remembers the files and folders that were hidden under a name other than the usual one, such as .foo.1 when .foo
+// already existed, or moved into a vault, along with the paths they had before, so unhiding can give them back their
+// original names. Paths are kept absolute, so the same journal works from any directory.
+#[derive(Debug)]
+pub struct Journal {
+    store: Box<dyn Store>,
+}
+
+impl Journal {
+    // Load the journal at the given path. If it doesn't exist yet, then it starts out empty, and is created when it's
+    // written. If no format is given, then it's worked out from the extension of the path, defaulting to JSON. If a
+    // cipher is given, then it's written encrypted with it, and can be read whether it was encrypted before or not.
+    pub fn load(
+        path: PathBuf,
+        format: Option<JournalFormat>,
+        modes: CreateModes,
+        cipher: Option<Cipher>,
+    ) -> Result<Self> {
+        let format = format.unwrap_or_else(|| {
+            if path.extension().is_some_and(|ext| {
+                ["db", "sqlite", "sqlite3"]
+                    .iter()
+                    .any(|sqlite| ext.eq_ignore_ascii_case(sqlite))
+            }) {
+                JournalFormat::Sqlite
+            } else {
+                JournalFormat::Json
+            }
+        });
+        if format == JournalFormat::Sqlite && cipher.is_some() {
+            bail!("--encrypt-metadata only works with json journals, since sqlite ones are written a row at a time");
+        }
+        let store: Box<dyn Store> = match format {
+            JournalFormat::Json => Box::new(JsonStore::load(path, modes, cipher)?),
+            JournalFormat::Sqlite => Box::new(SqliteStore::open(path, modes)?),
+        };
+        Ok(Self { store })
+    }
+
+    // Record that a path was hidden under a name other than the usual one.
+    pub fn record(&self, hidden: &Path, original: &Path) -> Result<()> {
+        self.store.record(&absolute(hidden), &absolute(original))
+    }
+
+    // Returns the path a hidden path had before it was hidden, if it was recorded.
+    pub fn original(&self, hidden: &Path) -> Option<PathBuf> {
+        self.store.original(&absolute(hidden))
+    }
+
+    // Returns every hidden path recorded, along with the path it had before it was hidden.
+    pub fn entries(&self) -> Vec<(PathBuf, PathBuf)> {
+        self.store.entries()
+    }
+
+    // Forget a hidden path, once it has been unhidden.
+    pub fn forget(&self, hidden: &Path) -> Result<()> {
+        self.store.forget(&absolute(hidden))
+    }
+
+    // Write the journal back to where it's kept, once the run is finished.
+    pub fn write(&self) -> Result<()> {
+        self.store.write()
+    }
+}
+
+// A journal kept as a JSON object of hidden paths to original paths, encrypted if a cipher is given. It's read when the
+// run starts, and written back once it's finished.
+#[derive(Debug)]
+struct JsonStore {
+    path: PathBuf,
+    modes: CreateModes,
+    cipher: Option<Cipher>,
+    entries: Mutex<BTreeMap<PathBuf, PathBuf>>,
+}
+
+impl JsonStore {
+    // Read the journal at the given path, if there is one yet.
+    fn load(path: PathBuf, modes: CreateModes, cipher: Option<Cipher>) -> Result<Self> {
+        let entries = match fs::read(&path) {
+            Ok(contents) => {
+                let contents = match &cipher {
+                    Some(cipher) if cipher::is_encrypted(&contents) => {
+                        cipher.decrypt(&contents, &path)?
+                    }
+                    None if cipher::is_encrypted(&contents) => {
+                        bail!(
+                            "Journal {} is encrypted, so it needs --encrypt-metadata",
+                            path.display()
+                        )
+                    }
+                    _ => contents,
+                };
+                serde_json::from_slice(&contents)
+                    .with_context(|| format!("Failed to parse journal {}", path.display()))?
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read journal {}", path.display()))
+            }
+        };
+        Ok(Self {
+            path,
+            modes,
+            cipher,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    // Helper function to lock the entries.
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<PathBuf, PathBuf>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Store for JsonStore {
+    fn record(&self, hidden: &Path, original: &Path) -> Result<()> {
+        self.lock()
+            .insert(hidden.to_path_buf(), original.to_path_buf());
+        Ok(())
+    }
+
+    fn original(&self, hidden: &Path) -> Option<PathBuf> {
+        self.lock().get(hidden).cloned()
+    }
+
+    fn entries(&self) -> Vec<(PathBuf, PathBuf)> {
+        self.lock()
+            .iter()
+            .map(|(hidden, original)| (hidden.clone(), original.clone()))
+            .collect()
+    }
+
+    fn forget(&self, hidden: &Path) -> Result<()> {
+        self.lock().remove(hidden);
+        Ok(())
+    }
+
+    fn write(&self) -> Result<()> {
+        let entries = self.lock();
+        let mut contents = serde_json::to_vec_pretty(&*entries)
+            .with_context(|| format!("Failed to write journal {}", self.path.display()))?;
+        contents.push(b'\n');
+        if let Some(cipher) = &self.cipher {
+            contents = cipher.encrypt(&contents)?;
+        }
+        self.modes
+            .write(&self.path, contents)
+            .with_context(|| format!("Failed to write journal {}", self.path.display()))
+    }
+}
+
+// A journal kept in a sqlite database, indexed by hidden path. Every change is written straight away, so nothing is
+// read up front or lost if a long running watcher is killed, and lookups stay quick however many paths it holds.
+#[cfg(feature = "sqlite")]
+#[derive(Debug)]
+struct SqliteStore {
+    path: PathBuf,
+    connection: Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStore {
+    // Open the database at the given path, creating it and its table if they don't exist yet. The file is created
+    // first, so it's given the file mode, which sqlite gives its own files alongside it too.
+    fn open(path: PathBuf, modes: CreateModes) -> Result<Self> {
+        modes
+            .open_options()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Failed to open journal {}", path.display()))?;
+        let connection = rusqlite::Connection::open(&path)
+            .and_then(|connection| {
+                connection.execute_batch(
+                    "PRAGMA journal_mode = WAL;
+                     PRAGMA synchronous = NORMAL;
+                     CREATE TABLE IF NOT EXISTS journal (hidden TEXT PRIMARY KEY, original TEXT NOT NULL);",
+                )?;
+                Ok(connection)
+            })
+            .with_context(|| format!("Failed to open journal {}", path.display()))?;
+        Ok(Self {
+            path,
+            connection: Mutex::new(connection),
+        })
+    }
+
+    // Helper function to lock the connection.
+    fn lock(&self) -> std::sync::MutexGuard<'_, rusqlite::Connection> {
+        self.connection.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Helper function to get a path as text to store it, since paths that aren't valid Unicode can't be.
+    fn text<'p>(&self, path: &'p Path) -> Result<&'p str> {
+        path.to_str().with_context(|| {
+            format!(
+                "Failed to write {} to journal {}, as it isn't valid Unicode",
+                path.display(),
+                self.path.display()
+            )
+        })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl Store for SqliteStore {
+    fn record(&self, hidden: &Path, original: &Path) -> Result<()> {
+        self.lock()
+            .execute(
+                "INSERT OR REPLACE INTO journal (hidden, original) VALUES (?1, ?2)",
+                (self.text(hidden)?, self.text(original)?),
+            )
+            .with_context(|| format!("Failed to write journal {}", self.path.display()))?;
+        Ok(())
+    }
+
+    fn original(&self, hidden: &Path) -> Option<PathBuf> {
+        use rusqlite::OptionalExtension;
+
+        self.lock()
+            .query_row(
+                "SELECT original FROM journal WHERE hidden = ?1",
+                (hidden.to_str()?,),
+                |row| row.get::<_, String>(0),
+            )
+            .optional()
+            .ok()
+            .flatten()
+            .map(PathBuf::from)
+    }
+
+    fn entries(&self) -> Vec<(PathBuf, PathBuf)> {
+        let connection = self.lock();
+        let Ok(mut statement) =
+            connection.prepare("SELECT hidden, original FROM journal ORDER BY hidden")
+        else {
+            return Vec::new();
+        };
+        statement
+            .query_map((), |row| {
+                Ok((
+                    PathBuf::from(row.get::<_, String>(0)?),
+                    PathBuf::from(row.get::<_, String>(1)?),
+                ))
+            })
+            .map(|rows| rows.filter_map(Result::ok).collect())
+            .unwrap_or_default()
+    }
+
+    fn forget(&self, hidden: &Path) -> Result<()> {
+        self.lock()
+            .execute(
+                "DELETE FROM journal WHERE hidden = ?1",
+                (self.text(hidden)?,),
+            )
+            .with_context(|| format!("Failed to write journal {}", self.path.display()))?;
+        Ok(())
+    }
+
+    // Everything was written as it happened.
+    fn write(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Stand-in for a sqlite journal in builds without the sqlite feature. One can never be opened, so sqlite journals are
+// rejected up front.
+#[cfg(not(feature = "sqlite"))]
+#[derive(Debug)]
+enum SqliteStore {}
+
+#[cfg(not(feature = "sqlite"))]
+impl SqliteStore {
+    fn open(_path: PathBuf, _modes: CreateModes) -> Result<Self> {
+        anyhow::bail!(
+            "Sqlite journals aren't supported by this build of cloak. Rebuild it with the sqlite feature enabled."
+        )
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+impl Store for SqliteStore {
+    fn record(&self, _hidden: &Path, _original: &Path) -> Result<()> {
+        match *self {}
+    }
+
+    fn original(&self, _hidden: &Path) -> Option<PathBuf> {
+        match *self {}
+    }
+
+    fn entries(&self) -> Vec<(PathBuf, PathBuf)> {
+        match *self {}
+    }
+
+    fn forget(&self, _hidden: &Path) -> Result<()> {
+        match *self {}
+    }
+
+    fn write(&self) -> Result<()> {
+        match *self {}
+    }
+}
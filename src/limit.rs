@@ -0,0 +1,39 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Caps how many paths a run changes, shared across the rayon thread pool and the watcher. A slot is taken before each
+// change, and given back if nothing ended up changing, so exactly the limit is reached however many threads race for
+// the last slots.
+#[derive(Debug)]
+pub struct Limit {
+    max: usize,
+    used: AtomicUsize,
+}
+
+impl Limit {
+    // Build a new limit allowing at most max changes.
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    // Take a slot for a change. Returns false if the limit has already been reached.
+    pub fn take(&self) -> bool {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                (used < self.max).then_some(used + 1)
+            })
+            .is_ok()
+    }
+
+    // Give back a slot taken for a change that didn't happen.
+    pub fn give_back(&self) {
+        self.used.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    // Returns the most changes allowed.
+    pub fn max(&self) -> usize {
+        self.max
+    }
+}
@@ -1,25 +1,223 @@
-use anyhow::{Context, Result};
-use clap::Parser;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{CommandFactory, Parser, Subcommand};
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
 
+mod actions;
+mod changes;
+mod cipher;
+mod context;
+mod control;
+mod cooldown;
+#[cfg(feature = "watch")]
+mod dispatch;
+mod empty;
+mod exempt;
 mod filesystem;
 mod filter;
+mod git;
+mod import;
+mod journal;
+mod limit;
 mod matcher;
+mod mounts;
+mod output;
+mod pipeline;
+mod progress;
+mod prompt;
+mod report;
+mod review;
+mod rules;
+mod safety;
+mod scan;
+mod schema;
 mod search;
+mod selftest;
+mod service;
+#[cfg(feature = "watch")]
+mod shard;
+mod summary;
+mod systemlog;
+mod unhiders;
+mod vault;
+mod verify;
+mod walk;
+#[cfg(feature = "watch")]
 mod watcher;
+#[cfg(feature = "watch")]
+mod writing;
 
+// The watch options are still accepted without the watch feature, so that asking to watch gives a clear error, but
+// they go unused.
 #[derive(Debug, Parser)]
 #[clap(version)]
+#[cfg_attr(not(feature = "watch"), allow(dead_code))]
 struct Opts {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// Flag for recursive search and watch
     /// (default: false)
     #[clap(short, long)]
     recursive: bool,
 
+    /// Only hide files and folders at most this many levels below the paths, where entries directly in a path are at
+    /// depth 1. Overrides --recursive.
+    /// (default: 1, or unlimited with --recursive)
+    #[clap(long)]
+    max_depth: Option<usize>,
+
+    /// Only hide files and folders at least this many levels below the paths, where entries directly in a path are at
+    /// depth 1.
+    /// (default: 0)
+    #[clap(long)]
+    min_depth: Option<usize>,
+
+    /// Whether the paths themselves can be hidden if they match, rather than just what's in them: never, allow, or
+    /// only, to hide nothing but the paths themselves. Hiding a path being watched stops it from being watched.
+    /// (default: never)
+    #[clap(long, value_enum)]
+    include_root: Option<filter::IncludeRoot>,
+
+    /// Flag to follow symlinks to directories when searching and watching. Following them can escape the paths given,
+    /// and can loop forever if a link points back up the tree.
+    /// (default: false)
+    #[clap(long, overrides_with = "no_follow_symlinks")]
+    follow_symlinks: bool,
+
+    /// Flag to not follow symlinks to directories, overriding an earlier --follow-symlinks.
+    /// (default: false)
+    #[clap(long, overrides_with = "follow_symlinks")]
+    no_follow_symlinks: bool,
+
+    /// Flag to not search through folders that are already hidden, since everything in them is effectively hidden
+    /// already. The folders themselves are still checked. This makes running again over a mostly hidden tree much
+    /// faster.
+    /// (default: false)
+    #[clap(long, conflicts_with = "unhide")]
+    skip_hidden_dirs: bool,
+
+    /// Flag to hide the targets of matching symlinks, rather than the symlinks themselves. Targets are still left alone
+    /// if they're in the vault, somewhere only hidden with --force, deliberately unhidden, or outside the paths being
+    /// searched or watched.
+    /// (default: false)
+    #[clap(long)]
+    deref: bool,
+
+    /// Flag to hide the targets of symlinks followed with --deref even when they're outside the paths being searched or
+    /// watched, which are left alone otherwise.
+    /// (default: false)
+    #[clap(long, requires = "deref")]
+    deref_outside_roots: bool,
+
     /// Flag to watch for changes, rather than just run once
     /// (default: false)
     #[clap(short, long)]
     watch: bool,
 
+    /// Flag to skip the initial search when watching, so only new changes are hidden.
+    /// (default: false)
+    #[clap(long, requires = "watch")]
+    no_initial_scan: bool,
+
+    /// Write the pid of the watcher to this file while it's running, for service managers and scripts to find it by.
+    /// It's removed again once watching stops.
+    /// (default: no pidfile)
+    #[clap(long, requires = "watch")]
+    pidfile: Option<PathBuf>,
+
+    /// Listen for commands on a Unix socket at this path while watching, so the watcher can be checked on and
+    /// controlled with cloak ctl without restarting it. It's removed again once watching stops.
+    /// (default: no control socket)
+    #[clap(long, requires = "watch")]
+    control: Option<PathBuf>,
+
+    /// Backend to use when watching for changes. The poll backend works on network shares and FUSE filesystems
+    /// where the native backend misses events.
+    /// (default: native)
+    #[cfg(feature = "watch")]
+    #[clap(long)]
+    watch_backend: Option<watcher::WatchBackend>,
+
+    /// Interval in seconds between scans when using the poll watch backend.
+    /// (default: 2)
+    #[clap(long)]
+    poll_interval: Option<u64>,
+
+    /// When watching, split the paths across several watchers with at most this many paths each, for when there are too
+    /// many paths for one watcher. Any watcher that fails is restarted on its own, and its paths are searched again.
+    /// (default: every path is watched by one watcher)
+    #[clap(long, requires = "watch")]
+    roots_per_shard: Option<usize>,
+
+    /// Glob pattern for files and folders whose changes are handled before any others when watching, so they're hidden
+    /// as soon as possible even while lots of other changes are waiting. Can be specified multiple times to add more
+    /// patterns.
+    /// (default: [])
+    #[clap(long, requires = "watch")]
+    priority: Option<Vec<String>>,
+
+    /// Interval in seconds between full rescans of the paths while watching, to catch any changes the watcher missed.
+    /// (default: no rescans)
+    #[clap(long, requires = "watch")]
+    rescan_interval: Option<u64>,
+
+    /// When watching, wait until newly created files have stopped growing for this long before deciding whether to hide
+    /// them, so files that are still being written aren't hidden, and filters such as --min-size see them as they end
+    /// up. Where supported, files closed after writing are looked at straight away. Accepts a number with a unit, such
+    /// as 500ms, 5s, 10m, 2h or 30d.
+    /// (default: no waiting, or 1s when filtering by size, MIME type or content)
+    #[clap(long, value_parser = parse_duration, requires = "watch")]
+    wait_for_write: Option<Duration>,
+
+    /// When watching, leave files and folders alone for this long after hiding them fails several times in a row,
+    /// rather than failing again on every change. They're listed as problem paths when watching stops. Accepts a number
+    /// with a unit, such as 500ms, 5s, 10m, 2h or 30d.
+    /// (default: 5m)
+    #[clap(long, value_parser = parse_duration, default_value = "5m", requires = "watch")]
+    failure_cooldown: Duration,
+
+    /// When watching, the most changes to handle at once, so a burst of changes can't take every thread away from
+    /// searching.
+    /// (default: half the threads in the thread pool, at least 1)
+    #[clap(long, requires = "watch")]
+    watch_jobs: Option<usize>,
+
+    /// When watching, the most changes to keep waiting to be handled. Once that many are waiting, no more are taken
+    /// from the watchers until there's room, and more changes to a path that's already waiting are dropped.
+    /// (default: 10000)
+    #[clap(long, requires = "watch")]
+    watch_queue: Option<usize>,
+
+    /// Flag to print out every event exactly as it comes from the watchers, with its kind, paths and anything else the
+    /// backend says about it, before anything is done with it, to find out why a change never gets something hidden.
+    /// They're written to stderr, or as raw events with --output json.
+    /// (default: false)
+    #[clap(long, requires = "watch")]
+    print_events: bool,
+
+    /// Flag to hide or unhide files and folders in the root of a filesystem, a home directory, or a system location
+    /// such as /usr or C:\Windows, which is refused otherwise.
+    /// (default: false)
+    #[clap(long)]
+    force: bool,
+
+    /// Stop a search before changing anything if it would hide or unhide more than this many files and folders, and
+    /// report it as an error. When watching, it applies to each full search, but not to changes as they happen.
+    /// (default: no limit)
+    #[clap(long)]
+    max_operations: Option<usize>,
+
+    /// Stop changing files and folders once this many have been hidden or unhidden, or would have been in test mode,
+    /// and report how many more were left alone. Which ones are changed first isn't fixed, since they're found in
+    /// parallel. When watching, nothing more is changed once the limit is reached.
+    /// (default: no limit)
+    #[clap(long)]
+    limit: Option<usize>,
+
     /// Flag to enable test mode, which will not actually hide files or folders.
     /// (default: false)
     #[clap(short = 'm', long)]
@@ -30,6 +228,243 @@ struct Opts {
     #[clap(short, long)]
     verbose: bool,
 
+    /// Flag to unhide files and folders instead of hiding them. Patterns are matched against the names files and
+    /// folders had before they were hidden, so -p '*.pdf' unhides .report.pdf on Unix, unless --match-target is given.
+    /// Only hidden files and folders are changed.
+    /// (default: false)
+    #[clap(short, long, conflicts_with_all = ["watch", "prefer_parent"])]
+    unhide: bool,
+
+    /// Flag to ask before hiding each file or folder, like rm -i. Answer y to hide it, n to skip it, a to hide it and
+    /// everything after it without asking again, or q to skip it and everything after it.
+    /// (default: false)
+    #[clap(short, long, conflicts_with_all = ["test", "progress"])]
+    interactive: bool,
+
+    /// Write a report of every file and folder hidden, or that would be hidden in test mode, to this file. Each entry
+    /// has the path, the type of object, the kind of pattern that matched it and the pattern itself, along with the
+    /// rules file it came from if any, and the path it was found under. Paths are only listed as hidden once they have
+    /// been, and ones that couldn't be are listed as failed, or as conflict if something already had their hidden name.
+    /// Entries are written as they happen, in the order they happen, or a thousand at a time if the report is
+    /// encrypted.
+    /// (default: no report)
+    #[clap(long)]
+    report: Option<PathBuf>,
+
+    /// Format to write the report in.
+    /// (default: json if the report file ends in .json, otherwise csv)
+    #[clap(long, requires = "report")]
+    report_format: Option<report::ReportFormat>,
+
+    /// What to do on Unix when a file or folder to hide would be renamed over something that already has its hidden
+    /// name, such as when both foo and .foo exist. Skip leaves it alone with a warning. Overwrite replaces whatever
+    /// has the hidden name. Suffix hides it under the first free name with a number after it, such as .foo.1, which
+    /// is recorded in the --journal if one is kept. On filesystems that ignore case, such as the default ones on macOS,
+    /// something whose name only differs in case, such as .foo when hiding Foo, has its hidden name too.
+    /// (default: skip)
+    #[clap(long)]
+    on_conflict: Option<filesystem::OnConflict>,
+
+    /// Keep a journal in this file of every file and folder hidden under a name other than the usual one, such as
+    /// .foo.1 with --on-conflict suffix, so that unhiding with the same journal gives them back their original names.
+    /// Files and folders moved into the --vault are recorded in it too, so they can be moved back. It's created if it
+    /// doesn't exist yet, and updated once the run is finished, or as each change is made if it's kept in sqlite.
+    /// (default: no journal)
+    #[clap(long, required_if_eq("strategy", "move"))]
+    journal: Option<PathBuf>,
+
+    /// Format to keep the journal in. Sqlite writes every change straight away and looks paths up without reading the
+    /// whole journal first, which suits a watcher that hides a lot of things, but needs cloak to be built with the
+    /// sqlite feature.
+    /// (default: sqlite if the journal file ends in .db, .sqlite or .sqlite3, otherwise json)
+    #[clap(long, requires = "journal")]
+    journal_format: Option<journal::JournalFormat>,
+
+    /// Flag to encrypt the --journal and --report with a passphrase, since a plaintext list of every hidden path gives
+    /// away what was hidden. The passphrase is read from the CLOAK_PASSPHRASE environment variable, or from
+    /// --passphrase-file, and the same one is needed to unhide with the journal later. A journal that isn't encrypted
+    /// yet is encrypted when it's written back. Only json journals can be encrypted.
+    /// (default: false)
+    #[clap(long)]
+    encrypt_metadata: bool,
+
+    /// File to read the passphrase for --encrypt-metadata from, rather than the CLOAK_PASSPHRASE environment variable.
+    /// (default: none)
+    #[clap(long, requires = "encrypt_metadata")]
+    passphrase_file: Option<PathBuf>,
+
+    /// How to hide files and folders: rename to hide them where they are, by adding a dot to their names on Unix or
+    /// setting their hidden attribute on Windows, or move to move them into the --vault, keeping the structure they had
+    /// below the paths. Moving keeps them out of the way of anything that would make them again under their hidden
+    /// names, and needs a --journal to remember where they came from. Unhiding with move moves everything in the vault
+    /// that came from inside the paths and matches back where it came from.
+    /// (default: rename)
+    #[clap(long, value_enum)]
+    strategy: Option<vault::Strategy>,
+
+    /// Folder to move files and folders into with --strategy move, which is created if it doesn't exist yet. It can be
+    /// inside the paths, since nothing in it is ever hidden, but has to be on the same filesystem as them. Give it a
+    /// name starting with a dot on Unix to keep it hidden too.
+    /// (default: none)
+    #[clap(long, required_if_eq("strategy", "move"), conflicts_with = "verify_hide")]
+    vault: Option<PathBuf>,
+
+    /// Flag to hide the folders that moving files and folders into the --vault leaves with nothing in them but empty
+    /// folders, after each search, or whenever a watcher goes quiet. Only the highest one below the paths being
+    /// searched or watched is hidden, with the empty folders below it left as they are. They're recorded in the
+    /// --journal, so moving things back out of the vault shows them again first.
+    /// (default: false)
+    #[clap(long, requires = "vault")]
+    prune_empty: bool,
+
+    /// What to do to the files and folders that match: hide them, make them read-only, change their permissions to
+    /// the mode given by --chmod on Unix, or mark them with a cloak attribute that other tools can look for, as a user
+    /// extended attribute on Linux or an alternate data stream on Windows. Everything but hiding is done first, and
+    /// symlinks are only ever hidden. Output and reports still call a path hidden once everything has been done to it.
+    /// Can be specified multiple times to do more than one.
+    /// (default: ["hide"])
+    #[clap(long, value_enum)]
+    action: Option<Vec<actions::Action>>,
+
+    /// Mode to change the permissions of files and folders to with --action chmod, in octal, such as 600.
+    /// (default: none)
+    #[clap(long, value_parser = parse_mode)]
+    chmod: Option<u32>,
+
+    /// Check each file or folder is still hidden this long after hiding it, by reading its attributes, or looking for
+    /// its hidden name on Unix, again. Some network filesystems report success without keeping the change, so any
+    /// that didn't stay hidden are hidden again, up to 3 times, before being reported as errors. Everything hidden by a
+    /// search is checked together once the last of it has been hidden this long, as is everything hidden by a watcher
+    /// since it last checked. Failed checks are counted as unverified in --stats. Accepts a number with a unit, such as
+    /// 500ms, 5s, 10m, 2h or 30d.
+    /// (default: hiding isn't checked)
+    #[clap(long, value_parser = parse_duration, conflicts_with_all = ["test", "unhide"])]
+    verify_hide: Option<Duration>,
+
+    /// Share the files and folders deliberately unhidden with other runs through this file. Unhiding records each one
+    /// in it before unhiding it, and hiding or watching with the same file leaves them alone, so a watcher enforcing
+    /// hiding doesn't hide again what was just unhidden. It's created if it doesn't exist yet.
+    /// (default: unhidden files and folders aren't recorded)
+    #[clap(long)]
+    exemptions: Option<PathBuf>,
+
+    /// How long files and folders recorded in --exemptions are left alone once they've been unhidden. Accepts a number
+    /// with a unit, such as 500ms, 5s, 10m, 2h or 30d.
+    /// (default: forever)
+    #[clap(long, value_parser = parse_duration, requires = "exemptions")]
+    exempt_for: Option<Duration>,
+
+    /// Permissions to give the files cloak makes for itself on Unix, such as the --report, --journal and --exemptions,
+    /// in octal. They're only given to new files, and the umask can still take more away. On Windows, they get whatever
+    /// they inherit from the folder they're in.
+    /// (default: 600)
+    #[clap(long, value_parser = parse_mode)]
+    file_mode: Option<u32>,
+
+    /// Permissions to give the folders cloak makes for itself on Unix, such as the --vault, in octal. They're only
+    /// given to new folders, and the umask can still take more away. On Windows, they get whatever they inherit from
+    /// the folder they're in.
+    /// (default: 700)
+    #[clap(long, value_parser = parse_mode)]
+    dir_mode: Option<u32>,
+
+    /// When watching, leave alone whatever this user unhides, given by name or uid, rather than hiding it again.
+    /// Anything unhidden by anyone else is hidden again, and reported. Telling who unhid what needs fanotify, so it
+    /// only works on Linux 5.17 or later, as root. Can be specified multiple times to allow more users.
+    /// (default: [])
+    #[clap(long, requires = "watch", conflicts_with = "unhide")]
+    allow_unhide_user: Option<Vec<String>>,
+
+    /// When watching, leave alone whatever this program unhides, given by its full path or just its file name, rather
+    /// than hiding it again. Works the same as --allow-unhide-user otherwise, and either can allow an unhide. Can be
+    /// specified multiple times to allow more programs.
+    /// (default: [])
+    #[clap(long, requires = "watch", conflicts_with = "unhide")]
+    allow_unhide_program: Option<Vec<PathBuf>>,
+
+    /// How to write out what happens during the run. Human is plain sentences. Json is a JSON object for every event,
+    /// one per line. Porcelain is a line for every action taken, with the action and the path separated by a tab, in a
+    /// format that won't change between versions. Quiet is nothing but errors. Tui is human output scrolling above a
+    /// live status line, when the output is a terminal, and can't be used with --interactive or --progress. Log is
+    /// human output written to the system log, which is syslog or the systemd journal on Unix, and the event log on
+    /// Windows.
+    /// (default: human, or log for cloak daemon)
+    #[clap(long)]
+    output: Option<output::OutputFormat>,
+
+    /// Maximum number of paths to list in the output, after which they're only counted, and how many more there were
+    /// is written out once finished, such as "… (+12345 more)". Errors are always written out in full. Output to a pipe
+    /// or a file is written in chunks rather than a line at a time either way, so huge runs don't hold up on it.
+    /// (default: no limit)
+    #[clap(long)]
+    output_limit: Option<usize>,
+
+    /// When to color human output, with actions in green, skips in yellow and errors in red. Auto only colors output
+    /// written to a terminal, and never when the NO_COLOR environment variable is set.
+    /// (default: auto)
+    #[clap(long)]
+    color: Option<output::ColorChoice>,
+
+    /// Flag to print out stats once finished, such as how many files and folders were scanned, hidden and skipped, and
+    /// how many each pattern and path hid, so a pattern that hides more than expected stands out. When watching, they
+    /// are also printed out every minute.
+    /// (default: false)
+    #[clap(long)]
+    stats: bool,
+
+    /// Flag to show a live counter of directories walked, entries processed and hides performed while searching. Only
+    /// shown when the output is a terminal.
+    /// (default: false)
+    #[clap(long)]
+    progress: bool,
+
+    /// Flag to enumerate the NTFS alternate data streams of files and folders to hide, and flag their presence in
+    /// the output. Alternate data streams only exist on Windows.
+    /// (default: false)
+    #[clap(long)]
+    include_ads: bool,
+
+    /// Flag to preserve the access and modification times of directories that files and folders are hidden in.
+    /// Hiding renames entries on Unix, which updates the times of their directory and can confuse backup tools.
+    /// Does nothing on Windows.
+    /// (default: false)
+    #[clap(long)]
+    preserve_dir_times: bool,
+
+    /// Flag to gather up everything to hide before hiding anything, then hide it one directory at a time, so each
+    /// directory is only touched once per run. Prints out how many entries were hidden in each directory.
+    /// (default: false)
+    #[clap(long)]
+    batch: bool,
+
+    /// Hide a folder itself, rather than its entries, when at least this fraction of its entries would be hidden. For
+    /// example, 0.8 hides a folder instead when 80% or more of what's in it matches. Implies --batch.
+    /// (default: entries are always hidden individually)
+    #[clap(long, value_parser = parse_fraction)]
+    prefer_parent: Option<f64>,
+
+    /// Skip files and folders modified after the search started, or less than this long before it, so files that are
+    /// still being written aren't hidden. Accepts a number with a unit, such as 500ms, 5s, 10m, 2h or 30d.
+    /// (default: nothing is skipped)
+    #[clap(long, value_parser = parse_duration)]
+    settle: Option<Duration>,
+
+    /// Give up on a file or folder if reading its metadata, or hiding it, takes longer than this, such as on a dying
+    /// disk or a hung network mount. It's skipped with an error, and the run carries on without it, skipping
+    /// everything else on the same mount point until whatever hung there finishes. Accepts a number with a unit, such
+    /// as 500ms, 5s, 10m, 2h or 30d.
+    /// (default: no timeout)
+    #[clap(long, value_parser = parse_duration)]
+    op_timeout: Option<Duration>,
+
+    /// Check each mount point, including any path given that is one, before searching through it, and skip it if it
+    /// doesn't respond within this long, so one dead network mount can't hang the whole run. Skipped mount points are
+    /// reported as errors, and retried every minute while watching. Accepts a number with a unit, such as 500ms, 5s,
+    /// 10m, 2h or 30d.
+    /// (default: mount points aren't checked)
+    #[clap(long, value_parser = parse_duration)]
+    mount_timeout: Option<Duration>,
+
     /// Glob pattern to match files and folders to hide. Can be specified multiple times to add more patterns.
     /// These are matched after glob and regex exclude patterns, but before regex patterns.
     /// By default, all files and folders are hidden.
@@ -37,15 +472,32 @@ struct Opts {
     #[clap(short, long)]
     pattern: Option<Vec<String>>,
 
+    /// Extension of files and folders to hide, with or without the leading dot. Can be specified multiple times to add
+    /// more extensions. Each one is a shortcut for a glob pattern, so --ext tmp is the same as -p '*.tmp', and they can
+    /// be mixed with other glob patterns.
+    /// (default: [])
+    #[clap(long)]
+    ext: Option<Vec<String>>,
+
     /// Glob pattern to exclude files and folders from hiding. Can be specified multiple times to add more patterns.
-    /// These are matched first, before regex exclude patterns, and glob and regex patterns.
+    /// These are matched first, before regex exclude patterns, and glob and regex patterns. A pattern ending in /**,
+    /// such as '**/node_modules/**', also stops folders matching the rest of it from being searched through at all.
     /// By default, no files or folders are excluded.
     /// (default: [])
     #[clap(short = 'x', long)]
     exclude: Option<Vec<String>>,
 
+    /// Name of folders to leave alone wherever they are, such as .git or node_modules. Can be specified multiple times
+    /// to add more names, which can be glob patterns. Matching folders are neither hidden nor searched through, and
+    /// neither is anything below them, when searching and watching alike, whatever --match-on and --match-target are.
+    /// Only folders below the paths being searched or watched count, so those paths can be inside one themselves.
+    /// (default: [])
+    #[clap(long)]
+    exclude_dir: Option<Vec<String>>,
+
     /// Regex pattern to match files and folders to hide. Can be specified multiple times to add more patterns.
-    /// Regex patterns are matched against the full path of the file or folder.
+    /// Regex patterns are matched against the raw bytes of the full path of the file or folder, so names that aren't
+    /// valid UTF-8 can be matched exactly using (?-u) and escapes like \xFF.
     /// They are matched last, after glob and regex exclude patterns, and glob patterns.
     /// By default, all files and folders are hidden.
     /// (default: [".*"])
@@ -60,12 +512,169 @@ struct Opts {
     #[clap(short = 'e', long)]
     regex_exclude: Option<Vec<String>>,
 
-    /// Types of objects to hide. Can be specified multiple times to add more types.
+    /// Name to match patterns against. Current is the name on disk, original is the name with the dot added by hiding
+    /// removed, and post-hide is the name once hidden. These only differ on Unix, where hiding renames.
+    /// (default: original with --unhide, otherwise current)
+    #[clap(long)]
+    match_target: Option<matcher::MatchTarget>,
+
+    /// Part of each path to match patterns against. Path is the whole path, and name is just the final component, so
+    /// a regex like ^build$ matches anything named build wherever it is.
+    /// (default: path)
+    #[clap(long)]
+    match_on: Option<matcher::MatchOn>,
+
+    /// Flag to match patterns against paths relative to the path being searched or watched, rather than the paths as
+    /// they were found, so a regex like ^tmp/ matches the tmp folder directly inside it.
+    /// (default: false)
+    #[clap(long)]
+    relative: bool,
+
+    /// Flag to ignore case in every pattern, including extensions. Useful on Windows, where the filesystem ignores case
+    /// but patterns otherwise don't.
+    /// (default: false)
+    #[clap(long)]
+    ignore_case: bool,
+
+    /// Flag to match globs a character at a time with Unicode rules, the same as regexes, rather than a byte at a time.
+    /// Classes like [а-я] and ? then match whole non-ASCII characters, and --ignore-case folds the case of every
+    /// letter in globs, not just ASCII ones, so globs and regexes agree on what a name matches. Names that aren't valid
+    /// UTF-8 are only matched by the parts of globs that don't need a whole character. Needs regex support.
+    /// (default: false)
+    #[clap(long)]
+    unicode_case_fold: bool,
+
+    /// Types of objects to hide. Can be specified multiple times to add more types. Sockets, fifos and block and
+    /// character devices are only told apart on Unix, and junctions and other reparse points that link elsewhere only
+    /// on Windows. Anything else that isn't a file, folder or symlink is unknown.
     /// By default, all types are hidden.
     /// (default: ["file", "folder", "symlink"])
     #[clap(short, long)]
     types: Option<Vec<filesystem::ObjectType>>,
 
+    /// Rules for a single path, given as RULES:PATH, where RULES is a JSON file with any of the fields pattern, ext,
+    /// exclude, exclude_dir, regex, regex_exclude and types, which mean the same as the options of the same names. The
+    /// path is searched or watched along with the others, but using only the patterns and types in its rules. When
+    /// watching, the files are read again whenever they change, on SIGHUP, or when sent reload with cloak ctl. Can be
+    /// specified multiple times to give more paths their own rules.
+    /// (default: [])
+    #[clap(long)]
+    path_rules: Option<Vec<String>>,
+
+    /// Read the files and folders to hide from this file, or from stdin if it's -, one per line, instead of searching
+    /// for them, so another tool such as find or fd can find them. Each one is still filtered and matched, and patterns
+    /// are matched against it as it's given, but nothing below it is searched through, and depths don't apply.
+    /// (default: files and folders are searched for)
+    #[clap(long, conflicts_with_all = ["watch", "path_rules"])]
+    files_from: Option<PathBuf>,
+
+    /// Flag to separate the paths read by --files-from with NUL characters rather than new lines, as written by
+    /// find -print0 or fd -0, so paths with new lines in them can be read too.
+    /// (default: false)
+    #[clap(short = '0', long, requires = "files_from")]
+    null: bool,
+
+    /// Only search what changed since the last run given this state file, rather than everything, which is read from
+    /// the NTFS change journal on Windows, which needs administrator rights, and the FSEvents history on macOS. Each
+    /// path is searched in full the first time, or if its history doesn't go back to the last run, and always on other
+    /// systems. The file is only updated by runs without errors, so anything that failed is looked at again. Only what
+    /// changed is matched, so changing the patterns needs a fresh state file to apply them to everything else.
+    /// (default: everything is searched)
+    #[clap(long, conflicts_with_all = ["watch", "files_from", "unhide", "prefer_parent"])]
+    since_last: Option<PathBuf>,
+
+    /// Only hide files at least this big. Accepts a number with an optional unit, such as 512, 10K, 10M or 1.5G, where
+    /// K is 1024 bytes. Folders and symlinks are never hidden when a size is given.
+    /// (default: no minimum)
+    #[clap(long, value_parser = parse_size)]
+    min_size: Option<u64>,
+
+    /// Only hide files at most this big. Accepts a number with an optional unit, such as 512, 10K, 10M or 1.5G, where
+    /// K is 1024 bytes. Folders and symlinks are never hidden when a size is given.
+    /// (default: no maximum)
+    #[clap(long, value_parser = parse_size)]
+    max_size: Option<u64>,
+
+    /// Only hide files and folders last modified at least this long ago, so only stale ones are hidden. Accepts a
+    /// number with a unit, such as 500ms, 5s, 10m, 2h or 30d.
+    /// (default: no minimum age)
+    #[clap(long, value_parser = parse_duration)]
+    older_than: Option<Duration>,
+
+    /// Only hide files and folders last modified at most this long ago. Accepts a number with a unit, such as 500ms,
+    /// 5s, 10m, 2h or 30d.
+    /// (default: no maximum age)
+    #[clap(long, value_parser = parse_duration)]
+    newer_than: Option<Duration>,
+
+    /// Only hide files and folders that have a sibling with the name given by this template. The template can refer to
+    /// the name of the file or folder as {name}, its name without the extension as {stem}, and its extension as {ext}.
+    /// For example, "{stem}.meta" only hides files that have a .meta file alongside them.
+    /// (default: no sibling is required)
+    #[clap(long)]
+    requires_sibling: Option<String>,
+
+    /// Only hide files and folders owned by this user on Unix, given by name or uid. Can be specified multiple times to
+    /// hide what any of them own. Names can only be looked up on Linux so far. Ignored on Windows.
+    /// (default: any owner)
+    #[clap(long)]
+    owner: Option<Vec<String>>,
+
+    /// Only hide files and folders in this group on Unix, given by name or gid. Can be specified multiple times to hide
+    /// what's in any of them. Names can only be looked up on Linux so far. Ignored on Windows.
+    /// (default: any group)
+    #[clap(long)]
+    group: Option<Vec<String>>,
+
+    /// Only hide files and folders with every one of these permission bits set on Unix, in octal, such as 004 for
+    /// anything anyone can read, or 002 for anything anyone can write. Ignored on Windows.
+    /// (default: any permissions)
+    #[clap(long, value_parser = parse_mode)]
+    perm: Option<u32>,
+
+    /// Flag to only hide folders with nothing visible in them, such as the empty placeholder folders some tools leave
+    /// everywhere, leaving alone any with something in them. Files and symlinks aren't affected. When watching, a
+    /// folder hidden for being empty is unhidden again as soon as something visible is made in it.
+    /// (default: false)
+    #[clap(long)]
+    only_empty: bool,
+
+    /// Only hide files and folders that git doesn't track: untracked ones that it doesn't ignore either, ignored ones,
+    /// or both. Anything git tracks is never hidden, nor is any folder with something tracked in it, or anything
+    /// outside a git repository. Git is asked about each repository once, and again whenever something it didn't know
+    /// about turns up.
+    /// (default: git isn't asked)
+    #[clap(long, value_enum)]
+    git: Option<git::GitMode>,
+
+    /// Only hide files whose content is of this type, such as image/png, as worked out from the magic bytes at its
+    /// start rather than its extension. Can be a glob pattern, such as image/*, and can be specified multiple times to
+    /// hide files of any of the types. Only files that get past every other filter are read, and folders, symlinks and
+    /// files whose type can't be recognized, which includes plain text, are never hidden.
+    /// (default: any type)
+    #[clap(long)]
+    mime: Option<Vec<String>>,
+
+    /// Only hide files with something in them that matches this regex, such as a secret or a marker. Can be specified
+    /// multiple times to hide files that match any of them. Only files that get past every other filter are read, up
+    /// to --content-max-bytes, and folders and symlinks are never hidden. Matches are found a chunk at a time, so one
+    /// longer than 4K can be missed.
+    /// (default: content isn't read)
+    #[clap(long)]
+    content_regex: Option<Vec<String>>,
+
+    /// The most of each file to read for --content-regex. Accepts a number with an optional unit, such as 512, 10K,
+    /// 10M or 1.5G, where K is 1024 bytes.
+    /// (default: 1M)
+    #[clap(long, value_parser = parse_size, requires = "content_regex")]
+    content_max_bytes: Option<u64>,
+
+    /// The most files to read at once for --content-regex, in a thread pool of its own, so scanning big files can't
+    /// take every thread away from searching.
+    /// (default: the number of threads in the thread pool)
+    #[clap(long, requires = "content_regex")]
+    content_threads: Option<usize>,
+
     /// Set the number of threads to use in the thread pool. Still will spawn a small number of threads for other tasks.
     /// (default: number of logical cores)
     #[clap(short = 'j', long)]
@@ -73,14 +682,238 @@ struct Opts {
 
     /// Path(s) to the directory to hide files and folders in. Defaults to the current directory.
     /// (default: ".")
-    #[clap(value_parser)]
+    #[clap(value_parser, conflicts_with = "files_from")]
     path: Option<Vec<String>>,
 }
 
-fn main() -> Result<()> {
+// Commands that do something other than hiding files and folders
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run a battery of scenarios through the real pipeline in a temporary tree, and report whether each behaved as
+    /// expected. Useful to check cloak works on an unusual filesystem, like NFS, FAT or overlayfs.
+    Selftest {
+        /// Directory to create the temporary tree in, on the filesystem to check.
+        /// (default: the system temp directory)
+        dir: Option<PathBuf>,
+
+        /// Flag to keep the temporary tree afterwards, to look into any failures.
+        /// (default: false)
+        #[clap(long)]
+        keep: bool,
+    },
+
+    /// Print the JSON Schema of one of the formats cloak writes or reads, so other tools can check against it:
+    /// decisions for the events written by --output json, report for reports written by --report in JSON, or rules
+    /// (also called config) for the files read by --path-rules.
+    Schema {
+        /// Format to print the schema of.
+        #[clap(value_enum)]
+        kind: schema::SchemaKind,
+    },
+
+    /// Print a script that completes cloak's options and their values when pressing tab in a shell. Load it from the
+    /// shell's startup file, e.g. `source <(cloak completions bash)` in ~/.bashrc.
+    Completions {
+        /// Shell to complete in.
+        #[clap(value_enum)]
+        shell: clap_complete::Shell,
+    },
+
+    /// Register cloak as a background service that watches for changes, or remove it again: with systemd on Linux, or
+    /// with the service manager on Windows.
+    Service {
+        #[clap(subcommand)]
+        command: service::ServiceCommand,
+    },
+
+    /// Watch in the background as a daemon, for systemd or the Windows service manager to run, which is what cloak
+    /// service install sets up. The options are read from a config file, a JSON object of options by their long
+    /// names, such as {"path": ["/home/me/Downloads"], "pattern": ["*.tmp"], "recursive": true}, followed by anything
+    /// given after --. It detaches from the terminal, writes a pidfile, and logs to the system log, which is syslog or
+    /// the systemd journal on Unix, and the event log on Windows, unless the options say otherwise.
+    Daemon {
+        /// Name of the service it runs as, which its pidfile is named after.
+        /// (default: cloak)
+        #[clap(long)]
+        name: Option<String>,
+
+        /// Config file to read the options from. The default one is only read if it exists.
+        /// (default: cloak/daemon.json in the user's config directory, ~/.config on Unix and %APPDATA% on Windows)
+        #[clap(long)]
+        config: Option<PathBuf>,
+
+        /// Flag to stay in the foreground rather than detaching, as service managers such as systemd expect. Ignored on
+        /// Windows, where the service manager runs it in the background.
+        /// (default: false)
+        #[clap(long)]
+        foreground: bool,
+
+        /// Options and paths to watch with, after those in the config file.
+        #[clap(last = true)]
+        args: Vec<String>,
+    },
+
+    /// Send a command to a running watcher through the socket given to its --control option: status to see how long
+    /// it has been watching, what and the counts so far, pause to stop hiding anything until resumed, resume to start
+    /// again, searching for whatever changed while paused, rescan to search everything again, or reload to read the
+    /// --path-rules files again.
+    Ctl {
+        /// Control socket of the watcher.
+        socket: PathBuf,
+
+        /// Command to send.
+        #[clap(value_enum)]
+        command: control::ControlCommand,
+    },
+
+    /// Show stats from a running watcher through the socket given to its --control option: how long it has been
+    /// watching, how many events it has received and how fast, how many are waiting to be handled, and how many paths
+    /// it has hidden, skipped and failed on.
+    Stats {
+        /// Control socket of the watcher.
+        socket: PathBuf,
+
+        /// Flag to keep showing them, redrawn in place, until interrupted.
+        /// (default: false)
+        #[clap(long)]
+        live: bool,
+
+        /// How often to redraw them with --live. Accepts a number with a unit, such as 500ms, 5s, 10m, 2h or 30d.
+        /// (default: 1s)
+        #[clap(long, value_parser = parse_duration, default_value = "1s", requires = "live")]
+        interval: Duration,
+    },
+
+    /// Search the paths with the rest of the options as usual, without changing anything, then show what would be
+    /// hidden, or unhidden with --unhide, in a scrollable list grouped by folder. Each path, or everything below a
+    /// folder, can be toggled before only the approved paths are changed. Options go before tui, e.g.
+    /// `cloak -r -p '*.log' tui PATHS`.
+    Tui {
+        /// Path(s) to the directory to hide files and folders in.
+        /// (default: ".")
+        paths: Vec<String>,
+    },
+
+    /// Find everything already hidden by something other than cloak in the paths and everything below them, and
+    /// register it with cloak: attrib for anything with the hidden attribute on Windows, or dotfiles for anything whose
+    /// name starts with a dot on Unix. Each is recorded in the journal, so unhiding with it gives it back the name it
+    /// has without the dot, or marked with the cloak attribute, or both.
+    ImportState {
+        /// How the files and folders were hidden.
+        #[clap(long, value_enum)]
+        from: import::ImportFrom,
+
+        /// Journal to record them in, which is created if it doesn't exist yet.
+        /// (default: no journal)
+        #[clap(long)]
+        journal: Option<PathBuf>,
+
+        /// Flag to mark them with the cloak attribute, as --action mark does.
+        /// (default: false)
+        #[clap(long)]
+        mark: bool,
+
+        /// Flag to only print out what would be imported.
+        /// (default: false)
+        #[clap(short = 'm', long)]
+        test: bool,
+
+        /// Paths to search.
+        #[clap(required = true)]
+        paths: Vec<PathBuf>,
+    },
+
+    /// Print a journal or report encrypted with --encrypt-metadata, decrypted with the passphrase in the
+    /// CLOAK_PASSPHRASE environment variable or the given file.
+    Decrypt {
+        /// Journal or report to decrypt.
+        file: PathBuf,
+
+        /// File to read the passphrase from, rather than the CLOAK_PASSPHRASE environment variable.
+        /// (default: none)
+        #[clap(long)]
+        passphrase_file: Option<PathBuf>,
+    },
+}
+
+fn main() -> ExitCode {
     // Parse the command line arguments
     let opts: Opts = Opts::parse();
 
+    // Exit with 2 if there was a fatal error, rather than an error handling a particular path. Daemons read the rest of
+    // their options before they run.
+    let result = match opts.command {
+        Some(Command::Daemon {
+            name,
+            config,
+            foreground,
+            args,
+        }) => daemon(name, config, foreground, args),
+        _ => run(opts),
+    };
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+// Run the watcher as a daemon, with the options in its config file followed by the ones it was given. It detaches from
+// the terminal unless it's to stay in the foreground, and runs as a service when the Windows service manager starts it.
+// It writes a pidfile and logs to the system log, unless the options already say otherwise. Anything that stops it
+// from running is logged too when it is, since there's nowhere else for it to go once detached.
+fn daemon(
+    name: Option<String>,
+    config: Option<PathBuf>,
+    foreground: bool,
+    args: Vec<String>,
+) -> Result<ExitCode> {
+    let name = name.unwrap_or_else(|| "cloak".to_owned());
+
+    // Read the options, starting out without any if the default config file doesn't exist
+    let (options, paths) = match config {
+        Some(config) => service::config_args(&config)?,
+        None => {
+            let config = service::default_config()?;
+            if config.exists() {
+                service::config_args(&config)?
+            } else {
+                Default::default()
+            }
+        }
+    };
+    let mut argv: Vec<OsString> = vec!["cloak".into(), "--watch".into()];
+    argv.extend(options);
+    argv.extend(args.into_iter().map(OsString::from));
+    if !paths.is_empty() {
+        argv.push("--".into());
+        argv.extend(paths);
+    }
+    let mut opts = Opts::try_parse_from(argv).with_context(|| "Failed to read the daemon's options")?;
+    opts.output.get_or_insert(output::OutputFormat::Log);
+    opts.pidfile
+        .get_or_insert_with(|| service::default_pidfile(&name));
+
+    // Detach before anything starts any threads, which wouldn't carry on in the background
+    if !foreground {
+        service::detach()?;
+    }
+
+    let logged = opts.output == Some(output::OutputFormat::Log);
+    service::serve(&name, move || match run(opts) {
+        Err(e) if logged => {
+            systemlog::SystemLog::open().write(systemlog::Level::Error, format_args!("Error: {e:?}"));
+            Ok(ExitCode::from(2))
+        }
+        result => result,
+    })
+}
+
+// Set up and run the search, and the watcher if needed. Returns an exit code of 0 if every path was handled, or 1 if
+// some of them failed.
+fn run(opts: Opts) -> Result<ExitCode> {
     // Set a new global threadpool with the number of threads specified by the user.
     if let Some(threads) = opts.threads {
         rayon::ThreadPoolBuilder::new()
@@ -89,47 +922,568 @@ fn main() -> Result<()> {
             .with_context(|| "Failed to build new threadpool")?;
     }
 
-    // Get the paths to hide files and folders in.
-    let paths = opts
-        .path
-        .map_or_else(|| vec![".".to_owned()], |paths| paths);
+    // Run a command instead of hiding anything, if one was given. Reviewing searches the same as hiding does.
+    let review = match opts.command {
+        Some(Command::Selftest { dir, keep }) => return selftest::run(dir, keep),
+        Some(Command::Schema { kind }) => return schema::run(kind),
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(shell, &mut Opts::command(), "cloak", &mut std::io::stdout());
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Service { command }) => return service::run(command),
+        Some(Command::Daemon { .. }) => bail!("A daemon's options can't run another daemon"),
+        Some(Command::Ctl { socket, command }) => return control::send(&socket, command),
+        Some(Command::Stats {
+            socket,
+            live,
+            interval,
+        }) => return control::stats(&socket, live, interval),
+        Some(Command::ImportState {
+            from,
+            journal,
+            mark,
+            test,
+            paths,
+        }) => return import::run(from, &paths, journal, mark, test),
+        Some(Command::Decrypt {
+            file,
+            passphrase_file,
+        }) => return cipher::run(&file, passphrase_file.as_deref()),
+        Some(Command::Tui { paths }) => Some(paths),
+        None => None,
+    };
+    if review.is_some() {
+        if opts.watch
+            || opts.interactive
+            || opts.test
+            || opts.output == Some(output::OutputFormat::Tui)
+        {
+            bail!(
+                "tui reviews a single search itself, so it can't be used with --watch, --interactive, --test or \
+                 --output tui"
+            );
+        }
+        if opts.vault.is_some() && opts.unhide {
+            bail!("tui can't review unhiding with --vault, which moves everything back out of the vault at once");
+        }
+        if opts.since_last.is_some() {
+            bail!("tui reviews everything it finds, so it can't be used with --since-last");
+        }
+    }
 
-    // Build a matcher to match files and folders to hide
-    let matcher =
-        matcher::Matcher::new(opts.pattern, opts.exclude, opts.regex, opts.regex_exclude)?;
+    // Work out how deep to search. Explicit depths override the recursive flag, and only the paths themselves are
+    // searched if they're all that can be hidden.
+    let include_root = opts.include_root.unwrap_or_default();
+    if include_root == filter::IncludeRoot::Only
+        && (opts.max_depth.is_some() || opts.min_depth.is_some() || opts.recursive)
+    {
+        bail!("--include-root only can't be used with --max-depth, --min-depth or --recursive");
+    }
+    let max_depth = match include_root {
+        filter::IncludeRoot::Only => 0,
+        _ => opts
+            .max_depth
+            .unwrap_or(if opts.recursive { usize::MAX } else { 1 }),
+    };
+    let min_depth = opts.min_depth.unwrap_or(0);
+    if min_depth > max_depth {
+        bail!("Minimum depth {min_depth} is greater than maximum depth {max_depth}");
+    }
 
-    // If the watch flag is set, then spawn a new thread to search for files and folders to hide.
-    // Otherwise, just search for files and folders to hide.
-    if opts.watch {
-        std::thread::scope(|s| {
-            s.spawn(|| {
-                search::search(
-                    &paths,
-                    &matcher,
-                    opts.types.as_deref(),
-                    opts.recursive,
-                    opts.test,
-                    opts.verbose,
+    // Work out how to write out what happens. The TUI draws over the terminal, so it can't share it.
+    let output = opts.output.unwrap_or_default();
+    if output == output::OutputFormat::Tui && (opts.interactive || opts.progress) {
+        bail!("The tui output can't be used with --interactive or --progress");
+    }
+    let reporter = output::reporter(
+        output,
+        opts.verbose,
+        opts.output_limit,
+        opts.color.unwrap_or_default(),
+    );
+
+    // Build a matcher to match files and folders to hide. When unhiding, paths are matched by the names they had before
+    // they were hidden, unless asked otherwise.
+    let match_target = opts.match_target.unwrap_or(if opts.unhide {
+        matcher::MatchTarget::Original
+    } else {
+        matcher::MatchTarget::Current
+    });
+    let mut pattern = opts.pattern;
+    if let Some(ext) = opts.ext {
+        // Extensions are just a shortcut for glob patterns, so add them to the rest.
+        pattern
+            .get_or_insert_with(Vec::new)
+            .extend(ext.iter().map(|ext| matcher::ext_pattern(ext)));
+    }
+    let matcher = matcher::Matcher::new(
+        pattern,
+        opts.exclude,
+        opts.regex,
+        opts.regex_exclude,
+        opts.exclude_dir,
+        match_target,
+        opts.match_on.unwrap_or_default(),
+        opts.ignore_case,
+        opts.unicode_case_fold,
+    )?;
+
+    // Load the rules for any paths that have their own
+    let root_rules = opts
+        .path_rules
+        .map(|specs| {
+            rules::RootRules::load(
+                &specs,
+                match_target,
+                opts.match_on.unwrap_or_default(),
+                opts.ignore_case,
+                opts.unicode_case_fold,
+            )
+        })
+        .transpose()?;
+
+    // Get the paths to hide files and folders in, including any that have their own rules.
+    let mut paths = opts.path.unwrap_or_default();
+    paths.extend(review.iter().flatten().cloned());
+    if let Some(root_rules) = &root_rules {
+        paths.extend(root_rules.paths().iter().cloned());
+    }
+    if paths.is_empty() {
+        paths.push(".".to_owned());
+    }
+
+    // Read the list of paths to change instead, if they're given by something else
+    let listed = opts
+        .files_from
+        .as_deref()
+        .map(|from| {
+            if from == std::path::Path::new("-") && opts.interactive {
+                bail!("Paths can't be read from stdin with --interactive, which reads answers from it");
+            }
+            search::read_listed(from, opts.null)
+        })
+        .transpose()?;
+
+    // Refuse to change anything in places where it could break the system, unless forced to
+    if !opts.force && !opts.test {
+        let protected = match &listed {
+            Some(listed) => listed.iter().find_map(|path| {
+                safety::protected(path).map(|reason| (path.display().to_string(), reason))
+            }),
+            None => paths.iter().find_map(|path| {
+                safety::protected(std::path::Path::new(path))
+                    .map(|reason| (format!("files and folders in {path}"), reason))
+            }),
+        };
+        if let Some((what, reason)) = protected {
+            bail!(
+                "Refusing to {} {what} because it's {reason}. Use --force to do it anyway.",
+                if opts.unhide { "unhide" } else { "hide" }
+            );
+        }
+    }
+
+    // Keep track of what happens to each path, so a summary can be given at the end of a watch session
+    let summary = summary::Summary::default();
+
+    // Keep everything cloak makes for itself to the user, since it lists what they wanted hidden
+    let modes = filesystem::CreateModes::new(opts.file_mode, opts.dir_mode);
+
+    // Encrypt what cloak keeps about what it hid if asked to
+    if opts.encrypt_metadata && opts.journal.is_none() && opts.report.is_none() {
+        bail!("--encrypt-metadata only encrypts the --journal and --report, so it needs at least one of them");
+    }
+    let cipher = opts
+        .encrypt_metadata
+        .then(|| cipher::Cipher::load(opts.passphrase_file.as_deref()))
+        .transpose()?;
+
+    // Keep a report of every action taken if one was asked for
+    let report = opts
+        .report
+        .map(|path| report::Report::new(path, opts.report_format, modes, cipher.clone()))
+        .transpose()?;
+
+    // Load the journal of paths hidden under other names if one is being kept
+    let journal = opts
+        .journal
+        .map(|path| journal::Journal::load(path, opts.journal_format, modes, cipher.clone()))
+        .transpose()?;
+
+    // Work out what to do to the paths that match. Unhiding only ever unhides, and the vault is only for hiding.
+    let actions = match opts.action {
+        Some(action) => actions::Actions::new(&action, opts.chmod)?,
+        None => actions::Actions::new(&[actions::Action::Hide], opts.chmod)?,
+    };
+    if opts.unhide && (actions.extra() || !actions.hides()) {
+        bail!("--unhide only unhides, so it can't be used with --action other than hide");
+    }
+    if opts.vault.is_some() && !actions.hides() {
+        bail!("--vault is only used to hide things, so it needs --action hide");
+    }
+
+    // Build the types of content to hide, if only some are
+    let mimes = opts
+        .mime
+        .map(|mimes| {
+            let mut builder = globset::GlobSetBuilder::new();
+            for mime in mimes {
+                builder.add(
+                    globset::GlobBuilder::new(&mime)
+                        .case_insensitive(true)
+                        .literal_separator(true)
+                        .build()
+                        .with_context(|| format!("Failed to parse type {mime}"))?,
                 );
-            });
-            watcher::watch(
+            }
+            builder.build().with_context(|| "Failed to build type matcher")
+        })
+        .transpose()?;
+
+    // Work out who what's hidden has to belong to, and which permissions it has to have, if anyone or any
+    let ownership = filter::Ownership::new(opts.owner, opts.group, opts.perm)?;
+
+    // Keep track of the folders hidden for being empty, if only empty ones are to be hidden
+    let empty = opts.only_empty.then(empty::EmptyFolders::default);
+
+    // Build the scanner for the content of files, if only files with something in them are to be hidden
+    let scanner = opts
+        .content_regex
+        .map(|regexes| {
+            scan::Scanner::new(
+                &regexes,
+                opts.content_max_bytes.unwrap_or(1024 * 1024),
+                opts.content_threads
+                    .unwrap_or_else(rayon::current_num_threads),
+            )
+        })
+        .transpose()?;
+
+    // Check git is there to ask, if only paths it doesn't track are to be hidden
+    let git = opts.git.map(git::Git::new).transpose()?;
+
+    // Open the vault to move paths into if they're to be moved rather than renamed
+    let vault = match (opts.strategy.unwrap_or_default(), &opts.vault) {
+        (vault::Strategy::Move, Some(_)) if opts.unhide && opts.watch => {
+            bail!("Unhiding with --strategy move moves everything back out of the vault at once, so it can't watch")
+        }
+        (vault::Strategy::Move, Some(dir)) => Some(vault::Vault::open(dir, modes, opts.prune_empty)?),
+        (vault::Strategy::Rename, Some(_)) => bail!("--vault is only used with --strategy move"),
+        _ => None,
+    };
+
+    // Share the paths deliberately unhidden with other runs if asked to
+    let exemptions = opts
+        .exemptions
+        .map(|path| exempt::Exemptions::new(path, opts.exempt_for, modes));
+
+    // Find out who unhides what while watching, if only some are allowed to
+    let unhiders = (opts.allow_unhide_user.is_some() || opts.allow_unhide_program.is_some())
+        .then(|| {
+            unhiders::Unhiders::new(
                 &paths,
-                &matcher,
-                opts.types.as_deref(),
-                opts.recursive,
-                opts.test,
-                opts.verbose,
+                &opts.allow_unhide_user.unwrap_or_default(),
+                &opts.allow_unhide_program.unwrap_or_default(),
+                reporter.clone(),
             )
         })
+        .transpose()?
+        .map(Arc::new);
+    if let Some(unhiders) = &unhiders {
+        unhiders.clone().listen();
+    }
+
+    // Cap how many paths are changed if asked to
+    let limit = opts.limit.map(limit::Limit::new);
+
+    // Check that what's hidden stays hidden if asked to
+    let verifier = opts.verify_hide.map(verify::Verifier::new);
+
+    // Ask before hiding each path if asked to
+    let prompt = opts.interactive.then(prompt::Prompt::default);
+
+    // Keep track of directory times if they are to be preserved
+    let dir_times = opts.preserve_dir_times.then(filesystem::DirTimes::default);
+    let mounts = opts
+        .mount_timeout
+        .map(|timeout| Arc::new(mounts::Mounts::new(timeout)));
+
+    // Keep the targets of symlinks that are followed inside the paths being searched or watched, unless they can be
+    // anywhere
+    let deref_within: Option<Vec<PathBuf>> = (opts.deref && !opts.deref_outside_roots).then(|| {
+        paths
+            .iter()
+            .map(|path| safety::canonical(std::path::Path::new(path)))
+            .collect()
+    });
+
+    // Gather up everything that decides how paths are found and hidden, to share with searching and watching
+    let ctx = context::RunContext {
+        matcher: &matcher,
+        types: opts.types.as_deref(),
+        min_size: opts.min_size,
+        max_size: opts.max_size,
+        older_than: opts.older_than,
+        ownership: ownership.as_ref(),
+        newer_than: opts.newer_than,
+        requires_sibling: opts.requires_sibling.as_deref(),
+        git: git.as_ref(),
+        mimes: mimes.as_ref(),
+        scanner: scanner.as_ref(),
+        empty: empty.as_ref(),
+        settle: opts.settle,
+        relative: opts.relative,
+        min_depth,
+        max_depth,
+        include_root,
+        actions,
+        follow_symlinks: opts.follow_symlinks,
+        skip_hidden_dirs: opts.skip_hidden_dirs,
+        deref: opts.deref,
+        deref_within: deref_within.as_deref(),
+        mounts: mounts.as_ref(),
+        test: opts.test,
+        force: opts.force,
+        unhide: opts.unhide,
+        verbose: opts.verbose,
+        include_ads: opts.include_ads,
+        batch: opts.batch,
+        prefer_parent: opts.prefer_parent,
+        op_timeout: opts.op_timeout,
+        on_conflict: opts.on_conflict.unwrap_or_default(),
+        max_operations: opts.max_operations,
+        dir_times: dir_times.as_ref(),
+        report: report.as_ref(),
+        journal: journal.as_ref(),
+        vault: vault.as_ref(),
+        exemptions: exemptions.as_ref(),
+        unhiders: unhiders.as_deref(),
+        limit: limit.as_ref(),
+        verifier: verifier.as_ref(),
+        prompt: prompt.as_ref(),
+        root_rules: root_rules.as_ref(),
+        cooldown: None,
+        summary: &summary,
+        reporter: &reporter,
+    };
+
+    // Leave out any path given more than once, or inside another one, so nothing is searched or watched twice
+    let paths = search::roots(paths, &ctx);
+
+    // Find out what changed since the last run, if only that is to be searched
+    let since = opts
+        .since_last
+        .map(|since_last| changes::Since::load(since_last, &paths, modes, &ctx))
+        .transpose()?;
+
+    // If the watch flag is set, then spawn a new thread to search for files and folders to hide, unless
+    // the initial search is skipped. Otherwise, just search for files and folders to hide.
+    if opts.watch {
+        #[cfg(not(feature = "watch"))]
+        bail!("Watching isn't supported by this build of cloak. Rebuild it with the watch feature enabled.");
+
+        #[cfg(feature = "watch")]
+        {
+            // Only poll for changes if the poll backend was selected
+            let poll_interval = (opts.watch_backend.unwrap_or_default()
+                == watcher::WatchBackend::Poll)
+                .then(|| Duration::from_secs(opts.poll_interval.unwrap_or(2)));
+
+            // Build a matcher for the paths whose changes should be handled first
+            let priority = opts
+                .priority
+                .map(|priority| {
+                    matcher::Matcher::new(
+                        Some(priority),
+                        None,
+                        None,
+                        None,
+                        None,
+                        matcher::MatchTarget::Current,
+                        matcher::MatchOn::Path,
+                        opts.ignore_case,
+                        opts.unicode_case_fold,
+                    )
+                })
+                .transpose()?;
+
+            // Let everything else know it's running, for as long as it's watching
+            let _pidfile = opts.pidfile.map(service::Pidfile::create).transpose()?;
+
+            // Listen for commands while watching if asked to
+            let control = opts.control.map(control::Control::bind).transpose()?;
+
+            std::thread::scope(|s| {
+                if !opts.no_initial_scan {
+                    s.spawn(|| {
+                        search::search(&paths, &ctx);
+                    });
+                }
+                watcher::watch(
+                    &paths,
+                    &ctx,
+                    &watcher::WatchOptions {
+                        priority: priority.as_ref(),
+                        poll_interval,
+                        roots_per_shard: opts.roots_per_shard,
+                        rescan_interval: opts.rescan_interval.map(Duration::from_secs),
+                        stats: opts.stats,
+                        wait_for_write: opts.wait_for_write,
+                        failure_cooldown: opts.failure_cooldown,
+                        jobs: opts
+                            .watch_jobs
+                            .unwrap_or_else(|| (rayon::current_num_threads() / 2).max(1)),
+                        queue: opts.watch_queue.unwrap_or(10000),
+                        control: control.as_ref(),
+                        print_events: opts.print_events,
+                    },
+                )
+            })?;
+
+            // Print out a summary of the session
+            reporter.report(&output::Event::Finished {
+                summary: &summary,
+                watched: true,
+            });
+        }
+    } else {
+        // Review what would be changed first if asked to, and then only change what was approved
+        let approved = match review {
+            Some(_) => match review::run(&paths, listed.as_deref(), &ctx)? {
+                Some(approved) => Some(approved),
+                None => {
+                    reporter.report(&output::Event::Notice(
+                        "Cancelled the review, so nothing was changed".to_owned(),
+                    ));
+                    return Ok(ExitCode::SUCCESS);
+                }
+            },
+            None => None,
+        };
+
+        // Unhiding with a vault moves things back out of it, rather than searching for anything
+        let search = || match (&approved, &listed, &vault) {
+            (Some(approved), _, _) => search::search_listed(approved, &ctx),
+            (None, Some(listed), Some(vault)) if opts.unhide => vault::restore(vault, listed, &ctx),
+            (None, None, Some(vault)) if opts.unhide => vault::restore(vault, &paths, &ctx),
+            (None, Some(listed), _) => search::search_listed(listed, &ctx),
+            (None, None, _) => match &since {
+                Some(since) => search::search_since(&paths, since, &ctx),
+                None => search::search(&paths, &ctx),
+            },
+        };
+
+        // Show progress while searching if it was asked for
+        if opts.progress {
+            progress::show(&summary, search);
+        } else {
+            search();
+        }
+
+        // Say so explicitly if everything was already as it should be
+        reporter.report(&output::Event::Finished {
+            summary: &summary,
+            watched: false,
+        });
+    }
+
+    // Say how much was left alone if the limit was reached. Verbose mode lists each one as it's skipped.
+    if let Some(limit) = &limit {
+        if summary.limited() > 0 {
+            reporter.report(&output::Event::Notice(format!(
+                "Stopped after {} changes because of --limit, leaving {} more files and folders that matched",
+                limit.max(),
+                summary.limited()
+            )));
+        }
+    }
+
+    // Write out the report now that everything is done
+    if let Some(report) = report {
+        report.write()?;
+    }
+    if let Some(journal) = journal {
+        journal.write()?;
+    }
+
+    // Remember where everything was up to for the next run, unless something still has to be looked at again, or
+    // nothing was really changed
+    if let Some(since) = &since {
+        if summary.errors() == 0 && summary.limited() == 0 && !opts.test {
+            since.save()?;
+        }
+    }
+
+    // Print out stats and any errors that occurred, and fail if any paths couldn't be handled.
+    if opts.stats {
+        reporter.report(&output::Event::Stats(&summary));
+    }
+    if summary.errors() > 0 {
+        reporter.report(&output::Event::Errors(&summary));
+        Ok(ExitCode::FAILURE)
+    } else {
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+// Parse a duration given as a number followed by a unit, such as 500ms, 5s, 10m, 2h or 30d.
+fn parse_duration(s: &str) -> Result<Duration> {
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .ok_or_else(|| anyhow!("Missing unit in duration {s}. Use one of ms, s, m, h or d"))?;
+    let (value, unit) = s.split_at(split);
+    let value: f64 = value
+        .parse()
+        .with_context(|| format!("Failed to parse duration {s}"))?;
+    let seconds = match unit {
+        "ms" => value / 1000.0,
+        "s" => value,
+        "m" => value * 60.0,
+        "h" => value * 60.0 * 60.0,
+        "d" => value * 60.0 * 60.0 * 24.0,
+        _ => bail!("Unknown unit {unit} in duration {s}. Use one of ms, s, m, h or d"),
+    };
+    Duration::try_from_secs_f64(seconds).with_context(|| format!("Invalid duration {s}"))
+}
+
+// Parse a size given as a number with an optional unit, such as 512, 10K, 10M or 1.5G. Units are powers of 1024, and
+// can be written as K, KB or KiB, in any case.
+fn parse_size(s: &str) -> Result<u64> {
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value: f64 = value
+        .parse()
+        .with_context(|| format!("Failed to parse size {s}"))?;
+    let power = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 0,
+        "k" | "kb" | "kib" => 1,
+        "m" | "mb" | "mib" => 2,
+        "g" | "gb" | "gib" => 3,
+        "t" | "tb" | "tib" => 4,
+        _ => bail!("Unknown unit {unit} in size {s}. Use one of B, K, M, G or T"),
+    };
+    Ok((value * 1024f64.powi(power)) as u64)
+}
+
+// Parse a mode in octal, such as 600 or 0755.
+fn parse_mode(s: &str) -> Result<u32> {
+    let mode = u32::from_str_radix(s, 8).with_context(|| format!("Failed to parse mode {s}, which must be octal"))?;
+    if mode > 0o7777 {
+        bail!("Mode {s} has more than the 12 bits of a mode");
+    }
+    Ok(mode)
+}
+
+// Parse a fraction between 0 and 1, such as 0.8.
+fn parse_fraction(s: &str) -> Result<f64> {
+    let fraction: f64 = s
+        .parse()
+        .with_context(|| format!("Failed to parse fraction {s}"))?;
+    if (0.0..=1.0).contains(&fraction) {
+        Ok(fraction)
     } else {
-        search::search(
-            &paths,
-            &matcher,
-            opts.types.as_deref(),
-            opts.recursive,
-            opts.test,
-            opts.verbose,
-        );
-        Ok(())
+        bail!("Fraction {s} must be between 0 and 1")
     }
 }
@@ -1,72 +1,313 @@
+use crate::{filesystem, filter};
 use anyhow::{Context, Result};
-use globset::GlobSet;
-use regex::RegexSet;
-use std::path::Path;
+use clap::ValueEnum;
+use globset::{Glob, GlobSet};
+#[cfg(feature = "regex")]
+use regex::bytes::RegexSet;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
 
+// Matches paths against glob and regex patterns. Regexes are always matched a character at a time with Unicode rules,
+// so classes like [а-я] and . match whole characters, and ignoring case folds the case of every letter, not just ASCII
+// ones. Globs are matched a byte at a time by default, as globset does, so classes and ? only match ASCII characters
+// properly, and ignoring case only folds ASCII letters. With --unicode-case-fold, globs are matched with the same
+// Unicode rules as regexes instead, so the two always agree on what a name matches.
 #[derive(Debug, Clone)]
 pub struct Matcher {
-    globs: Option<GlobSet>,
-    globs_exclude: Option<GlobSet>,
+    globs: Option<Globs>,
+    globs_exclude: Option<Globs>,
     regexes: Option<RegexSet>,
     regexes_exclude: Option<RegexSet>,
+    exclude_dirs: Option<Globs>,
+    prune: Option<Globs>,
+    target: MatchTarget,
+    match_on: MatchOn,
+    patterns: Vec<String>,
+    regex_patterns: Vec<String>,
+    source: Option<PathBuf>,
+    include_all: bool,
 }
 
-// The result of a match, including the type of matcher that matched. Lossy holds the result of converting the path to a string
-// if there was a lossy conversion. Globs can match on full paths, but lossy will still hold a string if the path was not a valid
-// UTF-8 string for printing purposes.
+// The folders whose entries are all excluded, so they don't need to be walked into, along with the root being walked,
+// and whether paths are matched relative to it. Excluded folder names are pruned anywhere below the root. It owns
+// everything, so it can be handed to the walker.
+#[derive(Debug, Clone)]
+pub struct Prune {
+    globs: Option<Globs>,
+    dirs: Option<Globs>,
+    root: PathBuf,
+    relative: bool,
+}
+
+// A set of globs, matched a byte at a time by globset, or a character at a time with Unicode rules, as regexes are,
+// once they've been turned into regexes.
+#[derive(Debug, Clone)]
+enum Globs {
+    Bytes(GlobSet),
+    Unicode(RegexSet),
+}
+
+// Enum of names a path can be matched by. Current is the name on disk, original is the name it had before it was
+// hidden, and post-hide is the name it will have once it's hidden. They only differ on Unix, where hiding renames.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum MatchTarget {
+    #[default]
+    Current,
+    Original,
+    PostHide,
+}
+
+// Enum of parts of a path that patterns can be matched against. Path is the whole path, and name is just its final
+// component.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, ValueEnum)]
+pub enum MatchOn {
+    #[default]
+    Path,
+    Name,
+}
+
+// The result of a match, including the type of matcher that matched and the part of the path it was matched on. Lossy
+// holds the result of converting the path to a string if there was a lossy conversion. Globs can match on full paths,
+// but lossy will still hold a string if the path was not a valid UTF-8 string for printing purposes. Rule holds the
+// pattern that included the path, if one did, rather than it being included because there are no patterns.
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub struct MatchResult {
     pub result: bool,
     pub matcher_type: Option<MatcherType>,
+    pub match_on: MatchOn,
     pub lossy: Option<String>,
+    pub rule: Option<Rule>,
 }
 
+// A pattern that included a path, along with its kind, described with the rules file it came from, if any, such as
+// glob *.tmp from rules.toml. Patterns are checked in order, so it's the first one the path matches.
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct Rule {
+    pub matcher_type: MatcherType,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum MatcherType {
     Glob,
     Regex,
 }
 
+// Stand-in for a set of regexes in builds without the regex feature. One can never be built, so regex patterns are
+// rejected up front, and the matcher never has any to check.
+#[cfg(not(feature = "regex"))]
+#[derive(Debug, Clone)]
+enum RegexSet {}
+
+#[cfg(not(feature = "regex"))]
+impl RegexSet {
+    fn new(_regexes: Vec<String>) -> Result<Self> {
+        anyhow::bail!(
+            "Regex patterns aren't supported by this build of cloak. Rebuild it with the regex feature enabled."
+        )
+    }
+
+    fn is_match(&self, _path: &[u8]) -> bool {
+        match *self {}
+    }
+
+    fn matches(&self, _path: &[u8]) -> Vec<usize> {
+        match *self {}
+    }
+}
+
+impl Globs {
+    // Build a set of globs, matched with Unicode rules if asked to.
+    fn new(globs: Vec<Glob>, unicode: bool) -> Result<Self> {
+        Ok(if unicode {
+            Self::Unicode(RegexSet::new(
+                globs.iter().map(unicode_regex).collect::<Vec<_>>(),
+            )?)
+        } else {
+            let mut builder = globset::GlobSetBuilder::new();
+            for glob in globs {
+                builder.add(glob);
+            }
+            Self::Bytes(builder.build()?)
+        })
+    }
+
+    // Check if a path matches any of the globs.
+    fn is_match(&self, path: impl AsRef<Path>) -> bool {
+        match self {
+            Self::Bytes(globs) => globs.is_match(path),
+            Self::Unicode(globs) => globs.is_match(&candidate(path.as_ref())),
+        }
+    }
+
+    // Returns the index of the first glob a path matches, if any.
+    fn first_match(&self, path: &Path) -> Option<usize> {
+        match self {
+            Self::Bytes(globs) => globs.matches(path).first().copied(),
+            Self::Unicode(globs) => globs.matches(&candidate(path)).into_iter().next(),
+        }
+    }
+}
+
+// Helper function to turn a glob into a regex that matches the same paths a character at a time with Unicode rules,
+// rather than a byte at a time. Globset writes the regex with Unicode turned off, and each byte of a non-ASCII
+// character as its own \xNN escape, so those are turned back into the characters they encode. Dots match newlines, as
+// they do in globset.
+fn unicode_regex(glob: &Glob) -> String {
+    let regex = glob.regex();
+    let mut unicode = String::from("(?s)");
+    let mut bytes = Vec::new();
+    let mut rest = regex.strip_prefix("(?-u)").unwrap_or(regex);
+    while !rest.is_empty() {
+        // Collect the bytes of an escaped character until there are no more of them
+        if let Some(byte) = rest
+            .strip_prefix("\\x")
+            .and_then(|hex| hex.get(..2))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            bytes.push(byte);
+            rest = &rest[4..];
+            continue;
+        }
+        unicode.push_str(&String::from_utf8_lossy(&bytes));
+        bytes.clear();
+
+        // Keep anything else escaped as it is, so an escaped backslash isn't taken for the start of a byte.
+        let len = match rest.strip_prefix('\\') {
+            Some(escaped) => 1 + escaped.chars().next().map_or(0, char::len_utf8),
+            None => rest.chars().next().map_or(0, char::len_utf8),
+        };
+        unicode.push_str(&rest[..len]);
+        rest = &rest[len..];
+    }
+    unicode.push_str(&String::from_utf8_lossy(&bytes));
+    unicode
+}
+
+// Helper function to get the bytes of a path to match globs against with Unicode rules. Globset matches paths on
+// Windows with their separators as forward slashes, so they are here too.
+fn candidate(path: &Path) -> Cow<'_, [u8]> {
+    let bytes = path.as_os_str().as_encoded_bytes();
+    if cfg!(target_family = "windows") && bytes.contains(&b'\\') {
+        Cow::Owned(
+            bytes
+                .iter()
+                .map(|&b| if b == b'\\' { b'/' } else { b })
+                .collect(),
+        )
+    } else {
+        Cow::Borrowed(bytes)
+    }
+}
+
+// Helper function to parse globs, naming what they are in any errors, such as glob pattern.
+fn parse_globs<'g>(
+    globs: impl IntoIterator<Item = &'g str>,
+    ignore_case: bool,
+    literal_separator: bool,
+    what: &str,
+) -> Result<Vec<Glob>> {
+    globs
+        .into_iter()
+        .map(|glob| {
+            globset::GlobBuilder::new(glob)
+                .case_insensitive(ignore_case)
+                .literal_separator(literal_separator)
+                .build()
+                .with_context(|| format!("Failed to parse {what} {glob}"))
+        })
+        .collect()
+}
+
 impl Matcher {
-    // Build a new matcher.
+    // Build a new matcher, which matches paths by the name given by the target, against the part of them given by
+    // match_on. Excluded folders are glob patterns matched against each folder name along a path below its root as it
+    // is on disk, so they exclude the folders themselves and everything below them whatever the target and match_on
+    // are. If ignore_case is set, then every pattern ignores case. If unicode is set, then globs are matched with
+    // Unicode rules, the same as regexes.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         globs: Option<Vec<String>>,
         globs_exclude: Option<Vec<String>>,
         regexes: Option<Vec<String>>,
         regexes_exclude: Option<Vec<String>>,
+        exclude_dirs: Option<Vec<String>>,
+        target: MatchTarget,
+        match_on: MatchOn,
+        ignore_case: bool,
+        unicode: bool,
     ) -> Result<Self> {
+        // Regexes ignore case with an inline flag, so part of a pattern can still be made case-sensitive with (?-i).
+        let regex_case = |regexes: Option<Vec<String>>| {
+            regexes.map(|regexes| {
+                regexes
+                    .into_iter()
+                    .map(|regex| {
+                        if ignore_case {
+                            format!("(?i){regex}")
+                        } else {
+                            regex
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            })
+        };
+        let patterns = globs.clone().unwrap_or_default();
+        let regex_patterns = regexes.clone().unwrap_or_default();
+        let regexes = regex_case(regexes);
+        let regexes_exclude = regex_case(regexes_exclude);
+
+        // An exclude pattern ending in /** excludes everything below a folder matching the rest of it, so those folders
+        // can be pruned from the walk. Names have no folders in them, so this only applies when matching whole paths.
+        let prune = match (&globs_exclude, match_on) {
+            (Some(globs_exclude), MatchOn::Path) => {
+                let prune = parse_globs(
+                    globs_exclude
+                        .iter()
+                        .filter_map(|glob| glob.strip_suffix("/**")),
+                    ignore_case,
+                    false,
+                    "glob exclude pattern",
+                )?;
+                (!prune.is_empty())
+                    .then(|| Globs::new(prune, unicode))
+                    .transpose()
+                    .with_context(|| "Failed to build glob prune matcher")?
+            }
+            _ => None,
+        };
+
         Ok(Self {
             globs: match globs {
-                Some(globs) => {
-                    let mut builder = globset::GlobSetBuilder::new();
-                    for glob in globs {
-                        builder.add(
-                            globset::Glob::new(&glob)
-                                .with_context(|| format!("Failed to parse glob pattern {glob}"))?,
-                        );
-                    }
-                    Some(
-                        builder
-                            .build()
-                            .with_context(|| "Failed to build glob matcher")?,
+                Some(globs) => Some(
+                    Globs::new(
+                        parse_globs(
+                            globs.iter().map(String::as_str),
+                            ignore_case,
+                            false,
+                            "glob pattern",
+                        )?,
+                        unicode,
                     )
-                }
+                    .with_context(|| "Failed to build glob matcher")?,
+                ),
                 None => None,
             },
             globs_exclude: match globs_exclude {
-                Some(globs_exclude) => {
-                    let mut builder = globset::GlobSetBuilder::new();
-                    for glob in globs_exclude {
-                        builder.add(globset::Glob::new(&glob).with_context(|| {
-                            format!("Failed to parse glob exclude pattern {glob}")
-                        })?);
-                    }
-                    Some(
-                        builder
-                            .build()
-                            .with_context(|| "Failed to build glob exclude matcher")?,
+                Some(globs_exclude) => Some(
+                    Globs::new(
+                        parse_globs(
+                            globs_exclude.iter().map(String::as_str),
+                            ignore_case,
+                            false,
+                            "glob exclude pattern",
+                        )?,
+                        unicode,
                     )
-                }
+                    .with_context(|| "Failed to build glob exclude matcher")?,
+                ),
                 None => None,
             },
             regexes: match regexes {
@@ -82,17 +323,122 @@ impl Matcher {
                 ),
                 None => None,
             },
+            exclude_dirs: match exclude_dirs {
+                Some(exclude_dirs) => Some(
+                    Globs::new(
+                        parse_globs(
+                            exclude_dirs.iter().map(String::as_str),
+                            ignore_case,
+                            true,
+                            "excluded folder",
+                        )?,
+                        unicode,
+                    )
+                    .with_context(|| "Failed to build excluded folder matcher")?,
+                ),
+                None => None,
+            },
+            prune,
+            target,
+            match_on,
+            patterns,
+            regex_patterns,
+            source: None,
+            include_all: false,
+        })
+    }
+
+    // Returns the matcher, noting that its patterns came from the given rules file rather than the command line.
+    pub fn sourced_from(self, file: &Path) -> Self {
+        Self {
+            source: Some(file.to_path_buf()),
+            ..self
+        }
+    }
+
+    // Returns a matcher for folders hidden in place of their entries, which matches anything the matcher doesn't
+    // exclude, since the folders are only hidden because their entries matched.
+    pub fn for_parents(&self) -> Self {
+        Self {
+            include_all: true,
+            ..self.clone()
+        }
+    }
+
+    // Helper function to describe a pattern that matched a path, with its kind and the rules file it came from, if any.
+    fn rule(&self, matcher_type: MatcherType, pattern: &str) -> Rule {
+        let kind = match matcher_type {
+            MatcherType::Glob => "glob",
+            MatcherType::Regex => "regex",
+        };
+        Rule {
+            matcher_type,
+            name: match &self.source {
+                Some(source) => format!("{kind} {pattern} from {}", source.display()),
+                None => format!("{kind} {pattern}"),
+            },
+        }
+    }
+
+    // Returns the folders to prune from a walk of a root, if any exclude patterns exclude everything below them,
+    // matched relative to the root if relative is set.
+    pub fn prune(&self, root: &Path, relative: bool) -> Option<Prune> {
+        if self.prune.is_none() && self.exclude_dirs.is_none() {
+            return None;
+        }
+        Some(Prune {
+            globs: self.prune.clone(),
+            dirs: self.exclude_dirs.clone(),
+            root: root.to_path_buf(),
+            relative,
         })
     }
 
-    // Check if a path matches the matcher. If there are no patterns, then the path matches.
+    // Check if a path found under a root, if known, matches the matcher, relative to the root if relative is set. It
+    // doesn't match if it's inside an excluded folder, going by the names on disk of the folders below the root, or is
+    // one itself, if it's a folder. The folders the root is in are never checked, so nothing is excluded just for where
+    // it's being searched.
+    pub fn matches_entry(
+        &self,
+        path: &Path,
+        root: Option<&Path>,
+        relative: bool,
+        folder: bool,
+    ) -> MatchResult {
+        if let Some(exclude_dirs) = self.exclude_dirs.as_ref() {
+            let mut names = filter::relative_to(path, root).iter().peekable();
+            while let Some(name) = names.next() {
+                if (folder || names.peek().is_some()) && exclude_dirs.is_match(Path::new(name)) {
+                    return MatchResult {
+                        result: false,
+                        matcher_type: Some(MatcherType::Glob),
+                        match_on: self.match_on,
+                        lossy: path
+                            .to_str()
+                            .is_none()
+                            .then(|| path.to_string_lossy().into()),
+                        rule: None,
+                    };
+                }
+            }
+        }
+        self.matches(filter::relative_to(path, root.filter(|_| relative)))
+    }
+
+    // Check if a path matches the patterns. If there are no patterns, then the path matches. Excluded folders are only
+    // checked by matches_entry, which knows where the path was found.
     pub fn matches(&self, path: &Path) -> MatchResult {
-        // Regex patterns need strings, so convert the path to a string. If there is a lossy conversion, then store the
-        // lossy string, and set the lossy flag to true.
+        let path = self.name(path);
+        let path = path.as_ref();
+
+        // Regex patterns are matched against the raw bytes of the path, which are WTF-8 on Windows, so paths that
+        // aren't valid UTF-8 still match exactly. The path is only converted to a string for printing, so if there is a
+        // lossy conversion, then store the lossy string, and set the lossy flag to true.
+        let path_bytes = path.as_os_str().as_encoded_bytes();
         let (path_str, lossy) = path
             .to_str()
             .map_or_else(|| (path.to_string_lossy(), true), |s| (s.into(), false));
-        
+
         // Short-circuit if there are no patterns
         if self.globs.is_none()
             && self.globs_exclude.is_none()
@@ -102,59 +448,106 @@ impl Matcher {
             return MatchResult {
                 result: true,
                 matcher_type: None,
+                match_on: self.match_on,
                 lossy: if lossy { Some(path_str.into()) } else { None },
+                rule: None,
             };
         }
-        
+
         // Check if the path matches any of the glob exclude patterns
         if let Some(globs_exclude) = self.globs_exclude.as_ref() {
             if globs_exclude.is_match(path) {
                 return MatchResult {
                     result: false,
                     matcher_type: Some(MatcherType::Glob),
+                    match_on: self.match_on,
                     lossy: if lossy { Some(path_str.into()) } else { None },
+                    rule: None,
                 };
             }
         }
 
         // Check if the path matches any of the regex exclude patterns
         if let Some(regexes_exclude) = self.regexes_exclude.as_ref() {
-            if regexes_exclude.is_match(&path_str) {
+            if regexes_exclude.is_match(path_bytes) {
                 return MatchResult {
                     result: false,
                     matcher_type: Some(MatcherType::Regex),
+                    match_on: self.match_on,
                     lossy: if lossy { Some(path_str.into()) } else { None },
+                    rule: None,
                 };
             }
         }
 
+        // Anything that isn't excluded matches if every path is to be included
+        if self.include_all {
+            return MatchResult {
+                result: true,
+                matcher_type: None,
+                match_on: self.match_on,
+                lossy: if lossy { Some(path_str.into()) } else { None },
+                rule: None,
+            };
+        }
+
         // Check if the path matches any of the glob patterns
-        if let Some(globs) = self.globs.as_ref() {
-            if globs.is_match(path) {
-                return MatchResult {
-                    result: true,
-                    matcher_type: Some(MatcherType::Glob),
-                    lossy: if lossy { Some(path_str.into()) } else { None },
-                };
-            }
+        if let Some(index) = self
+            .globs
+            .as_ref()
+            .and_then(|globs| globs.first_match(path))
+        {
+            return MatchResult {
+                result: true,
+                matcher_type: Some(MatcherType::Glob),
+                match_on: self.match_on,
+                lossy: if lossy { Some(path_str.into()) } else { None },
+                rule: Some(self.rule(MatcherType::Glob, &self.patterns[index])),
+            };
         }
 
         // Check if the path matches any of the regex patterns
-        if let Some(regexes) = self.regexes.as_ref() {
-            if regexes.is_match(&path_str) {
-                return MatchResult {
-                    result: true,
-                    matcher_type: Some(MatcherType::Regex),
-                    lossy: if lossy { Some(path_str.into()) } else { None },
-                };
-            }
+        if let Some(index) = self
+            .regexes
+            .as_ref()
+            .and_then(|regexes| regexes.matches(path_bytes).into_iter().next())
+        {
+            return MatchResult {
+                result: true,
+                matcher_type: Some(MatcherType::Regex),
+                match_on: self.match_on,
+                lossy: if lossy { Some(path_str.into()) } else { None },
+                rule: Some(self.rule(MatcherType::Regex, &self.regex_patterns[index])),
+            };
         }
 
         // If the path didn't match any of the patterns, then it doesn't match
         MatchResult {
             result: false,
             matcher_type: None,
+            match_on: self.match_on,
             lossy: if lossy { Some(path_str.into()) } else { None },
+            rule: None,
+        }
+    }
+
+    // Helper function to get the name of a path to match by, which is only its final component if asked to. Paths
+    // without one, like the root, are matched whole.
+    fn name<'p>(&self, path: &'p Path) -> Cow<'p, Path> {
+        let path: Cow<Path> = match self.target {
+            MatchTarget::Current => Cow::Borrowed(path),
+            MatchTarget::Original => Cow::Owned(filesystem::unhidden_path(path)),
+            MatchTarget::PostHide => Cow::Owned(filesystem::hidden_path(path)),
+        };
+        match (self.match_on, path) {
+            (MatchOn::Path, path) => path,
+            (MatchOn::Name, Cow::Borrowed(path)) => {
+                Cow::Borrowed(path.file_name().map_or(path, Path::new))
+            }
+            (MatchOn::Name, Cow::Owned(path)) => match path.file_name() {
+                Some(name) => Cow::Owned(PathBuf::from(name)),
+                None => Cow::Owned(path),
+            },
         }
     }
 }
@@ -175,3 +568,25 @@ impl std::fmt::Display for MatcherType {
         }
     }
 }
+
+impl Prune {
+    // Check if everything below a folder is excluded, so it doesn't need to be walked into. The root is never pruned
+    // for its name, since only the folders below it count.
+    pub fn prunes(&self, dir: &Path) -> bool {
+        let root = Some(self.root.as_path()).filter(|_| self.relative);
+        self.globs
+            .as_ref()
+            .is_some_and(|globs| globs.is_match(filter::relative_to(dir, root)))
+            || self.dirs.as_ref().is_some_and(|dirs| {
+                dir != self.root
+                    && dir
+                        .file_name()
+                        .is_some_and(|name| dirs.is_match(Path::new(name)))
+            })
+    }
+}
+
+// Turn an extension into the glob pattern it's a shortcut for, with or without its leading dot.
+pub fn ext_pattern(ext: &str) -> String {
+    format!("*.{}", globset::escape(ext.trim_start_matches('.')))
+}
@@ -0,0 +1,192 @@
+use crate::filesystem;
+use anyhow::{bail, Result};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Mount points are checked before they're walked into, so one dead network mount can't hang a whole run. Any that
+// don't respond within the timeout are skipped and remembered, so they can be retried later.
+#[derive(Debug)]
+pub struct Mounts {
+    timeout: Duration,
+    names: Option<HashSet<OsString>>,
+    unresponsive: Mutex<HashSet<PathBuf>>,
+}
+
+impl Mounts {
+    // Build a new set of mount checks with the given timeout. On Linux, the names of every mount point are read up
+    // front, so only directories with one of those names need checking.
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            names: mount_point_names(),
+            unresponsive: Mutex::new(HashSet::new()),
+        }
+    }
+
+    // Check that a directory can be walked into, which is the case unless it's a mount point that doesn't respond
+    // within the timeout. Roots are always checked, since they're often mount points themselves. Unresponsive mount
+    // points are remembered, so they can be retried later.
+    pub fn check(&self, dir: &Path, root: bool) -> Result<()> {
+        let candidate = root
+            || self
+                .names
+                .as_ref()
+                .is_none_or(|names| dir.file_name().is_some_and(|name| names.contains(name)));
+        if !candidate {
+            return Ok(());
+        }
+
+        // Only a timeout counts. Any other error is left for the walk to report.
+        let result = filesystem::within(dir, Some(self.timeout), move |dir| {
+            if root || crosses_device(dir) {
+                let _ = fs::read_dir(dir).map(|mut entries| entries.next());
+            }
+            Ok(())
+        });
+        if result.is_err() {
+            self.unresponsive
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(dir.to_path_buf());
+            bail!(
+                "Skipped mount point {} and everything below it, because it didn't respond within {:?}",
+                dir.display(),
+                self.timeout
+            );
+        }
+        Ok(())
+    }
+
+    // Check the unresponsive mount points again, and forget any that respond now. Returns true if any did, so
+    // everything below them can be searched. Only the watcher retries them, so builds without it never do.
+    #[cfg_attr(not(feature = "watch"), allow(dead_code))]
+    pub fn recovered(&self) -> bool {
+        let unresponsive: Vec<PathBuf> = self
+            .unresponsive
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .drain()
+            .collect();
+        let mut recovered = false;
+        for dir in unresponsive {
+            recovered |= self.check(&dir, true).is_ok();
+        }
+        recovered
+    }
+}
+
+// Linux only function to get the mount point a path is on, without touching the path itself, which is the deepest one
+// in the mount table that it's inside. The table is only read once, so anything mounted since counts as part of
+// whatever it was mounted on.
+#[cfg(target_os = "linux")]
+pub fn mount_of(path: &Path) -> PathBuf {
+    use std::sync::OnceLock;
+
+    static MOUNT_POINTS: OnceLock<Vec<PathBuf>> = OnceLock::new();
+
+    let path = filesystem::absolute(path);
+    MOUNT_POINTS
+        .get_or_init(|| mount_points().unwrap_or_default())
+        .iter()
+        .filter(|mount_point| path.starts_with(mount_point))
+        .max_by_key(|mount_point| mount_point.components().count())
+        .cloned()
+        .unwrap_or_else(|| PathBuf::from("/"))
+}
+
+// Function to get the mount point a path is on, on Unix systems other than Linux, where there's no mount table to find
+// it in without touching the path. The folder the path is in stands in for it instead.
+#[cfg(all(target_family = "unix", not(target_os = "linux")))]
+pub fn mount_of(path: &Path) -> PathBuf {
+    let path = filesystem::absolute(path);
+    path.parent().unwrap_or(&path).to_path_buf()
+}
+
+// Windows only function to get the mount point a path is on, which is the drive, volume or share at the start of it.
+#[cfg(target_family = "windows")]
+pub fn mount_of(path: &Path) -> PathBuf {
+    filesystem::absolute(path)
+        .ancestors()
+        .last()
+        .map(Path::to_path_buf)
+        .unwrap_or_default()
+}
+
+// Linux only function to get every mount point, from the mount table of this process. Paths in the table have spaces,
+// tabs, newlines and backslashes escaped as octal.
+#[cfg(target_os = "linux")]
+fn mount_points() -> Option<Vec<PathBuf>> {
+    use std::os::unix::ffi::OsStringExt;
+
+    let table = fs::read("/proc/self/mountinfo").ok()?;
+    let mount_points = table
+        .split(|&byte| byte == b'\n')
+        .filter_map(|line| line.split(|&byte| byte == b' ').nth(4))
+        .filter_map(|mount_point| {
+            let mut path = Vec::new();
+            let mut bytes = mount_point.iter();
+            while let Some(&byte) = bytes.next() {
+                if byte == b'\\' {
+                    let digits: Vec<u8> = bytes.by_ref().take(3).copied().collect();
+                    let code = std::str::from_utf8(&digits).ok()?;
+                    path.push(u8::from_str_radix(code, 8).ok()?);
+                } else {
+                    path.push(byte);
+                }
+            }
+            Some(PathBuf::from(OsString::from_vec(path)))
+        })
+        .collect();
+    Some(mount_points)
+}
+
+// Linux only function to get the names of every mount point, from the mount table of this process.
+#[cfg(target_os = "linux")]
+fn mount_point_names() -> Option<HashSet<OsString>> {
+    let names = mount_points()?
+        .iter()
+        .filter_map(|mount_point| mount_point.file_name())
+        .map(ToOwned::to_owned)
+        .collect();
+    Some(names)
+}
+
+// Function to get the names of every mount point, on Unix systems other than Linux, where there's no mount table to
+// read them from. Every directory is checked instead.
+#[cfg(all(target_family = "unix", not(target_os = "linux")))]
+fn mount_point_names() -> Option<HashSet<OsString>> {
+    None
+}
+
+// Windows only function to get the names of every mount point. Only roots are checked on Windows, so there are none.
+#[cfg(target_family = "windows")]
+fn mount_point_names() -> Option<HashSet<OsString>> {
+    Some(HashSet::new())
+}
+
+// Unix only function to check if a directory is on a different device than its parent, which makes it a mount point.
+// Anything that can't be checked isn't treated as one.
+#[cfg(target_family = "unix")]
+fn crosses_device(dir: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    let parent = match dir.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    match (fs::symlink_metadata(dir), fs::symlink_metadata(parent)) {
+        (Ok(dir), Ok(parent)) => dir.dev() != parent.dev(),
+        _ => false,
+    }
+}
+
+// Windows only function to check if a directory is a mount point. Only roots are checked on Windows, so this is never
+// reached for anything else.
+#[cfg(target_family = "windows")]
+fn crosses_device(_dir: &Path) -> bool {
+    false
+}
@@ -0,0 +1,778 @@
+use crate::report::Action;
+use crate::summary::{Phase, Summary};
+use crate::systemlog::{Level, SystemLog};
+use clap::ValueEnum;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde_json::json;
+use std::fmt::Display;
+use std::io::{BufWriter, IsTerminal, Stdout, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::Duration;
+
+// How often output to a pipe or a file is flushed, so whatever is reading it still sees lines soon after they happen.
+const FLUSH_INTERVAL: Duration = Duration::from_millis(250);
+
+// How wide the column of what's being done to a path is in human output, so the paths after it line up.
+const LABEL_WIDTH: usize = 12;
+
+// Enum of the ways everything that happens during a run can be written out
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Human,
+    Json,
+    Porcelain,
+    Quiet,
+    Log,
+    Tui,
+}
+
+// Enum of when human output is colored
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+// Whether lines written to stdout and to stderr are colored. Auto only colors a stream that's a terminal, and never
+// when the NO_COLOR environment variable is set, so piped output is left plain.
+#[derive(Clone, Copy, Debug)]
+struct Colors {
+    stdout: bool,
+    stderr: bool,
+}
+
+impl Colors {
+    fn new(choice: ColorChoice) -> Self {
+        let auto = || std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty());
+        match choice {
+            ColorChoice::Auto => Self {
+                stdout: auto() && std::io::stdout().is_terminal(),
+                stderr: auto() && std::io::stderr().is_terminal(),
+            },
+            ColorChoice::Always => Self {
+                stdout: true,
+                stderr: true,
+            },
+            ColorChoice::Never => Self {
+                stdout: false,
+                stderr: false,
+            },
+        }
+    }
+}
+
+// Enum of what a line of human output is about, which decides its color
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Style {
+    Plain,
+    Action,
+    Skip,
+    Error,
+}
+
+impl Style {
+    // Color a line with ANSI escapes, if it's colored at all.
+    fn paint(self, line: String, colored: bool) -> String {
+        let code = match self {
+            _ if !colored => return line,
+            Style::Plain => return line,
+            Style::Action => 32,
+            Style::Skip => 33,
+            Style::Error => 31,
+        };
+        format!("\x1b[{code}m{line}\x1b[0m")
+    }
+}
+
+// Everything that happens during a run that's worth telling the user about. Searching, watching and the pipeline they
+// share only ever report these, and the reporter decides how, or whether, to write each one out. Events that are only
+// of interest when verbose are only reported when verbose is set, so they aren't built for nothing.
+pub enum Event<'a> {
+    // A path is about to be searched. Only reported when verbose.
+    Searching(&'a Path),
+    // A path was left alone, and why. Only reported when verbose.
+    Skipped {
+        path: &'a Path,
+        because: String,
+    },
+    // Anything else worth knowing about how the run is going. Only reported when verbose.
+    Info(String),
+    // Something that didn't stop anything from being handled, but should be seen anyway.
+    Notice(String),
+    // An action was, or would be, taken on a path, along with any alternate data streams it has.
+    Action {
+        action: Action,
+        path: &'a Path,
+        streams: &'a [String],
+    },
+    // A batch of paths in a directory was, or would be, changed.
+    Batch {
+        action: Action,
+        changed: usize,
+        total: usize,
+        dir: &'a Path,
+    },
+    // A phase of a search finished, with how many entries it ended up with. Only reported when verbose.
+    Phase {
+        phase: Phase,
+        count: usize,
+        elapsed: Duration,
+    },
+    // An error that stopped a path from being handled.
+    Error(&'a dyn Display),
+    // A path that has been put on cooldown after failing over and over, and how many times it failed.
+    Problem {
+        path: &'a Path,
+        failures: usize,
+    },
+    // The status of one of several watcher shards. Only the watcher has them, so builds without it never do.
+    #[cfg_attr(not(feature = "watch"), allow(dead_code))]
+    Shard {
+        index: usize,
+        running: bool,
+        roots: usize,
+        restarts: usize,
+    },
+    // An event exactly as it came from a watcher, before anything is done with it, asked for with --print-events. Only
+    // the watcher has them, so builds without it never do.
+    #[cfg_attr(not(feature = "watch"), allow(dead_code))]
+    Raw {
+        shard: usize,
+        kind: String,
+        paths: &'a [PathBuf],
+        flags: Vec<String>,
+    },
+    // The counts so far, asked for with --stats.
+    Stats(&'a Summary),
+    // Every error that occurred during the run, listed together once it's finished.
+    Errors(&'a Summary),
+    // The run has finished, along with what happened during it.
+    Finished {
+        summary: &'a Summary,
+        watched: bool,
+    },
+}
+
+// Writes out the events reported during a run. It's shared across the rayon thread pool, and with jwalk's threads.
+pub trait Reporter: Send + Sync + std::fmt::Debug {
+    fn report(&self, event: &Event);
+}
+
+// Build the reporter for a format, writing out at most limit paths if one is given, and coloring human output as asked.
+// The TUI is only drawn when stderr is a terminal, and is written out the same as human output otherwise.
+pub fn reporter(
+    format: OutputFormat,
+    verbose: bool,
+    limit: Option<usize>,
+    color: ColorChoice,
+) -> Arc<dyn Reporter> {
+    let out = Sink::new(limit);
+    let colors = Colors::new(color);
+    match format {
+        OutputFormat::Human => Arc::new(Human {
+            verbose,
+            out,
+            colors,
+        }),
+        OutputFormat::Json => Arc::new(Json { out }),
+        OutputFormat::Porcelain => Arc::new(Porcelain { out }),
+        OutputFormat::Quiet => Arc::new(Quiet { out }),
+        OutputFormat::Log => Arc::new(Log {
+            verbose,
+            log: SystemLog::open(),
+        }),
+        OutputFormat::Tui if std::io::stderr().is_terminal() => {
+            Arc::new(Tui::new(verbose, out, colors))
+        }
+        OutputFormat::Tui => Arc::new(Human {
+            verbose,
+            out,
+            colors,
+        }),
+    }
+}
+
+// Where reporters write their lines to stdout. A terminal is written to a line at a time, so each is seen as it
+// happens, but a pipe or a file is written to in chunks, and flushed every so often by a thread of its own, so printing
+// millions of lines isn't held up by a write for each one. Lines listing paths stop being written out once the limit
+// asked for with --output-limit is reached, and are only counted from then on. Anything still waiting to be written is
+// flushed when it's dropped, and before anything else writes to stdout, such as stats. A closed pipe is ignored, rather
+// than stopping the run.
+#[derive(Debug)]
+struct Sink {
+    writer: Arc<Mutex<BufWriter<Stdout>>>,
+    terminal: bool,
+    limit: Option<usize>,
+    listed: AtomicUsize,
+}
+
+impl Sink {
+    fn new(limit: Option<usize>) -> Self {
+        let writer = Arc::new(Mutex::new(BufWriter::new(std::io::stdout())));
+        let terminal = std::io::stdout().is_terminal();
+        if !terminal {
+            let weak = Arc::downgrade(&writer);
+            // If the thread can't be started, then output is still flushed once it's dropped.
+            let _ = std::thread::Builder::new()
+                .name("cloak-flush".to_owned())
+                .spawn(move || flush_every(weak));
+        }
+        Self {
+            writer,
+            terminal,
+            limit,
+            listed: AtomicUsize::new(0),
+        }
+    }
+
+    // Returns true if another path can be listed, or false if the limit has been reached, counting it either way.
+    fn admit(&self) -> bool {
+        let listed = self.listed.fetch_add(1, Ordering::Relaxed);
+        self.limit.is_none_or(|limit| listed < limit)
+    }
+
+    // Returns how many paths weren't listed because of the limit.
+    fn withheld(&self) -> usize {
+        self.limit.map_or(0, |limit| {
+            self.listed.load(Ordering::Relaxed).saturating_sub(limit)
+        })
+    }
+
+    // Write out a line listing a path, unless the limit has been reached.
+    fn list(&self, line: impl Display) {
+        if self.admit() {
+            self.line(line);
+        }
+    }
+
+    // Write out a line, whatever the limit.
+    fn line(&self, line: impl Display) {
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = writeln!(writer, "{line}");
+        if self.terminal {
+            let _ = writer.flush();
+        }
+    }
+
+    // Write out anything still waiting to be written.
+    fn flush(&self) {
+        let _ = self
+            .writer
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .flush();
+    }
+}
+
+impl Drop for Sink {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+// Helper function to flush a sink's writer every so often, until the sink is dropped.
+fn flush_every(writer: Weak<Mutex<BufWriter<Stdout>>>) {
+    loop {
+        std::thread::sleep(FLUSH_INTERVAL);
+        let Some(writer) = writer.upgrade() else {
+            return;
+        };
+        let _ = writer.lock().unwrap_or_else(|e| e.into_inner()).flush();
+    }
+}
+
+// Helper function to check whether an event lists a path, and so counts towards --output-limit.
+fn lists_path(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::Searching(_)
+            | Event::Skipped { .. }
+            | Event::Action { .. }
+            | Event::Batch { .. }
+            | Event::Raw { .. }
+    )
+}
+
+// Helper function to write out how many paths weren't listed because of --output-limit.
+fn truncated(more: usize) -> String {
+    format!("\u{2026} (+{more} more)")
+}
+
+// Sentences for a person to read, with what's being done to each path lined up in a column before it, and paths
+// under the current directory shown relative to it. Actions are colored green, skips yellow and errors red, when
+// colored. Paths are only listed as they're hidden or unhidden when verbose, or when they have alternate data streams,
+// but always in test mode.
+#[derive(Debug)]
+pub struct Human {
+    verbose: bool,
+    out: Sink,
+    colors: Colors,
+}
+
+impl Reporter for Human {
+    fn report(&self, event: &Event) {
+        match event {
+            Event::Stats(summary) => {
+                self.out.flush();
+                summary.print_stats();
+            }
+            Event::Errors(summary) => {
+                self.out.flush();
+                summary.print_errors();
+            }
+            Event::Finished { summary, watched } => finished(&self.out, summary, *watched),
+            _ => match human(event, self.verbose) {
+                Some(_) if lists_path(event) && !self.out.admit() => {}
+                Some((line, true, style)) => eprintln!("{}", style.paint(line, self.colors.stderr)),
+                Some((line, false, style)) => self.out.line(style.paint(line, self.colors.stdout)),
+                None => {}
+            },
+        }
+    }
+}
+
+// A JSON object for every event, one per line, for scripts to read. Everything is written to stdout, errors included,
+// so it's all in one stream. Once paths stop being listed because of --output-limit, how many more there were is
+// written out as a truncated event just before it finishes.
+#[derive(Debug)]
+pub struct Json {
+    out: Sink,
+}
+
+impl Reporter for Json {
+    fn report(&self, event: &Event) {
+        let value = match event {
+            Event::Searching(path) => {
+                json!({ "event": "searching", "path": path.to_string_lossy() })
+            }
+            Event::Skipped { path, because } => {
+                json!({ "event": "skipped", "path": path.to_string_lossy(), "because": because })
+            }
+            Event::Info(message) => json!({ "event": "info", "message": message }),
+            Event::Notice(message) => json!({ "event": "notice", "message": message }),
+            Event::Action {
+                action,
+                path,
+                streams,
+            } => json!({
+                "event": "action",
+                "action": action,
+                "path": path.to_string_lossy(),
+                "streams": streams,
+            }),
+            Event::Batch {
+                action,
+                changed,
+                total,
+                dir,
+            } => json!({
+                "event": "batch",
+                "action": action,
+                "changed": changed,
+                "total": total,
+                "dir": dir.to_string_lossy(),
+            }),
+            Event::Phase {
+                phase,
+                count,
+                elapsed,
+            } => json!({
+                "event": "phase",
+                "phase": phase.name().to_lowercase(),
+                "count": count,
+                "elapsed_ms": elapsed.as_secs_f64() * 1000.0,
+            }),
+            Event::Error(error) => json!({ "event": "error", "message": format!("{error:#}") }),
+            Event::Problem { path, failures } => {
+                json!({ "event": "problem", "path": path.to_string_lossy(), "failures": failures })
+            }
+            Event::Shard {
+                index,
+                running,
+                roots,
+                restarts,
+            } => json!({
+                "event": "shard",
+                "index": index,
+                "running": running,
+                "roots": roots,
+                "restarts": restarts,
+            }),
+            Event::Raw {
+                shard,
+                kind,
+                paths,
+                flags,
+            } => json!({
+                "event": "raw",
+                "shard": shard,
+                "kind": kind,
+                "paths": paths.iter().map(|path| path.to_string_lossy()).collect::<Vec<_>>(),
+                "flags": flags,
+            }),
+            Event::Stats(summary) => {
+                let mut value = json!({ "event": "stats" });
+                for (name, count) in summary.counts() {
+                    value[name.to_lowercase().replace(' ', "_")] = json!(count);
+                }
+                value["hidden_by_rule"] = summary
+                    .hidden_by_rule()
+                    .into_iter()
+                    .map(|(rule, count)| json!({ "rule": rule, "count": count }))
+                    .collect();
+                value["hidden_by_root"] = summary
+                    .hidden_by_root()
+                    .into_iter()
+                    .map(|(root, count)| json!({ "root": root, "count": count }))
+                    .collect();
+                value["latency"] = json!(summary.latencies());
+                value
+            }
+            Event::Errors(summary) => json!({
+                "event": "errors",
+                "count": summary.errors(),
+                "messages": summary.error_messages(),
+            }),
+            Event::Finished { summary, .. } => {
+                let more = self.out.withheld();
+                if more > 0 {
+                    self.out.line(json!({ "event": "truncated", "more": more }));
+                }
+                json!({
+                    "event": "finished",
+                    "no_changes_needed": summary.no_changes_needed(),
+                })
+            }
+        };
+        if lists_path(event) {
+            self.out.list(value);
+        } else {
+            self.out.line(value);
+        }
+    }
+}
+
+// A line for every action taken, with the action and the path separated by a tab, in a format that won't change
+// between versions. Errors are written to stderr the same way, as is how many actions weren't listed because of
+// --output-limit. Nothing else is written out.
+#[derive(Debug)]
+pub struct Porcelain {
+    out: Sink,
+}
+
+impl Reporter for Porcelain {
+    fn report(&self, event: &Event) {
+        match event {
+            Event::Action { action, path, .. } => {
+                self.out
+                    .list(format_args!("{}\t{}", action_name(*action), path.display()))
+            }
+            Event::Error(error) => eprintln!("error\t{error}"),
+            Event::Raw { .. } if self.out.admit() => {
+                if let Some((line, ..)) = human(event, false) {
+                    eprintln!("{line}");
+                }
+            }
+            Event::Finished { .. } => {
+                self.out.flush();
+                let more = self.out.withheld();
+                if more > 0 {
+                    eprintln!("{}", truncated(more));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Nothing but errors, and the raw events asked for with --print-events, which are written to stderr.
+#[derive(Debug)]
+pub struct Quiet {
+    out: Sink,
+}
+
+impl Reporter for Quiet {
+    fn report(&self, event: &Event) {
+        match event {
+            Event::Error(error) => eprintln!("{error}"),
+            Event::Raw { .. } if self.out.admit() => {
+                if let Some((line, ..)) = human(event, false) {
+                    eprintln!("{line}");
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Human output written to the system log instead, a line for each event, at the level that fits it: errors and problem
+// paths as errors, notices as notices, and everything else as info. There's nothing to list everything else after,
+// so the summary is logged whenever stats are asked for, and once the run finishes.
+#[derive(Debug)]
+pub struct Log {
+    verbose: bool,
+    log: SystemLog,
+}
+
+impl Reporter for Log {
+    fn report(&self, event: &Event) {
+        match event {
+            Event::Stats(summary) | Event::Finished { summary, .. } => self
+                .log
+                .write(Level::Info, format_args!("Summary: {summary}")),
+            Event::Errors(_) => {}
+            _ => match human(event, self.verbose) {
+                Some((line, _, Style::Error)) => self.log.write(Level::Error, line),
+                Some((line, ..)) if matches!(event, Event::Notice(_)) => {
+                    self.log.write(Level::Notice, line)
+                }
+                Some((line, ..)) => self.log.write(Level::Info, line),
+                None => {}
+            },
+        }
+    }
+}
+
+// Human output, scrolling above a live status line of how many paths have been changed, and how many errors there
+// have been. It's drawn on stderr, and cleared once the run finishes.
+#[derive(Debug)]
+pub struct Tui {
+    verbose: bool,
+    bar: ProgressBar,
+    changed: AtomicUsize,
+    errors: AtomicUsize,
+    out: Sink,
+    colors: Colors,
+}
+
+impl Tui {
+    fn new(verbose: bool, out: Sink, colors: Colors) -> Self {
+        let bar = ProgressBar::new_spinner().with_style(
+            ProgressStyle::with_template("{spinner} [{elapsed}] {msg}")
+                .unwrap_or_else(|_| ProgressStyle::default_spinner()),
+        );
+        bar.enable_steady_tick(Duration::from_millis(100));
+        Self {
+            verbose,
+            bar,
+            changed: AtomicUsize::new(0),
+            errors: AtomicUsize::new(0),
+            out,
+            colors,
+        }
+    }
+}
+
+impl Reporter for Tui {
+    fn report(&self, event: &Event) {
+        match event {
+            Event::Action {
+                action: Action::WouldHide | Action::Hidden | Action::WouldUnhide | Action::Unhidden,
+                ..
+            } => {
+                self.changed.fetch_add(1, Ordering::Relaxed);
+            }
+            Event::Error(_) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+        self.bar.set_message(format!(
+            "{} changed, {} errors",
+            self.changed.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed)
+        ));
+
+        match event {
+            Event::Stats(summary) => self.bar.suspend(|| summary.print_stats()),
+            Event::Errors(summary) => self.bar.suspend(|| summary.print_errors()),
+            Event::Finished { summary, watched } => {
+                self.bar.finish_and_clear();
+                finished(&self.out, summary, *watched);
+            }
+            _ => match human(event, self.verbose) {
+                Some(_) if lists_path(event) && !self.out.admit() => {}
+                Some((line, _, style)) => self.bar.println(style.paint(line, self.colors.stderr)),
+                None => {}
+            },
+        }
+    }
+}
+
+// Helper function to write out an event as a sentence, along with whether it belongs on stderr, and what it's about.
+// Returns None if it shouldn't be written out at all.
+fn human(event: &Event, verbose: bool) -> Option<(String, bool, Style)> {
+    let (line, style) = match event {
+        Event::Searching(path) => (
+            format!(
+                "Searching for files and folders to hide in {}...",
+                shown(path).display()
+            ),
+            Style::Plain,
+        ),
+        Event::Skipped { path, because } => (
+            format!(
+                "{:<LABEL_WIDTH$} {} because {because}",
+                "Skipping",
+                shown(path).display()
+            ),
+            Style::Skip,
+        ),
+        Event::Info(message) => (message.clone(), Style::Plain),
+        Event::Notice(message) => return Some((message.clone(), true, Style::Plain)),
+        Event::Action {
+            action,
+            path,
+            streams,
+        } => {
+            let streams = if streams.is_empty() {
+                String::new()
+            } else {
+                format!(" (alternate data streams: {})", streams.join(", "))
+            };
+            let path = shown(path);
+            let (label, style, because) = match action {
+                Action::WouldHide => ("Would hide", Style::Action, ""),
+                Action::WouldUnhide => ("Would unhide", Style::Action, ""),
+                Action::Hidden if verbose || !streams.is_empty() => ("Hiding", Style::Action, ""),
+                Action::Unhidden if verbose || !streams.is_empty() => {
+                    ("Unhiding", Style::Action, "")
+                }
+                Action::AlreadyHidden if verbose => {
+                    ("Skipping", Style::Skip, " because it's already hidden")
+                }
+                Action::AlreadyVisible if verbose => {
+                    ("Skipping", Style::Skip, " because it's already visible")
+                }
+                _ => return None,
+            };
+            (
+                format!("{label:<LABEL_WIDTH$} {}{because}{streams}", path.display()),
+                style,
+            )
+        }
+        Event::Batch {
+            action,
+            changed,
+            total,
+            dir,
+        } => (
+            format!(
+                "{:<LABEL_WIDTH$} {changed} of {total} entries in {}",
+                match action {
+                    Action::WouldHide => "Would hide",
+                    Action::WouldUnhide => "Would unhide",
+                    Action::Unhidden | Action::AlreadyVisible => "Unhid",
+                    _ => "Hid",
+                },
+                shown(dir).display()
+            ),
+            Style::Action,
+        ),
+        Event::Phase {
+            phase,
+            count,
+            elapsed,
+        } => (
+            format!(
+                "{} phase finished with {count} entries in {elapsed:.2?}",
+                phase.name()
+            ),
+            Style::Plain,
+        ),
+        Event::Error(error) => return Some((error.to_string(), true, Style::Error)),
+        Event::Problem { path, failures } => {
+            return Some((
+                format!("  {} ({failures} failures)", shown(path).display()),
+                true,
+                Style::Error,
+            ))
+        }
+        Event::Shard {
+            index,
+            running,
+            roots,
+            restarts,
+        } => (
+            format!(
+                "Shard {index:<14} {:>12} {roots} roots, {restarts} restarts",
+                if *running { "running" } else { "failed" }
+            ),
+            if *running { Style::Plain } else { Style::Error },
+        ),
+        Event::Raw {
+            shard,
+            kind,
+            paths,
+            flags,
+        } => {
+            let paths = paths
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            let flags = if flags.is_empty() {
+                String::new()
+            } else {
+                format!(" [{}]", flags.join(", "))
+            };
+            return Some((
+                format!("Event from shard {shard}: {kind} {paths}{flags}"),
+                true,
+                Style::Plain,
+            ));
+        }
+        Event::Stats(_) | Event::Errors(_) | Event::Finished { .. } => return None,
+    };
+    Some((line, false, style))
+}
+
+// Helper function to show a path relative to the current directory, if it's an absolute path under it, so human output
+// isn't cluttered by the same long prefix on every line. Anything else is shown as it is.
+fn shown(path: &Path) -> &Path {
+    static CURRENT: OnceLock<Option<PathBuf>> = OnceLock::new();
+    let current = CURRENT.get_or_init(|| std::env::current_dir().ok());
+    match current
+        .as_deref()
+        .filter(|_| path.is_absolute())
+        .and_then(|current| path.strip_prefix(current).ok())
+    {
+        Some(relative) if relative.as_os_str().is_empty() => Path::new("."),
+        Some(relative) => relative,
+        None => path,
+    }
+}
+
+// Helper function to write out how a run went once it's finished, for a person to read, after how many paths weren't
+// listed because of --output-limit.
+fn finished(out: &Sink, summary: &Summary, watched: bool) {
+    let more = out.withheld();
+    if more > 0 {
+        out.line(truncated(more));
+    }
+    if watched {
+        out.line(format_args!("Summary: {summary}"));
+    } else if summary.no_changes_needed() {
+        out.line("No changes needed");
+    }
+    out.flush();
+}
+
+// Helper function to get the name of an action, as written in porcelain output and reports.
+fn action_name(action: Action) -> &'static str {
+    match action {
+        Action::WouldHide => "would_hide",
+        Action::Hidden => "hidden",
+        Action::AlreadyHidden => "already_hidden",
+        Action::WouldUnhide => "would_unhide",
+        Action::Unhidden => "unhidden",
+        Action::AlreadyVisible => "already_visible",
+        Action::Failed => "failed",
+        Action::Conflict => "conflict",
+    }
+}
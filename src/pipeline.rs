@@ -0,0 +1,533 @@
+use crate::{context, filesystem, filter, output, report, safety, summary};
+use std::path::Path;
+use std::time::SystemTime;
+
+// The decision step shared by searching and watching. Check a path against every filter and the matcher, in the same
+// order wherever it was found, then resolve it to the path that should actually be hidden. Returns None if it should be
+// left alone. The root is the path it was found under, and the depth is how far below it the path is, if known. If
+// cutoff is given, then anything modified after it is skipped. Anything new that decides whether a path is hidden
+// belongs here, so that searching and watching can't drift apart. The path's info carries whatever is already known
+// about it, so the filters don't read its metadata more than once between them.
+pub fn process(
+    info: filesystem::Info,
+    root: Option<&Path>,
+    depth: Option<usize>,
+    cutoff: Option<SystemTime>,
+    ctx: &context::RunContext,
+) -> Option<filesystem::Info> {
+    let rules = ctx.rules_for(root);
+    let ctx = &ctx.with_rules(rules.as_deref());
+    let path = info.path();
+
+    // Never hide anything already in the vault, which could be inside the paths being searched.
+    if ctx.vault.is_some_and(|vault| vault.contains(path)) {
+        if ctx.verbose {
+            ctx.reporter.report(&output::Event::Skipped {
+                path,
+                because: "it's in the vault".to_owned(),
+            });
+        }
+        return None;
+    }
+
+    let accepted = filter::depth_in_range(
+        path,
+        depth,
+        ctx.min_depth,
+        ctx.max_depth,
+        ctx.include_root,
+        ctx.verbose,
+        ctx.summary,
+        ctx.reporter.as_ref(),
+    ) && responsive(&info, ctx)
+        && ctx.summary.time(summary::Stage::Type, || {
+            filter::file_type_matches(
+                &info,
+                ctx.types,
+                ctx.verbose,
+                ctx.summary,
+                ctx.reporter.as_ref(),
+            )
+        })
+        && filter::size_in_range(
+            &info,
+            ctx.min_size,
+            ctx.max_size,
+            ctx.verbose,
+            ctx.summary,
+            ctx.reporter.as_ref(),
+        )
+        && filter::age_in_range(
+            &info,
+            ctx.older_than,
+            ctx.newer_than,
+            ctx.verbose,
+            ctx.summary,
+            ctx.reporter.as_ref(),
+        )
+        && filter::ownership_matches(
+            &info,
+            ctx.ownership,
+            ctx.verbose,
+            ctx.summary,
+            ctx.reporter.as_ref(),
+        )
+        && ctx.summary.time(summary::Stage::Match, || {
+            filter::path_matches_pattern(
+                &info,
+                root,
+                ctx.relative,
+                ctx.matcher,
+                ctx.verbose,
+                ctx.summary,
+                ctx.reporter.as_ref(),
+            )
+        })
+        && filter::sibling_exists(
+            path,
+            ctx.requires_sibling,
+            ctx.verbose,
+            ctx.summary,
+            ctx.reporter.as_ref(),
+        )
+        && filter::git_allows(
+            path,
+            ctx.git,
+            ctx.verbose,
+            ctx.summary,
+            ctx.reporter.as_ref(),
+        )
+        && filter::empty_if_folder(
+            &info,
+            ctx.empty,
+            ctx.verbose,
+            ctx.summary,
+            ctx.reporter.as_ref(),
+        )
+        && filter::settled(
+            &info,
+            cutoff,
+            ctx.verbose,
+            ctx.summary,
+            ctx.reporter.as_ref(),
+        )
+        && filter::mime_matches(
+            &info,
+            ctx.mimes,
+            ctx.verbose,
+            ctx.summary,
+            ctx.reporter.as_ref(),
+        )
+        && filter::content_matches(
+            &info,
+            ctx.scanner,
+            ctx.verbose,
+            ctx.summary,
+            ctx.reporter.as_ref(),
+        );
+    if !accepted {
+        return None;
+    }
+    ctx.summary.record_matched();
+
+    // Hide the target of the path instead if it's a symlink that should be dereferenced, as long as the target is
+    // somewhere it could be hidden from.
+    let link = ctx.deref.then(|| path.to_path_buf());
+    let target = filter::dereference(
+        info,
+        ctx.deref,
+        ctx.verbose,
+        ctx.summary,
+        ctx.reporter.as_ref(),
+    )?;
+    match link.filter(|link| link != target.path()) {
+        Some(link) if !target_allowed(&link, target.path(), ctx) => None,
+        _ => Some(target),
+    }
+}
+
+// The action step shared by searching and watching. The root is the path it was found under, if known. Hide a single
+// path, or print out what would be hidden if the test flag is set. If unhide is set, then the path is unhidden instead.
+// Paths that are already as they should be are never touched, so running the same command twice changes nothing the
+// second time. Returns true if the path was, or would be, changed. If a report is being kept, then the action is
+// recorded in it. If a journal is being kept, then paths hidden under a name other than the usual one are recorded in
+// it, and unhidden back to the names they had. If a prompt is given, then the path is only changed if confirmed. If a
+// cooldown is given, then paths that keep failing are left alone for a while. If exemptions are being kept, then paths
+// are recorded in them before they're unhidden, and paths in them aren't hidden. If users or programs are allowed to
+// unhide things, then paths they unhid aren't hidden again. If a limit is given, then no more paths are changed once
+// it's been reached. If a vault is given, then paths are moved into it rather than renamed, whether they're already
+// hidden or not. Any actions other than hiding are done first, and the path counts as changed if any of them changed
+// it, even if it isn't to be hidden at all. If only empty folders are hidden, then the folders hidden where they are
+// are recorded, so a watcher can show them again once something is made in them.
+pub fn hide(info: &filesystem::Info, root: Option<&Path>, ctx: &context::RunContext) -> bool {
+    let rules = ctx.rules_for(root);
+    let ctx = &ctx.with_rules(rules.as_deref());
+    let path = info.path();
+    let object_type = info.object_type().ok();
+    let destination = ctx.vault.map(|vault| vault.destination(path, root));
+
+    // Leave the path alone if it has failed too many times recently.
+    if ctx
+        .cooldown
+        .is_some_and(|cooldown| cooldown.is_cooling(path))
+    {
+        ctx.summary.record_skipped(summary::SkipReason::Cooldown);
+        if ctx.verbose {
+            ctx.reporter.report(&output::Event::Skipped {
+                path,
+                because: "it has failed too many times recently".to_owned(),
+            });
+        }
+        return false;
+    }
+
+    // Leave the path alone if it was deliberately unhidden, so it isn't hidden again straight away.
+    if !ctx.unhide
+        && ctx
+            .exemptions
+            .is_some_and(|exemptions| exemptions.is_exempt(path))
+    {
+        ctx.summary.record_skipped(summary::SkipReason::Exempt);
+        if ctx.verbose {
+            ctx.reporter.report(&output::Event::Skipped {
+                path,
+                because: "it was deliberately unhidden".to_owned(),
+            });
+        }
+        return false;
+    }
+
+    // Leave the path alone if it was unhidden by someone allowed to unhide things.
+    if !ctx.unhide && ctx.unhiders.is_some_and(|unhiders| unhiders.allowed(path)) {
+        ctx.summary.record_skipped(summary::SkipReason::Exempt);
+        if ctx.verbose {
+            ctx.reporter.report(&output::Event::Skipped {
+                path,
+                because: "it was unhidden by a user or program allowed to".to_owned(),
+            });
+        }
+        return false;
+    }
+
+    // Record an action in the report, if one is being kept, using the type the path had before it was changed, and the
+    // pattern it was included by.
+    let log = |action: report::Action| {
+        if let Some(report) = ctx.report {
+            report.record(action, path, object_type, info.rule(), root);
+        }
+    };
+
+    // Report an action, both to the user and to the report.
+    let record = |action: report::Action, streams: &[String]| {
+        ctx.reporter.report(&output::Event::Action {
+            action,
+            path,
+            streams,
+        });
+        log(action);
+    };
+
+    // Check whether the path is already as it should be. Anything that can't be checked is handled as
+    // if it isn't, so that the error is reported when hiding or unhiding it. Anything outside the vault still needs
+    // moving into it, hidden or not.
+    let hidden = info.is_hidden().unwrap_or(ctx.unhide);
+    let hides = ctx.unhide || ctx.actions.hides();
+    let pending = !ctx.unhide && ctx.actions.pending(path);
+    if !pending && (!hides || (destination.is_none() && hidden != ctx.unhide)) {
+        if ctx.unhide {
+            ctx.summary.record_already_visible();
+            record(report::Action::AlreadyVisible, &[]);
+        } else {
+            ctx.summary.record_already_hidden();
+            record(report::Action::AlreadyHidden, &[]);
+        }
+        return false;
+    }
+
+    // Leave the path alone if something already has its hidden name, unless asked otherwise. Anything that can't be
+    // checked is checked again when hiding it.
+    if !ctx.unhide
+        && hides
+        && (destination.is_some() || !hidden)
+        && ctx.on_conflict == filesystem::OnConflict::Skip
+    {
+        let existing = match &destination {
+            Some(destination) => filesystem::existing(destination).ok().flatten(),
+            None => filesystem::conflict(path).ok().flatten(),
+        };
+        if let Some(existing) = existing {
+            conflicted(path, &existing, ctx);
+            log(report::Action::Conflict);
+            return false;
+        }
+    }
+
+    // List any alternate data streams, so their presence can be flagged alongside the path.
+    let streams =
+        filter::alternate_data_streams(path, ctx.include_ads, ctx.summary, ctx.reporter.as_ref());
+    let record_changed = || {
+        if ctx.unhide {
+            ctx.summary.record_unhidden();
+        } else {
+            ctx.summary
+                .record_hidden(info.rule().map(|rule| rule.name.clone()), root);
+        }
+    };
+
+    // Leave the path alone if as many paths have been changed as allowed. The slot taken for it is given back if it
+    // ends up not being changed after all.
+    if ctx.limit.is_some_and(|limit| !limit.take()) {
+        ctx.summary.record_skipped(summary::SkipReason::Limit);
+        if ctx.verbose {
+            ctx.reporter.report(&output::Event::Skipped {
+                path,
+                because: "the limit of changes has been reached".to_owned(),
+            });
+        }
+        return false;
+    }
+    let release = || {
+        if let Some(limit) = ctx.limit {
+            limit.give_back();
+        }
+    };
+
+    // If the test flag is set, then report the file or folder that would be hidden. Otherwise, hide
+    // the file or folder.
+    if ctx.test {
+        record_changed();
+        if ctx.unhide {
+            record(report::Action::WouldUnhide, &streams);
+        } else {
+            record(report::Action::WouldHide, &streams);
+        }
+        return true;
+    }
+
+    // Ask before changing anything, if asking was asked for.
+    if let Some(prompt) = ctx.prompt {
+        match prompt.confirm(if ctx.unhide { "Unhide" } else { "Hide" }, path) {
+            Ok(true) => {}
+            Ok(false) => {
+                ctx.summary.record_skipped(summary::SkipReason::Declined);
+                release();
+                return false;
+            }
+            Err(e) => {
+                ctx.reporter.report(&output::Event::Error(&e));
+                ctx.summary.record_error(&e);
+                log(report::Action::Failed);
+                release();
+                return false;
+            }
+        }
+    }
+
+    // Record that the path is being unhidden deliberately before unhiding it, so anything watching it knows to leave
+    // it alone by the time it's unhidden.
+    let original = ctx
+        .journal
+        .filter(|_| ctx.unhide)
+        .and_then(|journal| journal.original(path));
+    if let Some(exemptions) = ctx.exemptions.filter(|_| ctx.unhide) {
+        if let Err(e) = exemptions.record(&filesystem::unhidden_as(path, original.as_deref())) {
+            ctx.reporter.report(&output::Event::Error(&e));
+            ctx.summary.record_error(&e);
+            log(report::Action::Failed);
+            release();
+            return false;
+        }
+    }
+
+    // The path could have changed since it was checked, in which case there's nothing left to do.
+    // Everything else is done before hiding, while the path still has its original name.
+    let (unhide, actions, dir_times, on_conflict, owned) = (
+        ctx.unhide,
+        ctx.actions,
+        ctx.dir_times.cloned(),
+        ctx.on_conflict,
+        info.clone(),
+    );
+    let result = ctx.summary.time(summary::Stage::Hide, || {
+        filesystem::within(path, ctx.op_timeout, move |_| {
+            if unhide {
+                return filesystem::unhide(&owned, dir_times.as_ref(), original.as_deref())
+                    .map(|change| (false, change));
+            }
+            let applied = actions.apply(owned.path())?;
+            let change = if !actions.hides() {
+                filesystem::Change::Unchanged
+            } else if let Some(destination) = destination {
+                filesystem::relocate(owned.path(), &destination, dir_times.as_ref(), on_conflict)?
+            } else {
+                filesystem::hide(&owned, dir_times.as_ref(), on_conflict)?
+            };
+            Ok((applied, change))
+        })
+    });
+    let changed = match result {
+        Ok((applied, change)) => {
+            if let Some(cooldown) = ctx.cooldown {
+                cooldown.record_success(path);
+            }
+            let action = if ctx.unhide {
+                report::Action::Unhidden
+            } else {
+                report::Action::Hidden
+            };
+            match change {
+                filesystem::Change::Changed(changed) => {
+                    record_changed();
+                    record(action, &streams);
+                    if let Some(journal) = ctx.journal {
+                        let written = if ctx.unhide {
+                            journal.forget(path)
+                        } else if changed != filesystem::hidden_path(path) {
+                            journal.record(&changed, path)
+                        } else {
+                            Ok(())
+                        };
+                        if let Err(e) = written {
+                            ctx.reporter.report(&output::Event::Error(&e));
+                            ctx.summary.record_error(&e);
+                        }
+                    }
+                    if let Some(vault) = ctx.vault.filter(|_| !ctx.unhide) {
+                        vault.moved(path, root);
+                    }
+                    if let Some(empty) = ctx.empty.filter(|_| {
+                        !ctx.unhide
+                            && ctx.vault.is_none()
+                            && info.object_type().ok() == Some(filesystem::ObjectType::Folder)
+                    }) {
+                        empty.record(&changed);
+                    }
+                    if let Some(verifier) = ctx.verifier.filter(|_| !ctx.unhide) {
+                        verifier.record(path, changed);
+                    }
+                    true
+                }
+                filesystem::Change::Unchanged if applied => {
+                    record_changed();
+                    record(action, &streams);
+                    true
+                }
+                filesystem::Change::Unchanged if ctx.unhide => {
+                    ctx.summary.record_already_visible();
+                    log(report::Action::AlreadyVisible);
+                    false
+                }
+                filesystem::Change::Unchanged => {
+                    ctx.summary.record_already_hidden();
+                    log(report::Action::AlreadyHidden);
+                    false
+                }
+                filesystem::Change::Conflict(existing) => {
+                    conflicted(path, &existing, ctx);
+                    log(report::Action::Conflict);
+                    false
+                }
+            }
+        }
+        Err(e) => {
+            ctx.reporter.report(&output::Event::Error(&e));
+            ctx.summary.record_error(&e);
+            log(report::Action::Failed);
+            if ctx
+                .cooldown
+                .is_some_and(|cooldown| cooldown.record_failure(path))
+            {
+                ctx.reporter.report(&output::Event::Notice(format!(
+                    "Giving up on {} for a while after repeated failures",
+                    path.display()
+                )));
+            }
+            false
+        }
+    };
+    if !changed {
+        release();
+    }
+    changed
+}
+
+// Helper function to check that the target of a dereferenced symlink can be hidden. It's left alone if it's in the
+// vault, somewhere protected unless forced, was deliberately unhidden, or is outside the paths being searched or
+// watched unless targets can be anywhere.
+fn target_allowed(link: &Path, target: &Path, ctx: &context::RunContext) -> bool {
+    let reason = if ctx.vault.is_some_and(|vault| vault.contains(target)) {
+        Some("is in the vault".to_owned())
+    } else if let Some(reason) = (!ctx.force && !ctx.test)
+        .then(|| safety::protected(target))
+        .flatten()
+    {
+        Some(format!("is {reason}, which needs --force"))
+    } else if !ctx.unhide
+        && ctx
+            .exemptions
+            .is_some_and(|exemptions| exemptions.is_exempt(target))
+    {
+        Some("was deliberately unhidden".to_owned())
+    } else if ctx
+        .deref_within
+        .is_some_and(|roots| !roots.iter().any(|root| target.starts_with(root)))
+    {
+        Some("is outside the paths being searched, which needs --deref-outside-roots".to_owned())
+    } else {
+        None
+    };
+    let Some(reason) = reason else {
+        return true;
+    };
+    ctx.summary.record_skipped(summary::SkipReason::Target);
+    if ctx.verbose {
+        ctx.reporter.report(&output::Event::Skipped {
+            path: link,
+            because: format!("its target {} {reason}", target.display()),
+        });
+    }
+    false
+}
+
+// Find the closest of the roots that a path is inside, which is the one it was found under.
+pub fn root_of<'a>(path: &Path, roots: &'a [impl AsRef<Path>]) -> Option<&'a Path> {
+    roots
+        .iter()
+        .map(AsRef::as_ref)
+        .filter(|root| path.starts_with(root))
+        .max_by_key(|root| root.components().count())
+}
+
+// Helper function to check the metadata of a path can be read within the operation timeout, if there is one, so a path
+// on a filesystem that has stopped responding is skipped with an error, rather than blocking every filter after it. The
+// metadata is kept in the path's info for the filters to use.
+fn responsive(info: &filesystem::Info, ctx: &context::RunContext) -> bool {
+    if ctx.op_timeout.is_none() {
+        return true;
+    }
+    let owned = info.clone();
+    match filesystem::within(info.path(), ctx.op_timeout, move |_| {
+        owned.metadata().cloned()
+    }) {
+        Ok(metadata) => {
+            info.set_metadata(metadata);
+            true
+        }
+        Err(e) => {
+            ctx.reporter.report(&output::Event::Error(&e));
+            ctx.summary.record_error(&e);
+            false
+        }
+    }
+}
+
+// Helper function to report a path that was left alone because something already has its hidden name. It's always
+// reported, since hiding it was asked for, and nothing else will say it wasn't.
+fn conflicted(path: &Path, existing: &Path, ctx: &context::RunContext) {
+    ctx.summary.record_skipped(summary::SkipReason::Conflict);
+    ctx.reporter.report(&output::Event::Notice(format!(
+        "Skipped hiding {} because {} already exists",
+        path.display(),
+        existing.display()
+    )));
+}
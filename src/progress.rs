@@ -0,0 +1,42 @@
+use crate::summary::Summary;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// How often the counter is redrawn
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+// Run a function while showing a live counter of the directories walked, entries processed, and hides performed so
+// far. The counter is drawn on stderr, and only shown when stdout is a terminal, so it never ends up in piped output.
+pub fn show<T>(summary: &Summary, f: impl FnOnce() -> T) -> T {
+    if !std::io::stdout().is_terminal() {
+        return f();
+    }
+
+    let bar = ProgressBar::new_spinner().with_style(
+        ProgressStyle::with_template("{spinner} [{elapsed}] {msg}")
+            .unwrap_or_else(|_| ProgressStyle::default_spinner()),
+    );
+    let done = AtomicBool::new(false);
+
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            while !done.load(Ordering::Relaxed) {
+                bar.set_message(format!(
+                    "{} directories walked, {} entries processed, {} hidden",
+                    summary.directories(),
+                    summary.scanned(),
+                    summary.hidden()
+                ));
+                bar.tick();
+                std::thread::sleep(TICK_INTERVAL);
+            }
+            bar.finish_and_clear();
+        });
+
+        let result = f();
+        done.store(true, Ordering::Relaxed);
+        result
+    })
+}
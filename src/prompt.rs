@@ -0,0 +1,58 @@
+use anyhow::{Context, Result};
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+// Enum of the states a prompt can be in, depending on earlier answers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum State {
+    #[default]
+    Ask,
+    All,
+    Quit,
+}
+
+// Asks before each path is hidden. Paths are found in parallel, so the state is kept behind a mutex, which also makes
+// sure only one question is asked at a time.
+#[derive(Debug, Default)]
+pub struct Prompt {
+    state: Mutex<State>,
+}
+
+impl Prompt {
+    // Ask whether to take an action, such as "Hide", on a path. Answering "a" hides this and every later path without
+    // asking again, and answering "q", or closing stdin, skips this and every later path.
+    pub fn confirm(&self, action: &str, path: &Path) -> Result<bool> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            match *state {
+                State::All => return Ok(true),
+                State::Quit => return Ok(false),
+                State::Ask => {}
+            }
+
+            print!("{action} {}? [y/n/a/q] ", path.display());
+            std::io::stdout()
+                .flush()
+                .with_context(|| "Failed to write prompt")?;
+
+            let mut answer = String::new();
+            let read = std::io::stdin()
+                .lock()
+                .read_line(&mut answer)
+                .with_context(|| "Failed to read answer to prompt")?;
+            if read == 0 {
+                *state = State::Quit;
+                continue;
+            }
+
+            match answer.trim().to_ascii_lowercase().as_str() {
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                "a" | "all" => *state = State::All,
+                "q" | "quit" => *state = State::Quit,
+                _ => println!("Please answer y (yes), n (no), a (all) or q (quit)."),
+            }
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use crate::cipher::Cipher;
+use crate::filesystem::{CreateModes, ObjectType};
+use crate::matcher::{MatcherType, Rule};
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// Enum of formats a report can be written in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ReportFormat {
+    Csv,
+    Json,
+}
+
+// Enum of actions that can be taken on a path. Failed and conflict are only ever recorded in reports, for paths that
+// couldn't be changed because of an error, or because something already had the name they'd be changed to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    WouldHide,
+    Hidden,
+    AlreadyHidden,
+    WouldUnhide,
+    Unhidden,
+    AlreadyVisible,
+    Failed,
+    Conflict,
+}
+
+// A single action in the report, along with the type of the object, the kind of pattern that matched it and the pattern
+// itself, if any, and the root it was found under, if known.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportEntry {
+    pub action: Action,
+    pub path: String,
+    #[serde(rename = "type")]
+    pub object_type: Option<ObjectType>,
+    pub matcher: Option<MatcherType>,
+    pub rule: Option<String>,
+    pub root: Option<String>,
+}
+
+// How many entries an encrypted report holds on to before sealing them as a section of the file, since sealing each one
+// on its own would mean deriving a key for every entry.
+const SECTION_ENTRIES: usize = 1000;
+
+// Writes out the actions taken during a run as they're taken, in the order they were taken, so that a long watch
+// doesn't keep them all.
+#[derive(Debug)]
+pub struct Report {
+    path: PathBuf,
+    format: ReportFormat,
+    cipher: Option<Cipher>,
+    output: Mutex<Output>,
+}
+
+// The file a report is written to, along with whatever hasn't been written to it yet. Entries are written as soon as
+// they're recorded, unless the report is encrypted, in which case they wait until there are enough to seal. The first
+// error writing stops anything more being written, and is returned once the report is finished.
+#[derive(Debug)]
+struct Output {
+    file: Option<File>,
+    pending: Vec<u8>,
+    waiting: usize,
+    recorded: usize,
+    error: Option<anyhow::Error>,
+}
+
+impl Report {
+    // Build a new report to write to the given path. If no format is given, then it's worked out from the extension of
+    // the path, defaulting to CSV. The file is created straight away, so that a bad path fails before anything is done.
+    // If a cipher is given, then the report is encrypted with it.
+    pub fn new(
+        path: PathBuf,
+        format: Option<ReportFormat>,
+        modes: CreateModes,
+        cipher: Option<Cipher>,
+    ) -> Result<Self> {
+        let format = format.unwrap_or_else(|| {
+            if path
+                .extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+            {
+                ReportFormat::Json
+            } else {
+                ReportFormat::Csv
+            }
+        });
+        let file = modes
+            .create(&path)
+            .with_context(|| format!("Failed to create report {}", path.display()))?;
+        let pending = match format {
+            ReportFormat::Csv => b"action,path,type,matcher,rule,root\n".to_vec(),
+            ReportFormat::Json => Vec::new(),
+        };
+        Ok(Self {
+            path,
+            format,
+            cipher,
+            output: Mutex::new(Output {
+                file: Some(file),
+                pending,
+                waiting: 0,
+                recorded: 0,
+                error: None,
+            }),
+        })
+    }
+
+    // Record an action taken on a path of the given type, found under a root, if known, along with the pattern that
+    // included it, if any.
+    pub fn record(
+        &self,
+        action: Action,
+        path: &Path,
+        object_type: Option<ObjectType>,
+        rule: Option<&Rule>,
+        root: Option<&Path>,
+    ) {
+        let entry = ReportEntry {
+            action,
+            path: path.to_string_lossy().into_owned(),
+            object_type,
+            matcher: rule.map(|rule| rule.matcher_type),
+            rule: rule.map(|rule| rule.name.clone()),
+            root: root.map(|root| root.to_string_lossy().into_owned()),
+        };
+        let mut output = self.output.lock().unwrap_or_else(|e| e.into_inner());
+        if output.error.is_some() || output.file.is_none() {
+            return;
+        }
+        match self.format {
+            ReportFormat::Csv => {
+                let line = format!(
+                    "{},{},{},{},{},{}\n",
+                    csv_field(&entry.action),
+                    csv_field(&entry.path),
+                    csv_field(&entry.object_type),
+                    csv_field(&entry.matcher),
+                    csv_field(&entry.rule),
+                    csv_field(&entry.root)
+                );
+                output.pending.extend(line.as_bytes());
+            }
+            ReportFormat::Json => {
+                // Entries are written as the elements of an array, indented as if the whole array was written at once.
+                let separator = if output.recorded == 0 { "[\n" } else { ",\n" };
+                let json = serde_json::to_string_pretty(&entry).unwrap_or_default();
+                let json = format!("{separator}  {}", json.replace('\n', "\n  "));
+                output.pending.extend(json.as_bytes());
+            }
+        }
+        output.recorded += 1;
+        output.waiting += 1;
+        if self.cipher.is_none() || output.waiting >= SECTION_ENTRIES {
+            self.flush(&mut output);
+        }
+    }
+
+    // Finish writing out the report, with anything still waiting to be written. Can only be done once.
+    pub fn write(&self) -> Result<()> {
+        let mut output = self.output.lock().unwrap_or_else(|e| e.into_inner());
+        if output.file.is_none() {
+            bail!("Report {} was already written", self.path.display());
+        }
+        if self.format == ReportFormat::Json {
+            let end: &[u8] = if output.recorded == 0 {
+                b"[]\n"
+            } else {
+                b"\n]\n"
+            };
+            output.pending.extend(end);
+        }
+        self.flush(&mut output);
+        output.file = None;
+        match output.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    // Helper function to write out everything waiting to be written, sealing it first if the report is encrypted.
+    fn flush(&self, output: &mut Output) {
+        if output.error.is_some() || output.pending.is_empty() {
+            return;
+        }
+        let pending = std::mem::take(&mut output.pending);
+        output.waiting = 0;
+        let written = match &self.cipher {
+            Some(cipher) => cipher.encrypt(&pending),
+            None => Ok(pending),
+        }
+        .and_then(|contents| {
+            let file = output
+                .file
+                .as_mut()
+                .with_context(|| format!("Report {} was already written", self.path.display()))?;
+            file.write_all(&contents)
+                .with_context(|| format!("Failed to write report {}", self.path.display()))
+        });
+        if let Err(e) = written {
+            output.error = Some(e);
+        }
+    }
+}
+
+// Format a value as a CSV field, using its serialized name, and quoting it if needed.
+fn csv_field(value: &impl Serialize) -> String {
+    let value = match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(s)) => s,
+        Ok(serde_json::Value::Null) | Err(_) => String::new(),
+        Ok(other) => other.to_string(),
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
@@ -0,0 +1,250 @@
+use crate::output::{Event, Reporter};
+use crate::report::Action;
+use crate::{context, search, summary};
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// Collects the paths a search would change, rather than writing them out, so they can be reviewed before anything is
+// changed. Errors and notices are still passed on, so they're seen before the review starts.
+#[derive(Debug)]
+struct Collector {
+    matched: Mutex<Vec<PathBuf>>,
+    inner: Arc<dyn Reporter>,
+}
+
+impl Reporter for Collector {
+    fn report(&self, event: &Event) {
+        match event {
+            Event::Action {
+                action: Action::WouldHide | Action::WouldUnhide,
+                path,
+                ..
+            } => self
+                .matched
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(path.to_path_buf()),
+            Event::Error(_) | Event::Notice(_) => self.inner.report(event),
+            _ => {}
+        }
+    }
+}
+
+// Search the paths, or only the listed ones if there are any, for everything that would be changed, without changing
+// anything, and let it be reviewed before anything is. Returns the paths that were approved, or None if the review was
+// cancelled. The search doesn't count towards the summary, the report, the journal or the limit, since nothing is
+// changed by it, and they all apply once the approved paths are changed.
+pub fn run(
+    paths: &[String],
+    listed: Option<&[PathBuf]>,
+    ctx: &context::RunContext,
+) -> Result<Option<Vec<PathBuf>>> {
+    // Fail before searching, rather than after, if the review can't be shown.
+    check()?;
+
+    let collector = Arc::new(Collector {
+        matched: Mutex::new(Vec::new()),
+        inner: Arc::clone(ctx.reporter),
+    });
+    let reporter: Arc<dyn Reporter> = collector.clone();
+    let summary = summary::Summary::default();
+    let scan = context::RunContext {
+        test: true,
+        report: None,
+        journal: None,
+        limit: None,
+        prompt: None,
+        dir_times: None,
+        summary: &summary,
+        reporter: &reporter,
+        ..*ctx
+    };
+    match listed {
+        Some(listed) => search::search_listed(listed, &scan),
+        None => search::search(paths, &scan),
+    }
+
+    let mut matched =
+        std::mem::take(&mut *collector.matched.lock().unwrap_or_else(|e| e.into_inner()));
+    if matched.is_empty() {
+        return Ok(Some(matched));
+    }
+    matched.sort();
+    matched.dedup();
+    review(matched, if ctx.unhide { "unhide" } else { "hide" })
+}
+
+// A line in the review, either a folder with matches below it, or a match in the folder above it.
+#[cfg_attr(not(feature = "tui"), allow(dead_code))]
+#[derive(Debug)]
+enum Row {
+    Folder(PathBuf),
+    Match(usize),
+}
+
+// Helper function to lay out the matches as rows, with each one listed under the folder it's in, and the folders in
+// order, so everything below a folder comes after it.
+#[cfg_attr(not(feature = "tui"), allow(dead_code))]
+fn rows(matched: &[PathBuf]) -> Vec<Row> {
+    let mut folders: std::collections::BTreeMap<&Path, Vec<usize>> = Default::default();
+    for (index, path) in matched.iter().enumerate() {
+        folders
+            .entry(path.parent().unwrap_or(Path::new("")))
+            .or_default()
+            .push(index);
+    }
+    folders
+        .into_iter()
+        .flat_map(|(folder, indices)| {
+            std::iter::once(Row::Folder(folder.to_path_buf()))
+                .chain(indices.into_iter().map(Row::Match))
+        })
+        .collect()
+}
+
+// Show the matches in a scrollable list, grouped by the folder they're in, all approved to start with. Each one can be
+// toggled, or everything below a folder at once, before the approved ones are changed. Returns the approved paths, or
+// None if the review was cancelled.
+#[cfg(feature = "tui")]
+fn review(matched: Vec<PathBuf>, verb: &str) -> Result<Option<Vec<PathBuf>>> {
+    use anyhow::Context;
+    use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+    use ratatui::layout::{Constraint, Layout};
+    use ratatui::style::{Modifier, Style};
+    use ratatui::text::Line;
+    use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+
+    let rows = rows(&matched);
+    let mut approved = vec![true; matched.len()];
+    let mut state = ListState::default().with_selected(Some(0));
+    let mut terminal = ratatui::init();
+    let result = loop {
+        let drawn = terminal.draw(|frame| {
+            let [list_area, help_area] =
+                Layout::vertical([Constraint::Min(1), Constraint::Length(2)]).areas(frame.area());
+            let items = rows.iter().map(|row| match row {
+                Row::Folder(folder) => {
+                    let below = below(&matched, folder);
+                    let count = below.clone().filter(|&index| approved[index]).count();
+                    let total = below.count();
+                    let mark = match count {
+                        0 => "[ ]",
+                        _ if count == total => "[x]",
+                        _ => "[-]",
+                    };
+                    ListItem::new(format!("{mark} {}/ ({count} of {total})", folder.display()))
+                        .style(Style::new().add_modifier(Modifier::BOLD))
+                }
+                Row::Match(index) => ListItem::new(format!(
+                    "    {} {}",
+                    if approved[*index] { "[x]" } else { "[ ]" },
+                    matched[*index].file_name().map_or_else(
+                        || matched[*index].display().to_string(),
+                        |name| { name.to_string_lossy().into_owned() }
+                    )
+                )),
+            });
+            let count = approved.iter().filter(|&&approved| approved).count();
+            frame.render_stateful_widget(
+                List::new(items)
+                    .block(Block::bordered().title(format!(
+                        " Review: {count} of {} will {verb} ",
+                        matched.len()
+                    )))
+                    .highlight_style(Style::new().add_modifier(Modifier::REVERSED)),
+                list_area,
+                &mut state,
+            );
+            frame.render_widget(
+                Paragraph::new(vec![
+                    Line::from(
+                        "space: toggle, including everything below a folder   a: all   n: none",
+                    ),
+                    Line::from(format!(
+                        "enter: {verb} the approved paths   q or esc: cancel"
+                    )),
+                ]),
+                help_area,
+            );
+        });
+        if let Err(e) = drawn {
+            break Err(e).with_context(|| "Failed to draw review");
+        }
+
+        let key = match event::read() {
+            Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => key,
+            Ok(_) => continue,
+            Err(e) => break Err(e).with_context(|| "Failed to read key"),
+        };
+        let page = terminal
+            .size()
+            .map_or(10, |size| size.height.saturating_sub(4).max(1) as usize);
+        let selected = state.selected().unwrap_or(0);
+        match key.code {
+            KeyCode::Up | KeyCode::Char('k') => state.select_previous(),
+            KeyCode::Down | KeyCode::Char('j') => state.select_next(),
+            KeyCode::PageUp => state.select(Some(selected.saturating_sub(page))),
+            KeyCode::PageDown => state.select(Some((selected + page).min(rows.len() - 1))),
+            KeyCode::Home => state.select_first(),
+            KeyCode::End => state.select(Some(rows.len() - 1)),
+            KeyCode::Char(' ') => match &rows[selected] {
+                Row::Folder(folder) => {
+                    let toggled = !below(&matched, folder).all(|index| approved[index]);
+                    for index in below(&matched, folder) {
+                        approved[index] = toggled;
+                    }
+                }
+                Row::Match(index) => approved[*index] = !approved[*index],
+            },
+            KeyCode::Char('a') => approved.fill(true),
+            KeyCode::Char('n') => approved.fill(false),
+            KeyCode::Enter => {
+                break Ok(Some(
+                    matched
+                        .iter()
+                        .zip(&approved)
+                        .filter(|(_, &approved)| approved)
+                        .map(|(path, _)| path.clone())
+                        .collect(),
+                ))
+            }
+            KeyCode::Char('q') | KeyCode::Esc => break Ok(None),
+            _ => {}
+        }
+    };
+    ratatui::restore();
+    result
+}
+
+// Helper function to check the review can be shown, which needs a terminal to read keys from and draw on.
+#[cfg(feature = "tui")]
+fn check() -> Result<()> {
+    use std::io::IsTerminal;
+
+    if !std::io::stdin().is_terminal() || !std::io::stdout().is_terminal() {
+        anyhow::bail!("The review needs a terminal to be shown in");
+    }
+    Ok(())
+}
+
+// Helper function to get the indices of every match below a folder, however deep.
+#[cfg(feature = "tui")]
+fn below<'m>(matched: &'m [PathBuf], folder: &'m Path) -> impl Iterator<Item = usize> + Clone + 'm {
+    matched
+        .iter()
+        .enumerate()
+        .filter(move |(_, path)| path.starts_with(folder))
+        .map(|(index, _)| index)
+}
+
+// The review can't be shown in builds without the tui feature.
+#[cfg(not(feature = "tui"))]
+fn check() -> Result<()> {
+    anyhow::bail!("The review TUI isn't supported by this build of cloak. Rebuild it with the tui feature enabled.")
+}
+
+#[cfg(not(feature = "tui"))]
+fn review(_matched: Vec<PathBuf>, _verb: &str) -> Result<Option<Vec<PathBuf>>> {
+    check().map(|()| None)
+}
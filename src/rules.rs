@@ -0,0 +1,194 @@
+use crate::filesystem::ObjectType;
+use crate::matcher::{self, MatchOn, MatchTarget, Matcher};
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+// The patterns and types in a rules file, which take the place of the ones given on the command line for a single
+// path. Every field is optional, and they mean the same as the command line options of the same names.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RulesFile {
+    pattern: Option<Vec<String>>,
+    ext: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    exclude_dir: Option<Vec<String>>,
+    regex: Option<Vec<String>>,
+    regex_exclude: Option<Vec<String>>,
+    types: Option<Vec<ObjectType>>,
+}
+
+// The matcher and types to use for a single path
+#[derive(Debug)]
+pub struct Rules {
+    pub matcher: Matcher,
+    pub types: Option<Vec<ObjectType>>,
+}
+
+// The paths that have rules of their own, each with its rules. Paths are kept both as given and canonicalized, so
+// they can be found from the roots used by searching and watching alike. The rules files can be read again while
+// watching, and the new rules take the place of the old ones all at once. Whatever is matching against the old rules
+// keeps them until it's finished, and they're freed once nothing is using them any more.
+#[derive(Debug)]
+pub struct RootRules {
+    paths: Vec<String>,
+    files: Vec<PathBuf>,
+    rules: Vec<(PathBuf, usize)>,
+    loaded: RwLock<Vec<Arc<Rules>>>,
+    modified: Mutex<Vec<Option<SystemTime>>>,
+    target: MatchTarget,
+    match_on: MatchOn,
+    ignore_case: bool,
+    unicode: bool,
+}
+
+impl RootRules {
+    // Load the rules from each spec, given as RULES:PATH. The matchers are built the same way as the command line one.
+    pub fn load(
+        specs: &[String],
+        target: MatchTarget,
+        match_on: MatchOn,
+        ignore_case: bool,
+        unicode: bool,
+    ) -> Result<Self> {
+        let mut root_rules = Self {
+            paths: Vec::new(),
+            files: Vec::new(),
+            rules: Vec::new(),
+            loaded: RwLock::new(Vec::new()),
+            modified: Mutex::new(Vec::new()),
+            target,
+            match_on,
+            ignore_case,
+            unicode,
+        };
+        for spec in specs {
+            let (file, path) = split_spec(spec)?;
+            let index = root_rules.files.len();
+            root_rules.paths.push(path.to_owned());
+            root_rules.files.push(PathBuf::from(file));
+            root_rules.rules.push((PathBuf::from(path), index));
+            if let Ok(canonical) = Path::new(path).canonicalize() {
+                root_rules.rules.push((canonical, index));
+            }
+        }
+        root_rules.reload()?;
+        Ok(root_rules)
+    }
+
+    // Read every rules file again, and use the new rules from now on. If any of them can't be read, then the old rules
+    // are all kept, and they're only counted as changed again once they've changed since.
+    pub fn reload(&self) -> Result<()> {
+        *self.modified.lock().unwrap_or_else(|e| e.into_inner()) =
+            self.files.iter().map(|file| modified(file)).collect();
+        let loaded = self
+            .files
+            .iter()
+            .map(|file| {
+                read(
+                    file,
+                    self.target,
+                    self.match_on,
+                    self.ignore_case,
+                    self.unicode,
+                )
+                .map(Arc::new)
+            })
+            .collect::<Result<_>>()?;
+        *self.loaded.write().unwrap_or_else(|e| e.into_inner()) = loaded;
+        Ok(())
+    }
+
+    // Returns true if any of the rules files have been changed since they were last read. Only the watcher reads them
+    // again, so builds without it never ask.
+    #[cfg_attr(not(feature = "watch"), allow(dead_code))]
+    pub fn changed(&self) -> bool {
+        let last = self.modified.lock().unwrap_or_else(|e| e.into_inner());
+        self.files
+            .iter()
+            .zip(last.iter())
+            .any(|(file, last)| modified(file) != *last)
+    }
+
+    // Returns the paths that have rules of their own, as given.
+    pub fn paths(&self) -> &[String] {
+        &self.paths
+    }
+
+    // Returns the rules for a root, if it has any of its own.
+    pub fn get(&self, root: &Path) -> Option<Arc<Rules>> {
+        let loaded = self.loaded.read().unwrap_or_else(|e| e.into_inner());
+        self.rules
+            .iter()
+            .find(|(path, _)| path == root)
+            .map(|(_, index)| Arc::clone(&loaded[*index]))
+    }
+}
+
+// Helper function to read a rules file, and build its matcher the same way as the command line one.
+fn read(
+    file: &Path,
+    target: MatchTarget,
+    match_on: MatchOn,
+    ignore_case: bool,
+    unicode: bool,
+) -> Result<Rules> {
+    let contents = fs::read_to_string(file)
+        .with_context(|| format!("Failed to read rules file {}", file.display()))?;
+    let rules: RulesFile = serde_json::from_str(&contents)
+        .with_context(|| format!("Failed to parse rules file {}", file.display()))?;
+
+    let mut pattern = rules.pattern;
+    if let Some(ext) = rules.ext {
+        pattern
+            .get_or_insert_with(Vec::new)
+            .extend(ext.iter().map(|ext| matcher::ext_pattern(ext)));
+    }
+    let matcher = Matcher::new(
+        pattern,
+        rules.exclude,
+        rules.regex,
+        rules.regex_exclude,
+        rules.exclude_dir,
+        target,
+        match_on,
+        ignore_case,
+        unicode,
+    )
+    .with_context(|| format!("Failed to build matcher from rules file {}", file.display()))?
+    .sourced_from(file);
+    Ok(Rules {
+        matcher,
+        types: rules.types,
+    })
+}
+
+// Helper function to get when a rules file was last changed, if it can be told.
+fn modified(file: &Path) -> Option<SystemTime> {
+    fs::metadata(file)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+// Helper function to split a spec into the rules file and the path it applies to, at the first colon that isn't part
+// of a Windows drive letter at the start of the spec.
+fn split_spec(spec: &str) -> Result<(&str, &str)> {
+    let bytes = spec.as_bytes();
+    let start = if bytes.len() > 2
+        && bytes[0].is_ascii_alphabetic()
+        && bytes[1] == b':'
+        && matches!(bytes[2], b'\\' | b'/')
+    {
+        2
+    } else {
+        0
+    };
+    let index = spec[start..]
+        .find(':')
+        .map(|index| start + index)
+        .ok_or_else(|| anyhow!("Path rules {spec} should be given as RULES:PATH"))?;
+    Ok((&spec[..index], &spec[index + 1..]))
+}
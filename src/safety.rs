@@ -0,0 +1,94 @@
+use crate::filesystem;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Unix system locations that nothing should ever be hidden in, nor anywhere below them
+#[cfg(target_family = "unix")]
+const SYSTEM_PATHS: &[&str] = &[
+    "/bin",
+    "/boot",
+    "/dev",
+    "/etc",
+    "/lib",
+    "/lib32",
+    "/lib64",
+    "/libx32",
+    "/opt",
+    "/proc",
+    "/run",
+    "/sbin",
+    "/snap",
+    "/sys",
+    "/usr",
+    "/var",
+    "/Applications",
+    "/Library",
+    "/System",
+    "/private/etc",
+];
+
+// Where home directories are usually kept, as well as wherever the current user's is
+const HOME_PARENTS: &[&str] = &["/home", "/Users"];
+
+// Environment variables holding the Windows system locations that nothing should ever be hidden in, nor anywhere below
+// them
+#[cfg(target_family = "windows")]
+const SYSTEM_VARS: &[&str] = &[
+    "SystemRoot",
+    "ProgramFiles",
+    "ProgramFiles(x86)",
+    "ProgramData",
+];
+
+// Returns why a path is too dangerous to hide or unhide everything in, if it is. That's the case for the root of a
+// filesystem, a home directory, the directory home directories are kept in, and any well known system location, or
+// anywhere below one. Home directories are only protected themselves, since hiding things inside them is what cloak is
+// for.
+pub fn protected(path: &Path) -> Option<String> {
+    let path = canonical(path);
+    if path.parent().is_none() {
+        return Some("the root of a filesystem".to_owned());
+    }
+
+    let homes: Vec<PathBuf> = ["HOME", "USERPROFILE"]
+        .into_iter()
+        .filter_map(std::env::var_os)
+        .map(|home| canonical(Path::new(&home)))
+        .collect();
+    if homes.contains(&path) {
+        return Some("a home directory".to_owned());
+    }
+    if homes.iter().any(|home| home.parent() == Some(&path))
+        || HOME_PARENTS.iter().any(|parent| path == Path::new(parent))
+    {
+        return Some("where home directories are kept".to_owned());
+    }
+
+    system_paths()
+        .into_iter()
+        .find(|system| path.starts_with(system))
+        .map(|system| format!("inside the system location {}", system.display()))
+}
+
+// Helper function to get the system locations to protect on Unix. They're left as they are, rather than resolved, so
+// that on macOS /tmp, which is really /private/tmp, isn't caught up in them.
+#[cfg(target_family = "unix")]
+fn system_paths() -> Vec<PathBuf> {
+    SYSTEM_PATHS.iter().map(PathBuf::from).collect()
+}
+
+// Helper function to get the system locations to protect on Windows, from wherever the environment says they are.
+#[cfg(target_family = "windows")]
+fn system_paths() -> Vec<PathBuf> {
+    SYSTEM_VARS
+        .iter()
+        .filter_map(std::env::var_os)
+        .map(|system| canonical(Path::new(&system)))
+        .collect()
+}
+
+// Helper function to resolve a path, so that it's protected however it's written. Paths that can't be resolved, such
+// as ones that don't exist, are only made absolute.
+pub fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| filesystem::absolute(path))
+}
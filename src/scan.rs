@@ -0,0 +1,102 @@
+use anyhow::Result;
+use std::path::Path;
+
+// How much of a file is read at a time while scanning it.
+#[cfg_attr(not(feature = "regex"), allow(dead_code))]
+const CHUNK_BYTES: usize = 64 * 1024;
+
+// How much of the end of each chunk is kept and scanned again with the next one, so a match split across the two is
+// still found. Only matches longer than this can be missed.
+#[cfg_attr(not(feature = "regex"), allow(dead_code))]
+const OVERLAP_BYTES: usize = 4 * 1024;
+
+// Scans the content of files for regexes, so only files containing something, such as a secret or a marker, are
+// hidden. Files are read a chunk at a time, up to a limit, and stop being read as soon as something matches, so only
+// a chunk of each is ever held at once. Scanning runs in a thread pool of its own, so however many paths are being
+// filtered at once, only as many files as it has threads are ever being read at once.
+#[cfg(feature = "regex")]
+#[derive(Debug)]
+pub struct Scanner {
+    regexes: regex::bytes::RegexSet,
+    max_bytes: u64,
+    pool: rayon::ThreadPool,
+}
+
+#[cfg(feature = "regex")]
+impl Scanner {
+    // Build a new scanner for the given regexes, reading at most max_bytes of each file, with the given number of
+    // threads.
+    pub fn new(regexes: &[String], max_bytes: u64, threads: usize) -> Result<Self> {
+        use anyhow::Context;
+
+        Ok(Self {
+            regexes: regex::bytes::RegexSet::new(regexes)
+                .with_context(|| "Failed to build content regex matcher")?,
+            max_bytes,
+            pool: rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .thread_name(|i| format!("cloak-scan-{i}"))
+                .build()
+                .with_context(|| "Failed to start content scanning threads")?,
+        })
+    }
+
+    // Returns the most bytes read of each file.
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes
+    }
+
+    // Returns true if anything in the file at the given path matches one of the regexes.
+    pub fn matches(&self, path: &Path) -> Result<bool> {
+        self.pool.install(|| self.scan(path))
+    }
+
+    // Helper function to read a file a chunk at a time, until something matches or the limit is reached.
+    fn scan(&self, path: &Path) -> Result<bool> {
+        use anyhow::Context;
+        use std::io::Read;
+
+        let mut file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open {} to scan it", path.display()))?
+            .take(self.max_bytes);
+        let mut buffer = vec![0; OVERLAP_BYTES + CHUNK_BYTES];
+        let mut kept = 0;
+        loop {
+            let read = file
+                .read(&mut buffer[kept..])
+                .with_context(|| format!("Failed to read {} to scan it", path.display()))?;
+            if read == 0 {
+                return Ok(false);
+            }
+            let filled = kept + read;
+            if self.regexes.is_match(&buffer[..filled]) {
+                return Ok(true);
+            }
+            kept = filled.min(OVERLAP_BYTES);
+            buffer.copy_within(filled - kept..filled, 0);
+        }
+    }
+}
+
+// Stand-in for a scanner in builds without the regex feature. One can never be built, so content regexes are rejected
+// up front.
+#[cfg(not(feature = "regex"))]
+#[derive(Debug)]
+pub enum Scanner {}
+
+#[cfg(not(feature = "regex"))]
+impl Scanner {
+    pub fn new(_regexes: &[String], _max_bytes: u64, _threads: usize) -> Result<Self> {
+        anyhow::bail!(
+            "Content regexes aren't supported by this build of cloak. Rebuild it with the regex feature enabled."
+        )
+    }
+
+    pub fn max_bytes(&self) -> u64 {
+        match *self {}
+    }
+
+    pub fn matches(&self, _path: &Path) -> Result<bool> {
+        match *self {}
+    }
+}
@@ -0,0 +1,34 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use std::process::ExitCode;
+
+// Enum of the formats cloak writes or reads that other tools might want to check against a JSON Schema
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SchemaKind {
+    // The events written one per line by --output json
+    Decisions,
+    // The reports written by --report in JSON
+    Report,
+    // The rules files read by --path-rules, the only configuration cloak reads
+    #[value(alias = "config")]
+    Rules,
+}
+
+// The schemas themselves, kept in the schemas directory so they can be published as they are, and built in so they
+// always match the binary that wrote the output
+const DECISIONS: &str = include_str!("../schemas/decisions.schema.json");
+const REPORT: &str = include_str!("../schemas/report.schema.json");
+const RULES: &str = include_str!("../schemas/rules.schema.json");
+
+// Print out the JSON Schema for a format.
+pub fn run(kind: SchemaKind) -> Result<ExitCode> {
+    print!(
+        "{}",
+        match kind {
+            SchemaKind::Decisions => DECISIONS,
+            SchemaKind::Report => REPORT,
+            SchemaKind::Rules => RULES,
+        }
+    );
+    Ok(ExitCode::SUCCESS)
+}
@@ -1,66 +1,638 @@
-use crate::{filesystem, filter, matcher};
-use anyhow::Context;
+use crate::{changes, context, filesystem, output, pipeline, report, summary, walk};
+use anyhow::{anyhow, Context, Result};
 use rayon::prelude::*;
-use std::path::Path;
-use std::time::Duration;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
 
-pub fn search(
+// Everything found by walking the roots, each entry along with the root it was found under, if it was found under one.
+// For preferring parents, the number of entries in each directory is kept too, along with the roots themselves.
+struct Found<'a> {
+    entries: Vec<(Option<&'a Path>, walk::Entry)>,
+    totals: HashMap<PathBuf, usize>,
+    roots: HashSet<PathBuf>,
+}
+
+// What a search is going to change. Without batching, every match is changed on its own. With it, matches are grouped
+// by their parent directory, so each directory is only touched once.
+enum Plan<'a> {
+    Each(Vec<(Option<&'a Path>, filesystem::Info)>),
+    Batches(BTreeMap<PathBuf, Vec<filesystem::Info>>),
+}
+
+impl Plan<'_> {
+    // Returns the number of paths the plan is going to change.
+    fn len(&self) -> usize {
+        match self {
+            Plan::Each(matches) => matches.len(),
+            Plan::Batches(batches) => batches.values().map(Vec::len).sum(),
+        }
+    }
+
+    // Returns the number of paths the plan is going to change that aren't already as they should be.
+    fn pending(&self, unhide: bool) -> usize {
+        let pending = |info: &filesystem::Info| info.is_hidden().unwrap_or(unhide) == unhide;
+        match self {
+            Plan::Each(matches) => matches.iter().filter(|(_, info)| pending(info)).count(),
+            Plan::Batches(batches) => batches
+                .values()
+                .flatten()
+                .filter(|info| pending(info))
+                .count(),
+        }
+    }
+}
+
+// Search the paths for files and folders to hide, from min_depth to max_depth levels below them,
+// where the paths themselves are at depth 0. Symlinked directories are only searched if
+// follow_symlinks is set. The search runs in phases, one after another. Every path is walked to
+// enumerate its entries, then each entry is run through the pipeline to filter out the ones that
+// shouldn't be hidden, then the matches are planned into what to change, and finally the plan is
+// executed. How many entries each phase ended up with, and how long it took, is recorded in the
+// summary. If batch is set, then matches are hidden one directory at a time, so each directory is
+// only touched once per run. If settle is given, then anything modified after the search started,
+// or less than settle before it, is skipped so that files still being written aren't disturbed. If
+// prefer_parent is given, then matches are batched, and any directory where at least that fraction
+// of its entries match is hidden itself instead of its entries. If max_operations is given, then
+// nothing is changed if the plan would change more than that many paths.
+pub fn search(paths: &[impl AsRef<Path> + Send + Sync + 'static], ctx: &context::RunContext) {
+    let cutoff = cutoff(ctx);
+    let found = phase(
+        summary::Phase::Enumerate,
+        ctx,
+        || enumerate(paths, None, ctx),
+        |found| found.entries.len(),
+    );
+    run(found, paths, cutoff, ctx);
+}
+
+// Search the paths the same way, except only for what changed below each of them since the last run, as far as since
+// knows. Anything it doesn't know about is searched in full.
+pub fn search_since(
     paths: &[impl AsRef<Path> + Send + Sync + 'static],
-    matcher: &matcher::Matcher,
-    types: Option<&[filesystem::ObjectType]>,
-    recursive: bool,
-    test: bool,
-    verbose: bool,
+    since: &changes::Since,
+    ctx: &context::RunContext,
 ) {
-    // Iterate over the root paths using jwalk
-    paths.par_iter().for_each(|dir| {
-        if verbose {
-            println!(
-                "Searching for files and folders to hide in {}...",
-                dir.as_ref().display()
-            );
+    let cutoff = cutoff(ctx);
+    let found = phase(
+        summary::Phase::Enumerate,
+        ctx,
+        || enumerate(paths, Some(since), ctx),
+        |found| found.entries.len(),
+    );
+    run(found, paths, cutoff, ctx);
+}
+
+// Search a list of paths given by something else, such as find or fd, rather than walking for them. Each path is
+// only run through the pipeline and changed itself, the same as the entries found by a search, but without a root, so
+// depths don't apply to them, and patterns are matched against them as they're given.
+pub fn search_listed(paths: &[PathBuf], ctx: &context::RunContext) {
+    let cutoff = cutoff(ctx);
+    let found = phase(
+        summary::Phase::Enumerate,
+        ctx,
+        || listed(paths, ctx),
+        |found| found.entries.len(),
+    );
+    run(found, &[] as &[PathBuf], cutoff, ctx);
+}
+
+// Read a list of paths, one per line, or separated by NUL characters if nul is set, from a file, or stdin if it's -.
+// Empty entries are left out, and so are the carriage returns of Windows line endings.
+pub fn read_listed(from: &Path, nul: bool) -> Result<Vec<PathBuf>> {
+    use std::io::Read;
+
+    let mut contents = Vec::new();
+    if from == Path::new("-") {
+        std::io::stdin()
+            .read_to_end(&mut contents)
+            .with_context(|| "Failed to read paths from stdin")?;
+    } else {
+        contents = std::fs::read(from)
+            .with_context(|| format!("Failed to read paths from {}", from.display()))?;
+    }
+
+    let separator = if nul { b'\0' } else { b'\n' };
+    Ok(contents
+        .split(|&b| b == separator)
+        .map(|entry| {
+            if nul {
+                entry
+            } else {
+                entry.strip_suffix(b"\r").unwrap_or(entry)
+            }
+        })
+        .filter(|entry| !entry.is_empty())
+        .map(path_from_bytes)
+        .collect())
+}
+
+// Work out which of the paths given to search or watch actually need to be, so nothing is walked or watched twice. The
+// paths are compared as they resolve, so the same folder written two ways, or through a symlink, is only kept the
+// first time it's given. When searching all the way down, a path inside another one is folded into it too, since
+// everything in it is found by searching the other one anyway. A path isn't folded if that would change how it's
+// searched: if patterns are matched relative to it, if it's matched with different rules to the path it's inside, or if
+// it's inside a hidden folder that isn't walked into. Whatever was dropped is reported, so it's clear why. Paths that
+// can't be resolved are compared as they're written, and their errors are left to searching them.
+pub fn roots(paths: Vec<String>, ctx: &context::RunContext) -> Vec<String> {
+    let resolved: Vec<PathBuf> = paths
+        .iter()
+        .map(|path| {
+            std::fs::canonicalize(path).unwrap_or_else(|_| filesystem::absolute(Path::new(path)))
+        })
+        .collect();
+
+    let mut kept: Vec<usize> = Vec::new();
+    for (index, path) in resolved.iter().enumerate() {
+        match kept.iter().find(|&&other| resolved[other] == *path) {
+            Some(&other) => ctx.reporter.report(&output::Event::Notice(format!(
+                "Searching {} only once, since it's the same as {}",
+                paths[index], paths[other]
+            ))),
+            None => kept.push(index),
         }
+    }
 
-        // The rayon thread pool can get busy, so try to start iteration continuously until it succeeds.
-        loop {
-            match jwalk::WalkDir::new(dir)
-                .follow_links(true)
-                .skip_hidden(false)
-                .parallelism(jwalk::Parallelism::RayonDefaultPool {
-                    busy_timeout: Duration::from_secs(3),
+    let foldable = |index: usize, into: usize| {
+        let same_rules = match (
+            ctx.rules_for(Some(Path::new(&paths[index]))),
+            ctx.rules_for(Some(Path::new(&paths[into]))),
+        ) {
+            (Some(inner), Some(outer)) => Arc::ptr_eq(&inner, &outer),
+            (inner, outer) => inner.is_none() && outer.is_none(),
+        };
+        ctx.max_depth == usize::MAX && !ctx.relative && same_rules
+    };
+    let folded: Vec<Option<usize>> = kept
+        .iter()
+        .map(|&index| {
+            kept.iter()
+                .copied()
+                .filter(|&other| other != index && resolved[index].starts_with(&resolved[other]))
+                .filter(|&other| foldable(index, other))
+                .filter(|&other| {
+                    !ctx.skip_hidden_dirs
+                        || !resolved[index]
+                            .ancestors()
+                            .take_while(|dir| *dir != resolved[other])
+                            .any(|dir| filesystem::is_hidden(dir).unwrap_or(false))
                 })
-                .max_depth(if recursive { usize::MAX } else { 1 })
-                .try_into_iter()
-            {
-                Ok(iter) => break iter,
-                Err(_) if verbose => eprintln!(
-                    "Failed to start iteration on path {}. Retrying...",
-                    dir.as_ref().display()
-                ),
-                Err(_) => continue,
-            };
+                .min_by_key(|&other| resolved[other].components().count())
+        })
+        .collect();
+    kept.iter()
+        .zip(folded)
+        .filter_map(|(&index, into)| match into {
+            Some(into) => {
+                ctx.reporter.report(&output::Event::Notice(format!(
+                    "Searching {} as part of {}, since it's inside it",
+                    paths[index], paths[into]
+                )));
+                None
+            }
+            None => Some(paths[index].clone()),
+        })
+        .collect()
+}
+
+// Run the phases after enumerating in turn, each one handing what it ended up with to the next.
+fn run(
+    found: Found,
+    roots: &[impl AsRef<Path> + Sync],
+    cutoff: Option<SystemTime>,
+    ctx: &context::RunContext,
+) {
+    let matches = phase(
+        summary::Phase::Filter,
+        ctx,
+        || filter(found.entries, cutoff, ctx),
+        Vec::len,
+    );
+    let plan = phase(
+        summary::Phase::Plan,
+        ctx,
+        || plan(matches, &found.totals, &found.roots, cutoff, ctx),
+        Plan::len,
+    );
+
+    // Stop before changing anything if the plan would change more than allowed.
+    if let Some(max_operations) = ctx.max_operations {
+        let pending = plan.pending(ctx.unhide);
+        if pending > max_operations {
+            let e = anyhow!(
+                "Refusing to {} {pending} files and folders, more than --max-operations {max_operations} allows",
+                if ctx.unhide { "unhide" } else { "hide" }
+            );
+            ctx.reporter.report(&output::Event::Error(&e));
+            ctx.summary.record_error(&e);
+            return;
         }
-        // Now iterate over the files and folders, filtering out errors first, then filtering
-        // by the types of objects to hide, then filtering by the matcher.
-        .filter_map(|dir| {
-            // If there's an error, print it out and return None.
-            dir.with_context(|| "Failed to get path.")
-                .inspect_err(|e| eprintln!("{e}"))
-                .ok()
+    }
+
+    phase(
+        summary::Phase::Execute,
+        ctx,
+        || execute(plan, roots, ctx),
+        |changed| *changed,
+    );
+
+    // Hide any folders that moving things into the vault left empty, then, now that the batch of renames is done,
+    // restore the times of the directories they were in.
+    if let Some(vault) = ctx.vault {
+        vault.prune(ctx);
+    }
+    if let Some(dir_times) = ctx.dir_times {
+        dir_times.restore().unwrap_or_else(|e| {
+            ctx.reporter.report(&output::Event::Error(&e));
+            ctx.summary.record_error(&e);
+        });
+    }
+
+    // Check that everything hidden in the batch stayed hidden.
+    if let Some(verifier) = ctx.verifier {
+        verifier.check(ctx);
+    }
+}
+
+// Walk every path, gathering up everything found below it, or only what changed below it if since is given and knows
+// that. Roots are walked in parallel, and any errors are reported as they're found.
+fn enumerate<'a>(
+    paths: &'a [impl AsRef<Path> + Sync],
+    since: Option<&changes::Since>,
+    ctx: &context::RunContext,
+) -> Found<'a> {
+    let entries: Vec<(Option<&Path>, walk::Entry)> = paths
+        .par_iter()
+        .map(AsRef::as_ref)
+        .flat_map_iter(|dir| {
+            if ctx.verbose {
+                ctx.reporter.report(&output::Event::Searching(dir));
+            }
+
+            // Skip the root entirely if it's an unresponsive mount point.
+            if let Some(Err(e)) = ctx.mounts.map(|mounts| mounts.check(dir, true)) {
+                ctx.reporter.report(&output::Event::Error(&e));
+                ctx.summary.record_error(&e);
+                return Vec::new();
+            }
+
+            // Folders whose entries are all excluded aren't walked into at all
+            let options = walk::Options {
+                follow_symlinks: ctx.follow_symlinks,
+                skip_hidden_dirs: ctx.skip_hidden_dirs,
+                max_depth: ctx.max_depth,
+                prune: ctx
+                    .with_rules(ctx.rules_for(Some(dir)).as_deref())
+                    .matcher
+                    .prune(dir, ctx.relative),
+                mounts: ctx.mounts.cloned(),
+                verbose: ctx.verbose,
+                reporter: Arc::clone(ctx.reporter),
+            };
+
+            let entries: Box<dyn Iterator<Item = Result<walk::Entry>>> =
+                match since.and_then(|since| since.changed(dir)) {
+                    Some(changed) => Box::new(walk::walk_only(dir, changed, options)),
+                    None => Box::new(walk::walk(dir, options)),
+                };
+            entries
+                // If there's an error, print it out and leave it out.
+                .filter_map(|entry| {
+                    entry
+                        .inspect_err(|e| {
+                            ctx.reporter.report(&output::Event::Error(e));
+                            ctx.summary.record_error(&e);
+                        })
+                        .ok()
+                })
+                .inspect(|entry| {
+                    if entry.is_dir {
+                        ctx.summary.record_directory();
+                    }
+                    ctx.summary.record_scanned();
+                })
+                .map(|entry| (Some(dir), entry))
+                .collect()
         })
-        .filter(|dir| filter::file_type_matches(&dir.path(), types, verbose))
-        .filter(|dir| filter::path_matches_pattern(&dir.path(), matcher, verbose))
-        .for_each(|entry| {
-            // If the test flag is set, then print out the path of the file or folder to hide.
-            // Otherwise, hide the file or folder.
-            if test {
-                println!("Would hide {}", entry.path().display());
-            } else {
-                if verbose {
-                    println!("Hiding {}", entry.path().display());
+        .collect();
+
+    // Count up the entries in each directory, for preferring parents.
+    let mut totals: HashMap<PathBuf, usize> = HashMap::new();
+    let mut roots: HashSet<PathBuf> = HashSet::new();
+    if ctx.prefer_parent.is_some() {
+        for (_, entry) in &entries {
+            if entry.depth == 0 {
+                roots.insert(entry.path.clone());
+            } else if let Some(parent) = entry.path.parent() {
+                *totals.entry(parent.to_path_buf()).or_default() += 1;
+            }
+        }
+    }
+
+    Found {
+        entries,
+        totals,
+        roots,
+    }
+}
+
+// Gather up the listed paths, reading what each one is as it's found, and reporting any that can't be read. They're
+// all treated as entries at depth 0, without a root.
+fn listed<'a>(paths: &[PathBuf], ctx: &context::RunContext) -> Found<'a> {
+    let entries = paths
+        .par_iter()
+        .filter_map(|path| {
+            let metadata = filesystem::reachable(path).and_then(|reachable| {
+                reachable
+                    .symlink_metadata()
+                    .with_context(|| format!("Failed to get metadata for {}", path.display()))
+            });
+            match metadata {
+                Ok(metadata) => {
+                    ctx.summary.record_scanned();
+                    let entry = walk::Entry {
+                        path: path.clone(),
+                        depth: 0,
+                        is_dir: metadata.is_dir(),
+                        object_type: Some(filesystem::type_of(path, metadata.file_type())),
+                        metadata: Some(metadata),
+                    };
+                    Some((None, entry))
+                }
+                Err(e) => {
+                    ctx.reporter.report(&output::Event::Error(&e));
+                    ctx.summary.record_error(&e);
+                    None
                 }
-                filesystem::hide(&entry.path()).unwrap_or_else(|e| eprintln!("{e}"));
             }
+        })
+        .collect();
+
+    Found {
+        entries,
+        totals: HashMap::new(),
+        roots: HashSet::new(),
+    }
+}
+
+// Run every entry through the shared pipeline in parallel, keeping only what it decides to hide. Depths are only
+// checked for entries found under a root.
+fn filter<'a>(
+    entries: Vec<(Option<&'a Path>, walk::Entry)>,
+    cutoff: Option<SystemTime>,
+    ctx: &context::RunContext,
+) -> Vec<(Option<&'a Path>, filesystem::Info)> {
+    entries
+        .into_par_iter()
+        .filter_map(|(root, entry)| {
+            let info = filesystem::Info::with(entry.path, entry.object_type, entry.metadata);
+            let depth = root.map(|_| entry.depth);
+            pipeline::process(info, root, depth, cutoff, ctx).map(|info| (root, info))
+        })
+        .collect()
+}
+
+// Plan out what to change. Preferring parents needs every match batched up by directory, so it's batched then too.
+fn plan<'a>(
+    matches: Vec<(Option<&'a Path>, filesystem::Info)>,
+    totals: &HashMap<PathBuf, usize>,
+    roots: &HashSet<PathBuf>,
+    cutoff: Option<SystemTime>,
+    ctx: &context::RunContext,
+) -> Plan<'a> {
+    if !ctx.batch && ctx.prefer_parent.is_none() {
+        return Plan::Each(matches);
+    }
+
+    let mut batches: BTreeMap<PathBuf, Vec<filesystem::Info>> = BTreeMap::new();
+    for (_, info) in matches {
+        let parent = info.path().parent().unwrap_or(Path::new("")).to_path_buf();
+        batches.entry(parent).or_default().push(info);
+    }
+    if let Some(threshold) = ctx.prefer_parent {
+        prefer_parents(&mut batches, totals, roots, threshold, cutoff, ctx);
+    }
+    Plan::Batches(batches)
+}
+
+// Carry out the plan, returning how many paths were, or would be, changed. The deepest paths are done first, so that
+// hiding a folder never moves entries that haven't been hidden yet. Paths at the same depth are independent, so those
+// are done in parallel.
+fn execute(plan: Plan, roots: &[impl AsRef<Path> + Sync], ctx: &context::RunContext) -> usize {
+    let matches = match plan {
+        Plan::Each(matches) => matches,
+        Plan::Batches(batches) => return hide_batches(batches, roots, ctx),
+    };
+
+    let mut levels: BTreeMap<usize, Vec<(Option<&Path>, filesystem::Info)>> = BTreeMap::new();
+    for (root, info) in matches {
+        levels
+            .entry(info.path().components().count())
+            .or_default()
+            .push((root, info));
+    }
+    levels
+        .into_values()
+        .rev()
+        .map(|level| {
+            level
+                .into_par_iter()
+                .filter(|(root, info)| pipeline::hide(info, *root, ctx))
+                .count()
+        })
+        .sum()
+}
+
+// Helper function to get the time anything modified after hasn't settled yet, if settling was asked for.
+fn cutoff(ctx: &context::RunContext) -> Option<SystemTime> {
+    ctx.settle.map(|settle| {
+        SystemTime::now()
+            .checked_sub(settle)
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+    })
+}
+
+// Helper function to run a phase of the search, then record how many entries it ended up with and how long it took.
+// They're reported too, if verbose is set.
+fn phase<T>(
+    phase: summary::Phase,
+    ctx: &context::RunContext,
+    run: impl FnOnce() -> T,
+    count: impl FnOnce(&T) -> usize,
+) -> T {
+    let start = Instant::now();
+    let result = run();
+    let (count, elapsed) = (count(&result), start.elapsed());
+    ctx.summary.record_phase(phase, count, elapsed);
+    if ctx.verbose {
+        ctx.reporter.report(&output::Event::Phase {
+            phase,
+            count,
+            elapsed,
         });
-    });
+    }
+    result
+}
+
+// Replace the matches in any directory where at least the threshold fraction of its entries match
+// with the directory itself. The deepest directories are done first, so a directory that is hidden
+// itself counts as a match in its parent, and can be folded into it in turn. The roots are never
+// hidden in place of their entries. The directory is run through the pipeline the same as any
+// other match, at its own depth below its root, with only the patterns that exclude things, so
+// it's left alone, and its matching entries hidden instead, if it couldn't be hidden on its own.
+fn prefer_parents(
+    batches: &mut BTreeMap<PathBuf, Vec<filesystem::Info>>,
+    totals: &HashMap<PathBuf, usize>,
+    roots: &HashSet<PathBuf>,
+    threshold: f64,
+    cutoff: Option<SystemTime>,
+    ctx: &context::RunContext,
+) {
+    let root_paths: Vec<&PathBuf> = roots.iter().collect();
+    let mut dirs: Vec<PathBuf> = batches.keys().cloned().collect();
+    dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+
+    let mut index = 0;
+    while index < dirs.len() {
+        let dir = dirs[index].clone();
+        index += 1;
+
+        let (Some(matches), Some(total)) = (batches.get(&dir), totals.get(&dir)) else {
+            continue;
+        };
+        if roots.contains(&dir) || (matches.len() as f64) < threshold * *total as f64 {
+            continue;
+        }
+        let Some(parent) = dir.parent() else {
+            continue;
+        };
+
+        // Check the directory could be hidden on its own. Its rules are the ones for its root, so they're settled
+        // before its include patterns are left out.
+        let root = pipeline::root_of(&dir, &root_paths);
+        let depth = root.map(|root| {
+            dir.components()
+                .count()
+                .saturating_sub(root.components().count())
+        });
+        let rules = ctx.rules_for(root);
+        let rooted = ctx.with_rules(rules.as_deref());
+        let matcher = rooted.matcher.for_parents();
+        let parent_ctx = context::RunContext {
+            matcher: &matcher,
+            types: rooted.types,
+            root_rules: None,
+            ..*ctx
+        };
+        let Some(info) = pipeline::process(
+            filesystem::Info::new(dir.clone()),
+            root,
+            depth,
+            cutoff,
+            &parent_ctx,
+        ) else {
+            continue;
+        };
+
+        if ctx.verbose {
+            ctx.reporter.report(&output::Event::Info(format!(
+                "Preferring {} over {} of its {total} entries",
+                dir.display(),
+                matches.len()
+            )));
+        }
+
+        // Drop the directory's own matches, and any left over from deeper inside it.
+        batches.retain(|batch, _| !batch.starts_with(&dir));
+
+        // Then add it to its parent's matches, and check the parent after the rest of this level if
+        // it isn't already due to be.
+        let siblings = batches.entry(parent.to_path_buf()).or_default();
+        if !siblings.iter().any(|sibling| sibling.path() == dir) {
+            siblings.push(info);
+        }
+        if !dirs[index..].iter().any(|d| d == parent) {
+            let depth = parent.components().count();
+            let position = dirs[index..]
+                .iter()
+                .position(|d| d.components().count() <= depth)
+                .map_or(dirs.len(), |position| index + position);
+            dirs.insert(position, parent.to_path_buf());
+        }
+    }
+}
+
+// Hide the gathered batches of matches, one directory at a time, returning how many were, or would
+// be, changed. The deepest directories are done first, so that hiding a folder never moves entries
+// that haven't been hidden yet. Directories at the same depth are independent, so those are done
+// in parallel.
+fn hide_batches(
+    batches: BTreeMap<PathBuf, Vec<filesystem::Info>>,
+    roots: &[impl AsRef<Path> + Sync],
+    ctx: &context::RunContext,
+) -> usize {
+    // Group the directories by depth
+    let mut levels: BTreeMap<usize, Vec<(PathBuf, Vec<filesystem::Info>)>> = BTreeMap::new();
+    for (dir, paths) in batches {
+        levels
+            .entry(dir.components().count())
+            .or_default()
+            .push((dir, paths));
+    }
+
+    let mut changed = 0;
+    for (_, level) in levels.into_iter().rev() {
+        changed += level
+            .into_par_iter()
+            .map(|(dir, paths)| {
+                let hidden = paths
+                    .iter()
+                    .filter(|info| pipeline::hide(info, pipeline::root_of(info.path(), roots), ctx))
+                    .count();
+                // The parent of a bare file name is empty, which is the current directory.
+                let dir = if dir.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    &dir
+                };
+                ctx.reporter.report(&output::Event::Batch {
+                    action: match (ctx.test, ctx.unhide) {
+                        (true, false) => report::Action::WouldHide,
+                        (false, false) => report::Action::Hidden,
+                        (true, true) => report::Action::WouldUnhide,
+                        (false, true) => report::Action::Unhidden,
+                    },
+                    changed: hidden,
+                    total: paths.len(),
+                    dir,
+                });
+                hidden
+            })
+            .sum::<usize>();
+
+        // Restore the directory times once the whole level is done.
+        if let Some(dir_times) = ctx.dir_times {
+            dir_times.restore().unwrap_or_else(|e| {
+                ctx.reporter.report(&output::Event::Error(&e));
+                ctx.summary.record_error(&e);
+            });
+        }
+    }
+    changed
+}
+
+// Helper function to turn the bytes of a listed path back into a path. They're taken as they are on Unix, where paths
+// are just bytes.
+#[cfg(target_family = "unix")]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+// Helper function to turn the bytes of a listed path back into a path. They should be UTF-8 on Windows, so anything
+// that isn't is replaced.
+#[cfg(target_family = "windows")]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
 }
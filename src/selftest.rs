@@ -0,0 +1,478 @@
+use crate::{actions, context, filesystem, filter, matcher, output, search, summary};
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+#[cfg(feature = "watch")]
+use std::time::Duration;
+
+// How long to give the watcher to start before writing anything, and how long to give it to handle what was written
+// before stopping it, in scenarios that watch
+#[cfg(feature = "watch")]
+const WATCH_STARTUP: Duration = Duration::from_millis(500);
+#[cfg(feature = "watch")]
+const WATCH_HANDLING: Duration = Duration::from_secs(3);
+
+// A scenario to run against a directory of its own. The entries are created first, where names ending in a slash are
+// folders, along with any symlinks, given as (link, target) pairs. Then the directory is searched with the scenario's
+// options, twice, so that running again can be checked to change nothing. Afterwards, every entry in hidden should be
+// hidden, and every entry in visible should be visible, both given by the names they had before hiding. Scenarios that
+// are expected to leave errors or conflicts behind are expected to leave them on the second run too. Scenarios that
+// watch the directory instead are only run once, writing each of the written files a piece at a time while watching,
+// given as (name, size) pairs, as a download or copy would.
+#[derive(Default)]
+struct Scenario {
+    name: &'static str,
+    entries: &'static [&'static str],
+    symlinks: &'static [(&'static str, &'static str)],
+    hidden_before: &'static [&'static str],
+    globs: &'static [&'static str],
+    globs_exclude: &'static [&'static str],
+    regexes: &'static [&'static str],
+    regexes_exclude: &'static [&'static str],
+    exclude_dirs: &'static [&'static str],
+    ignore_case: bool,
+    min_size: Option<u64>,
+    unhide: bool,
+    watch: bool,
+    #[cfg_attr(not(feature = "watch"), allow(dead_code))]
+    written: &'static [(&'static str, usize)],
+    only_unix: bool,
+    on_conflict: filesystem::OnConflict,
+    expect_errors: bool,
+    expect_conflicts: bool,
+    hidden: &'static [&'static str],
+    visible: &'static [&'static str],
+}
+
+// The outcome of running a single scenario
+enum Outcome {
+    Passed,
+    Failed(String),
+    Skipped(&'static str),
+}
+
+// Every scenario that is run, covering pattern precedence, pruning, unicode names, symlinks, unhiding and collisions.
+fn scenarios() -> Vec<Scenario> {
+    vec![
+        Scenario {
+            name: "no patterns hide everything",
+            entries: &["file", "folder/"],
+            hidden: &["file", "folder"],
+            ..Default::default()
+        },
+        Scenario {
+            name: "glob patterns hide only matches",
+            entries: &["a.log", "b.txt"],
+            globs: &["*.log"],
+            hidden: &["a.log"],
+            visible: &["b.txt"],
+            ..Default::default()
+        },
+        Scenario {
+            name: "globs and regexes both hide matches",
+            entries: &["a.log", "b.tmp", "c.txt"],
+            globs: &["*.log"],
+            regexes: &[r"\.tmp$"],
+            hidden: &["a.log", "b.tmp"],
+            visible: &["c.txt"],
+            ..Default::default()
+        },
+        Scenario {
+            name: "glob excludes take precedence over globs",
+            entries: &["a.log", "keep.log"],
+            globs: &["*.log"],
+            globs_exclude: &["*keep*"],
+            hidden: &["a.log"],
+            visible: &["keep.log"],
+            ..Default::default()
+        },
+        Scenario {
+            name: "glob excludes take precedence over regexes",
+            entries: &["a.tmp", "keep.tmp"],
+            regexes: &[r"\.tmp$"],
+            globs_exclude: &["*keep*"],
+            hidden: &["a.tmp"],
+            visible: &["keep.tmp"],
+            ..Default::default()
+        },
+        Scenario {
+            name: "regex excludes take precedence over globs",
+            entries: &["a.log", "keep.log"],
+            globs: &["*.log"],
+            regexes_exclude: &["keep"],
+            hidden: &["a.log"],
+            visible: &["keep.log"],
+            ..Default::default()
+        },
+        Scenario {
+            name: "excluded folders are pruned",
+            entries: &["a.log", "node_modules/", "node_modules/b.log"],
+            globs: &["*.log"],
+            globs_exclude: &["**/node_modules", "**/node_modules/**"],
+            hidden: &["a.log"],
+            visible: &["node_modules", "node_modules/b.log"],
+            ..Default::default()
+        },
+        Scenario {
+            name: "excluded folders are left alone at any depth",
+            entries: &[
+                "a.log",
+                "node_modules/",
+                "node_modules/b.log",
+                "src/",
+                "src/build/",
+                "src/build/c.log",
+            ],
+            globs: &["*.log", "*/build"],
+            exclude_dirs: &["build", "node_modules"],
+            hidden: &["a.log"],
+            visible: &["node_modules/b.log", "src/build", "src/build/c.log"],
+            ..Default::default()
+        },
+        Scenario {
+            name: "unicode names are matched",
+            entries: &["café.log", "日本語.log", "naïve.txt"],
+            globs: &["*.log"],
+            hidden: &["café.log", "日本語.log"],
+            visible: &["naïve.txt"],
+            ..Default::default()
+        },
+        Scenario {
+            name: "ignoring case matches any case",
+            entries: &["REPORT.LOG", "report.txt"],
+            globs: &["*report.log"],
+            ignore_case: true,
+            hidden: &["REPORT.LOG"],
+            visible: &["report.txt"],
+            ..Default::default()
+        },
+        Scenario {
+            name: "symlinks are hidden themselves, not followed",
+            entries: &["real/", "real/a.log"],
+            symlinks: &[("link", "real"), ("shortcut.log", "real/a.log")],
+            globs: &["*.log"],
+            hidden: &["real/a.log", "shortcut.log"],
+            visible: &["link"],
+            ..Default::default()
+        },
+        Scenario {
+            name: "unhiding restores only matching names",
+            entries: &["a.log", "b.txt"],
+            hidden_before: &["a.log", "b.txt"],
+            globs: &["*.log"],
+            unhide: true,
+            hidden: &["b.txt"],
+            visible: &["a.log"],
+            ..Default::default()
+        },
+        Scenario {
+            name: "unhiding never overwrites an existing entry",
+            entries: &[".x.log", "x.log"],
+            globs: &["*.log"],
+            unhide: true,
+            only_unix: true,
+            expect_errors: true,
+            hidden: &[".x.log"],
+            visible: &["x.log"],
+            ..Default::default()
+        },
+        Scenario {
+            name: "hiding never overwrites an existing hidden entry",
+            entries: &[".x.log", "x.log"],
+            globs: &["*.log"],
+            only_unix: true,
+            expect_conflicts: true,
+            hidden: &[".x.log"],
+            visible: &["x.log"],
+            ..Default::default()
+        },
+        Scenario {
+            name: "hiding over an existing hidden entry adds a suffix",
+            entries: &[".x.log", ".x.log.1", "x.log"],
+            globs: &["*.log"],
+            only_unix: true,
+            on_conflict: filesystem::OnConflict::Suffix,
+            hidden: &[".x.log", ".x.log.1", "x.log.2"],
+            ..Default::default()
+        },
+        Scenario {
+            name: "watching checks the size of new files once they're written",
+            watch: true,
+            written: &[("big.bin", 64 * 1024), ("small.bin", 16)],
+            min_size: Some(1024),
+            hidden: &["big.bin"],
+            visible: &["small.bin"],
+            ..Default::default()
+        },
+    ]
+}
+
+// Run every scenario in a temporary tree inside dir, or the system temp directory if it isn't given, and print out
+// whether each passed. The tree is removed afterwards unless keep is set. Returns an exit code of 1 if any failed.
+pub fn run(dir: Option<PathBuf>, keep: bool) -> Result<ExitCode> {
+    let base = dir
+        .unwrap_or_else(std::env::temp_dir)
+        .join(format!("cloak-selftest-{}", std::process::id()));
+    fs::create_dir_all(&base)
+        .with_context(|| format!("Failed to create self-test directory {}", base.display()))?;
+    println!("Running self-test in {}", base.display());
+
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for (index, scenario) in scenarios().iter().enumerate() {
+        let outcome = run_scenario(scenario, &base.join(index.to_string()))
+            .unwrap_or_else(|e| Outcome::Failed(format!("{e:#}")));
+        match outcome {
+            Outcome::Passed => {
+                passed += 1;
+                println!("ok       {}", scenario.name);
+            }
+            Outcome::Failed(reason) => {
+                failed += 1;
+                println!("FAILED   {}: {reason}", scenario.name);
+            }
+            Outcome::Skipped(reason) => {
+                skipped += 1;
+                println!("skipped  {}: {reason}", scenario.name);
+            }
+        }
+    }
+    println!("{passed} passed, {failed} failed, {skipped} skipped");
+
+    if keep {
+        println!("Kept self-test directory {}", base.display());
+    } else {
+        fs::remove_dir_all(&base)
+            .with_context(|| format!("Failed to remove self-test directory {}", base.display()))?;
+    }
+
+    if failed > 0 {
+        Ok(ExitCode::FAILURE)
+    } else {
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+// Set up a scenario in its directory, run it twice through the real search and hiding pipeline, and check the results.
+fn run_scenario(scenario: &Scenario, dir: &Path) -> Result<Outcome> {
+    if scenario.only_unix && !cfg!(target_family = "unix") {
+        return Ok(Outcome::Skipped("only applies where hiding renames"));
+    }
+    let uses_regexes = !scenario.regexes.is_empty() || !scenario.regexes_exclude.is_empty();
+    if uses_regexes && !cfg!(feature = "regex") {
+        return Ok(Outcome::Skipped("built without regex support"));
+    }
+    if scenario.watch && !cfg!(feature = "watch") {
+        return Ok(Outcome::Skipped("built without watching"));
+    }
+
+    // Create the entries, then the symlinks, then hide anything that should start out hidden.
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    for entry in scenario.entries {
+        let path = dir.join(entry);
+        if entry.ends_with('/') {
+            fs::create_dir_all(&path)
+        } else {
+            fs::File::create(&path).map(|_| ())
+        }
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    }
+    for (link, target) in scenario.symlinks {
+        if symlink(&dir.join(target), &dir.join(link)).is_err() {
+            return Ok(Outcome::Skipped("symlinks can't be created here"));
+        }
+    }
+    for entry in scenario.hidden_before {
+        filesystem::hide(
+            &filesystem::Info::new(dir.join(entry)),
+            None,
+            filesystem::OnConflict::Skip,
+        )?;
+    }
+
+    // Build the matcher the same way as the command line does.
+    let patterns = |patterns: &[&str]| {
+        (!patterns.is_empty()).then(|| patterns.iter().map(|p| p.to_string()).collect())
+    };
+    let matcher = matcher::Matcher::new(
+        patterns(scenario.globs),
+        patterns(scenario.globs_exclude),
+        patterns(scenario.regexes),
+        patterns(scenario.regexes_exclude),
+        patterns(scenario.exclude_dirs),
+        if scenario.unhide {
+            matcher::MatchTarget::Original
+        } else {
+            matcher::MatchTarget::Current
+        },
+        matcher::MatchOn::Path,
+        scenario.ignore_case,
+        false,
+    )?;
+
+    // Run the scenario twice, or once if it watches. The second run should find nothing left to do. Only errors are
+    // written out, so they can be seen alongside the result.
+    let reporter = output::reporter(
+        output::OutputFormat::Quiet,
+        false,
+        None,
+        output::ColorChoice::Never,
+    );
+    for run in 0..if scenario.watch { 1 } else { 2 } {
+        let summary = summary::Summary::default();
+        let ctx = context::RunContext {
+            matcher: &matcher,
+            types: None,
+            min_size: scenario.min_size,
+            max_size: None,
+            older_than: None,
+            newer_than: None,
+            requires_sibling: None,
+            git: None,
+            mimes: None,
+            scanner: None,
+            ownership: None,
+            empty: None,
+            settle: None,
+            relative: false,
+            min_depth: 1,
+            max_depth: usize::MAX,
+            include_root: filter::IncludeRoot::Never,
+            actions: actions::Actions::default(),
+            follow_symlinks: false,
+            skip_hidden_dirs: false,
+            deref: false,
+            deref_within: None,
+            mounts: None,
+            test: false,
+            force: false,
+            unhide: scenario.unhide,
+            verbose: false,
+            include_ads: false,
+            batch: false,
+            prefer_parent: None,
+            op_timeout: None,
+            on_conflict: scenario.on_conflict,
+            max_operations: None,
+            dir_times: None,
+            report: None,
+            journal: None,
+            exemptions: None,
+            unhiders: None,
+            vault: None,
+            limit: None,
+            verifier: None,
+            prompt: None,
+            root_rules: None,
+            cooldown: None,
+            summary: &summary,
+            reporter: &reporter,
+        };
+        if scenario.watch {
+            watch(scenario, dir, &ctx)?;
+        } else {
+            search::search(&[dir.to_path_buf()], &ctx);
+        }
+
+        if (summary.errors() > 0) != scenario.expect_errors {
+            return Ok(Outcome::Failed(format!(
+                "{} errors on run {}",
+                summary.errors(),
+                run + 1
+            )));
+        }
+        let leaves_work = scenario.expect_errors || scenario.expect_conflicts;
+        if run == 1 && !leaves_work && !summary.no_changes_needed() {
+            return Ok(Outcome::Failed(
+                "the second run changed something".to_owned(),
+            ));
+        }
+    }
+
+    // Check everything ended up as expected.
+    for entry in scenario.hidden {
+        let path = dir.join(entry);
+        let hidden = filesystem::hidden_path(&path);
+        let moved = hidden == path || path.symlink_metadata().is_err();
+        if hidden.symlink_metadata().is_err() || !filesystem::is_hidden(&hidden)? || !moved {
+            return Ok(Outcome::Failed(format!("{entry} should be hidden")));
+        }
+    }
+    for entry in scenario.visible {
+        let path = dir.join(entry);
+        if path.symlink_metadata().is_err() || filesystem::is_hidden(&path)? {
+            return Ok(Outcome::Failed(format!("{entry} should be visible")));
+        }
+    }
+    Ok(Outcome::Passed)
+}
+
+// Helper function to watch a scenario's directory while its files are written, then stop watching once they've had time
+// to be handled. Watching can only be stopped once, so only one scenario can watch.
+#[cfg(feature = "watch")]
+fn watch(scenario: &Scenario, dir: &Path, ctx: &context::RunContext) -> Result<()> {
+    use std::io::Write;
+
+    let paths = [dir.to_string_lossy().into_owned()];
+    let options = crate::watcher::WatchOptions {
+        priority: None,
+        poll_interval: None,
+        roots_per_shard: None,
+        rescan_interval: None,
+        stats: false,
+        wait_for_write: None,
+        failure_cooldown: Duration::from_secs(60),
+        jobs: 1,
+        queue: 1000,
+        control: None,
+        print_events: false,
+    };
+    std::thread::scope(|scope| {
+        let watching = scope.spawn(|| crate::watcher::watch(&paths, ctx, &options));
+        std::thread::sleep(WATCH_STARTUP);
+
+        // Write each file in a few pieces, so it's created empty and grows from there, a while after it's created.
+        let written = scenario.written.iter().try_for_each(|(name, size)| {
+            let path = dir.join(name);
+            let mut file = fs::File::create(&path)
+                .with_context(|| format!("Failed to create {}", path.display()))?;
+            for _ in 0..4 {
+                std::thread::sleep(Duration::from_millis(200));
+                file.write_all(&vec![0; size / 4])
+                    .and_then(|()| file.flush())
+                    .with_context(|| format!("Failed to write {}", path.display()))?;
+            }
+            Ok(())
+        });
+
+        std::thread::sleep(WATCH_HANDLING);
+        crate::watcher::stop();
+        let watched = watching
+            .join()
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("Watching panicked")));
+        written.and(watched)
+    })
+}
+
+// Scenarios can only watch when built with watching, and are skipped before getting here otherwise.
+#[cfg(not(feature = "watch"))]
+fn watch(_: &Scenario, _: &Path, _: &context::RunContext) -> Result<()> {
+    Ok(())
+}
+
+// Helper function to create a symlink to a file or folder.
+#[cfg(target_family = "unix")]
+fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+// Helper function to create a symlink to a file or folder. Windows needs to know which it is, and creating them needs
+// developer mode or administrator rights.
+#[cfg(target_family = "windows")]
+fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    if target.is_dir() {
+        std::os::windows::fs::symlink_dir(target, link)
+    } else {
+        std::os::windows::fs::symlink_file(target, link)
+    }
+}
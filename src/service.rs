@@ -0,0 +1,581 @@
+use anyhow::{bail, Context, Result};
+use clap::Subcommand;
+use serde_json::Value;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+// Enum of what to do with the background service
+#[derive(Debug, Subcommand)]
+pub enum ServiceCommand {
+    /// Register a service that runs cloak daemon in the background: a systemd user unit started with the user's
+    /// session on Linux, or a service started with the system on Windows, which needs an administrator. Everything
+    /// after -- is passed on to the daemon, after the options in its config file.
+    Install {
+        /// Name of the service, so several can be installed with different options.
+        /// (default: cloak)
+        #[clap(long)]
+        name: Option<String>,
+
+        /// Config file for the daemon to read its options from.
+        /// (default: the daemon's default config file)
+        #[clap(long)]
+        config: Option<PathBuf>,
+
+        /// Options and paths to watch with.
+        #[clap(last = true)]
+        args: Vec<String>,
+    },
+
+    /// Remove a service registered with install.
+    Uninstall {
+        /// Name of the service.
+        /// (default: cloak)
+        #[clap(long)]
+        name: Option<String>,
+    },
+}
+
+// The pid of a running watcher, written to a file for service managers and scripts to find it by, and removed again
+// once it stops. Only the watcher writes one, so builds without it never do.
+#[cfg_attr(not(feature = "watch"), allow(dead_code))]
+#[derive(Debug)]
+pub struct Pidfile {
+    path: PathBuf,
+}
+
+#[cfg_attr(not(feature = "watch"), allow(dead_code))]
+impl Pidfile {
+    // Write the pid of this process to the given file, replacing whatever was in it.
+    pub fn create(path: PathBuf) -> Result<Self> {
+        fs::write(&path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("Failed to write pidfile {}", path.display()))?;
+        Ok(Self { path })
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Install or uninstall the service.
+pub fn run(command: ServiceCommand) -> Result<ExitCode> {
+    match command {
+        ServiceCommand::Install { name, config, args } => {
+            install(name.as_deref().unwrap_or("cloak"), config.as_deref(), &args)?
+        }
+        ServiceCommand::Uninstall { name } => uninstall(name.as_deref().unwrap_or("cloak"))?,
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+// Read the options in a daemon's config file as command line arguments, returning the options and the paths to watch
+// apart, so the paths can be given after everything else. The file is a JSON object of options by their long names,
+// such as {"pattern": ["*.tmp"], "recursive": true, "path": ["/home/me/Downloads"]}. Flags are given as true or false,
+// options that can be given more than once as arrays, and the paths to watch as path. Daemons always watch, so watch
+// is left out.
+pub fn config_args(path: &Path) -> Result<(Vec<OsString>, Vec<OsString>)> {
+    let contents =
+        fs::read(path).with_context(|| format!("Failed to read config file {}", path.display()))?;
+    let options: serde_json::Map<String, Value> = serde_json::from_slice(&contents)
+        .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+
+    let mut args = Vec::new();
+    let mut paths = Vec::new();
+    for (name, value) in options {
+        let values = match value {
+            Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                Value::Bool(false) | Value::Null => continue,
+                Value::Bool(true) => None,
+                Value::String(value) => Some(value),
+                Value::Number(value) => Some(value.to_string()),
+                value => bail!(
+                    "Option {name} in config file {} can't be {value}",
+                    path.display()
+                ),
+            };
+            let option = format!("--{}", name.replace('_', "-"));
+            match (name.as_str(), value) {
+                ("watch", _) => {}
+                ("path", Some(value)) => paths.push(value.into()),
+                ("path", None) => bail!(
+                    "Paths in config file {} have to be given as strings",
+                    path.display()
+                ),
+                (_, Some(value)) => args.push(format!("{option}={value}").into()),
+                (_, None) => args.push(option.into()),
+            }
+        }
+    }
+    Ok((args, paths))
+}
+
+// Returns where a daemon's config file is kept by default: cloak/daemon.json in the user's config directory.
+pub fn default_config() -> Result<PathBuf> {
+    Ok(config_dir()?.join("cloak").join("daemon.json"))
+}
+
+// Returns where a daemon writes its pidfile by default, named after its service: the user's runtime directory, if
+// there is one, or the temp directory otherwise.
+pub fn default_pidfile(name: &str) -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join(format!("{name}.pid"))
+}
+
+// Linux only function to detach from the terminal, carrying on in the background with stdin, stdout and stderr pointed
+// at /dev/null. It has to happen before anything starts any threads, which don't survive the fork. The current
+// directory is kept, so relative paths in the options still work.
+#[cfg(target_os = "linux")]
+pub fn detach() -> Result<()> {
+    nix::unistd::daemon(true, false).with_context(|| "Failed to detach from the terminal")
+}
+
+// Windows only function to detach, which the service manager does by running the daemon as a service, so there's
+// nothing more to do.
+#[cfg(target_family = "windows")]
+pub fn detach() -> Result<()> {
+    Ok(())
+}
+
+// Detaching is only supported on Linux and Windows so far.
+#[cfg(not(any(target_os = "linux", target_family = "windows")))]
+pub fn detach() -> Result<()> {
+    bail!("Detaching is only supported on Linux so far, so run cloak daemon with --foreground")
+}
+
+// Run the daemon. Nothing else runs it but the Windows service manager, so it just runs.
+#[cfg(not(target_family = "windows"))]
+pub fn serve(
+    _name: &str,
+    run: impl FnOnce() -> Result<ExitCode> + Send + 'static,
+) -> Result<ExitCode> {
+    run()
+}
+
+// Windows only function to run the daemon as the service with the given name, if the service manager started it,
+// telling it the service is running, and stopping the watcher when it asks. Run by hand, it just runs.
+#[cfg(target_family = "windows")]
+pub fn serve(
+    name: &str,
+    run: impl FnOnce() -> Result<ExitCode> + Send + 'static,
+) -> Result<ExitCode> {
+    windows::serve(name, Box::new(run))
+}
+
+// Write a systemd user unit that runs cloak daemon with the given config file and arguments, from the current
+// directory. It stays in the foreground, as systemd expects, and logs to the journal.
+#[cfg(target_os = "linux")]
+fn install(name: &str, config: Option<&Path>, args: &[String]) -> Result<()> {
+    let exe = std::env::current_exe().with_context(|| "Failed to find the cloak executable")?;
+    let dir = std::env::current_dir().with_context(|| "Failed to get the current directory")?;
+    let exec = daemon_args(exe, name, config, args, true)
+        .iter()
+        .map(|arg| quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let path = unit_path(name)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+    }
+    fs::write(
+        &path,
+        format!(
+            "[Unit]\n\
+             Description=Hide files and folders with cloak\n\n\
+             [Service]\n\
+             ExecStart={exec}\n\
+             ExecReload=/bin/kill -HUP $MAINPID\n\
+             WorkingDirectory={}\n\
+             Restart=on-failure\n\n\
+             [Install]\n\
+             WantedBy=default.target\n",
+            dir.to_string_lossy().replace('%', "%%")
+        ),
+    )
+    .with_context(|| format!("Failed to write service {}", path.display()))?;
+
+    println!("Installed {}", path.display());
+    println!("Start it now and at every login with: systemctl --user enable --now {name}");
+    Ok(())
+}
+
+// Remove a systemd user unit written by install. It's up to the user to stop it first, as with any other unit.
+#[cfg(target_os = "linux")]
+fn uninstall(name: &str) -> Result<()> {
+    let path = unit_path(name)?;
+    match fs::remove_file(&path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            bail!("There's no service called {name} at {}", path.display())
+        }
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to remove service {}", path.display()))
+        }
+    }
+
+    println!("Removed {}", path.display());
+    println!("Stop it if it's still running with: systemctl --user disable --now {name}");
+    Ok(())
+}
+
+// Windows only function to register a service that runs cloak daemon with the given config file and arguments,
+// started with the system. Services don't run as the user, or from the current directory, so the config file is always
+// given by its full path, and so should any paths in the arguments.
+#[cfg(target_family = "windows")]
+fn install(name: &str, config: Option<&Path>, args: &[String]) -> Result<()> {
+    let exe = std::env::current_exe().with_context(|| "Failed to find the cloak executable")?;
+    let config = match config {
+        Some(config) => crate::filesystem::absolute(config),
+        None => default_config()?,
+    };
+    let command = daemon_args(exe, name, Some(&config), args, false)
+        .iter()
+        .map(|arg| quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+    windows::install(name, &command)?;
+
+    println!("Installed service {name}");
+    println!("Start it now with: sc start {name}");
+    Ok(())
+}
+
+// Windows only function to remove a service registered with install. It's up to the user to stop it first, as with
+// any other service.
+#[cfg(target_family = "windows")]
+fn uninstall(name: &str) -> Result<()> {
+    windows::uninstall(name)?;
+
+    println!("Removed service {name}");
+    println!("Stop it if it's still running with: sc stop {name}");
+    Ok(())
+}
+
+// Services are only registered with systemd and the Windows service manager so far.
+#[cfg(not(any(target_os = "linux", target_family = "windows")))]
+fn install(_: &str, _: Option<&Path>, _: &[String]) -> Result<()> {
+    bail!("Installing a service is only supported on Linux with systemd, and on Windows, so far")
+}
+
+// Services are only registered with systemd and the Windows service manager so far.
+#[cfg(not(any(target_os = "linux", target_family = "windows")))]
+fn uninstall(_: &str) -> Result<()> {
+    bail!("Uninstalling a service is only supported on Linux with systemd, and on Windows, so far")
+}
+
+// Helper function to build the command line a service runs cloak daemon with.
+#[cfg(any(target_os = "linux", target_family = "windows"))]
+fn daemon_args(
+    exe: PathBuf,
+    name: &str,
+    config: Option<&Path>,
+    args: &[String],
+    foreground: bool,
+) -> Vec<String> {
+    let mut command = vec![
+        exe.to_string_lossy().into_owned(),
+        "daemon".to_owned(),
+        format!("--name={name}"),
+    ];
+    if foreground {
+        command.push("--foreground".to_owned());
+    }
+    if let Some(config) = config {
+        let config = crate::filesystem::absolute(config);
+        command.push(format!("--config={}", config.to_string_lossy()));
+    }
+    if !args.is_empty() {
+        command.push("--".to_owned());
+        command.extend(args.iter().cloned());
+    }
+    command
+}
+
+// Helper function to get the user's config directory, which is XDG_CONFIG_HOME, or ~/.config, on Unix.
+#[cfg(target_family = "unix")]
+fn config_dir() -> Result<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .with_context(|| {
+            "Failed to find the config directory, as neither XDG_CONFIG_HOME nor HOME are set"
+        })
+}
+
+// Helper function to get the user's config directory, which is APPDATA on Windows.
+#[cfg(target_family = "windows")]
+fn config_dir() -> Result<PathBuf> {
+    std::env::var_os("APPDATA")
+        .map(PathBuf::from)
+        .with_context(|| "Failed to find the config directory, as APPDATA isn't set")
+}
+
+// Helper function to get where the systemd user unit for a service is kept.
+#[cfg(target_os = "linux")]
+fn unit_path(name: &str) -> Result<PathBuf> {
+    if name.is_empty() || name.contains(['/', '\\']) {
+        bail!("Invalid service name {name}");
+    }
+    Ok(config_dir()?
+        .join("systemd/user")
+        .join(format!("{name}.service")))
+}
+
+// Helper function to quote an argument for a systemd unit, so spaces, quotes and specifiers in it are kept as they are.
+#[cfg(target_os = "linux")]
+fn quote(arg: &str) -> String {
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    format!("\"{escaped}\"")
+}
+
+// Helper function to quote an argument for a Windows command line, the way programs split it back up again, where
+// backslashes are only escapes right before a quote.
+#[cfg(target_family = "windows")]
+fn quote(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '"']) {
+        return arg.to_owned();
+    }
+    let mut quoted = String::from('"');
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                quoted.extend(std::iter::repeat_n('\\', backslashes * 2 + 1));
+                quoted.push('"');
+                backslashes = 0;
+            }
+            _ => {
+                quoted.extend(std::iter::repeat_n('\\', backslashes));
+                quoted.push(c);
+                backslashes = 0;
+            }
+        }
+    }
+    quoted.extend(std::iter::repeat_n('\\', backslashes * 2));
+    quoted.push('"');
+    quoted
+}
+
+// Talking to the Windows service manager, to register services with it, and to run as one.
+#[cfg(target_family = "windows")]
+mod windows {
+    use anyhow::{Context, Result};
+    use std::ffi::OsStr;
+    use std::io::Error;
+    use std::os::windows::ffi::OsStrExt;
+    use std::process::ExitCode;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Mutex, OnceLock};
+
+    use winapi::{
+        shared::{
+            minwindef::{DWORD, FALSE, LPVOID},
+            winerror::{
+                ERROR_CALL_NOT_IMPLEMENTED, ERROR_FAILED_SERVICE_CONTROLLER_CONNECT, NO_ERROR,
+            },
+        },
+        um::{
+            winnt::{
+                DELETE, LPWSTR, SERVICE_AUTO_START, SERVICE_ERROR_NORMAL, SERVICE_WIN32_OWN_PROCESS,
+            },
+            winsvc::{
+                CloseServiceHandle, CreateServiceW, DeleteService, OpenSCManagerW, OpenServiceW,
+                RegisterServiceCtrlHandlerExW, SetServiceStatus, StartServiceCtrlDispatcherW,
+                SC_HANDLE, SC_MANAGER_CONNECT, SC_MANAGER_CREATE_SERVICE, SERVICE_ACCEPT_SHUTDOWN,
+                SERVICE_ACCEPT_STOP, SERVICE_CONTROL_INTERROGATE, SERVICE_CONTROL_SHUTDOWN,
+                SERVICE_CONTROL_STOP, SERVICE_QUERY_STATUS, SERVICE_RUNNING, SERVICE_STATUS,
+                SERVICE_STATUS_HANDLE, SERVICE_STOPPED, SERVICE_STOP_PENDING, SERVICE_TABLE_ENTRYW,
+            },
+        },
+    };
+
+    // What the service runs, and what that ended up with. The service manager calls back into the service without
+    // anything of its own, so they're kept here, along with the service's name and the handle to report its status to.
+    type Run = Box<dyn FnOnce() -> Result<ExitCode> + Send>;
+    static RUN: Mutex<Option<Run>> = Mutex::new(None);
+    static RESULT: Mutex<Option<Result<ExitCode>>> = Mutex::new(None);
+    static NAME: OnceLock<Vec<u16>> = OnceLock::new();
+    static STATUS: AtomicUsize = AtomicUsize::new(0);
+
+    // How long stopping may take before the service manager gives up on it, in milliseconds
+    const STOP_WAIT: DWORD = 30_000;
+
+    // Closes a handle to the service manager, or a service, when it's dropped
+    struct Handle(SC_HANDLE);
+
+    impl Drop for Handle {
+        fn drop(&mut self) {
+            unsafe { CloseServiceHandle(self.0) };
+        }
+    }
+
+    // Register a service that runs the given command line, started with the system.
+    pub fn install(name: &str, command: &str) -> Result<()> {
+        let manager = open_manager(SC_MANAGER_CREATE_SERVICE)?;
+        let service = unsafe {
+            CreateServiceW(
+                manager.0,
+                wide(name).as_ptr(),
+                wide(&format!("cloak ({name})")).as_ptr(),
+                SERVICE_QUERY_STATUS,
+                SERVICE_WIN32_OWN_PROCESS,
+                SERVICE_AUTO_START,
+                SERVICE_ERROR_NORMAL,
+                wide(command).as_ptr(),
+                std::ptr::null(),
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                std::ptr::null(),
+                std::ptr::null(),
+            )
+        };
+        if service.is_null() {
+            return Err(Error::last_os_error())
+                .with_context(|| format!("Failed to install service {name}"));
+        }
+        drop(Handle(service));
+        Ok(())
+    }
+
+    // Remove a service.
+    pub fn uninstall(name: &str) -> Result<()> {
+        let manager = open_manager(SC_MANAGER_CONNECT)?;
+        let service = unsafe { OpenServiceW(manager.0, wide(name).as_ptr(), DELETE) };
+        if service.is_null() {
+            return Err(Error::last_os_error())
+                .with_context(|| format!("Failed to find service {name}"));
+        }
+        let service = Handle(service);
+        if unsafe { DeleteService(service.0) } == FALSE {
+            return Err(Error::last_os_error())
+                .with_context(|| format!("Failed to remove service {name}"));
+        }
+        Ok(())
+    }
+
+    // Run as the service with the given name, or just run if the service manager didn't start this process.
+    pub fn serve(name: &str, run: Run) -> Result<ExitCode> {
+        *RUN.lock().unwrap_or_else(|e| e.into_inner()) = Some(run);
+        let name = NAME.get_or_init(|| wide(name));
+        let table = [
+            SERVICE_TABLE_ENTRYW {
+                lpServiceName: name.as_ptr(),
+                lpServiceProc: Some(service_main),
+            },
+            SERVICE_TABLE_ENTRYW {
+                lpServiceName: std::ptr::null(),
+                lpServiceProc: None,
+            },
+        ];
+
+        // The dispatcher only returns once the service has stopped
+        if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == FALSE {
+            let e = Error::last_os_error();
+            if e.raw_os_error() != Some(ERROR_FAILED_SERVICE_CONTROLLER_CONNECT as i32) {
+                return Err(e).with_context(|| "Failed to connect to the service manager");
+            }
+            let run = RUN.lock().unwrap_or_else(|e| e.into_inner()).take();
+            return run.map_or(Ok(ExitCode::SUCCESS), |run| run());
+        }
+        RESULT
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+            .unwrap_or(Ok(ExitCode::SUCCESS))
+    }
+
+    // Called by the service manager on a thread of its own to start the service. It says it's running, runs until the
+    // watcher stops, then says it's stopped, and whether it failed.
+    unsafe extern "system" fn service_main(_argc: DWORD, _argv: *mut LPWSTR) {
+        let Some(name) = NAME.get() else {
+            return;
+        };
+        let status =
+            RegisterServiceCtrlHandlerExW(name.as_ptr(), Some(handler), std::ptr::null_mut());
+        if status.is_null() {
+            return;
+        }
+        STATUS.store(status as usize, Ordering::Relaxed);
+        set_status(SERVICE_RUNNING, NO_ERROR);
+
+        let run = RUN.lock().unwrap_or_else(|e| e.into_inner()).take();
+        let result = run.map_or(Ok(ExitCode::SUCCESS), |run| run());
+        let exit_code = if result.is_ok() { NO_ERROR } else { 1 };
+        *RESULT.lock().unwrap_or_else(|e| e.into_inner()) = Some(result);
+        set_status(SERVICE_STOPPED, exit_code);
+    }
+
+    // Called by the service manager to control the service. Stopping it, or shutting down, stops the watcher, which
+    // finishes whatever it's handling first.
+    unsafe extern "system" fn handler(control: DWORD, _: DWORD, _: LPVOID, _: LPVOID) -> DWORD {
+        match control {
+            SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+                set_status(SERVICE_STOP_PENDING, NO_ERROR);
+                #[cfg(feature = "watch")]
+                crate::watcher::stop();
+                NO_ERROR
+            }
+            SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+            _ => ERROR_CALL_NOT_IMPLEMENTED,
+        }
+    }
+
+    // Helper function to tell the service manager what state the service is in.
+    fn set_status(state: DWORD, exit_code: DWORD) {
+        let mut status = SERVICE_STATUS {
+            dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+            dwCurrentState: state,
+            dwControlsAccepted: if state == SERVICE_RUNNING {
+                SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN
+            } else {
+                0
+            },
+            dwWin32ExitCode: exit_code,
+            dwServiceSpecificExitCode: 0,
+            dwCheckPoint: 0,
+            dwWaitHint: if state == SERVICE_STOP_PENDING {
+                STOP_WAIT
+            } else {
+                0
+            },
+        };
+        unsafe {
+            SetServiceStatus(
+                STATUS.load(Ordering::Relaxed) as SERVICE_STATUS_HANDLE,
+                &mut status,
+            )
+        };
+    }
+
+    // Helper function to connect to the service manager with the given access.
+    fn open_manager(access: DWORD) -> Result<Handle> {
+        let manager = unsafe { OpenSCManagerW(std::ptr::null(), std::ptr::null(), access) };
+        if manager.is_null() {
+            return Err(Error::last_os_error()).with_context(|| {
+                "Failed to connect to the service manager, which needs an administrator"
+            });
+        }
+        Ok(Handle(manager))
+    }
+
+    // Helper function to convert a string to a wide string for the Windows API.
+    fn wide(s: &str) -> Vec<u16> {
+        OsStr::new(s).encode_wide().chain(Some(0)).collect()
+    }
+}
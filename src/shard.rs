@@ -0,0 +1,280 @@
+use crate::output::{self, Reporter};
+use anyhow::{anyhow, Context, Result};
+use notify::event::{CreateKind, EventKind, ModifyKind};
+use notify::{Event, EventHandler, PollWatcher, RecursiveMode, Watcher};
+use std::path::Path;
+use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// How long to wait after a shard fails before restarting it
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+// How often to poll for changes when the native backend isn't available, the same as the default poll interval
+const FALLBACK_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// A group of watched paths that share a single watcher, so that no one watcher has to hold every path. A shard
+// without a watcher has failed, and is waiting to be restarted. A shard polls from then on once the system has run out
+// of watches for the native backend.
+struct Shard {
+    paths: Vec<String>,
+    watcher: Option<Box<dyn Watcher>>,
+    failed_at: Option<Instant>,
+    restarts: usize,
+    polling: bool,
+}
+
+// Spreads the watched paths across shards, each with its own watcher, and restarts any shard that fails without
+// disturbing the others. Events are sent down a single channel, tagged with the index of the shard they came from.
+pub struct Supervisor {
+    shards: Vec<Shard>,
+    tx: SyncSender<(usize, notify::Result<Event>)>,
+    poll_interval: Option<Duration>,
+    mode: RecursiveMode,
+    reporter: Arc<dyn Reporter>,
+}
+
+impl Supervisor {
+    // Build a new supervisor, and start watching every path. Paths are dealt out to the shards in turn, so every shard
+    // has about the same number. If roots_per_shard isn't given, then every path is watched by a single shard. If a
+    // poll interval is given, then the paths are polled for changes rather than using the native backend.
+    pub fn new(
+        paths: &[String],
+        roots_per_shard: Option<usize>,
+        tx: SyncSender<(usize, notify::Result<Event>)>,
+        poll_interval: Option<Duration>,
+        mode: RecursiveMode,
+        reporter: Arc<dyn Reporter>,
+    ) -> Result<Self> {
+        let count = roots_per_shard
+            .map_or(1, |roots_per_shard| {
+                paths.len().div_ceil(roots_per_shard.max(1))
+            })
+            .max(1);
+        let mut shards: Vec<Shard> = (0..count)
+            .map(|_| Shard {
+                paths: Vec::new(),
+                watcher: None,
+                failed_at: None,
+                restarts: 0,
+                polling: false,
+            })
+            .collect();
+        for (index, path) in paths.iter().enumerate() {
+            shards[index % count].paths.push(path.clone());
+        }
+
+        let mut supervisor = Self {
+            shards,
+            tx,
+            poll_interval,
+            mode,
+            reporter,
+        };
+        for index in 0..count {
+            let watcher = supervisor.start(index)?;
+            supervisor.shards[index].watcher = Some(watcher);
+        }
+        Ok(supervisor)
+    }
+
+    // Returns the paths watched by a shard.
+    pub fn paths(&self, index: usize) -> &[String] {
+        &self.shards[index].paths
+    }
+
+    // Check whether the system has run out of watches for a folder an event says was just made or moved in, which the
+    // native backend gives up on watching without saying so, leaving everything below it unwatched. If it has, then the
+    // shard polls from then on, and true is returned so its paths can be searched again. Only Linux has a limit to run
+    // out of, and only shards using the native backend check.
+    pub fn exhausted(&mut self, index: usize, event: &Event) -> bool {
+        let shard = &self.shards[index];
+        if self.poll_interval.is_some() || shard.polling || shard.watcher.is_none() {
+            return false;
+        }
+        let dir = match event.kind {
+            EventKind::Create(CreateKind::Folder) => event.paths.last(),
+            EventKind::Modify(ModifyKind::Name(_)) => {
+                event.paths.last().filter(|path| path.is_dir())
+            }
+            _ => None,
+        };
+        if !dir.is_some_and(|dir| out_of_watches(dir)) {
+            return false;
+        }
+
+        self.exhausted_at(index, dir.map(|dir| dir.display().to_string()));
+        self.shards[index].watcher = None;
+        match self.start(index) {
+            Ok(watcher) => self.shards[index].watcher = Some(watcher),
+            Err(e) => {
+                self.reporter.report(&output::Event::Notice(format!(
+                    "Failed to restart shard {index}: {e:#}"
+                )));
+                self.shards[index].failed_at = Some(Instant::now());
+            }
+        }
+        true
+    }
+
+    // Stop a shard that has failed, so it can be restarted later. Does nothing if it has already been stopped.
+    pub fn fail(&mut self, index: usize) {
+        let shard = &mut self.shards[index];
+        if shard.watcher.take().is_some() {
+            shard.failed_at = Some(Instant::now());
+        }
+    }
+
+    // Restart any shards that failed long enough ago, returning the indexes of the ones that were restarted. Any that
+    // fail to restart are tried again later.
+    pub fn restart_failed(&mut self) -> Vec<usize> {
+        let mut restarted = Vec::new();
+        for index in 0..self.shards.len() {
+            if self.shards[index]
+                .failed_at
+                .is_none_or(|failed_at| failed_at.elapsed() < RESTART_DELAY)
+            {
+                continue;
+            }
+            match self.start(index) {
+                Ok(watcher) => {
+                    let shard = &mut self.shards[index];
+                    shard.watcher = Some(watcher);
+                    shard.failed_at = None;
+                    shard.restarts += 1;
+                    restarted.push(index);
+                }
+                Err(e) => {
+                    self.reporter.report(&output::Event::Notice(format!(
+                        "Failed to restart shard {index}: {e:#}"
+                    )));
+                    self.shards[index].failed_at = Some(Instant::now());
+                }
+            }
+        }
+        restarted
+    }
+
+    // Report the status of every shard, if there's more than one.
+    pub fn report_status(&self) {
+        if self.shards.len() < 2 {
+            return;
+        }
+        for (index, shard) in self.shards.iter().enumerate() {
+            self.reporter.report(&output::Event::Shard {
+                index,
+                running: shard.watcher.is_some(),
+                roots: shard.paths.len(),
+                restarts: shard.restarts,
+            });
+        }
+    }
+
+    // Create a watcher for a shard, and watch each of its paths with it. If the system runs out of watches for the
+    // native backend while watching them, then the shard polls instead from then on.
+    fn start(&mut self, index: usize) -> Result<Box<dyn Watcher>> {
+        let tx = self.tx.clone();
+        let handler = move |event| {
+            // The receiver only goes away when shutting down, so there's nothing to do if sending fails.
+            let _ = tx.send((index, event));
+        };
+
+        // Create a new watcher, using the polling backend if a poll interval was given. If the native backend isn't
+        // available, then say so and poll instead, rather than failing outright.
+        let mut watcher = match self.poll_interval {
+            Some(interval) => poll(handler, interval)?,
+            None if self.shards[index].polling => poll(handler, FALLBACK_POLL_INTERVAL)?,
+            None => match native(handler.clone()) {
+                Ok(watcher) => watcher,
+                Err(e) => {
+                    self.reporter.report(&output::Event::Notice(format!(
+                        "{e:#}. Falling back to polling every {} seconds.",
+                        FALLBACK_POLL_INTERVAL.as_secs()
+                    )));
+                    poll(handler, FALLBACK_POLL_INTERVAL)?
+                }
+            },
+        };
+
+        // Add the paths to watch to the watcher
+        for path in &self.shards[index].paths {
+            match watcher.watch(Path::new(path), self.mode) {
+                Err(e)
+                    if matches!(e.kind, notify::ErrorKind::MaxFilesWatch)
+                        && !self.shards[index].polling =>
+                {
+                    self.exhausted_at(index, Some(path.clone()));
+                    drop(watcher);
+                    return self.start(index);
+                }
+                result => result.with_context(|| {
+                    format!(
+                        "Failed to watch path {path}. Make sure you have the required permissions"
+                    )
+                })?,
+            }
+        }
+        Ok(watcher)
+    }
+
+    // Report that the system ran out of watches for the native backend while watching a path, along with how to raise
+    // the limit, and make the shard poll from then on.
+    fn exhausted_at(&mut self, index: usize, path: Option<String>) {
+        let e = anyhow!(
+            "Ran out of file watches while watching {}, so changes below it would be missed. Raise the limit with \
+             sysctl fs.inotify.max_user_watches=524288, or set it in /etc/sysctl.conf to keep it",
+            path.as_deref().unwrap_or("a folder")
+        );
+        self.reporter.report(&output::Event::Error(&e));
+        self.reporter.report(&output::Event::Notice(format!(
+            "Falling back to polling {} every {} seconds",
+            self.shards[index].paths.join(", "),
+            FALLBACK_POLL_INTERVAL.as_secs()
+        )));
+        self.shards[index].polling = true;
+    }
+}
+
+// Helper function to check whether the system has run out of inotify watches, by trying to watch a folder with an
+// inotify instance of its own. Failing for any other reason doesn't count.
+#[cfg(target_os = "linux")]
+fn out_of_watches(dir: &Path) -> bool {
+    use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+
+    Inotify::init(InitFlags::IN_CLOEXEC)
+        .and_then(|inotify| inotify.add_watch(dir, AddWatchFlags::IN_CREATE))
+        .is_err_and(|e| e == nix::errno::Errno::ENOSPC)
+}
+
+// There's no limit on watches to run out of anywhere else.
+#[cfg(not(target_os = "linux"))]
+fn out_of_watches(_dir: &Path) -> bool {
+    false
+}
+
+// Create a watcher that polls for changes at the given interval.
+fn poll(handler: impl EventHandler, interval: Duration) -> Result<Box<dyn Watcher>> {
+    Ok(Box::new(
+        PollWatcher::new(
+            handler,
+            notify::Config::default().with_poll_interval(interval),
+        )
+        .with_context(|| "Failed to create new polling watcher.")?,
+    ))
+}
+
+// Create a watcher using the native backend for the platform.
+#[cfg(feature = "native-watch")]
+fn native(handler: impl EventHandler) -> Result<Box<dyn Watcher>> {
+    Ok(Box::new(
+        notify::RecommendedWatcher::new(handler, notify::Config::default()).with_context(|| {
+            "Failed to create new watcher. Make sure you have the required permissions."
+        })?,
+    ))
+}
+
+// Stand-in for builds without the native backend, which always have to poll.
+#[cfg(not(feature = "native-watch"))]
+fn native(_handler: impl EventHandler) -> Result<Box<dyn Watcher>> {
+    anyhow::bail!("The native watch backend isn't supported by this build of cloak")
+}
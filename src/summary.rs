@@ -0,0 +1,608 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// The most error messages to keep for the end of run summary. Any more are only counted, so that a long watch session
+// can't use up memory.
+const MAX_ERROR_MESSAGES: usize = 100;
+
+// How many buckets the times taken by a stage are counted in. Each doubling of the time is split into four buckets, so
+// every time up to the longest a u64 of nanoseconds can hold has one.
+const LATENCY_BUCKETS: usize = 256;
+
+// Reasons a path can be skipped rather than hidden
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SkipReason {
+    Type,
+    Excluded,
+    Unmatched,
+    Unsettled,
+    NoSibling,
+    Declined,
+    Depth,
+    Size,
+    Age,
+    Cooldown,
+    Conflict,
+    Exempt,
+    Limit,
+    Git,
+    Mime,
+    Content,
+    NotEmpty,
+    Target,
+    // Owners, groups and modes are only checked on Unix.
+    #[cfg_attr(not(target_family = "unix"), allow(dead_code))]
+    Ownership,
+}
+
+// The phases a search runs in, one after another: finding every entry, deciding which match, planning what to change,
+// and then changing it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    Enumerate,
+    Filter,
+    Plan,
+    Execute,
+}
+
+impl Phase {
+    const ALL: [Phase; 4] = [Phase::Enumerate, Phase::Filter, Phase::Plan, Phase::Execute];
+
+    // Returns the name of the phase, for printing out.
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Enumerate => "Enumerate",
+            Phase::Filter => "Filter",
+            Phase::Plan => "Plan",
+            Phase::Execute => "Execute",
+        }
+    }
+}
+
+// The stages of deciding on and handling a single path that are timed every time they run, so it's clear where the
+// time goes on a slow filesystem: reading its type, matching it against the patterns, and hiding it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    Type,
+    Match,
+    Hide,
+}
+
+impl Stage {
+    const ALL: [Stage; 3] = [Stage::Type, Stage::Match, Stage::Hide];
+
+    // Returns the name of the stage, for printing out.
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Type => "Type check",
+            Stage::Match => "Match",
+            Stage::Hide => "Hide",
+        }
+    }
+}
+
+// How long a stage took each time it ran. The times are counted in buckets rather than kept, so a long watch session
+// can't use up memory, which makes the percentiles accurate to within a quarter of a doubling of the time.
+#[derive(Debug)]
+struct Latency {
+    buckets: [AtomicUsize; LATENCY_BUCKETS],
+    nanos: AtomicU64,
+}
+
+impl Default for Latency {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicUsize::new(0)),
+            nanos: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Latency {
+    fn record(&self, elapsed: Duration) {
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.buckets[bucket(nanos)].fetch_add(1, Ordering::Relaxed);
+        self.nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    // Returns how many times the stage ran, how long it took on average, and how long the 95th and 99th percentile
+    // took, as the most each of their buckets could hold. Everything is zero if it never ran.
+    fn latencies(&self, stage: Stage) -> Latencies {
+        let counts = self
+            .buckets
+            .each_ref()
+            .map(|bucket| bucket.load(Ordering::Relaxed));
+        let count = counts.iter().sum::<usize>();
+        let percentile = |percent: usize| {
+            let rank = (count * percent).div_ceil(100).max(1);
+            let mut seen = 0;
+            let index = counts
+                .iter()
+                .position(|&bucket| {
+                    seen += bucket;
+                    seen >= rank
+                })
+                .unwrap_or(0);
+            millis(Duration::from_nanos(most(index)))
+        };
+        Latencies {
+            stage: stage.name().to_lowercase().replace(' ', "_"),
+            count,
+            mean_ms: millis(Duration::from_nanos(self.nanos.load(Ordering::Relaxed)))
+                / count.max(1) as f64,
+            p95_ms: percentile(95),
+            p99_ms: percentile(99),
+        }
+    }
+}
+
+// How long a stage took over a run, named in lower case with underscores, such as type_check, with the times in
+// milliseconds
+#[derive(Clone, Debug, Serialize)]
+pub struct Latencies {
+    pub stage: String,
+    pub count: usize,
+    pub mean_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+}
+
+// Helper function to get the bucket a time in nanoseconds is counted in. Times under four nanoseconds get a bucket
+// each, and every doubling after that is split into four.
+fn bucket(nanos: u64) -> usize {
+    if nanos < 4 {
+        return nanos as usize;
+    }
+    let doubling = 63 - nanos.leading_zeros() as usize;
+    doubling * 4 + (nanos >> (doubling - 2) & 3) as usize
+}
+
+// Helper function to get the most nanoseconds a bucket holds.
+fn most(index: usize) -> u64 {
+    if index < 8 {
+        return index as u64;
+    }
+    let (doubling, quarter) = (index / 4, index as u128 % 4);
+    u64::try_from(((5 + quarter) << (doubling - 2)) - 1).unwrap_or(u64::MAX)
+}
+
+// Helper function to get a duration in milliseconds.
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+// Counts of what happened to the paths seen during a run, along with the errors that occurred. These are shared
+// across the rayon thread pool, so they're kept as atomics or behind a mutex.
+#[derive(Debug)]
+pub struct Summary {
+    start: Instant,
+    directories: AtomicUsize,
+    scanned: AtomicUsize,
+    matched: AtomicUsize,
+    hidden: AtomicUsize,
+    already_hidden: AtomicUsize,
+    unhidden: AtomicUsize,
+    already_visible: AtomicUsize,
+    unverified: AtomicUsize,
+    skipped_type: AtomicUsize,
+    skipped_excluded: AtomicUsize,
+    skipped_unmatched: AtomicUsize,
+    skipped_unsettled: AtomicUsize,
+    skipped_no_sibling: AtomicUsize,
+    skipped_declined: AtomicUsize,
+    skipped_depth: AtomicUsize,
+    skipped_size: AtomicUsize,
+    skipped_age: AtomicUsize,
+    skipped_cooldown: AtomicUsize,
+    skipped_conflict: AtomicUsize,
+    skipped_exempt: AtomicUsize,
+    skipped_limit: AtomicUsize,
+    skipped_git: AtomicUsize,
+    skipped_mime: AtomicUsize,
+    skipped_content: AtomicUsize,
+    skipped_not_empty: AtomicUsize,
+    skipped_target: AtomicUsize,
+    skipped_ownership: AtomicUsize,
+    errors: AtomicUsize,
+    error_messages: Mutex<Vec<String>>,
+    phases: Mutex<[(usize, Duration); 4]>,
+    stages: [Latency; 3],
+    hidden_by_rule: Mutex<HashMap<Option<String>, usize>>,
+    hidden_by_root: Mutex<HashMap<PathBuf, usize>>,
+}
+
+// Build a new summary, timed from now.
+impl Default for Summary {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            directories: AtomicUsize::new(0),
+            scanned: AtomicUsize::new(0),
+            matched: AtomicUsize::new(0),
+            hidden: AtomicUsize::new(0),
+            already_hidden: AtomicUsize::new(0),
+            unhidden: AtomicUsize::new(0),
+            already_visible: AtomicUsize::new(0),
+            unverified: AtomicUsize::new(0),
+            skipped_type: AtomicUsize::new(0),
+            skipped_excluded: AtomicUsize::new(0),
+            skipped_unmatched: AtomicUsize::new(0),
+            skipped_unsettled: AtomicUsize::new(0),
+            skipped_no_sibling: AtomicUsize::new(0),
+            skipped_declined: AtomicUsize::new(0),
+            skipped_depth: AtomicUsize::new(0),
+            skipped_size: AtomicUsize::new(0),
+            skipped_age: AtomicUsize::new(0),
+            skipped_cooldown: AtomicUsize::new(0),
+            skipped_conflict: AtomicUsize::new(0),
+            skipped_exempt: AtomicUsize::new(0),
+            skipped_limit: AtomicUsize::new(0),
+            skipped_git: AtomicUsize::new(0),
+            skipped_mime: AtomicUsize::new(0),
+            skipped_content: AtomicUsize::new(0),
+            skipped_not_empty: AtomicUsize::new(0),
+            skipped_target: AtomicUsize::new(0),
+            skipped_ownership: AtomicUsize::new(0),
+            errors: AtomicUsize::new(0),
+            error_messages: Mutex::new(Vec::new()),
+            phases: Mutex::new([(0, Duration::ZERO); 4]),
+            stages: Default::default(),
+            hidden_by_rule: Mutex::new(HashMap::new()),
+            hidden_by_root: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Summary {
+    // Record a directory that was walked.
+    pub fn record_directory(&self) {
+        self.directories.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Record a path that was looked at, whether or not it ends up being hidden.
+    pub fn record_scanned(&self) {
+        self.scanned.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Record a path that passed every filter and pattern.
+    pub fn record_matched(&self) {
+        self.matched.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Record a path that was hidden, or would have been hidden in test mode, along with the pattern that matched it, if
+    // any, and the root it was found under, if known.
+    pub fn record_hidden(&self, rule: Option<String>, root: Option<&Path>) {
+        self.hidden.fetch_add(1, Ordering::Relaxed);
+        *self
+            .hidden_by_rule
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(rule)
+            .or_default() += 1;
+        if let Some(root) = root {
+            *self
+                .hidden_by_root
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .entry(root.to_path_buf())
+                .or_default() += 1;
+        }
+    }
+
+    // Record a path that matched, but was already hidden.
+    pub fn record_already_hidden(&self) {
+        self.already_hidden.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Record a path that was unhidden, or would have been unhidden in test mode.
+    pub fn record_unhidden(&self) {
+        self.unhidden.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Record a path that matched, but wasn't hidden to begin with.
+    pub fn record_already_visible(&self) {
+        self.already_visible.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Record a path that was found not to have stayed hidden when checked after hiding it.
+    pub fn record_unverified(&self) {
+        self.unverified.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Record a path that was skipped, and why.
+    pub fn record_skipped(&self, reason: SkipReason) {
+        match reason {
+            SkipReason::Type => &self.skipped_type,
+            SkipReason::Excluded => &self.skipped_excluded,
+            SkipReason::Unmatched => &self.skipped_unmatched,
+            SkipReason::Unsettled => &self.skipped_unsettled,
+            SkipReason::NoSibling => &self.skipped_no_sibling,
+            SkipReason::Declined => &self.skipped_declined,
+            SkipReason::Depth => &self.skipped_depth,
+            SkipReason::Size => &self.skipped_size,
+            SkipReason::Age => &self.skipped_age,
+            SkipReason::Cooldown => &self.skipped_cooldown,
+            SkipReason::Conflict => &self.skipped_conflict,
+            SkipReason::Exempt => &self.skipped_exempt,
+            SkipReason::Limit => &self.skipped_limit,
+            SkipReason::Git => &self.skipped_git,
+            SkipReason::Mime => &self.skipped_mime,
+            SkipReason::Content => &self.skipped_content,
+            SkipReason::NotEmpty => &self.skipped_not_empty,
+            SkipReason::Target => &self.skipped_target,
+            SkipReason::Ownership => &self.skipped_ownership,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Record an error that stopped a path from being handled.
+    pub fn record_error(&self, error: &impl Display) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        let mut error_messages = self
+            .error_messages
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if error_messages.len() < MAX_ERROR_MESSAGES {
+            error_messages.push(format!("{error:#}"));
+        }
+    }
+
+    // Record a phase of a search, with how many entries it ended up with and how long it took. Searches that run more
+    // than once, like the watcher's rescans, add up.
+    pub fn record_phase(&self, phase: Phase, count: usize, elapsed: Duration) {
+        let mut phases = self.phases.lock().unwrap_or_else(|e| e.into_inner());
+        let (total, time) = &mut phases[phase as usize];
+        *total += count;
+        *time += elapsed;
+    }
+
+    // Run a stage of deciding on or handling a path, recording how long it took.
+    pub fn time<T>(&self, stage: Stage, run: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = run();
+        self.stages[stage as usize].record(start.elapsed());
+        result
+    }
+
+    // Returns how long each stage has taken so far.
+    pub fn latencies(&self) -> Vec<Latencies> {
+        Stage::ALL
+            .into_iter()
+            .map(|stage| self.stages[stage as usize].latencies(stage))
+            .collect()
+    }
+
+    // Returns the number of directories walked so far.
+    pub fn directories(&self) -> usize {
+        self.directories.load(Ordering::Relaxed)
+    }
+
+    // Returns the number of paths looked at so far.
+    pub fn scanned(&self) -> usize {
+        self.scanned.load(Ordering::Relaxed)
+    }
+
+    // Returns the number of paths hidden so far.
+    pub fn hidden(&self) -> usize {
+        self.hidden.load(Ordering::Relaxed)
+    }
+
+    // Returns true if nothing was, or would have been, hidden or unhidden, and nothing was left undone because of an
+    // error, a declined prompt, a conflict or the limit. In other words, everything was already as it should be.
+    pub fn no_changes_needed(&self) -> bool {
+        self.hidden.load(Ordering::Relaxed) == 0
+            && self.unhidden.load(Ordering::Relaxed) == 0
+            && self.skipped_declined.load(Ordering::Relaxed) == 0
+            && self.skipped_conflict.load(Ordering::Relaxed) == 0
+            && self.limited() == 0
+            && self.errors() == 0
+    }
+
+    // Returns the number of paths left alone because the limit of changes had been reached.
+    pub fn limited(&self) -> usize {
+        self.skipped_limit.load(Ordering::Relaxed)
+    }
+
+    // Returns the number of errors recorded so far.
+    pub fn errors(&self) -> usize {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    // Print out the errors recorded so far, if there were any.
+    pub fn print_errors(&self) {
+        let errors = self.errors();
+        if errors == 0 {
+            return;
+        }
+
+        let error_messages = self
+            .error_messages
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        eprintln!("{errors} errors occurred:");
+        for message in error_messages.iter() {
+            eprintln!("  {message}");
+        }
+        if errors > error_messages.len() {
+            eprintln!("  ...and {} more", errors - error_messages.len());
+        }
+    }
+
+    // Returns the messages of the errors recorded so far, up to the most that are kept.
+    pub fn error_messages(&self) -> Vec<String> {
+        self.error_messages
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    // Returns every count, along with its name.
+    pub fn counts(&self) -> Vec<(&'static str, usize)> {
+        let rows = [
+            ("Directories", &self.directories),
+            ("Scanned", &self.scanned),
+            ("Matched", &self.matched),
+            ("Hidden", &self.hidden),
+            ("Already hidden", &self.already_hidden),
+            ("Unhidden", &self.unhidden),
+            ("Already visible", &self.already_visible),
+            ("Unverified", &self.unverified),
+            ("Skipped by type", &self.skipped_type),
+            ("Skipped by exclude", &self.skipped_excluded),
+            ("Skipped by no match", &self.skipped_unmatched),
+            ("Skipped by settle", &self.skipped_unsettled),
+            ("Skipped by sibling", &self.skipped_no_sibling),
+            ("Skipped by prompt", &self.skipped_declined),
+            ("Skipped by depth", &self.skipped_depth),
+            ("Skipped by size", &self.skipped_size),
+            ("Skipped by age", &self.skipped_age),
+            ("Skipped by cooldown", &self.skipped_cooldown),
+            ("Skipped by conflict", &self.skipped_conflict),
+            ("Skipped by exemption", &self.skipped_exempt),
+            ("Skipped by limit", &self.skipped_limit),
+            ("Skipped by git", &self.skipped_git),
+            ("Skipped by mime", &self.skipped_mime),
+            ("Skipped by content", &self.skipped_content),
+            ("Skipped as not empty", &self.skipped_not_empty),
+            ("Skipped by symlink target", &self.skipped_target),
+            ("Skipped by ownership", &self.skipped_ownership),
+            ("Errors", &self.errors),
+        ];
+        rows.into_iter()
+            .map(|(name, count)| (name, count.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    // Returns how many paths each pattern hid, most first. Paths hidden without a pattern are counted under none.
+    pub fn hidden_by_rule(&self) -> Vec<(Option<String>, usize)> {
+        most_first(&self.hidden_by_rule)
+    }
+
+    // Returns how many paths were hidden under each root, most first.
+    pub fn hidden_by_root(&self) -> Vec<(PathBuf, usize)> {
+        most_first(&self.hidden_by_root)
+    }
+
+    // Print out a table of every count, along with the time elapsed so far.
+    pub fn print_stats(&self) {
+        for (name, count) in self.counts() {
+            println!("{name:<20} {count:>12}");
+        }
+        println!(
+            "{:<20} {:>12}",
+            "Elapsed",
+            format!("{:.2?}", self.start.elapsed())
+        );
+
+        // Then how long each phase of searching took, if there was a search.
+        let phases = *self.phases.lock().unwrap_or_else(|e| e.into_inner());
+        if !phases.iter().all(|(_, time)| time.is_zero()) {
+            println!("{:<20} {:>12} {:>12}", "Phase", "Entries", "Time");
+            for phase in Phase::ALL {
+                let (count, time) = phases[phase as usize];
+                println!(
+                    "{:<20} {count:>12} {:>12}",
+                    phase.name(),
+                    format!("{time:.2?}")
+                );
+            }
+        }
+
+        // Then how long each stage took per path, if any ran, so a slow filesystem shows up where it's slow.
+        let latencies = self.latencies();
+        if latencies.iter().any(|latencies| latencies.count > 0) {
+            println!(
+                "{:<20} {:>12} {:>12} {:>12} {:>12}",
+                "Stage", "Paths", "Mean", "p95", "p99"
+            );
+            for (stage, latencies) in Stage::ALL.into_iter().zip(latencies) {
+                let time = |ms: f64| format!("{:.2?}", Duration::from_secs_f64(ms / 1000.0));
+                println!(
+                    "{:<20} {:>12} {:>12} {:>12} {:>12}",
+                    stage.name(),
+                    latencies.count,
+                    time(latencies.mean_ms),
+                    time(latencies.p95_ms),
+                    time(latencies.p99_ms)
+                );
+            }
+        }
+
+        // Then which patterns and roots the paths hidden came from, so a greedy pattern stands out.
+        let by_rule = self.hidden_by_rule();
+        if !by_rule.is_empty() {
+            println!("{:<12} Rule", "Hidden");
+            for (rule, count) in by_rule {
+                println!("{count:<12} {}", rule.as_deref().unwrap_or("none"));
+            }
+        }
+        let by_root = self.hidden_by_root();
+        if !by_root.is_empty() {
+            println!("{:<12} Root", "Hidden");
+            for (root, count) in by_root {
+                println!("{count:<12} {}", root.display());
+            }
+        }
+    }
+
+    // Returns the total number of paths skipped for any reason.
+    fn skipped(&self) -> usize {
+        self.skipped_type.load(Ordering::Relaxed)
+            + self.skipped_excluded.load(Ordering::Relaxed)
+            + self.skipped_unmatched.load(Ordering::Relaxed)
+            + self.skipped_unsettled.load(Ordering::Relaxed)
+            + self.skipped_no_sibling.load(Ordering::Relaxed)
+            + self.skipped_declined.load(Ordering::Relaxed)
+            + self.skipped_depth.load(Ordering::Relaxed)
+            + self.skipped_size.load(Ordering::Relaxed)
+            + self.skipped_age.load(Ordering::Relaxed)
+            + self.skipped_cooldown.load(Ordering::Relaxed)
+            + self.skipped_conflict.load(Ordering::Relaxed)
+            + self.skipped_exempt.load(Ordering::Relaxed)
+            + self.skipped_limit.load(Ordering::Relaxed)
+            + self.skipped_git.load(Ordering::Relaxed)
+            + self.skipped_mime.load(Ordering::Relaxed)
+            + self.skipped_content.load(Ordering::Relaxed)
+            + self.skipped_not_empty.load(Ordering::Relaxed)
+            + self.skipped_target.load(Ordering::Relaxed)
+            + self.skipped_ownership.load(Ordering::Relaxed)
+    }
+}
+
+// Helper function to list the counts kept for each key, most first, and then by key so the order is always the same.
+fn most_first<K: Clone + Ord>(counts: &Mutex<HashMap<K, usize>>) -> Vec<(K, usize)> {
+    let mut counts: Vec<_> = counts
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .map(|(key, count)| (key.clone(), *count))
+        .collect();
+    counts.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then_with(|| a.cmp(b)));
+    counts
+}
+
+// Pretty print a Summary. Unhidden counts are only included if anything was unhidden.
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} hidden, {} already hidden, ",
+            self.hidden.load(Ordering::Relaxed),
+            self.already_hidden.load(Ordering::Relaxed)
+        )?;
+        let unhidden = self.unhidden.load(Ordering::Relaxed);
+        let already_visible = self.already_visible.load(Ordering::Relaxed);
+        if unhidden > 0 || already_visible > 0 {
+            write!(
+                f,
+                "{unhidden} unhidden, {already_visible} already visible, "
+            )?;
+        }
+        write!(f, "{} skipped, {} errors", self.skipped(), self.errors())
+    }
+}
@@ -0,0 +1,126 @@
+use std::fmt::Display;
+
+// Enum of how serious a line written to the system log is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Notice,
+    Info,
+}
+
+// Writes lines to the system log, for when cloak runs as a daemon with nothing to write its output to. That's syslog
+// on Unix, which the systemd journal reads too, and the event log on Windows, where each line is an event from the
+// cloak source. Anything that can't be logged is written to stderr instead, so it isn't lost when run by hand.
+#[derive(Debug)]
+pub struct SystemLog {
+    #[cfg(target_family = "unix")]
+    socket: Option<std::os::unix::net::UnixDatagram>,
+    #[cfg(target_family = "windows")]
+    source: usize,
+}
+
+impl SystemLog {
+    // Connect to syslog through its socket.
+    #[cfg(target_family = "unix")]
+    pub fn open() -> Self {
+        use std::os::unix::net::UnixDatagram;
+
+        let path = if cfg!(target_os = "macos") {
+            "/var/run/syslog"
+        } else {
+            "/dev/log"
+        };
+        let socket = UnixDatagram::unbound()
+            .and_then(|socket| socket.connect(path).map(|()| socket))
+            .ok();
+        Self { socket }
+    }
+
+    // Register the cloak event source. Its events are shown as they're written, though the event viewer notes that
+    // there's no message file for it.
+    #[cfg(target_family = "windows")]
+    pub fn open() -> Self {
+        use std::os::windows::ffi::OsStrExt;
+        use winapi::um::winbase::RegisterEventSourceW;
+
+        let name = std::ffi::OsStr::new("cloak")
+            .encode_wide()
+            .chain(Some(0))
+            .collect::<Vec<_>>();
+        let source = unsafe { RegisterEventSourceW(std::ptr::null(), name.as_ptr()) };
+        Self {
+            source: source as usize,
+        }
+    }
+
+    // Write a line to syslog, from the daemon facility, tagged with cloak and its pid.
+    #[cfg(target_family = "unix")]
+    pub fn write(&self, level: Level, line: impl Display) {
+        // Facility 3 is daemon, and severities 3, 5 and 6 are err, notice and info
+        let priority = 3 * 8
+            + match level {
+                Level::Error => 3,
+                Level::Notice => 5,
+                Level::Info => 6,
+            };
+        let message = format!("<{priority}>cloak[{}]: {line}", std::process::id());
+        let sent = self
+            .socket
+            .as_ref()
+            .is_some_and(|socket| socket.send(message.as_bytes()).is_ok());
+        if !sent {
+            eprintln!("{line}");
+        }
+    }
+
+    // Write a line to the event log as an event of the matching type.
+    #[cfg(target_family = "windows")]
+    pub fn write(&self, level: Level, line: impl Display) {
+        use std::os::windows::ffi::OsStrExt;
+        use winapi::shared::minwindef::FALSE;
+        use winapi::um::winbase::ReportEventW;
+        use winapi::um::winnt::{
+            EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE, HANDLE,
+        };
+
+        let line = line.to_string();
+        let wide_line = std::ffi::OsStr::new(&line)
+            .encode_wide()
+            .chain(Some(0))
+            .collect::<Vec<_>>();
+        let kind = match level {
+            Level::Error => EVENTLOG_ERROR_TYPE,
+            Level::Notice => EVENTLOG_WARNING_TYPE,
+            Level::Info => EVENTLOG_INFORMATION_TYPE,
+        };
+        let mut strings = [wide_line.as_ptr()];
+        let reported = self.source != 0
+            && unsafe {
+                ReportEventW(
+                    self.source as HANDLE,
+                    kind,
+                    0,
+                    0,
+                    std::ptr::null_mut(),
+                    1,
+                    0,
+                    strings.as_mut_ptr(),
+                    std::ptr::null_mut(),
+                )
+            } != FALSE;
+        if !reported {
+            eprintln!("{line}");
+        }
+    }
+}
+
+#[cfg(target_family = "windows")]
+impl Drop for SystemLog {
+    fn drop(&mut self) {
+        use winapi::um::winbase::DeregisterEventSource;
+
+        if self.source != 0 {
+            unsafe { DeregisterEventSource(self.source as winapi::um::winnt::HANDLE) };
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use crate::output::Reporter;
+use anyhow::Result;
+#[cfg(target_os = "linux")]
+use nix::libc;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// Who unhid a path, as far as could be told
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+struct Unhider {
+    pid: i32,
+    uid: Option<u32>,
+    program: Option<PathBuf>,
+}
+
+// The users and programs allowed to unhide paths while watching, so what they unhide isn't hidden again. Everyone else
+// has what they unhid hidden again as usual, and is reported. It's told who unhid what by fanotify, which only works on
+// Linux, and only with root. Renames are read as they happen on a thread of its own, so the process behind each one
+// can be looked up before it exits, and checking a path reads anything still waiting first, so the watcher can never
+// see an unhide before it's been told who did it.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+#[derive(Debug)]
+pub struct Unhiders {
+    users: Vec<u32>,
+    programs: Vec<PathBuf>,
+    roots: Vec<PathBuf>,
+    #[cfg(target_os = "linux")]
+    fanotify: nix::sys::fanotify::Fanotify,
+    #[cfg(target_os = "linux")]
+    mounts: Vec<std::fs::File>,
+    allowed: Mutex<HashMap<PathBuf, bool>>,
+    reporter: Arc<dyn Reporter>,
+}
+
+impl Unhiders {
+    // Start being told about every rename on the filesystems the roots are on. Users can be given by name or uid, and
+    // programs by their full paths or just their file names.
+    #[cfg(target_os = "linux")]
+    pub fn new(
+        roots: &[impl AsRef<Path>],
+        users: &[String],
+        programs: &[PathBuf],
+        reporter: Arc<dyn Reporter>,
+    ) -> Result<Self> {
+        use anyhow::{anyhow, Context};
+        use nix::sys::fanotify::{EventFFlags, Fanotify, InitFlags, MarkFlags, MaskFlags};
+
+        let users = users
+            .iter()
+            .map(|user| match user.parse() {
+                Ok(uid) => Ok(uid),
+                Err(_) => nix::unistd::User::from_name(user)
+                    .ok()
+                    .flatten()
+                    .map(|user| user.uid.as_raw())
+                    .ok_or_else(|| anyhow!("Unknown user {user}")),
+            })
+            .collect::<Result<_>>()?;
+
+        let fanotify = Fanotify::init(
+            InitFlags::FAN_CLASS_NOTIF
+                | InitFlags::FAN_CLOEXEC
+                | InitFlags::FAN_NONBLOCK
+                | InitFlags::from_bits_retain(libc::FAN_REPORT_DFID_NAME),
+            EventFFlags::O_RDONLY,
+        )
+        .with_context(|| {
+            "Failed to start fanotify to tell who unhides what, which needs root and Linux 5.17 or later"
+        })?;
+        let mounts = roots
+            .iter()
+            .map(|root| {
+                let root = root.as_ref();
+                let mount = std::fs::File::open(root)
+                    .with_context(|| format!("Failed to open {}", root.display()))?;
+                fanotify
+                    .mark(
+                        MarkFlags::FAN_MARK_ADD | MarkFlags::FAN_MARK_FILESYSTEM,
+                        MaskFlags::FAN_RENAME | MaskFlags::FAN_ONDIR,
+                        &mount,
+                        None::<&Path>,
+                    )
+                    .with_context(|| {
+                        format!("Failed to watch who renames anything in {}", root.display())
+                    })?;
+                Ok(mount)
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            users,
+            programs: programs.to_vec(),
+            roots: roots.iter().map(|root| resolve(root.as_ref())).collect(),
+            fanotify,
+            mounts,
+            allowed: Mutex::new(HashMap::new()),
+            reporter,
+        })
+    }
+
+    // Only fanotify can tell who unhid a path.
+    #[cfg(not(target_os = "linux"))]
+    pub fn new(
+        _: &[impl AsRef<Path>],
+        _: &[String],
+        _: &[PathBuf],
+        _: Arc<dyn Reporter>,
+    ) -> Result<Self> {
+        anyhow::bail!("Allowing only some users and programs to unhide is only supported on Linux")
+    }
+
+    // Keep reading renames as they happen on a thread of its own, until cloak exits.
+    pub fn listen(self: Arc<Self>) {
+        #[cfg(target_os = "linux")]
+        std::thread::spawn(move || {
+            use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+            use std::os::fd::AsFd;
+
+            loop {
+                let mut fds = [PollFd::new(self.fanotify.as_fd(), PollFlags::POLLIN)];
+                match poll(&mut fds, PollTimeout::NONE) {
+                    Ok(_) | Err(nix::errno::Errno::EINTR) => self.read(&mut self.lock()),
+                    Err(_) => return,
+                }
+            }
+        });
+    }
+
+    // Returns true if the path was last unhidden by one of the users or programs allowed to.
+    pub fn allowed(&self, path: &Path) -> bool {
+        let mut allowed = self.lock();
+        self.read(&mut allowed);
+        allowed.get(&resolve(path)).copied().unwrap_or(false)
+    }
+
+    // Helper function to lock the paths that were unhidden, and whether whoever did it was allowed to.
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, bool>> {
+        self.allowed.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Helper function to read every rename waiting to be read, and note down any that unhid something.
+    #[cfg(target_os = "linux")]
+    fn read(&self, allowed: &mut HashMap<PathBuf, bool>) {
+        // Buffers are u64s, so the events in them are aligned.
+        let mut buffer = vec![0u64; 8192];
+        loop {
+            let bytes = unsafe {
+                std::slice::from_raw_parts_mut(buffer.as_mut_ptr().cast::<u8>(), buffer.len() * 8)
+            };
+            let Ok(read) = nix::unistd::read(&self.fanotify, bytes) else {
+                return;
+            };
+            if read == 0 {
+                return;
+            }
+            // Renames anywhere else on the same filesystems, or by cloak itself, are none of its business.
+            for (renamed_to, unhider) in self.unhides(&bytes[..read]) {
+                if unhider.pid == std::process::id() as i32
+                    || !self.roots.iter().any(|root| renamed_to.starts_with(root))
+                {
+                    continue;
+                }
+                let ok = self.is_allowed(&unhider);
+                if !ok {
+                    self.reporter.report(&crate::output::Event::Notice(format!(
+                        "{} was unhidden by {}, which isn't allowed to unhide anything",
+                        renamed_to.display(),
+                        describe(&unhider)
+                    )));
+                }
+                allowed.insert(renamed_to, ok);
+            }
+        }
+    }
+
+    // There's never anything to read without fanotify.
+    #[cfg(not(target_os = "linux"))]
+    fn read(&self, _: &mut HashMap<PathBuf, bool>) {}
+
+    // Helper function to pick out the renames that unhid something from a buffer of events, along with who did it.
+    #[cfg(target_os = "linux")]
+    fn unhides(&self, events: &[u8]) -> Vec<(PathBuf, Unhider)> {
+        let mut unhides = Vec::new();
+        let mut offset = 0;
+        while offset + std::mem::size_of::<libc::fanotify_event_metadata>() <= events.len() {
+            let metadata = unsafe {
+                std::ptr::read_unaligned(
+                    events[offset..]
+                        .as_ptr()
+                        .cast::<libc::fanotify_event_metadata>(),
+                )
+            };
+            let end = (offset + metadata.event_len as usize).min(events.len());
+            if metadata.event_len == 0 {
+                break;
+            }
+
+            // Each rename has records of the directory and name it had, and the ones it has now.
+            let (mut from, mut to) = (None, None);
+            let mut record = offset + metadata.metadata_len as usize;
+            while record + 4 <= end {
+                let info_type = events[record];
+                let len = u16::from_ne_bytes([events[record + 2], events[record + 3]]) as usize;
+                if len == 0 || record + len > end {
+                    break;
+                }
+                let named = self.named(&events[record..record + len]);
+                match info_type {
+                    libc::FAN_EVENT_INFO_TYPE_OLD_DFID_NAME => from = named,
+                    libc::FAN_EVENT_INFO_TYPE_NEW_DFID_NAME => to = named,
+                    _ => {}
+                }
+                record += len;
+            }
+
+            if let (Some((_, from)), Some((dir, to))) = (from, to) {
+                if from.starts_with('.') && !to.starts_with('.') {
+                    unhides.push((dir.join(to), unhider(metadata.pid)));
+                }
+            }
+            offset = end;
+        }
+        unhides
+    }
+
+    // Helper function to get the directory and name in a record of one, if the directory can still be found.
+    #[cfg(target_os = "linux")]
+    fn named(&self, record: &[u8]) -> Option<(PathBuf, String)> {
+        use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+        // The record has a header and a filesystem id, then a handle for the directory and the name after it.
+        let handle = record.get(12..)?;
+        let bytes = u32::from_ne_bytes(handle.get(..4)?.try_into().ok()?) as usize;
+        let handle = handle.get(..8 + bytes)?;
+        let name = record.get(12 + 8 + bytes..)?;
+        let name = name.split(|&b| b == 0).next()?;
+
+        // The directory is looked up from its handle on whichever filesystem it's on.
+        let mut owned = vec![0u64; handle.len().div_ceil(8)];
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                handle.as_ptr(),
+                owned.as_mut_ptr().cast::<u8>(),
+                handle.len(),
+            );
+        }
+        let dir = self.mounts.iter().find_map(|mount| {
+            let fd = unsafe {
+                libc::open_by_handle_at(
+                    mount.as_raw_fd(),
+                    owned.as_mut_ptr().cast::<libc::file_handle>(),
+                    libc::O_PATH | libc::O_CLOEXEC,
+                )
+            };
+            (fd >= 0).then(|| unsafe { OwnedFd::from_raw_fd(fd) })
+        })?;
+        let dir = std::fs::read_link(format!("/proc/self/fd/{}", dir.as_raw_fd())).ok()?;
+        Some((dir, String::from_utf8_lossy(name).into_owned()))
+    }
+
+    // Helper function to check if whoever unhid a path was allowed to.
+    #[cfg(target_os = "linux")]
+    fn is_allowed(&self, unhider: &Unhider) -> bool {
+        unhider.uid.is_some_and(|uid| self.users.contains(&uid))
+            || unhider.program.as_deref().is_some_and(|program| {
+                self.programs.iter().any(|allowed| {
+                    program == allowed
+                        || (allowed.parent() == Some(Path::new(""))
+                            && program.file_name() == Some(allowed.as_os_str()))
+                })
+            })
+    }
+}
+
+// Helper function to look up who a process is, while it's still running.
+#[cfg(target_os = "linux")]
+fn unhider(pid: i32) -> Unhider {
+    let uid = std::fs::read_to_string(format!("/proc/{pid}/status"))
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("Uid:"))
+                .and_then(|uids| uids.split_whitespace().next()?.parse().ok())
+        });
+    Unhider {
+        pid,
+        uid,
+        program: std::fs::read_link(format!("/proc/{pid}/exe")).ok(),
+    }
+}
+
+// Helper function to describe who unhid a path, for the user to read.
+#[cfg(target_os = "linux")]
+fn describe(unhider: &Unhider) -> String {
+    match (&unhider.program, unhider.uid) {
+        (Some(program), Some(uid)) => {
+            format!("{} (pid {}, uid {uid})", program.display(), unhider.pid)
+        }
+        _ => format!(
+            "pid {}, which exited before it could be looked up",
+            unhider.pid
+        ),
+    }
+}
+
+// Helper function to resolve the directory a path is in, the way fanotify reports it, leaving the path itself as it
+// is, since it could be a symlink.
+fn resolve(path: &Path) -> PathBuf {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => std::fs::canonicalize(crate::filesystem::absolute(parent))
+            .map_or_else(|_| path.to_path_buf(), |parent| parent.join(name)),
+        _ => path.to_path_buf(),
+    }
+}
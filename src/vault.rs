@@ -0,0 +1,286 @@
+use crate::summary::SkipReason;
+use crate::{context, filesystem, filter, journal, output, pipeline, report};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+// Enum of the ways files and folders can be hidden
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Strategy {
+    // Hide them where they are, with a dot on Unix or the hidden attribute on Windows
+    #[default]
+    Rename,
+    // Move them into a vault, out of the way of anything that would make them again under their hidden names
+    Move,
+}
+
+// A folder that files and folders are moved into to hide them, keeping the same structure below it as they had below
+// the path they were found in. Where each one came from is kept in the journal, so they can be moved back. If empty
+// folders are pruned, then the folders things were moved out of are kept, along with the roots they were found under,
+// until they're pruned.
+#[derive(Debug)]
+pub struct Vault {
+    dir: PathBuf,
+    vacated: Option<Mutex<BTreeSet<(PathBuf, PathBuf)>>>,
+}
+
+impl Vault {
+    // Open the vault at the given folder, creating it if it doesn't exist yet. If prune_empty is set, then the folders
+    // moving things into it leaves empty are hidden too.
+    pub fn open(dir: &Path, modes: filesystem::CreateModes, prune_empty: bool) -> Result<Self> {
+        modes
+            .create_dir_all(dir)
+            .with_context(|| format!("Failed to create vault {}", dir.display()))?;
+        let dir = fs::canonicalize(dir)
+            .with_context(|| format!("Failed to open vault {}", dir.display()))?;
+        Ok(Self {
+            dir,
+            vacated: prune_empty.then(|| Mutex::new(BTreeSet::new())),
+        })
+    }
+
+    // Record that a path found under a root was moved into the vault, so the folder it was in can be pruned if that
+    // left it empty. Paths found under no root are never pruned around, since there's nowhere to stop.
+    pub fn moved(&self, path: &Path, root: Option<&Path>) {
+        let (Some(vacated), Some(parent), Some(root)) = (&self.vacated, path.parent(), root) else {
+            return;
+        };
+        vacated
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert((parent.to_path_buf(), root.to_path_buf()));
+    }
+
+    // Hide the folders things were moved out of since the last time, if there's nothing left in them but empty folders.
+    // Only the highest such folder below the root is hidden, with everything below it left as it is, and it's recorded
+    // in the journal under its usual hidden name, which nothing else is ever recorded under, so that restoring shows it
+    // again before moving anything back into it.
+    pub fn prune(&self, ctx: &context::RunContext) {
+        let Some(vacated) = &self.vacated else {
+            return;
+        };
+        let vacated = std::mem::take(&mut *vacated.lock().unwrap_or_else(|e| e.into_inner()));
+        for (dir, root) in vacated {
+            let Some(top) = dir
+                .ancestors()
+                .take_while(|dir| *dir != root && dir.starts_with(&root) && only_empty_folders(dir))
+                .last()
+            else {
+                continue;
+            };
+            if ctx.test {
+                continue;
+            }
+            let info = filesystem::Info::with(
+                top.to_path_buf(),
+                Some(filesystem::ObjectType::Folder),
+                None,
+            );
+            match filesystem::hide(&info, ctx.dir_times, filesystem::OnConflict::Skip) {
+                Ok(filesystem::Change::Changed(hidden)) => {
+                    ctx.summary.record_hidden(None, Some(&root));
+                    ctx.reporter.report(&output::Event::Action {
+                        action: report::Action::Hidden,
+                        path: top,
+                        streams: &[],
+                    });
+                    if let Some(report) = ctx.report {
+                        report.record(
+                            report::Action::Hidden,
+                            top,
+                            Some(filesystem::ObjectType::Folder),
+                            None,
+                            Some(&root),
+                        );
+                    }
+                    if let Some(Err(e)) = ctx.journal.map(|journal| journal.record(&hidden, top)) {
+                        ctx.reporter.report(&output::Event::Error(&e));
+                        ctx.summary.record_error(&e);
+                    }
+                }
+                Ok(filesystem::Change::Conflict(existing)) => {
+                    ctx.summary.record_skipped(SkipReason::Conflict);
+                    ctx.reporter.report(&output::Event::Notice(format!(
+                        "Left empty folder {} visible because {} already exists",
+                        top.display(),
+                        existing.display()
+                    )));
+                }
+                Ok(filesystem::Change::Unchanged) => {}
+                Err(e) => {
+                    ctx.reporter.report(&output::Event::Error(&e));
+                    ctx.summary.record_error(&e);
+                }
+            }
+        }
+    }
+
+    // Returns true if the path is the vault itself, or anything in it.
+    pub fn contains(&self, path: &Path) -> bool {
+        resolve(path).starts_with(&self.dir)
+    }
+
+    // Returns where in the vault a path goes, keeping the structure it had below the root it was found in. Paths found
+    // anywhere else keep their whole absolute structure.
+    pub fn destination(&self, path: &Path, root: Option<&Path>) -> PathBuf {
+        match root.and_then(|root| path.strip_prefix(root).ok()) {
+            Some(relative) if !relative.as_os_str().is_empty() => self.dir.join(relative),
+            _ => self.dir.join(
+                filesystem::absolute(path)
+                    .components()
+                    .filter(|component| matches!(component, Component::Normal(_)))
+                    .collect::<PathBuf>(),
+            ),
+        }
+    }
+}
+
+// Move everything in the vault that came from inside the paths back to where it came from, if it matches. Where each
+// one came from is read from the journal, so anything in the vault it doesn't know about is left there. Anything that
+// has something else in its place by now is left in the vault.
+pub fn restore(vault: &Vault, paths: &[impl AsRef<Path>], ctx: &context::RunContext) {
+    let Some(journal) = ctx.journal else {
+        return;
+    };
+    let roots: Vec<PathBuf> = paths.iter().map(|path| resolve(path.as_ref())).collect();
+
+    for (hidden, original) in journal.entries() {
+        if !hidden.starts_with(&vault.dir) {
+            continue;
+        }
+        let resolved = resolve(&original);
+        let Some(root) = pipeline::root_of(&resolved, &roots) else {
+            continue;
+        };
+        ctx.summary.record_scanned();
+
+        // It's matched by the type of what's in the vault, since there's nothing where it came from yet.
+        let object_type = filesystem::object_type(&hidden).ok();
+        let matched = filesystem::Info::with(resolved, object_type, None);
+        if !filter::path_matches_pattern(
+            &matched,
+            Some(root),
+            ctx.relative,
+            ctx.matcher,
+            ctx.verbose,
+            ctx.summary,
+            ctx.reporter.as_ref(),
+        ) {
+            continue;
+        }
+        ctx.summary.record_matched();
+
+        let log = |action: report::Action| {
+            if let Some(report) = ctx.report {
+                report.record(action, &hidden, object_type, matched.rule(), None);
+            }
+        };
+        let record = |action: report::Action| {
+            ctx.reporter.report(&output::Event::Action {
+                action,
+                path: &hidden,
+                streams: &[],
+            });
+            log(action);
+        };
+        if ctx.test {
+            ctx.summary.record_unhidden();
+            record(report::Action::WouldUnhide);
+            continue;
+        }
+
+        // Show any folders that were pruned on the way to where it came from, so it's moved back into them, rather than
+        // into new ones made alongside them.
+        if let Err(e) = unprune(&original, journal, ctx) {
+            ctx.reporter.report(&output::Event::Error(&e));
+            ctx.summary.record_error(&e);
+            log(report::Action::Failed);
+            continue;
+        }
+
+        match filesystem::relocate(
+            &hidden,
+            &original,
+            ctx.dir_times,
+            filesystem::OnConflict::Skip,
+        ) {
+            Ok(filesystem::Change::Changed(_)) => {
+                ctx.summary.record_unhidden();
+                record(report::Action::Unhidden);
+                if let Err(e) = journal.forget(&hidden) {
+                    ctx.reporter.report(&output::Event::Error(&e));
+                    ctx.summary.record_error(&e);
+                }
+            }
+            Ok(filesystem::Change::Conflict(existing)) => {
+                ctx.summary.record_skipped(SkipReason::Conflict);
+                ctx.reporter.report(&output::Event::Notice(format!(
+                    "Left {} in the vault because {} already exists",
+                    hidden.display(),
+                    existing.display()
+                )));
+                log(report::Action::Conflict);
+            }
+            Ok(filesystem::Change::Unchanged) => {}
+            Err(e) => {
+                ctx.reporter.report(&output::Event::Error(&e));
+                ctx.summary.record_error(&e);
+                log(report::Action::Failed);
+            }
+        }
+    }
+}
+
+// Helper function to show the folders above a path that were hidden for being left empty, from the highest down, going
+// by the journal.
+fn unprune(path: &Path, journal: &journal::Journal, ctx: &context::RunContext) -> Result<()> {
+    let pruned: Vec<&Path> = path
+        .ancestors()
+        .skip(1)
+        .filter(|dir| journal.original(&filesystem::hidden_path(dir)).as_deref() == Some(*dir))
+        .collect();
+    for dir in pruned.into_iter().rev() {
+        let hidden = filesystem::hidden_path(dir);
+        let info =
+            filesystem::Info::with(hidden.clone(), Some(filesystem::ObjectType::Folder), None);
+        if let filesystem::Change::Changed(_) = filesystem::unhide(&info, ctx.dir_times, Some(dir))?
+        {
+            ctx.summary.record_unhidden();
+            ctx.reporter.report(&output::Event::Action {
+                action: report::Action::Unhidden,
+                path: &hidden,
+                streams: &[],
+            });
+        }
+        journal.forget(&hidden)?;
+    }
+    Ok(())
+}
+
+// Helper function to check whether a folder has nothing in it but empty folders, all the way down. Anything that can't
+// be read is taken to have something in it.
+fn only_empty_folders(dir: &Path) -> bool {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return false;
+    };
+    entries.into_iter().all(|entry| {
+        entry.is_ok_and(|entry| {
+            entry.file_type().is_ok_and(|file_type| file_type.is_dir())
+                && only_empty_folders(&entry.path())
+        })
+    })
+}
+
+// Helper function to resolve the folder a path is in, leaving the path itself as it is, since it could be a symlink.
+fn resolve(path: &Path) -> PathBuf {
+    let path = filesystem::absolute(path);
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => {
+            fs::canonicalize(parent).map_or(path.clone(), |parent| parent.join(name))
+        }
+        _ => path,
+    }
+}
@@ -0,0 +1,124 @@
+use crate::{context, filesystem, output};
+use anyhow::anyhow;
+use rayon::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// The most times a path is hidden again when it didn't stay hidden, before giving up on it
+const RETRIES: usize = 3;
+
+// Checks that paths stay hidden some time after they were hidden, since some network filesystems report success without
+// keeping the change. Paths are collected as they're hidden, then checked together in one pass once the last of them
+// has been hidden for long enough, so a whole batch only waits once. Any that didn't stick are hidden again and checked
+// again, up to RETRIES times, before giving up on them with an error.
+#[derive(Debug)]
+pub struct Verifier {
+    delay: Duration,
+    pending: Mutex<Vec<Hidden>>,
+}
+
+// A path that was hidden, along with the path it was hidden as, and when.
+#[derive(Debug)]
+struct Hidden {
+    path: PathBuf,
+    hidden: PathBuf,
+    at: Instant,
+}
+
+impl Verifier {
+    // Build a new verifier that checks paths are still hidden the given delay after they were hidden.
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            pending: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Record that a path was just hidden as the given path, to be checked with the rest of its batch.
+    pub fn record(&self, path: &Path, hidden: PathBuf) {
+        self.pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(Hidden {
+                path: path.to_path_buf(),
+                hidden,
+                at: Instant::now(),
+            });
+    }
+
+    // Returns true if any paths are waiting to be checked.
+    #[cfg_attr(not(feature = "watch"), allow(dead_code))]
+    pub fn waiting(&self) -> bool {
+        !self
+            .pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_empty()
+    }
+
+    // Check every path waiting to be checked, reading its attributes, or looking for its hidden name on Unix, afresh.
+    // It waits until the last of them has been hidden for long enough, then checks them all at once. Every failed check
+    // is counted.
+    pub fn check(&self, ctx: &context::RunContext) {
+        let mut batch =
+            std::mem::take(&mut *self.pending.lock().unwrap_or_else(|e| e.into_inner()));
+        for attempt in 0..=RETRIES {
+            let Some(last) = batch.iter().map(|hidden| hidden.at).max() else {
+                return;
+            };
+            std::thread::sleep(self.delay.saturating_sub(last.elapsed()));
+            let failed: Vec<Hidden> = batch
+                .into_par_iter()
+                .filter(|hidden| !filesystem::verify_hidden(&hidden.hidden).unwrap_or(false))
+                .collect();
+            for _ in &failed {
+                ctx.summary.record_unverified();
+            }
+            if attempt == RETRIES {
+                for hidden in failed {
+                    let e = anyhow!(
+                        "Failed to verify path {} stayed hidden after {} attempts",
+                        hidden.path.display(),
+                        RETRIES + 1
+                    );
+                    ctx.reporter.report(&output::Event::Error(&e));
+                    ctx.summary.record_error(&e);
+                }
+                return;
+            }
+            batch = failed
+                .into_par_iter()
+                .filter_map(|hidden| hide_again(hidden, ctx))
+                .collect();
+        }
+    }
+}
+
+// Helper function to hide a path again because it didn't stay hidden, returning it to be checked again, unless it
+// couldn't be hidden.
+fn hide_again(hidden: Hidden, ctx: &context::RunContext) -> Option<Hidden> {
+    if ctx.verbose {
+        ctx.reporter.report(&output::Event::Info(format!(
+            "Hiding {} again because it didn't stay hidden",
+            hidden.path.display()
+        )));
+    }
+    let info = filesystem::Info::new(hidden.path.clone());
+    match filesystem::hide(&info, ctx.dir_times, ctx.on_conflict) {
+        Ok(filesystem::Change::Changed(changed)) => Some(Hidden {
+            hidden: changed,
+            at: Instant::now(),
+            ..hidden
+        }),
+        Ok(_) => Some(Hidden {
+            at: Instant::now(),
+            ..hidden
+        }),
+        Err(e) => {
+            ctx.reporter.report(&output::Event::Error(&e));
+            ctx.summary.record_error(&e);
+            None
+        }
+    }
+}
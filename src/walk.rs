@@ -0,0 +1,357 @@
+use crate::output::{Event, Reporter};
+use crate::{filesystem, matcher::Prune, mounts::Mounts};
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// A file or folder found while walking, with the root itself at depth 0. Whatever the walk already learned about it
+// is kept, so it doesn't have to be read again. The type is always that of the path itself, even if symlinks are
+// followed, and so is the metadata, which is only kept when symlinks aren't followed.
+#[derive(Debug)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub depth: usize,
+    pub is_dir: bool,
+    pub object_type: Option<filesystem::ObjectType>,
+    pub metadata: Option<std::fs::Metadata>,
+}
+
+// How to walk a root: how far down to go, whether to follow symlinked directories, and which directories to leave out.
+// Everything is owned, so it can be handed to jwalk's threads.
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub follow_symlinks: bool,
+    pub skip_hidden_dirs: bool,
+    pub max_depth: usize,
+    pub prune: Option<Prune>,
+    pub mounts: Option<Arc<Mounts>>,
+    pub verbose: bool,
+    pub reporter: Arc<dyn Reporter>,
+}
+
+impl Options {
+    // Check whether a directory below the root should be walked into. Directories that prune matches aren't, nor are
+    // hidden ones if they're being skipped, and neither are mount points that don't respond, which are returned as an
+    // error instead.
+    fn enter(&self, dir: &Path) -> Result<bool> {
+        if self.skip_hidden_dirs && filesystem::is_hidden(dir).unwrap_or(false) {
+            if self.verbose {
+                self.reporter.report(&Event::Info(format!(
+                    "Skipping everything in {} because it's already hidden",
+                    dir.display()
+                )));
+            }
+            return Ok(false);
+        }
+        if self.prune.as_ref().is_some_and(|prune| prune.prunes(dir)) {
+            if self.verbose {
+                self.reporter.report(&Event::Info(format!(
+                    "Skipping everything in {} because it's all excluded",
+                    dir.display()
+                )));
+            }
+            return Ok(false);
+        }
+        if let Some(mounts) = &self.mounts {
+            mounts.check(dir, false)?;
+        }
+        Ok(true)
+    }
+}
+
+// What to do below a directory jwalk was told not to read, kept alongside its entry
+#[cfg(feature = "walk")]
+#[derive(Debug, Default)]
+enum Below {
+    #[default]
+    Nothing,
+    Walk,
+    Skipped(anyhow::Error),
+}
+
+// Walk the root in parallel on the rayon thread pool using jwalk, down to max_depth levels below it. Symlinked
+// directories, and junctions on Windows, are only walked into if follow_symlinks is set, and directories that are
+// pruned, or are unresponsive mount points, aren't walked into at all. Directories whose paths are too long for jwalk
+// to read are walked one at a time instead, so very deep trees are still walked all the way down.
+#[cfg(feature = "walk")]
+pub fn walk(root: &Path, options: Options) -> impl Iterator<Item = Result<Entry>> {
+    use anyhow::Context;
+    use std::time::Duration;
+
+    // The rayon thread pool can get busy, so try to start iteration continuously until it succeeds.
+    loop {
+        match jwalk::WalkDirGeneric::<((), Below)>::new(root)
+            .follow_links(options.follow_symlinks)
+            .skip_hidden(false)
+            .parallelism(jwalk::Parallelism::RayonDefaultPool {
+                busy_timeout: Duration::from_secs(3),
+            })
+            .max_depth(options.max_depth)
+            .process_read_dir({
+                let options = options.clone();
+                move |_, _, _, children| {
+                    for child in children.iter_mut().flatten() {
+                        if !child.file_type.is_dir() {
+                            continue;
+                        }
+                        let path = child.path();
+                        match options.enter(&path) {
+                            Ok(true) if too_long(&path) => {
+                                child.read_children_path = None;
+                                child.client_state = Below::Walk;
+                            }
+                            Ok(true) => {}
+                            Ok(false) => child.read_children_path = None,
+                            Err(e) => {
+                                child.read_children_path = None;
+                                child.client_state = Below::Skipped(e);
+                            }
+                        }
+                    }
+                }
+            })
+            .try_into_iter()
+        {
+            Ok(iter) => break iter,
+            Err(_) if options.verbose => options.reporter.report(&Event::Notice(format!(
+                "Failed to start iteration on path {}. Retrying...",
+                root.display()
+            ))),
+            Err(_) => continue,
+        };
+    }
+    .flat_map(move |entry| {
+        let mut below = Below::Nothing;
+        let entry = entry
+            .with_context(|| "Failed to get path.")
+            .map(|mut entry| {
+                below = std::mem::take(&mut entry.client_state);
+                let object_type = if entry.path_is_symlink() {
+                    filesystem::link_type(&entry.path())
+                } else {
+                    filesystem::type_of(&entry.path(), entry.file_type())
+                };
+                Entry {
+                    path: entry.path(),
+                    depth: entry.depth(),
+                    is_dir: entry.file_type().is_dir(),
+                    object_type: Some(object_type),
+                    metadata: None,
+                }
+            });
+
+        // Walk below any directory that was too long for jwalk to read, or report why it was skipped.
+        let (walked, skipped) = match (below, &entry) {
+            (Below::Walk, Ok(entry)) if entry.depth < options.max_depth => (
+                Some(walk_below(&entry.path, entry.depth, options.clone())),
+                None,
+            ),
+            (Below::Skipped(e), _) => (None, Some(Err(e))),
+            _ => (None, None),
+        };
+        std::iter::once(entry)
+            .chain(walked.into_iter().flatten())
+            .chain(skipped)
+    })
+}
+
+// Walk the root one directory at a time, down to max_depth levels below it, for builds without jwalk.
+#[cfg(not(feature = "walk"))]
+pub fn walk(root: &Path, options: Options) -> impl Iterator<Item = Result<Entry>> {
+    sequential(vec![Ok((root.to_path_buf(), 0))], options)
+}
+
+// Walk everything below a directory one directory at a time, for directories jwalk can't read.
+#[cfg(feature = "walk")]
+fn walk_below(dir: &Path, depth: usize, options: Options) -> impl Iterator<Item = Result<Entry>> {
+    let mut pending = Vec::new();
+    read_children(&mut pending, dir, depth);
+    sequential(pending, options)
+}
+
+// Walk the pending paths one directory at a time, down to max_depth levels below the root. Symlinked directories, and
+// junctions on Windows, are only walked into if follow_symlinks is set, and then only once each, so a loop can't be
+// walked forever, and directories that are pruned, or are unresponsive mount points, aren't walked into at all. Nothing
+// here recurses, and every path is reached through filesystem::reachable, so there's no limit to how deep a tree can
+// be.
+fn sequential(
+    mut pending: Vec<Result<(PathBuf, usize)>>,
+    options: Options,
+) -> impl Iterator<Item = Result<Entry>> {
+    use anyhow::Context;
+    use std::collections::HashSet;
+    use std::fs;
+
+    let mut visited: HashSet<PathBuf> = HashSet::new();
+
+    std::iter::from_fn(move || {
+        let (path, depth) = match pending.pop()? {
+            Ok(next) => next,
+            Err(e) => return Some(Err(e)),
+        };
+        let metadata = filesystem::reachable(&path).and_then(|reachable| {
+            if options.follow_symlinks {
+                fs::metadata(&*reachable)
+            } else {
+                fs::symlink_metadata(&*reachable)
+            }
+            .with_context(|| format!("Failed to get metadata for {}", path.display()))
+        });
+        let metadata = match metadata {
+            Ok(metadata) => metadata,
+            Err(e) => return Some(Err(e)),
+        };
+        let is_dir = metadata.is_dir();
+
+        // Queue up the entries of a directory, unless it's too deep, has already been walked through a symlink, or
+        // shouldn't be walked into. The root is always walked into.
+        let first_visit = !options.follow_symlinks
+            || fs::canonicalize(&path).map_or(true, |canonical| visited.insert(canonical));
+        if is_dir && depth < options.max_depth && first_visit {
+            match if depth == 0 {
+                Ok(true)
+            } else {
+                options.enter(&path)
+            } {
+                Ok(true) => read_children(&mut pending, &path, depth),
+                Ok(false) => {}
+                Err(e) => pending.push(Err(e)),
+            }
+        }
+
+        let object_type =
+            (!options.follow_symlinks).then(|| filesystem::type_of(&path, metadata.file_type()));
+        Some(Ok(Entry {
+            path,
+            depth,
+            is_dir,
+            object_type,
+            metadata: (!options.follow_symlinks).then_some(metadata),
+        }))
+    })
+}
+
+// Walk only the given paths below the root, such as the ones that changed since the last run, rather than everything
+// in it. Each one is given the depth it would have had if the root had been walked, and is left out if that's too deep,
+// if it's in a directory the walk wouldn't have gone into, or if it doesn't exist any more. Directories other than the
+// root are walked all the way down, since whatever was moved in along with them hasn't changed itself.
+pub fn walk_only(
+    root: &Path,
+    paths: &[PathBuf],
+    options: Options,
+) -> impl Iterator<Item = Result<Entry>> {
+    use std::collections::{BTreeSet, HashMap};
+    use std::io::ErrorKind;
+
+    // Paths sort before everything below them, so any directory that's walked comes before what it already covers
+    let paths: BTreeSet<&PathBuf> = paths.iter().collect();
+    let mut entered: HashMap<PathBuf, bool> = HashMap::new();
+    let mut walked: Vec<&Path> = Vec::new();
+    let mut found: Vec<Box<dyn Iterator<Item = Result<Entry>>>> = Vec::new();
+    for path in paths {
+        let Ok(relative) = path.strip_prefix(root) else {
+            continue;
+        };
+        let depth = relative.components().count();
+        if depth > options.max_depth {
+            continue;
+        }
+
+        // Leave it out if it's somewhere the walk wouldn't have gone, or was already walked through
+        let outside = path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| *dir != root)
+            .any(|dir| {
+                walked.contains(&dir)
+                    || !*entered.entry(dir.to_path_buf()).or_insert_with(|| {
+                        options.enter(dir).unwrap_or_else(|e| {
+                            found.push(Box::new(std::iter::once(Err(e))));
+                            false
+                        })
+                    })
+            });
+        if outside {
+            continue;
+        }
+
+        let metadata = match filesystem::reachable(path)
+            .and_then(|reachable| Ok(reachable.symlink_metadata()?))
+        {
+            Ok(metadata) => metadata,
+            Err(e)
+                if e.downcast_ref::<std::io::Error>()
+                    .is_some_and(|e| e.kind() == ErrorKind::NotFound) =>
+            {
+                continue;
+            }
+            Err(e) => {
+                let e = e.context(format!("Failed to get metadata for {}", path.display()));
+                found.push(Box::new(std::iter::once(Err(e))));
+                continue;
+            }
+        };
+
+        // Walk the directory in place of its entry, with everything in it a level deeper than it
+        if metadata.is_dir() && depth > 0 && depth < options.max_depth {
+            match options.enter(path) {
+                Ok(true) => {
+                    walked.push(path);
+                    let below = Options {
+                        max_depth: options.max_depth - depth,
+                        ..options.clone()
+                    };
+                    found.push(Box::new(walk(path, below).map(move |entry| {
+                        entry.map(|entry| Entry {
+                            depth: entry.depth + depth,
+                            ..entry
+                        })
+                    })));
+                    continue;
+                }
+                Ok(false) => {}
+                Err(e) => found.push(Box::new(std::iter::once(Err(e)))),
+            }
+        }
+        found.push(Box::new(std::iter::once(Ok(Entry {
+            path: path.clone(),
+            depth,
+            is_dir: metadata.is_dir(),
+            object_type: Some(if metadata.is_symlink() {
+                filesystem::link_type(path)
+            } else {
+                filesystem::type_of(path, metadata.file_type())
+            }),
+            metadata: Some(metadata),
+        }))));
+    }
+    found.into_iter().flatten()
+}
+
+// Helper function to queue up the entries of a directory to be walked. If it can't be read, a single error is queued
+// in their place, so the rest of the walk carries on without it.
+fn read_children(pending: &mut Vec<Result<(PathBuf, usize)>>, dir: &Path, depth: usize) {
+    use anyhow::Context;
+    use std::fs;
+
+    let entries = filesystem::reachable(dir).and_then(|reachable| Ok(fs::read_dir(&*reachable)?));
+    match entries {
+        Ok(entries) => pending.extend(entries.map(|entry| {
+            entry
+                .map(|entry| (dir.join(entry.file_name()), depth + 1))
+                .with_context(|| format!("Failed to read entry in {}", dir.display()))
+        })),
+        Err(e) => pending.push(Err(e).with_context(|| {
+            format!(
+                "Failed to read directory {}, so nothing below it was searched",
+                dir.display()
+            )
+        })),
+    }
+}
+
+// Helper function to check if a path is too long for jwalk, which can only read directories through their full paths.
+#[cfg(feature = "walk")]
+fn too_long(path: &Path) -> bool {
+    cfg!(target_os = "linux") && path.as_os_str().len() >= 4096
+}
@@ -1,102 +1,598 @@
-use crate::{filesystem, filter, matcher};
+use crate::control::{Control, ControlCommand};
+use crate::{
+    context, cooldown, dispatch, filesystem, matcher, output, pipeline, search, shard, writing,
+};
 use anyhow::{anyhow, Context, Result};
-use notify::{event, RecommendedWatcher, RecursiveMode, Watcher};
+use clap::ValueEnum;
+use notify::{event, RecursiveMode};
+use serde_json::json;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// How often to print out stats while watching, if they were asked for
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+// How often to retry unresponsive mount points while watching, if mount points are being checked
+const MOUNT_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+// How often to check whether the rules files have changed while watching, if there are any
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+// How long newly created files have to stop growing before they're looked at, when what's been written to them decides
+// whether they're hidden but wait_for_write wasn't given
+const WRITE_WAIT: Duration = Duration::from_secs(1);
+
+// Set when asked to stop watching, by Ctrl+C, SIGTERM, or the Windows service manager
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+// Set when a SIGHUP is received, asking for the rules files to be read again
+#[cfg(target_os = "linux")]
+static HANGUP: AtomicBool = AtomicBool::new(false);
+
+// Enum of backends that can be used to watch for changes. Builds without the native backend poll by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum WatchBackend {
+    #[cfg_attr(feature = "native-watch", default)]
+    Native,
+    #[cfg_attr(not(feature = "native-watch"), default)]
+    Poll,
+}
+
+// Options that only apply when watching
+#[derive(Clone, Copy, Debug)]
+pub struct WatchOptions<'a> {
+    pub priority: Option<&'a matcher::Matcher>,
+    pub poll_interval: Option<Duration>,
+    pub roots_per_shard: Option<usize>,
+    pub rescan_interval: Option<Duration>,
+    pub stats: bool,
+    pub wait_for_write: Option<Duration>,
+    pub failure_cooldown: Duration,
+    pub jobs: usize,
+    pub queue: usize,
+    pub control: Option<&'a Control>,
+    pub print_events: bool,
+}
+
+// Function to watch for changes and hide files and folders from min_depth to max_depth levels below
+// the paths. If roots_per_shard is given, then the paths are split across several watchers with at
+// most that many paths each, and any watcher that fails is restarted on its own. If priority is
+// given, then changes to paths it matches are handled before any others that are waiting. Changes
+// inside symlinked directories are ignored unless follow_symlinks is set. If a poll interval is
+// given, then the paths are polled for changes rather than using the native backend for the
+// platform. Watching stops once Ctrl+C or SIGTERM is received, after any events still being handled
+// have finished. If a rescan interval is given, then the paths are also searched again periodically
+// to catch any events that were dropped. If dir_times is given, then directory times are restored
+// whenever the watcher goes idle. If stats is set, then they are printed out periodically. If
+// wait_for_write is given, then newly created files aren't looked at until their size has been stable
+// for that long, or they have been closed after writing, on platforms that report it. Any still being
+// written when watching stops are left to the next search. Files are waited on for WRITE_WAIT even if it isn't
+// given, when the filters depend on what's been written to them, such as their size. Paths that
+// fail to be hidden several times in a row are left alone for failure_cooldown, and listed as
+// problem paths when watching stops. At most jobs events are handled at once, so a burst of changes
+// can't starve searches of threads, and at most queue events wait to be handled. Once that many are
+// waiting, no more are read until there's room, which holds back the watchers themselves. Events
+// for a path that's already waiting are dropped, since handling it reads the path as it is then. If control is given,
+// then commands sent to it are answered between events, and can pause and resume hiding, or ask for a rescan. Changes
+// made while paused are ignored, and everything is searched again once resumed. If print_events is set, then every
+// event is written out exactly as it came from the watchers, before anything is done with it.
+pub fn watch(paths: &[String], ctx: &context::RunContext, options: &WatchOptions) -> Result<()> {
+    // Set a flag when asked to shut down. If asked again while shutting down, then exit immediately.
+    let handler_reporter = Arc::clone(ctx.reporter);
+    ctrlc::set_handler(move || {
+        if stop() {
+            std::process::exit(130);
+        }
+        handler_reporter.report(&output::Event::Notice("Shutting down...".to_owned()));
+    })
+    .with_context(|| "Failed to set shutdown handler")?;
+    on_hangup()?;
+
+    // Only run one rescan at a time, in case a rescan takes longer than the interval, and likewise for checking that
+    // what was hidden stayed hidden.
+    let rescanning = AtomicBool::new(false);
+    let verifying = AtomicBool::new(false);
+    let mut last_rescan = Instant::now();
+    let mut last_stats = Instant::now();
+    let mut last_mount_retry = Instant::now();
+    let mut last_reload_check = Instant::now();
+    let started = Instant::now();
+    let mut paused = false;
+    let mut received: u64 = 0;
+
+    // The watched paths, both as given and canonicalized, to work out how deep the paths in events are.
+    let roots: Vec<PathBuf> = paths
+        .iter()
+        .flat_map(|path| {
+            [
+                Some(PathBuf::from(path)),
+                Path::new(path).canonicalize().ok(),
+            ]
+        })
+        .flatten()
+        .collect();
+
+    // Paths that keep failing to be hidden, so they can be left alone for a while.
+    let cooldown = cooldown::Cooldown::new(options.failure_cooldown);
+
+    // Events waiting to be handled, in order of priority.
+    let dispatcher: dispatch::Dispatcher<PathBuf, notify::Event> =
+        dispatch::Dispatcher::new(options.queue, options.jobs);
+
+    // Newly created files waiting for writes to finish, along with the events they were created by, and whether their
+    // sizes are being checked.
+    let writing = options
+        .wait_for_write
+        .or_else(|| reads_contents(ctx).then_some(WRITE_WAIT))
+        .map(writing::Writing::new);
+    let checking = AtomicBool::new(false);
+
+    // Everything handled while watching shares the cooldown, and is only ever hidden.
+    let ctx = &context::RunContext {
+        unhide: false,
+        cooldown: Some(&cooldown),
+        ..*ctx
+    };
 
-// Function to watch for changes and hide files and folders
-pub fn watch(
-    paths: &[String],
-    matcher: &matcher::Matcher,
-    types: Option<&[filesystem::ObjectType]>,
-    recursive: bool,
-    test: bool,
-    verbose: bool,
-) -> Result<()> {
     rayon::scope(|s| {
-        // Open a channel to receive events from the watcher
-        let (tx, rx) = std::sync::mpsc::channel();
-
-        // Create a new watcher
-        let mut watcher: RecommendedWatcher = Watcher::new(tx, notify::Config::default())
-            .with_context(|| {
-                "Failed to create new watcher. Make sure you have the required permissions."
-            })?;
-
-        // Add the paths to watch to the watcher
-        for path in paths {
-            watcher
-                .watch(
-                    Path::new(path),
-                    if recursive {
-                        RecursiveMode::Recursive
-                    } else {
-                        RecursiveMode::NonRecursive
+        // Open a channel to receive events from the watchers. It's bounded, so the watchers wait while it's full.
+        let (tx, rx) = std::sync::mpsc::sync_channel(options.queue);
+
+        // Start watching the paths, spread across as many shards as needed
+        let mut supervisor = shard::Supervisor::new(
+            paths,
+            options.roots_per_shard,
+            tx,
+            options.poll_interval,
+            if ctx.max_depth > 1 {
+                RecursiveMode::Recursive
+            } else {
+                RecursiveMode::NonRecursive
+            },
+            Arc::clone(ctx.reporter),
+        )?;
+
+        // Queue an event up in the high priority lane if any of its paths match a priority pattern, keyed by the path
+        // it's for. Then spawn a worker to handle the waiting events, unless there are already as many as there can be.
+        let dispatch = |event: notify::Event| {
+            let high = options.priority.is_some_and(|priority| {
+                event.paths.iter().any(|path| priority.matches(path).result)
+            });
+            let key = get_path(&event).and_then(Result::ok).cloned();
+            if !dispatcher.push(key, event, high) {
+                return;
+            }
+
+            let roots = &roots;
+            let dispatcher = &dispatcher;
+            s.spawn(move |_| {
+                while let Some(event) = dispatcher.pop() {
+                    handle_event(&event, ctx, roots);
+                }
+            });
+        };
+
+        // Begin looping through the events received from the watcher until asked to shut down. Wake up
+        // periodically to check the flag. Returning from the scope waits on any events still being handled.
+        while !SHUTDOWN.load(Ordering::Relaxed) {
+            // Answer any commands sent to the control socket.
+            let mut rescan_now = false;
+            if let Some(control) = options.control {
+                control.serve(|command| match command {
+                    ControlCommand::Status => json!({
+                        "uptime_secs": started.elapsed().as_secs_f64(),
+                        "paused": paused,
+                        "roots": paths,
+                        "events": received,
+                        "queued": dispatcher.waiting(),
+                        "counts": ctx.summary.counts(),
+                        "latency": ctx.summary.latencies(),
+                    }),
+                    ControlCommand::Pause => {
+                        paused = true;
+                        json!({ "message": "Paused, so nothing will be hidden until resumed" })
+                    }
+                    ControlCommand::Resume if paused => {
+                        paused = false;
+                        rescan_now = true;
+                        json!({ "message": "Resumed, and searching for anything changed while paused" })
+                    }
+                    ControlCommand::Resume => json!({ "error": "Not paused" }),
+                    ControlCommand::Rescan if paused => json!({ "error": "Can't rescan while paused" }),
+                    ControlCommand::Rescan => {
+                        rescan_now = true;
+                        json!({ "message": "Rescanning watched paths" })
+                    }
+                    ControlCommand::Reload => match reload(ctx) {
+                        Ok(()) => {
+                            rescan_now = !paused;
+                            json!({ "message": "Reloaded rules files" })
+                        }
+                        Err(e) => json!({ "error": format!("{e:#}") }),
                     },
-                )
-                .with_context(|| {
-                    format!(
-                        "Failed to watch path {path}. Make sure you have the required permissions"
-                    )
-                })?;
-        }
+                });
+            }
+
+            // Read the rules files again if they've changed or a SIGHUP asked for it, and search everything again with
+            // the new rules. If they can't be read, then the old rules are kept until they change again.
+            let changed = ctx.root_rules.is_some()
+                && last_reload_check.elapsed() >= RELOAD_CHECK_INTERVAL
+                && {
+                    last_reload_check = Instant::now();
+                    ctx.root_rules
+                        .is_some_and(|root_rules| root_rules.changed())
+                };
+            if changed || hung_up() {
+                match reload(ctx) {
+                    Ok(()) => {
+                        ctx.reporter.report(&output::Event::Notice(
+                            "Reloaded rules files, rescanning watched paths...".to_owned(),
+                        ));
+                        rescan_now |= !paused;
+                    }
+                    Err(e) => ctx.reporter.report(&output::Event::Notice(format!(
+                        "Failed to reload rules files, so the old rules are still used: {e:#}"
+                    ))),
+                }
+            }
 
-        // Begin looping infinitely through the events received from the watcher
-        loop {
-            let event = rx.recv().with_context(|| "Critical error in watcher")?;
+            // Start a rescan on the rayon thread pool if one is due or was asked for, and the last one has finished.
+            if rescan_now
+                || !paused
+                    && options
+                        .rescan_interval
+                        .is_some_and(|interval| last_rescan.elapsed() >= interval)
+            {
+                last_rescan = Instant::now();
+                if !rescanning.swap(true, Ordering::Relaxed) {
+                    let rescanning = &rescanning;
+                    s.spawn(move |_| {
+                        if ctx.verbose {
+                            ctx.reporter.report(&output::Event::Info(
+                                "Rescanning watched paths...".to_owned(),
+                            ));
+                        }
+                        search::search(paths, ctx);
+                        rescanning.store(false, Ordering::Relaxed);
+                    });
+                }
+            }
 
-            // If the event is an error, print it out and continue to the next event, otherwise
-            // pass the event to the rayon thread pool to handle.
-            match event {
-                Ok(event) => {
+            // Retry any unresponsive mount points now and then, and search again once one of them responds. This
+            // shares the rescan flag, so it never runs alongside a rescan.
+            if ctx.mounts.is_some() && last_mount_retry.elapsed() >= MOUNT_RETRY_INTERVAL {
+                last_mount_retry = Instant::now();
+                if !rescanning.swap(true, Ordering::Relaxed) {
+                    let rescanning = &rescanning;
                     s.spawn(move |_| {
-                        handle_event(&event, matcher, types, test, verbose);
+                        if ctx.mounts.is_some_and(|mounts| mounts.recovered()) {
+                            ctx.reporter.report(&output::Event::Notice(
+                                "A mount point is responding again, rescanning watched paths..."
+                                    .to_owned(),
+                            ));
+                            search::search(paths, ctx);
+                        }
+                        rescanning.store(false, Ordering::Relaxed);
                     });
                 }
-                Err(e) => eprintln!("{e}"),
+            }
+
+            // Restart any shards that have failed, and search their paths again to catch anything missed while
+            // they were down.
+            for index in supervisor.restart_failed() {
+                let shard_paths = supervisor.paths(index).to_vec();
+                if ctx.verbose {
+                    ctx.reporter.report(&output::Event::Info(format!(
+                        "Restarted shard {index}, rescanning its paths..."
+                    )));
+                }
+                s.spawn(move |_| {
+                    search::search(&shard_paths, ctx);
+                });
+            }
+
+            // Print out stats if they're due
+            if options.stats && last_stats.elapsed() >= STATS_INTERVAL {
+                last_stats = Instant::now();
+                ctx.reporter.report(&output::Event::Stats(ctx.summary));
+                supervisor.report_status();
+                cooldown.report_problems(ctx.reporter.as_ref());
+            }
+
+            // Check that everything hidden since the last check stayed hidden, away from the loop, since it waits until
+            // the last of it has been hidden for long enough.
+            if let Some(verifier) = ctx.verifier.filter(|verifier| verifier.waiting()) {
+                if !verifying.swap(true, Ordering::Relaxed) {
+                    let verifying = &verifying;
+                    s.spawn(move |_| {
+                        verifier.check(ctx);
+                        verifying.store(false, Ordering::Relaxed);
+                    });
+                }
+            }
+
+            // Check the sizes of files waiting for writes to finish away from the loop, and hand any that have finished
+            // on to be handled.
+            if let Some(writing) = &writing {
+                if writing.due() && !checking.swap(true, Ordering::Relaxed) {
+                    let checking = &checking;
+                    s.spawn(move |_| {
+                        writing.check(ctx.verbose, ctx.reporter.as_ref());
+                        checking.store(false, Ordering::Relaxed);
+                    });
+                }
+                for event in writing.finished() {
+                    dispatch(event);
+                }
+            }
+
+            // Stop reading events while too many are waiting to be handled, until there's room again.
+            if !dispatcher.wait_for_room(Duration::from_millis(100)) {
+                continue;
+            }
+
+            let (index, event) = match rx.recv_timeout(Duration::from_millis(100)) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => {
+                    finish_batch(ctx);
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(anyhow!("Critical error in watcher"))
+                }
+            };
+            received += 1;
+
+            // If the event is an error, print it out, stop the shard it came from so it can be restarted,
+            // and continue to the next event. Otherwise pass the event to the rayon thread pool to handle, unless
+            // paused.
+            if let (true, Ok(event)) = (options.print_events, &event) {
+                ctx.reporter.report(&raw(index, event));
+            }
+
+            // Poll instead if the system has run out of watches for a folder that was just made or moved in, and
+            // search the shard's paths again to catch anything missed below it.
+            if let Ok(event) = &event {
+                if supervisor.exhausted(index, event) {
+                    let shard_paths = supervisor.paths(index).to_vec();
+                    s.spawn(move |_| {
+                        search::search(&shard_paths, ctx);
+                    });
+                }
+            }
+            match event {
+                Ok(_) if paused => {}
+                Ok(event) => {
+                    // Let any file waiting on writes know that it has been closed after writing.
+                    if matches!(
+                        event.kind,
+                        event::EventKind::Access(event::AccessKind::Close(
+                            event::AccessMode::Write
+                        ))
+                    ) {
+                        if let Some(writing) = &writing {
+                            for path in &event.paths {
+                                writing.closed(path);
+                            }
+                        }
+                        continue;
+                    }
+
+                    // Wait for writes to newly created files to finish before handling them, so everything that decides
+                    // whether they're hidden sees them as they end up.
+                    if let Some(writing) = writing
+                        .as_ref()
+                        .filter(|_| matches!(event.kind, event::EventKind::Create(_)))
+                    {
+                        if let Some(path) = get_path(&event).and_then(Result::ok).cloned() {
+                            writing.start(path, event);
+                            continue;
+                        }
+                    }
+                    dispatch(event);
+                }
+                Err(e) => {
+                    ctx.reporter.report(&output::Event::Error(&e));
+                    ctx.summary.record_error(&e);
+                    supervisor.fail(index);
+                }
             }
         }
-    })
+        Ok(())
+    })?;
+
+    // Finish off anything changed since the watcher last went idle, and check anything hidden since the last check.
+    finish_batch(ctx);
+    if let Some(verifier) = ctx.verifier {
+        verifier.check(ctx);
+    }
+
+    // List the paths that kept failing, rather than leaving them lost among the errors.
+    cooldown.report_problems(ctx.reporter.as_ref());
+    Ok(())
+}
+
+// Helper function to read the rules files again, if there are any.
+fn reload(ctx: &context::RunContext) -> Result<()> {
+    match ctx.root_rules {
+        Some(root_rules) => root_rules.reload(),
+        None => Err(anyhow!("There are no --path-rules files to reload")),
+    }
+}
+
+// Ask the watcher to stop, once the events it's handling have finished. Returns true if it had already been asked.
+pub fn stop() -> bool {
+    SHUTDOWN.swap(true, Ordering::Relaxed)
+}
+
+// Helper function to reload the rules files on SIGHUP, rather than exiting, as is usual for daemons.
+#[cfg(target_os = "linux")]
+fn on_hangup() -> Result<()> {
+    use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+    extern "C" fn handler(_: nix::libc::c_int) {
+        HANGUP.store(true, Ordering::Relaxed);
+    }
+    let action = SigAction::new(
+        SigHandler::Handler(handler),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    unsafe { sigaction(Signal::SIGHUP, &action) }
+        .map(|_| ())
+        .with_context(|| "Failed to set reload handler")
+}
+
+// SIGHUP is only handled on Linux so far.
+#[cfg(not(target_os = "linux"))]
+fn on_hangup() -> Result<()> {
+    Ok(())
+}
+
+// Helper function to check whether a SIGHUP was received since this was last checked.
+#[cfg(target_os = "linux")]
+fn hung_up() -> bool {
+    HANGUP.swap(false, Ordering::Relaxed)
+}
+
+// SIGHUP is only handled on Linux so far.
+#[cfg(not(target_os = "linux"))]
+fn hung_up() -> bool {
+    false
 }
 
 // Helper function for the watch function that is run on the rayon thread pool. It does the actual
 // handling of the events.
-fn handle_event(
-    event: &notify::Event,
-    matcher: &matcher::Matcher,
-    types: Option<&[filesystem::ObjectType]>,
-    test: bool,
-    verbose: bool,
-) {
+fn handle_event(event: &notify::Event, ctx: &context::RunContext, roots: &[PathBuf]) {
     // Get the path from the event. If an event is not one that is supposed to be handled, then
     // return early. If the path is not found, then print out an error and return early.
     let path = match get_path(event) {
         Some(Ok(path)) => path,
         Some(Err(e)) => {
-            eprintln!("{e}");
+            ctx.reporter.report(&output::Event::Error(&e));
+            ctx.summary.record_error(&e);
             return;
         }
         None => return,
     };
+    ctx.summary.record_scanned();
+
+    // Find the closest of the watched paths that the path is in.
+    let root = pipeline::root_of(path, roots);
+
+    // Show a folder hidden for being empty again, now that something has been made in it, and carry on with the path
+    // as it is inside the folder once it's unhidden.
+    let mut moved = None;
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| ctx.empty.is_some_and(|empty| empty.filled(parent)))
+    {
+        let unhide = context::RunContext {
+            unhide: true,
+            exemptions: None,
+            ..*ctx
+        };
+        if ctx.verbose {
+            ctx.reporter.report(&output::Event::Info(format!(
+                "Unhiding {} now that it isn't empty",
+                parent.display()
+            )));
+        }
+        let original = ctx.journal.and_then(|journal| journal.original(parent));
+        let unhidden = pipeline::hide(
+            &filesystem::Info::new(parent.to_path_buf()),
+            pipeline::root_of(parent, roots),
+            &unhide,
+        );
+        if let Some(file_name) = path.file_name().filter(|_| unhidden) {
+            moved = Some(filesystem::unhidden_as(parent, original.as_deref()).join(file_name));
+        }
+    }
+    let path = moved.as_ref().unwrap_or(path);
 
-    // Check if the path matches the types of objects to hide.
-    if !filter::file_type_matches(path, types, verbose) {
+    // The watcher always follows symlinks, so ignore anything found through one unless they're being followed.
+    if !ctx.follow_symlinks && through_symlink(path, root) {
+        if ctx.verbose {
+            ctx.reporter.report(&output::Event::Skipped {
+                path,
+                because: "it's inside a symlinked directory".to_owned(),
+            });
+        }
         return;
     }
 
-    // Check if the path matches the matcher.
-    if !filter::path_matches_pattern(path, matcher, verbose) {
+    // Run the path through the shared pipeline to decide whether to hide it, and what to hide.
+    let info = filesystem::Info::new(path.to_path_buf());
+    let Some(info) = pipeline::process(info, root, depth(path, root), None, ctx) else {
         return;
+    };
+
+    // Hide the path, or print out what would be hidden if the test flag is set.
+    pipeline::hide(&info, root, ctx);
+}
+
+// Helper function to check whether anything that decides if a path is hidden depends on what's been written to it, so
+// newly created files need to be finished first.
+fn reads_contents(ctx: &context::RunContext) -> bool {
+    ctx.min_size.is_some() || ctx.max_size.is_some() || ctx.mimes.is_some() || ctx.scanner.is_some()
+}
+
+// Helper function to describe an event exactly as it came from a watcher, along with anything else its backend said
+// about it.
+fn raw(shard: usize, event: &notify::Event) -> output::Event<'_> {
+    let mut flags = Vec::new();
+    if event.flag() == Some(event::Flag::Rescan) {
+        flags.push("rescan".to_owned());
+    }
+    if let Some(tracker) = event.tracker() {
+        flags.push(format!("tracker={tracker}"));
+    }
+    if let Some(info) = event.info() {
+        flags.push(format!("info={info}"));
+    }
+    if let Some(source) = event.source() {
+        flags.push(format!("source={source}"));
     }
+    output::Event::Raw {
+        shard,
+        kind: format!("{:?}", event.kind),
+        paths: &event.paths,
+        flags,
+    }
+}
 
-    // If the test flag is set, then print out the path of the file or folder to hide.
-    // Otherwise, hide the file or folder.
-    if test {
-        println!("Would hide {}", path.display());
-    } else {
-        if verbose {
-            println!("Hiding {}", path.display());
-        }
-        filesystem::hide(path).unwrap_or_else(|e| eprintln!("{e}"));
+// Helper function to get the depth of a path below the watched path it's in, if any.
+fn depth(path: &Path, root: Option<&Path>) -> Option<usize> {
+    root.and_then(|root| path.strip_prefix(root).ok())
+        .map(|rest| rest.components().count())
+}
+
+// Helper function to check if a path is inside a symlinked directory below the watched path it's in.
+fn through_symlink(path: &Path, root: Option<&Path>) -> bool {
+    let Some(root) = root else {
+        return false;
+    };
+    path.ancestors()
+        .skip(1)
+        .take_while(|ancestor| *ancestor != root)
+        .any(|ancestor| ancestor.is_symlink())
+}
+
+// Helper function to finish off what was changed since the watcher last went idle. Folders that moving things into the
+// vault left empty are hidden, then the times of directories that have had entries renamed in them are restored, if
+// they are being preserved.
+fn finish_batch(ctx: &context::RunContext) {
+    if let Some(vault) = ctx.vault {
+        vault.prune(ctx);
+    }
+    if let Some(dir_times) = ctx.dir_times {
+        dir_times.restore().unwrap_or_else(|e| {
+            ctx.reporter.report(&output::Event::Error(&e));
+            ctx.summary.record_error(&e);
+        });
     }
 }
 
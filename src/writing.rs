@@ -0,0 +1,148 @@
+use crate::filesystem::{Info, ObjectType};
+use crate::output::{Event, Reporter};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// A file waiting for writes to it to finish, along with whatever is handed on once they have, and its size when it was
+// last checked
+#[derive(Debug)]
+struct Pending<T> {
+    item: T,
+    size: Option<u64>,
+    stable_since: Instant,
+}
+
+// Keeps track of newly created files until writes to them have finished, so they're only looked at once they're
+// complete. A file is finished once it's been closed after writing, on platforms that report it, or once its size has
+// stayed the same for stable_for. Nothing ever waits in here: the sizes are checked whenever check is called, and
+// whatever was kept alongside the files that have finished is taken back out with finished.
+#[derive(Debug)]
+pub struct Writing<T> {
+    stable_for: Duration,
+    pending: Mutex<HashMap<PathBuf, Pending<T>>>,
+    finished: Mutex<Vec<T>>,
+    last_check: Mutex<Instant>,
+}
+
+impl<T> Writing<T> {
+    // Build a new tracker, which counts files as finished once their size has been stable for the given duration.
+    pub fn new(stable_for: Duration) -> Self {
+        Self {
+            stable_for,
+            pending: Mutex::new(HashMap::new()),
+            finished: Mutex::new(Vec::new()),
+            last_check: Mutex::new(Instant::now()),
+        }
+    }
+
+    // Start waiting for writes to a file to finish. If it was already being waited on, then the wait starts over.
+    pub fn start(&self, path: PathBuf, item: T) {
+        self.pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(
+                path,
+                Pending {
+                    item,
+                    size: None,
+                    stable_since: Instant::now(),
+                },
+            );
+    }
+
+    // Count a file as finished, now that it's been closed after writing. Returns false if it wasn't being waited on.
+    pub fn closed(&self, path: &Path) -> bool {
+        let closed = self
+            .pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(path);
+        match closed {
+            Some(pending) => {
+                self.finished
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push(pending.item);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Returns true if the sizes are due to be checked again. They're checked a few times within stable_for, so each
+    // file is waited on for roughly that long.
+    pub fn due(&self) -> bool {
+        let interval = (self.stable_for / 4).max(Duration::from_millis(10));
+        !self
+            .pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_empty()
+            && self
+                .last_check
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .elapsed()
+                >= interval
+    }
+
+    // Check the size of every file being waited on, starting the clock over for any whose size has changed. Anything
+    // that turns out not to be a file, or can't be checked, counts as finished, and is left to whatever handles it
+    // next. The files are checked without holding the lock, so more can be started or closed in the meantime.
+    pub fn check(&self, verbose: bool, reporter: &dyn Reporter) {
+        *self.last_check.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+        let paths: Vec<PathBuf> = self
+            .pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .keys()
+            .cloned()
+            .collect();
+
+        for path in paths {
+            let info = Info::new(path);
+            let size = match info.object_type() {
+                Ok(ObjectType::File) => info.size().ok(),
+                _ => None,
+            };
+
+            let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+            let Some(file) = pending.get_mut(info.path()) else {
+                continue;
+            };
+            let finished = match size {
+                Some(size) if file.size == Some(size) => {
+                    file.stable_since.elapsed() >= self.stable_for
+                }
+                Some(size) => {
+                    if verbose && file.size.is_none() {
+                        reporter.report(&Event::Info(format!(
+                            "Waiting for writes to {} to finish",
+                            info.path().display()
+                        )));
+                    }
+                    file.size = Some(size);
+                    file.stable_since = Instant::now();
+                    false
+                }
+                None => true,
+            };
+            if !finished {
+                continue;
+            }
+            if let Some(file) = pending.remove(info.path()) {
+                self.finished
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push(file.item);
+            }
+        }
+    }
+
+    // Take whatever was kept alongside the files that have finished being written since this was last called.
+    pub fn finished(&self) -> Vec<T> {
+        std::mem::take(&mut *self.finished.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}